@@ -1,7 +1,7 @@
 //! Benchmarks for the micromail crate
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use micromail::{Config, Mail, Mailer};
+use micromail::{Attachment, Config, Mail, Mailer};
 
 fn bench_mail_format(c: &mut Criterion) {
     let config = Config::new("example.com");
@@ -18,6 +18,31 @@ fn bench_mail_format(c: &mut Criterion) {
     });
 }
 
+fn bench_mail_format_into(c: &mut Criterion) {
+    let config = Config::new("example.com");
+    let mail = Mail::new()
+        .from("sender@example.com")
+        .to("recipient@example.com")
+        .subject("Test Subject")
+        .body("A longer line of body text\n".repeat(4096))
+        .attach(Attachment::new("data.bin", vec![0x5a; 256 * 1024]));
+
+    c.bench_function("mail_format_large", |b| {
+        b.iter(|| {
+            black_box(mail.format(&config));
+        });
+    });
+    // Reuses one buffer, as a sender formatting mail after mail would
+    let mut buffer = String::new();
+    c.bench_function("mail_format_into_large", |b| {
+        b.iter(|| {
+            buffer.clear();
+            mail.format_into(&mut buffer, &config).unwrap();
+            black_box(&buffer);
+        });
+    });
+}
+
 fn bench_mail_creation(c: &mut Criterion) {
     c.bench_function("mail_creation", |b| {
         b.iter(|| {
@@ -59,6 +84,7 @@ fn bench_mailer_creation(c: &mut Criterion) {
 criterion_group!(
     benches,
     bench_mail_format,
+    bench_mail_format_into,
     bench_mail_creation,
     bench_config_creation,
     bench_mailer_creation,
@@ -85,19 +85,21 @@ int main() {
     if (micromail_mailer_send(mailer, mail) == 0) {
         printf("Email sent successfully!\n");
         
-        // Get the log
-        char* log = micromail_mailer_get_log(mailer);
-        if (log) {
-            printf("\nLog:\n%s\n", log);
-            micromail_free_string(log); // Free the log string
-        } else {
-            const char* error = micromail_get_last_error();
-            if (error) { // error might be null if CString creation failed for the log itself
-                printf("Failed to get log: %s\n", error);
-            } else {
-                printf("Failed to get log (unknown error, CString creation might have failed).\n");
+        // Print the protocol trace
+        printf("\nLog:\n");
+        int count = micromail_mailer_log_count(mailer);
+        for (int i = 0; i < count; i++) {
+            LogEntry entry;
+            if (micromail_mailer_log_entry_at(mailer, i, &entry) != 0) {
+                printf("Failed to get log entry %d: %s\n", i, micromail_get_last_error());
+                break;
             }
+            const char* arrow = entry.direction == MICROMAIL_LOG_SENT ? "C:" : entry.direction == MICROMAIL_LOG_RECEIVED ? "S:" : "  ";
+            printf("%s %s\n", arrow, entry.text);
+            micromail_free_string(entry.text);
         }
+    } else if (micromail_get_last_error_code() == MICROMAIL_SMTP_4XX) {
+        printf("Server deferred the email (%d), try again later: %s\n", micromail_get_last_smtp_code(), micromail_get_last_error());
     } else {
         printf("Failed to send email: %s\n", micromail_get_last_error());
     }
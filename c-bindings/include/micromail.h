@@ -8,6 +8,70 @@
 extern "C" {
 #endif
 
+/**
+ * Category of the last error, see micromail_get_last_error_code.
+ * The values are stable, new categories are only ever added at the end.
+ */
+typedef enum ErrorCode {
+    /** The last call succeeded */
+    MICROMAIL_OK = 0,
+    /** A null pointer or a string that is not UTF-8 was passed in */
+    MICROMAIL_INVALID_ARGUMENT = 1,
+    /** The recipient domain has no MX (or address) record, or a null MX */
+    MICROMAIL_NO_MX = 2,
+    /** No MX server could be reached, or the connection broke */
+    MICROMAIL_CONN_FAILED = 3,
+    /** The server answered with a temporary (4xx) failure, retrying later may succeed */
+    MICROMAIL_SMTP_4XX = 4,
+    /** The server answered with a permanent (5xx) failure */
+    MICROMAIL_SMTP_5XX = 5,
+    /** The server rejected the credentials */
+    MICROMAIL_AUTH = 6,
+    /** TLS negotiation failed */
+    MICROMAIL_TLS = 7,
+    /** A timeout or stall limit was hit */
+    MICROMAIL_TIMEOUT = 8,
+    /** DNS resolution failed */
+    MICROMAIL_DNS = 9,
+    /** The mail cannot be sent as it is */
+    MICROMAIL_INVALID_MAIL = 10,
+    /** The recipient is excluded by the recipient policy of the config */
+    MICROMAIL_RECIPIENT_BLOCKED = 11,
+    /** The config contains contradictory settings */
+    MICROMAIL_INVALID_CONFIG = 12,
+    /** A mail provider's HTTP API refused the message */
+    MICROMAIL_HTTP_API = 13,
+    /** DKIM signing failed */
+    MICROMAIL_SIGNING = 14,
+    /** Anything else */
+    MICROMAIL_OTHER = 15,
+} ErrorCode;
+
+/**
+ * Which way a transcript entry went, see micromail_mailer_log_entry_at
+ */
+typedef enum LogDirection {
+    /** Note of the client itself: DNS results, connection, TLS */
+    MICROMAIL_LOG_INFO = 0,
+    /** Command or message content sent to the server */
+    MICROMAIL_LOG_SENT = 1,
+    /** Reply received from the server */
+    MICROMAIL_LOG_RECEIVED = 2,
+} LogDirection;
+
+/**
+ * One entry of a Mailer's transcript, filled in by micromail_mailer_log_entry_at
+ */
+typedef struct LogEntry {
+    LogDirection direction;
+    /** Reply code of a received entry, 0 for the others */
+    int code;
+    /** The entry's lines joined with newlines, to be freed with micromail_free_string */
+    char* text;
+    /** When the entry was recorded, in milliseconds since the UNIX epoch */
+    int64_t timestamp_ms;
+} LogEntry;
+
 /**
  * Opaque pointer to a Config object
  */
@@ -141,6 +205,43 @@ int micromail_mail_set_body(MailPtr mail, const char* body);
  */
 int micromail_mail_add_header(MailPtr mail, const char* name, const char* value);
 
+/**
+ * Add a To recipient to a Mail object, after the ones already set
+ *
+ * @param mail Pointer to the Mail
+ * @param to To address
+ * @return int 0 on success, -1 on error
+ */
+int micromail_mail_add_to(MailPtr mail, const char* to);
+
+/**
+ * Add a Cc recipient to a Mail object
+ *
+ * @param mail Pointer to the Mail
+ * @param cc Cc address
+ * @return int 0 on success, -1 on error
+ */
+int micromail_mail_add_cc(MailPtr mail, const char* cc);
+
+/**
+ * Add a Bcc recipient to a Mail object, who gets the mail without appearing in its headers
+ *
+ * @param mail Pointer to the Mail
+ * @param bcc Bcc address
+ * @return int 0 on success, -1 on error
+ */
+int micromail_mail_add_bcc(MailPtr mail, const char* bcc);
+
+/**
+ * Set the envelope sender (MAIL FROM) of a Mail object, e.g. a bounce address,
+ * instead of its From address
+ *
+ * @param mail Pointer to the Mail
+ * @param address Envelope sender address
+ * @return int 0 on success, -1 on error
+ */
+int micromail_mail_set_envelope_from(MailPtr mail, const char* address);
+
 /**
  * Send a mail using a Mailer
  * 
@@ -151,14 +252,49 @@ int micromail_mail_add_header(MailPtr mail, const char* name, const char* value)
 int micromail_mailer_send(MailerPtr mailer, MailPtr mail);
 
 /**
- * Get the last error message
- * 
- * @return const char* Error message
+ * Get the message of the last error on this thread
+ *
+ * The string is owned by the library and stays valid until the next call on this
+ * thread that can fail, or micromail_clear_last_error. Use micromail_copy_last_error
+ * to keep it around.
+ *
+ * @return const char* Error message, an empty string if the last call succeeded
  */
 const char* micromail_get_last_error();
 
 /**
- * Get the log messages from a Mailer
+ * Copy the message of the last error on this thread into a caller-provided buffer
+ *
+ * The message is truncated to fit and always NUL-terminated when len > 0.
+ *
+ * @param buf Buffer to copy into, may be NULL if len is 0
+ * @param len Size of the buffer in bytes, including the terminating NUL
+ * @return int Full length of the message without the NUL; len or more means it was truncated
+ */
+int micromail_copy_last_error(char* buf, size_t len);
+
+/**
+ * Forget the last error on this thread, so the getters report success again
+ */
+void micromail_clear_last_error();
+
+/**
+ * Get the category of the last error on this thread
+ *
+ * @return ErrorCode MICROMAIL_OK if the last call succeeded
+ */
+ErrorCode micromail_get_last_error_code();
+
+/**
+ * Get the SMTP reply code of the server answer behind the last error
+ *
+ * @return int Reply code (e.g. 550), 0 if the error did not come from a server reply
+ */
+int micromail_get_last_smtp_code();
+
+/**
+ * Get the log messages from a Mailer as one string. micromail_mailer_log_entry_at
+ * gives the same transcript with direction, reply code and time of each entry.
  * 
  * @param mailer Pointer to the Mailer
  * @return char* Log messages (must be freed with micromail_free_string)
@@ -166,7 +302,25 @@ const char* micromail_get_last_error();
 char* micromail_mailer_get_log(MailerPtr mailer);
 
 /**
- * Free a string returned by micromail_mailer_get_log
+ * Get the number of entries in the transcript of the Mailer's last send
+ *
+ * @param mailer Pointer to the Mailer
+ * @return int Number of entries, -1 on error
+ */
+int micromail_mailer_log_count(MailerPtr mailer);
+
+/**
+ * Get one entry of the transcript of the Mailer's last send
+ *
+ * @param mailer Pointer to the Mailer
+ * @param index Index of the entry, from 0 to micromail_mailer_log_count() - 1
+ * @param entry Filled in with the entry; free entry->text with micromail_free_string
+ * @return int 0 on success, -1 on error
+ */
+int micromail_mailer_log_entry_at(MailerPtr mailer, int index, LogEntry* entry);
+
+/**
+ * Free a string returned by micromail_mailer_get_log or the text of a LogEntry
  *
  * @param str Pointer to the string to free
  */
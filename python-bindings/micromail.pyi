@@ -0,0 +1,108 @@
+"""Type stubs for the micromail extension module
+
+maturin ships this file with the wheel, keep it in sync with src/python_api.rs.
+"""
+
+from typing import Any, Awaitable, Dict, List, Optional, Tuple, TypedDict, Union
+
+class MicromailError(RuntimeError):
+    """Raised by a failed send, the base of the other micromail exceptions"""
+    code: Optional[int]
+    """Reply code of the server answer behind the error"""
+    enhanced: Optional[str]
+    """Enhanced status code of that answer, such as 5.1.1"""
+    phase: Optional[str]
+    """SMTP phase the error happened in"""
+    transcript: List[Dict[str, Any]]
+    """Everything recorded during the send, see Mailer.transcript"""
+
+class MicromailSmtpError(MicromailError):
+    """args: (reply code, message)"""
+
+class MicromailTransientError(MicromailSmtpError):
+    """The server answered 4xx, retrying later may succeed"""
+
+class MicromailPermanentError(MicromailSmtpError):
+    """The server answered 5xx"""
+
+class MicromailAuthError(MicromailError):
+    """args: (reply code or "N/A", message)"""
+
+class MicromailTlsError(MicromailError): ...
+
+class MicromailTimeoutError(MicromailError):
+    """A timeout or stall limit was hit"""
+
+class Config:
+    domain: str
+    def __init__(self, domain: str) -> None: ...
+    def timeout(self, timeout_secs: int) -> None: ...
+    def use_tls(self, use_tls: bool) -> None: ...
+    def ports(self, ports: List[int]) -> None: ...
+    def auth(self, username: str, password: str) -> None: ...
+    def enable_test_mode(self, enable: bool) -> None: ...
+
+class AttachmentDict(TypedDict, total=False):
+    filename: str
+    data: bytes
+    content_type: str
+    """Guessed from the file name and data if missing"""
+
+class Mail:
+    """The sender is read with getattr(mail, "from"), a keyword a stub cannot declare.
+    Subject, body and content type are in to_dict()."""
+    @property
+    def to(self) -> str: ...
+    @property
+    def headers(self) -> Dict[str, str]: ...
+    @property
+    def attachments(self) -> List[Tuple[str, str]]:
+        """(file name, content type) of each attachment"""
+    def __init__(
+        self,
+        *,
+        from_addr: Optional[str] = None,
+        to: Union[str, List[str], None] = None,
+        cc: Union[str, List[str], None] = None,
+        bcc: Union[str, List[str], None] = None,
+        subject: Optional[str] = None,
+        body: Optional[str] = None,
+        html: Optional[str] = None,
+        content_type: Optional[str] = None,
+        headers: Optional[Dict[str, str]] = None,
+        message_id: Optional[str] = None,
+        attachments: Optional[List[AttachmentDict]] = None,
+    ) -> None: ...
+    @staticmethod
+    def from_dict(fields: Dict[str, Any]) -> "Mail":
+        """Create a mail from a dict with the keyword arguments of Mail(), e.g. from to_dict"""
+    def to_dict(self) -> Dict[str, Any]: ...
+    def from_addr(self, from_addr: str) -> None: ...
+    def to_addr(self, to_addr: str) -> None: ...
+    def subject(self, subject: str) -> None: ...
+    def body(self, body: str) -> None: ...
+    def html(self, html: str) -> None:
+        """Set an HTML body, replacing the body and content type"""
+    def content_type(self, content_type: str) -> None: ...
+    def add_header(self, name: str, value: str) -> None: ...
+    def attach(self, filename: str, data: bytes, content_type: Optional[str] = None) -> None:
+        """Attach data as a file, the content type is guessed if not given"""
+    def attach_file(self, path: str) -> None:
+        """Attach the file at path, raises OSError if it can't be read"""
+
+class Mailer:
+    def __init__(self, config: Config) -> None: ...
+    def send(self, mail: Mail) -> None: ...
+    def get_log(self) -> List[str]: ...
+    def transcript(self) -> List[Dict[str, Any]]:
+        """The last send as dicts with the time, a type (note, connected, tls,
+        tls_established, command, response, data) and the fields of the event"""
+    def clear_log(self) -> None: ...
+
+class AsyncMailer:
+    """Only available if micromail was built with the tokio-runtime feature"""
+    def __init__(self, config: Config) -> None: ...
+    def send(self, mail: Mail) -> Awaitable[None]: ...
+    def get_log(self) -> List[str]: ...
+    def transcript(self) -> List[Dict[str, Any]]: ...
+    def clear_log(self) -> None: ...
@@ -1,14 +1,34 @@
+import ast
+import os
 import unittest
 import micromail
 import asyncio
 
-# Check if PyAsyncMailer is available (i.e., tokio-runtime feature is enabled)
-HAS_ASYNC_MAILER = hasattr(micromail, "PyAsyncMailer")
+# Check if AsyncMailer is available (i.e., tokio-runtime feature is enabled)
+HAS_ASYNC_MAILER = hasattr(micromail, "AsyncMailer")
+
+STUB_PATH = os.path.join(os.path.dirname(__file__), os.pardir, "micromail.pyi")
+
+def stub_classes():
+    """The classes declared in micromail.pyi, with the names each one declares"""
+    with open(STUB_PATH) as f:
+        tree = ast.parse(f.read(), "micromail.pyi")
+    classes = {}
+    for node in tree.body:
+        if isinstance(node, ast.ClassDef):
+            names = set()
+            for item in node.body:
+                if isinstance(item, ast.FunctionDef):
+                    names.add(item.name)
+                elif isinstance(item, ast.AnnAssign):
+                    names.add(item.target.id)
+            classes[node.name] = names
+    return classes
 
 class TestMicromail(unittest.TestCase):
     def test_config(self):
         config = micromail.Config("example.com")
-        self.assertEqual(config.get_domain(), "example.com")
+        self.assertEqual(config.domain, "example.com")
         
         config.timeout(60)
         config.use_tls(True)
@@ -24,15 +44,68 @@ class TestMicromail(unittest.TestCase):
         mail.content_type("text/html; charset=utf-8")
         mail.add_header("X-Custom-Header", "Custom Value")
         
-        self.assertEqual(mail.get_from(), "sender@example.com")
-        self.assertEqual(mail.get_to(), "recipient@example.com")
-        self.assertEqual(mail.get_subject(), "Test Subject")
-        self.assertEqual(mail.get_body(), "Test Body")
-        self.assertEqual(mail.get_content_type(), "text/html; charset=utf-8")
+        self.assertEqual(getattr(mail, "from"), "sender@example.com")
+        self.assertEqual(mail.to, "recipient@example.com")
+        fields = mail.to_dict()
+        self.assertEqual(fields["subject"], "Test Subject")
+        self.assertEqual(fields["body"], "Test Body")
+        self.assertEqual(fields["content_type"], "text/html; charset=utf-8")
         
-        headers = mail.get_headers()
+        headers = mail.headers
         self.assertEqual(headers["X-Custom-Header"], "Custom Value")
     
+    def test_mail_keywords_and_dict(self):
+        mail = micromail.Mail(
+            from_addr="sender@example.com",
+            to=["ann@example.com", "bob@example.com"],
+            cc="carol@example.com",
+            subject="Keywords",
+            html="<p>Hi</p>",
+            headers={"X-Custom-Header": "Custom Value"},
+            attachments=[{"filename": "notes.txt", "data": b"notes", "content_type": "text/plain"}],
+        )
+        fields = mail.to_dict()
+        self.assertEqual(fields["from_addr"], "sender@example.com")
+        self.assertEqual(fields["to"], ["ann@example.com", "bob@example.com"])
+        self.assertEqual(fields["cc"], ["carol@example.com"])
+        self.assertEqual(fields["content_type"], "text/html; charset=utf-8")
+        self.assertEqual(fields["headers"], {"X-Custom-Header": "Custom Value"})
+        self.assertEqual(fields["attachments"], [{"filename": "notes.txt", "content_type": "text/plain", "data": b"notes"}])
+
+        self.assertEqual(micromail.Mail.from_dict(fields).to_dict(), fields)
+        with self.assertRaises(ValueError):
+            micromail.Mail(form_addr="typo@example.com")
+
+    def test_mail_html_and_attachments(self):
+        mail = micromail.Mail()
+        mail.html("<p>Hello</p>")
+        mail.attach("notes.txt", b"some notes", "text/plain")
+        mail.attach("blob.bin", b"\x00\x01\x02")
+        self.assertEqual(mail.attachments[0], ("notes.txt", "text/plain"))
+        self.assertEqual(mail.attachments[1][0], "blob.bin")
+        with self.assertRaises(OSError):
+            mail.attach_file("/nonexistent/micromail-attachment")
+
+    @unittest.skipIf(not HAS_ASYNC_MAILER, "AsyncMailer not available (tokio-runtime feature disabled)")
+    def test_async_mailer_test_mode(self):
+        config = micromail.Config("example.com")
+        config.enable_test_mode(True)
+        mailer = micromail.AsyncMailer(config)
+
+        async def send_all():
+            mails = []
+            for i in range(3):
+                mail = micromail.Mail()
+                mail.from_addr("sender@example.com")
+                mail.to_addr("recipient%d@example.com" % i)
+                mail.subject("Async %d" % i)
+                mail.attach("data.bin", b"\xff" * 100)
+                mails.append(mail)
+            await asyncio.gather(*(mailer.send(mail) for mail in mails))
+
+        asyncio.run(send_all())
+        self.assertTrue(any("250 OK: message queued" in l for l in mailer.get_log()))
+
     def test_mailer(self):
         config = micromail.Config("example.com")
         mailer = micromail.Mailer(config)
@@ -58,7 +131,7 @@ class TestMicromail(unittest.TestCase):
         asyncio.set_event_loop(loop)
 
         try:
-            mailer1 = micromail.PyAsyncMailer(config)
+            mailer1 = micromail.AsyncMailer(config)
             # Ensure the config domain is what we expect initially
             # Note: PyAsyncMailer doesn't directly expose its config's domain.
             # We are testing if the *underlying Mailer* is shared.
@@ -185,6 +258,45 @@ class TestMicromail(unittest.TestCase):
         self.assertTrue("RCPT TO failed" in cm_rcpt.exception.args[1])
 
 
+    def test_mailer_error_hierarchy(self):
+        config = micromail.Config("example.com")
+        config.enable_test_mode(True)
+        mailer = micromail.Mailer(config)
+
+        mail = micromail.Mail(from_addr="trigger550@example.com", to="recipient@example.com", subject="Errors")
+        with self.assertRaises(micromail.MicromailPermanentError) as cm:
+            mailer.send(mail)
+        self.assertIsInstance(cm.exception, micromail.MicromailSmtpError)
+        self.assertIsInstance(cm.exception, RuntimeError)
+        self.assertEqual(cm.exception.code, 550)
+        self.assertEqual(cm.exception.phase, "MAIL FROM")
+        commands = [e["command"] for e in cm.exception.transcript if e["type"] == "command"]
+        self.assertTrue(any(c.startswith("MAIL FROM:<trigger550@example.com>") for c in commands))
+        self.assertEqual(cm.exception.transcript, mailer.transcript())
+
+        mail = micromail.Mail(from_addr="sender@example.com", to="trigger450@example.com", subject="Errors")
+        with self.assertRaises(micromail.MicromailTransientError) as cm:
+            mailer.send(mail)
+        self.assertEqual(cm.exception.code, 450)
+        self.assertEqual(cm.exception.enhanced, "4.2.1")
+
+    def test_type_stubs_match_module(self):
+        classes = stub_classes()
+        # Only the type of the attachments argument
+        del classes["AttachmentDict"]
+        if not HAS_ASYNC_MAILER:
+            del classes["AsyncMailer"]
+        module_classes = {name for name in dir(micromail) if isinstance(getattr(micromail, name), type)}
+        self.assertEqual(set(classes), module_classes)
+        for name, declared in classes.items():
+            cls = getattr(micromail, name)
+            if issubclass(cls, BaseException):
+                # Their attributes are set on each raised instance
+                continue
+            public = {attr for attr in vars(cls) if not attr.startswith("_")}
+            public.discard("from")
+            self.assertEqual(declared - {"__init__"}, public, name)
+
     # Similar test could be added for PyAsyncMailer if desired,
     # ensuring it rejects with the correct error types and args.
     # For brevity of this subtask, focusing on synchronous Mailer error path first.
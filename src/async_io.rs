@@ -0,0 +1,576 @@
+//! Async SMTP connections for [`AsyncMailer`](crate::AsyncMailer)
+//!
+//! The counterpart of the blocking `connection` and `io` modules on tokio sockets, with
+//! STARTTLS through `tokio-rustls`. Replies are framed by their last line, so a reply
+//! split over several reads (or several replies in one read) is handled.
+
+use std::io::{Read as _, SeekFrom, Write as _};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_rustls::client::TlsStream;
+
+use crate::{
+    config::{Config, Timeouts},
+    capabilities::Capabilities,
+    dsn::Dsn,
+    connection,
+    dane::{self, DaneMode, DanePolicy},
+    dns::{self, MxRecord},
+    error::{Error, SmtpPhase, SmtpReply},
+    io::{self, MockStream},
+    metrics::{self, Metrics},
+    resolver::{AsyncResolver, MicroDnsResolver},
+    smtp,
+    tls::TlsInfo,
+    trace,
+    tlsrpt::ResultType,
+    transcript::{self, SendEvent, SharedRecorder, TranscriptEvent},
+    utils,
+};
+
+/// The socket of an [`AsyncConnection`]
+#[derive(Debug)]
+pub(crate) enum AsyncStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Mock(MockStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// An SMTP connection driven by the tokio runtime
+#[derive(Debug)]
+pub(crate) struct AsyncConnection {
+    pub stream: AsyncStream,
+    pub address: SocketAddr,
+    /// Name of the MX host the address belongs to
+    pub mx_host: Option<String>,
+    pub phase: SmtpPhase,
+    pub phase_started: Instant,
+    pub timeouts: Timeouts,
+    pub deadline: Option<Instant>,
+    pub stall_timeout: Option<Duration>,
+    pub last_progress: Instant,
+    /// What the server advertised in its last EHLO reply
+    pub capabilities: Capabilities,
+    /// Whether the server speaks LMTP instead of SMTP
+    pub lmtp: bool,
+    pub tls_info: Option<TlsInfo>,
+    /// Bytes received after the last complete reply
+    pending: Vec<u8>,
+    /// Where the transcript and events of the send using the connection go
+    pub recorder: SharedRecorder,
+    pub metrics: Option<Arc<dyn Metrics>>,
+    pub log_credentials: bool,
+    /// DSN parameters for MAIL FROM and RCPT TO, see `Config::dsn`
+    pub dsn: Option<Dsn>,
+}
+
+impl AsyncConnection {
+    fn new(stream: AsyncStream, address: SocketAddr, config: &Config, recorder: &SharedRecorder) -> Self {
+        trace::record_address(address);
+        recorder.lock().unwrap().transcript.push(TranscriptEvent::Connected { address });
+        transcript::emit(recorder, &SendEvent::Connected { address });
+        Self {
+            stream,
+            address,
+            mx_host: None,
+            phase: SmtpPhase::Connect,
+            phase_started: Instant::now(),
+            timeouts: config.timeouts.clone(),
+            deadline: None,
+            stall_timeout: config.stall_timeout,
+            last_progress: Instant::now(),
+            capabilities: Capabilities::default(),
+            lmtp: config.lmtp,
+            tls_info: None,
+            pending: Vec::new(),
+            recorder: recorder.clone(),
+            metrics: config.metrics.clone(),
+            log_credentials: config.log_credentials,
+            dsn: config.dsn.clone(),
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        match &self.stream {
+            AsyncStream::Plain(_) => false,
+            AsyncStream::Tls(_) => true,
+            AsyncStream::Mock(mock) => mock.tls_active,
+            #[cfg(unix)]
+            AsyncStream::Unix(_) => false,
+        }
+    }
+
+    /// Whether the stream can be upgraded with STARTTLS, which a Unix socket cannot
+    pub fn can_starttls(&self) -> bool {
+        match &self.stream {
+            #[cfg(unix)]
+            AsyncStream::Unix(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Local address of the socket (None for mock streams and Unix sockets)
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.stream {
+            AsyncStream::Plain(stream) => stream.local_addr().ok(),
+            AsyncStream::Tls(stream) => stream.get_ref().0.local_addr().ok(),
+            AsyncStream::Mock(_) => None,
+            #[cfg(unix)]
+            AsyncStream::Unix(_) => None,
+        }
+    }
+
+    pub fn record(&self, event: TranscriptEvent) {
+        self.recorder.lock().unwrap().transcript.push(event);
+    }
+
+    pub fn emit(&self, event: SendEvent) {
+        transcript::emit(&self.recorder, &event);
+    }
+
+    pub fn enter_phase(&mut self, phase: SmtpPhase) {
+        self.phase = phase;
+        self.phase_started = Instant::now();
+    }
+
+    fn time_limit(&self) -> Result<Duration, Error> {
+        connection::time_limit(&self.timeouts, self.phase, self.phase_started, self.deadline, self.stall_timeout, self.last_progress)
+    }
+
+    fn timeout_error(&self) -> Error {
+        connection::timeout_error(self.phase, self.phase_started, self.stall_timeout, self.last_progress)
+    }
+
+    /// Sends a command
+    pub async fn send(&mut self, command: &str) -> Result<(), Error> {
+        self.record(TranscriptEvent::CommandSent(utils::sanitize_string_lite(command.trim_end())));
+        self.write_raw(command.as_bytes()).await
+    }
+
+    /// Sends a line of credentials, recorded as a placeholder unless `log_credentials` is set
+    pub async fn send_secret(&mut self, line: &str) -> Result<(), Error> {
+        if self.log_credentials {
+            return self.send(line).await;
+        }
+        self.record(TranscriptEvent::CommandSent(io::REDACTED.to_string()));
+        self.write_raw(line.as_bytes()).await
+    }
+
+    /// Performs a [`smtp::Action`] other than [`Action::Data`](smtp::Action::Data) and returns
+    /// the reply it asked for, like [`io::exchange`]
+    pub async fn exchange(&mut self, action: smtp::Action) -> Result<SmtpReply, Error> {
+        if let smtp::Action::Send { phase, line, secret } = action {
+            if let Some(phase) = phase {
+                self.enter_phase(phase);
+            }
+            if secret { self.send_secret(&line).await? } else { self.send(&line).await? }
+        }
+        self.read().await
+    }
+
+    /// Sends the message content after DATA, dot-stuffed and followed by the terminating
+    /// `<CRLF>.<CRLF>`, one chunk at a time like [`io::send_data`]
+    pub async fn send_data<I, B>(&mut self, chunks: I, total: usize) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = B>,
+        I::IntoIter: Send,
+        B: AsRef<[u8]> + Send,
+    {
+        let mut chunks = chunks.into_iter().peekable();
+        let first = chunks.peek().map_or(&[][..], |chunk| chunk.as_ref());
+        self.record(io::data_event(first, total));
+        let mut data = io::DataWriter::new(total);
+        for chunk in chunks {
+            self.write_raw(data.stuff(chunk.as_ref())).await?;
+            self.emit(data.progress());
+        }
+        self.write_raw(data.terminator()).await
+    }
+
+    /// Like [`send_data`](Self::send_data), reading the content from the start of `reader`
+    pub async fn send_reader<R: AsyncRead + AsyncSeek + Unpin + Send>(&mut self, reader: &mut R) -> Result<(), Error> {
+        let total = reader.seek(SeekFrom::End(0)).await? as usize;
+        reader.rewind().await?;
+        let mut chunk = vec![0; io::DATA_CHUNK_SIZE];
+        let mut data = io::DataWriter::new(total);
+        loop {
+            let mut read = 0;
+            while read < chunk.len() {
+                match reader.read(&mut chunk[read..]).await? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            if data.written == 0 {
+                self.record(io::data_event(&chunk[..read], total));
+            }
+            if read == 0 {
+                break;
+            }
+            self.write_raw(data.stuff(&chunk[..read])).await?;
+            self.emit(data.progress());
+        }
+        self.write_raw(data.terminator()).await
+    }
+
+    async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let limit = self.time_limit()?;
+        let write = async {
+            match &mut self.stream {
+                AsyncStream::Plain(stream) => stream.write_all(bytes).await,
+                AsyncStream::Tls(stream) => {
+                    stream.write_all(bytes).await?;
+                    stream.flush().await
+                }
+                AsyncStream::Mock(mock) => mock.write_all(bytes),
+                #[cfg(unix)]
+                AsyncStream::Unix(stream) => stream.write_all(bytes).await,
+            }
+        };
+        match tokio::time::timeout(limit, write).await {
+            Ok(Ok(())) => {
+                self.last_progress = Instant::now();
+                metrics::with(&self.metrics, |metrics| metrics.bytes_sent(bytes.len()));
+                Ok(())
+            }
+            Ok(Err(e)) => Err(Error::IoError(e)),
+            Err(_) => Err(self.timeout_error()),
+        }
+    }
+
+    /// Reads one reply, like [`io::secure_read`]
+    pub async fn read(&mut self) -> Result<SmtpReply, Error> {
+        let enhanced_codes = self.capabilities.enhanced_status_codes;
+        io::parse_reply(&self.read_reply().await?, enhanced_codes)
+            .ok_or_else(|| Error::Other("Invalid response format from server".to_string()))
+    }
+
+    /// Reads until a complete reply (up to a line without `-` after the code) has arrived,
+    /// or the server closed the connection
+    async fn read_reply(&mut self) -> Result<String, Error> {
+        let mut buffer = [0; 4096];
+        let reply = loop {
+            if let Some(end) = io::reply_end(&self.pending) {
+                break self.pending.drain(..end).collect::<Vec<_>>();
+            }
+            let limit = self.time_limit()?;
+            let read = async {
+                match &mut self.stream {
+                    AsyncStream::Plain(stream) => stream.read(&mut buffer).await,
+                    AsyncStream::Tls(stream) => stream.read(&mut buffer).await,
+                    AsyncStream::Mock(mock) => mock.read(&mut buffer),
+                    #[cfg(unix)]
+                    AsyncStream::Unix(stream) => stream.read(&mut buffer).await,
+                }
+            };
+            let len = match tokio::time::timeout(limit, read).await {
+                Ok(Ok(len)) => len,
+                Ok(Err(e)) => return Err(Error::IoError(e)),
+                Err(_) => return Err(self.timeout_error()),
+            };
+            if len == 0 {
+                break std::mem::take(&mut self.pending);
+            }
+            self.last_progress = Instant::now();
+            self.pending.extend_from_slice(&buffer[..len]);
+        };
+        let reply = String::from_utf8(reply).map_err(|_| Error::Other("Server response was not valid UTF-8".to_string()))?;
+        if let Some(event) = io::response_event(&reply) {
+            self.record(event);
+        }
+        Ok(reply)
+    }
+}
+
+/// Message content the DATA phase sends from its start, once per transaction, like [`io::Content`]
+#[async_trait]
+pub(crate) trait AsyncContent: Send {
+    async fn send(&mut self, connection: &mut AsyncConnection) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl<C: AsyncContent + ?Sized> AsyncContent for Box<C> {
+    async fn send(&mut self, connection: &mut AsyncConnection) -> Result<(), Error> {
+        (**self).send(connection).await
+    }
+}
+
+#[async_trait]
+impl AsyncContent for String {
+    async fn send(&mut self, connection: &mut AsyncConnection) -> Result<(), Error> {
+        connection.send_data(self.as_bytes().chunks(io::DATA_CHUNK_SIZE), self.len()).await
+    }
+}
+
+#[async_trait]
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> AsyncContent for io::Reader<R> {
+    async fn send(&mut self, connection: &mut AsyncConnection) -> Result<(), Error> {
+        connection.send_reader(&mut self.0).await
+    }
+}
+
+/// Connects to the first reachable MX host in `mx_records`, like [`connection::try_start_connection`]
+pub(crate) async fn connect(
+    mx_records: &[MxRecord],
+    config: &Config,
+    deadline: Option<Instant>,
+    recorder: &SharedRecorder,
+) -> Option<AsyncConnection> {
+    if config.test_mode {
+        recorder.lock().unwrap().transcript.note("TEST MODE: Using mock connection to localhost.testmode");
+        let address: SocketAddr = "127.0.0.1:25".parse().unwrap();
+        let mut connection = AsyncConnection::new(AsyncStream::Mock(MockStream::new()), address, config, recorder);
+        connection.mx_host = mx_records.first().map(|mx| mx.server.clone());
+        return Some(connection);
+    }
+    if let Some(path) = &config.unix_socket {
+        return connect_unix(path, config, recorder).await;
+    }
+    if let Some(addr) = config.direct_target {
+        let connect_timeout = match deadline {
+            Some(deadline) => config.timeout.min(deadline.saturating_duration_since(Instant::now())),
+            None => config.timeout,
+        };
+        if connect_timeout.is_zero() {
+            return None;
+        }
+        let started = Instant::now();
+        let result = connect_happy_eyeballs(&[addr], connect_timeout, config.bind_addr).await;
+        metrics::with(&config.metrics, |m| m.connect_attempt(&addr.to_string(), result.as_ref().map(|_| started.elapsed())));
+        return match result {
+            Ok((stream, address)) => Some(AsyncConnection::new(AsyncStream::Plain(stream), address, config, recorder)),
+            Err(e) => {
+                recorder.lock().unwrap().transcript.note(format!("Could not connect to {}: {}", addr, e));
+                None
+            }
+        };
+    }
+
+    for mx in mx_records {
+        let ip_addresses = dns::lookup_host_addrs_async(&mx.server, config).await;
+        if ip_addresses.is_empty() {
+            continue;
+        }
+        for port in &config.ports {
+            let socket_addrs = ip_addresses.iter().map(|ip| SocketAddr::new(*ip, *port)).collect::<Vec<_>>();
+            let connect_timeout = match deadline {
+                Some(deadline) => config.timeout.min(deadline.saturating_duration_since(Instant::now())),
+                None => config.timeout,
+            };
+            if connect_timeout.is_zero() {
+                return None;
+            }
+            let started = Instant::now();
+            let result = connect_happy_eyeballs(&socket_addrs, connect_timeout, config.bind_addr).await;
+            metrics::with(&config.metrics, |m| m.connect_attempt(&mx.server, result.as_ref().map(|_| started.elapsed())));
+            match result {
+                Ok((stream, address)) => {
+                    let mut connection = AsyncConnection::new(AsyncStream::Plain(stream), address, config, recorder);
+                    connection.mx_host = Some(mx.server.clone());
+                    return Some(connection);
+                }
+                Err(e) => {
+                    recorder.lock().unwrap().transcript.note(format!("Could not connect to {} port {}: {}", mx.server, port, e));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Connects to the server listening on the Unix socket at `path`
+#[cfg(unix)]
+async fn connect_unix(path: &Path, config: &Config, recorder: &SharedRecorder) -> Option<AsyncConnection> {
+    match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => {
+            recorder.lock().unwrap().transcript.note(format!("Connected to Unix socket {}", path.display()));
+            Some(AsyncConnection::new(AsyncStream::Unix(stream), connection::UNIX_SOCKET_ADDR, config, recorder))
+        }
+        Err(e) => {
+            recorder.lock().unwrap().transcript.note(format!("Could not connect to Unix socket {}: {}", path.display(), e));
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &Path, _config: &Config, recorder: &SharedRecorder) -> Option<AsyncConnection> {
+    recorder.lock().unwrap().transcript.note(format!("Could not connect to Unix socket {}: not supported on this platform", path.display()));
+    None
+}
+
+/// Connects to the first of `addrs` that answers, starting a new attempt every
+/// `CONNECTION_ATTEMPT_DELAY` (or as soon as one fails), like [`connection::connect_happy_eyeballs`]
+async fn connect_happy_eyeballs(
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    bind_addr: Option<IpAddr>,
+) -> Result<(TcpStream, SocketAddr), Error> {
+    let mut addrs = addrs.iter()
+        .copied()
+        .filter(|addr| bind_addr.is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4()));
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = Error::ConnectionFailed;
+    loop {
+        let started = match addrs.next() {
+            Some(addr) => {
+                attempts.push(async move { (addr, connect_one(addr, bind_addr).await) });
+                true
+            }
+            None => false,
+        };
+        if attempts.is_empty() {
+            return Err(last_error);
+        }
+        let next_attempt = match started {
+            true => tokio::time::Instant::now() + connection::CONNECTION_ATTEMPT_DELAY,
+            false => deadline,
+        };
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) => last_error = e,
+            },
+            _ = tokio::time::sleep_until(next_attempt.min(deadline)) => {
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(last_error);
+                }
+            }
+        }
+    }
+}
+
+async fn connect_one(addr: SocketAddr, bind_addr: Option<IpAddr>) -> Result<TcpStream, Error> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() }.map_err(Error::IoError)?;
+    if let Some(local_ip) = bind_addr {
+        socket.bind(SocketAddr::new(local_ip, 0)).map_err(Error::IoError)?;
+    }
+    socket.connect(addr).await.map_err(|_| Error::ConnectionFailed)
+}
+
+/// Waits for the greeting (unless `is_reconnect`) and sends EHLO, falling back to HELO,
+/// like [`connection::send_ehlo`]
+pub(crate) async fn send_ehlo(connection: &mut AsyncConnection, source_domain: &str, is_reconnect: bool) -> Result<Capabilities, Error> {
+    if !is_reconnect {
+        connection.enter_phase(SmtpPhase::Greeting);
+        let response = connection.read().await?;
+        if !response.is_positive_completion() {
+            return Err(response.into_error(SmtpPhase::Greeting));
+        }
+    }
+    connection.enter_phase(SmtpPhase::Ehlo);
+    let greetings: &[&str] = if connection.lmtp { &["LHLO"] } else { &["EHLO", "HELO"] };
+    for ty in greetings {
+        if connection.send(&format!("{ty} {source_domain}\r\n")).await.is_err() {
+            continue;
+        }
+        match connection.read().await {
+            Ok(reply) if reply.is_positive_completion() => {
+                connection.capabilities = if *ty == "HELO" { Capabilities::default() } else { Capabilities::from_reply(&reply) };
+                return Ok(connection.capabilities.clone());
+            }
+            Ok(_) => continue,
+            Err(e @ (Error::Timeout { .. } | Error::Stalled { .. })) => return Err(e),
+            Err(_) => continue,
+        }
+    }
+    connection.capabilities = Capabilities::default();
+    Ok(Capabilities::default())
+}
+
+/// The DANE policy for the connection, like [`dane::lookup`], querying TLSA records on the
+/// runtime, or on the blocking pool if `config` brings only a blocking resolver. The MX answers come from `resolved`.
+pub(crate) async fn dane_lookup(connection: &AsyncConnection, config: &Config, resolved: &Config, domains: Vec<String>) -> Result<Option<DanePolicy>, Error> {
+    if config.dane == DaneMode::Off {
+        return Ok(None);
+    }
+    let Some(mx_host) = connection.mx_host.clone() else { return Ok(None) };
+    let note = |message| connection.record(TranscriptEvent::Note(message));
+    if !dane::mx_secure(resolved, &mx_host, &domains, note) {
+        return Ok(None);
+    }
+    let name = dane::tlsa_name(&mx_host, connection.address.port());
+    let answer = match (&config.async_resolver, &config.resolver) {
+        (Some(resolver), _) => resolver.tlsa_records(&name).await,
+        (None, Some(resolver)) => {
+            let (resolver, name) = (resolver.clone(), name.clone());
+            dns::spawn_lookup(move || resolver.tlsa_records(&name)).await
+        }
+        (None, None) => AsyncResolver::tlsa_records(&MicroDnsResolver, &name).await,
+    };
+    dane::policy_from_answer(answer, mx_host, config, domains, note)
+}
+
+/// Sends STARTTLS and completes the handshake, like [`connection::establish_tls`].
+/// Returns whether the connection was upgraded, so EHLO has to be repeated.
+pub(crate) async fn establish_tls(mut connection: AsyncConnection, config: &Config, dane: Option<DanePolicy>) -> Result<(AsyncConnection, bool), Error> {
+    if connection.is_secure() {
+        return Ok((connection, false));
+    }
+    connection.enter_phase(SmtpPhase::StartTls);
+    connection.send("STARTTLS\r\n").await?;
+    let response = connection.read().await?;
+    if response.code != 220 {
+        return Err(response.into_error(SmtpPhase::StartTls));
+    }
+    // Anything sent after the 220 was not protected by TLS and must not be read as a reply (RFC 3207)
+    connection.pending.clear();
+
+    let mut dane_outcome = None;
+    connection.stream = match connection.stream {
+        AsyncStream::Plain(tcp) => {
+            let (tls_config, outcome) = connection::tls_client_config(config, dane.as_ref())?;
+            dane_outcome = outcome;
+            let server_name = connection::tls_server_name(config, connection.mx_host.as_deref(), connection.address)?;
+            let local_addr = tcp.local_addr().ok();
+            let limit = connection::time_limit(&connection.timeouts, connection.phase, connection.phase_started, connection.deadline, connection.stall_timeout, connection.last_progress)?;
+            // Handshake before anything is sent, so a rejected certificate never leaks the envelope
+            let handshake = tokio_rustls::TlsConnector::from(tls_config).connect(server_name, tcp);
+            match tokio::time::timeout(limit, handshake).await {
+                Ok(Ok(stream)) => {
+                    connection.tls_info = TlsInfo::from_connection(stream.get_ref().1);
+                    AsyncStream::Tls(Box::new(stream))
+                }
+                Ok(Err(e)) => {
+                    // rustls reports its errors wrapped in io::Error
+                    let e = Error::TlsError(e.into_inner().map_or_else(|| "handshake failed".to_string(), |e| e.to_string()));
+                    if let Some(policy) = &dane {
+                        policy.report(local_addr, connection.address, Err((ResultType::ValidationFailure, &e)));
+                    }
+                    return Err(e);
+                }
+                Err(_) => return Err(connection::timeout_error(connection.phase, connection.phase_started, connection.stall_timeout, connection.last_progress)),
+            }
+        }
+        AsyncStream::Mock(mut mock) => {
+            mock.tls_active = true;
+            AsyncStream::Mock(mock)
+        }
+        tls @ AsyncStream::Tls(_) => tls,
+        #[cfg(unix)]
+        AsyncStream::Unix(_) => return Err(Error::TlsError("STARTTLS is not supported over a Unix socket".to_string())),
+    };
+    connection.last_progress = Instant::now();
+    if let Some(info) = &connection.tls_info {
+        connection.record(TranscriptEvent::TlsNegotiated(info.clone()));
+    }
+    if let (Some(outcome), Some(policy)) = (dane_outcome, &dane) {
+        let note = connection::dane_verdict(&outcome, policy, config, connection.local_addr(), connection.address)?;
+        connection.record(TranscriptEvent::Note(note));
+    } else if dane.is_some() {
+        connection.record(TranscriptEvent::Note("TEST MODE: skipping DANE certificate check".to_string()));
+    }
+    connection.record(TranscriptEvent::TlsEstablished);
+    connection.emit(SendEvent::TlsEstablished);
+    Ok((connection, true))
+}
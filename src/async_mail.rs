@@ -1,29 +1,95 @@
 //! Async mail handling functionality
 //! 
 //! This module provides async versions of the mail sending functionality.
+//! Sends run on the tokio runtime itself: sockets, TLS and DNS are all async, so
+//! any number of concurrent sends share the runtime's worker threads.
+//! The progress of every send is published as a [`MailerEvent`], see [`AsyncMailer::events`].
 
 use async_trait::async_trait;
-use futures::future::BoxFuture;
+use futures::Stream;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::task;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncSeek};
+use tokio::sync::broadcast;
 
 use crate::{
-    config::Config,
-    error::Error,
-    mail::{Mail, Mailer},
+    async_io::{self, AsyncConnection, AsyncContent},
+    config::{Auth, Config},
+    dns,
+    error::{Error, SendFailure, SmtpPhase},
+    io,
+    mail::{self, Mail, Mailer, MxGroup, PreparedSend},
+    metrics,
+    report::{SendReport, TransactionReport},
+    resolver::{AsyncResolver, MicroDnsResolver, OnBlockingPool},
+    shutdown::Drain,
+    smtp,
+    trace::{self, Instrument},
+    transcript::{SendEvent, SharedRecorder},
 };
 
 /// Trait for async mail sending
 #[async_trait]
 pub trait AsyncMailSender {
     /// Send a mail asynchronously
-    async fn send(&mut self, mail: Mail) -> Result<(), Error>;
+    async fn send(&mut self, mail: Mail) -> Result<SendReport, Error>;
+}
+
+/// Identifier of a send through an [`AsyncMailer`], counting from 1 per mailer
+pub type SendId = u64;
+
+/// A step in the lifecycle of a send through an [`AsyncMailer`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MailerEvent {
+    /// A send started
+    Queued { id: SendId, recipients: Vec<String> },
+    /// Connecting to the MX host `host` for an attempt
+    Connecting { id: SendId, host: String },
+    /// All recipient domains accepted the message
+    Delivered { id: SendId, queue_ids: Vec<String> },
+    /// Attempt number `attempt` failed temporarily, the send is retried after `retry_in`
+    Deferred { id: SendId, attempt: u32, error: String, retry_in: Duration },
+    /// The send failed and is not retried
+    Failed { id: SendId, error: String },
+}
+
+/// Buffered events per subscriber before the slowest one starts missing events
+const EVENT_CAPACITY: usize = 1024;
+
+/// What a send on behalf of the [`MailQueue`](crate::MailQueue) carries over from earlier
+/// attempts and reports back while it runs
+#[derive(Clone, Copy)]
+pub(crate) struct Progress<'a> {
+    /// Recipients that already have the mail, they are left out
+    pub delivered: &'a [String],
+    /// Called whenever the whole content of the mail went out in a transaction, before
+    /// the server answered it
+    pub content_sent: &'a (dyn Fn() + Sync),
+}
+
+/// What a send through an [`AsyncMailer`] delivers
+pub(crate) enum Outgoing<'a> {
+    /// A mail, formatted and signed anew for every attempt
+    Mail(&'a Mail),
+    /// A message that is already formatted
+    Formatted(PreparedSend<Box<dyn AsyncContent + 'a>>),
+}
+
+impl Progress<'_> {
+    /// A send of its own, to every recipient
+    pub const NONE: Progress<'static> = Progress { delivered: &[], content_sent: &|| {} };
 }
 
 /// Async wrapper for the mailer
 pub struct AsyncMailer {
     /// Inner mailer wrapped in a mutex
     inner: Arc<Mutex<Mailer>>,
+    events: broadcast::Sender<MailerEvent>,
+    /// Id of the last send started
+    last_id: Arc<AtomicU64>,
+    /// Sends in flight, for [`AsyncMailer::shutdown`]
+    drain: Arc<Drain>,
 }
 
 impl AsyncMailer {
@@ -31,34 +97,331 @@ impl AsyncMailer {
     pub fn new(config: Config) -> Self {
         Self {
             inner: Arc::new(Mutex::new(Mailer::new(config))),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            last_id: Arc::new(AtomicU64::new(0)),
+            drain: Arc::default(),
         }
     }
+
+    /// Stop taking new sends and wait up to `deadline` for the ones in flight to finish.
+    /// Later sends through this mailer or its clones fail with [`Error::ShuttingDown`].
+    ///
+    /// Every send QUITs its own connection, so nothing is left open once this returns
+    /// `Ok`. Sends still running at the deadline are not aborted, the error tells how many.
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), Error> {
+        self.drain.close(deadline).await
+    }
     
     /// Get a clone of the inner mailer
     pub fn mailer(&self) -> Arc<Mutex<Mailer>> {
         self.inner.clone()
     }
+
+    /// Like [`AsyncMailSender::send`], but a failure carries the transcript of the session
+    pub async fn send_traced(&mut self, mail: Mail) -> Result<SendReport, SendFailure> {
+        let recorder = self.inner.lock().unwrap().send_recorder();
+        let mut report = SendReport::default();
+        match self.send_recorded(&recorder, Outgoing::Mail(&mail), Progress::NONE, &mut report).await {
+            Ok(()) => Ok(report),
+            Err(error) => Err(SendFailure { error, transcript: recorder.lock().unwrap().transcript.clone() }),
+        }
+    }
+
+    /// Registers `listener` for the [`SendEvent`]s of subsequent sends.
+    /// Listeners run on the task that performs the send, so they should not block.
+    pub fn on_event<F: FnMut(&SendEvent) + Send + 'static>(&self, listener: F) {
+        self.inner.lock().unwrap().on_event(listener);
+    }
+
+    /// Subscribe to the lifecycle events of all sends through this mailer and its clones,
+    /// e.g. to push live status to a dashboard.
+    ///
+    /// The stream only sees events published after this call. A subscriber that
+    /// falls more than 1024 events behind skips the oldest ones, with a warning in
+    /// the log, instead of slowing down sending.
+    pub fn events(&self) -> impl Stream<Item = MailerEvent> {
+        futures::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!(target: "micromail", "mailer event subscriber fell behind, skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
 }
 
 impl Clone for AsyncMailer {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            events: self.events.clone(),
+            last_id: Arc::clone(&self.last_id),
+            drain: Arc::clone(&self.drain),
         }
     }
 }
 
 #[async_trait]
 impl AsyncMailSender for AsyncMailer {
-    /// Send a mail asynchronously
-    async fn send(&mut self, mail: Mail) -> Result<(), Error> {
-        let mailer = self.inner.clone();
-        
-        task::spawn_blocking(move || {
-            let mut locked_mailer = mailer.lock().unwrap();
-            locked_mailer.send_sync(mail)
-        })
+    /// Send a mail asynchronously, with the same retries as [`Mailer::send_sync`]
+    async fn send(&mut self, mail: Mail) -> Result<SendReport, Error> {
+        let mut report = SendReport::default();
+        self.send_observed(Outgoing::Mail(&mail), Progress::NONE, &mut report).await?;
+        Ok(report)
+    }
+}
+
+impl AsyncMailer {
+    /// Sends a message that is already formatted from `from` to `recipients`, reading it
+    /// in chunks like [`Mailer::send_reader`], with the retries of [`AsyncMailSender::send`]
+    pub async fn send_reader<R: AsyncRead + AsyncSeek + Unpin + Send>(&self, from: &str, recipients: &[String], message: R) -> Result<SendReport, Error> {
+        let config = self.inner.lock().unwrap().config().clone();
+        let content: Box<dyn AsyncContent + '_> = Box::new(io::Reader(message));
+        let prepared = mail::prepare_raw(&config, from, recipients, content)?;
+        let mut report = SendReport::default();
+        self.send_observed(Outgoing::Formatted(prepared), Progress::NONE, &mut report).await?;
+        Ok(report)
+    }
+
+    /// [`AsyncMailSender::send`] resuming from `progress`, adding every transaction the
+    /// servers took to `report` as it completes, so a failed send still tells which
+    /// recipients have the mail
+    pub(crate) async fn send_observed(&self, outgoing: Outgoing<'_>, progress: Progress<'_>, report: &mut SendReport) -> Result<(), Error> {
+        let recorder = self.inner.lock().unwrap().send_recorder();
+        self.send_recorded(&recorder, outgoing, progress, report).await
+    }
+
+    /// [`send_observed`](Self::send_observed) recording into `recorder`. Concurrent sends
+    /// each have their own, and the last one to finish leaves its transcript in the mailer.
+    async fn send_recorded(&self, recorder: &SharedRecorder, mut outgoing: Outgoing<'_>, progress: Progress<'_>, report: &mut SendReport) -> Result<(), Error> {
+        let _in_flight = self.drain.enter()?;
+        let config = self.inner.lock().unwrap().config().clone();
+        let deadline = config.deadline.map(|limit| Instant::now() + limit);
+        let mut greylist_retried = false;
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let span = trace::send_span();
+        let recipients = match &outgoing {
+            Outgoing::Mail(mail) => mail.envelope_recipients(),
+            Outgoing::Formatted(prepared) => prepared.recipients.clone(),
+        };
+        let _ = self.events.send(MailerEvent::Queued { id, recipients });
+        let mut attempt = 1;
+        loop {
+            let error = match send_attempt(&config, recorder, deadline, &mut outgoing, (&self.events, id), progress, report).instrument(span.clone()).await {
+                Ok(()) => {
+                    self.inner.lock().unwrap().keep_transcript(recorder);
+                    let queue_ids = report.transactions.iter().filter_map(|t| t.queue_id.clone()).collect();
+                    let _ = self.events.send(MailerEvent::Delivered { id, queue_ids });
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            match mail::retry_delay(&config, recorder, deadline, &mut greylist_retried, &error, attempt) {
+                Some(delay) => {
+                    let _ = self.events.send(MailerEvent::Deferred { id, attempt, error: error.to_string(), retry_in: delay });
+                    tokio::time::sleep(delay).await;
+                }
+                None => {
+                    self.inner.lock().unwrap().keep_transcript(recorder);
+                    let _ = self.events.send(MailerEvent::Failed { id, error: error.to_string() });
+                    return Err(error);
+                }
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// A single delivery attempt, like `Mailer::send_attempt`, announcing connections on `events`
+async fn send_attempt(
+    config: &Config,
+    recorder: &SharedRecorder,
+    deadline: Option<Instant>,
+    outgoing: &mut Outgoing<'_>,
+    events: (&broadcast::Sender<MailerEvent>, SendId),
+    progress: Progress<'_>,
+    report: &mut SendReport,
+) -> Result<(), Error> {
+    match outgoing {
+        Outgoing::Mail(mail) => {
+            let mut prepared = mail::prepare_send(config, recorder, (*mail).clone())?;
+            deliver(config, recorder, deadline, &mut prepared, events, progress, report).await?;
+            report.message = mail::sent_message(config, prepared.content);
+            Ok(())
+        }
+        Outgoing::Formatted(prepared) => deliver(config, recorder, deadline, prepared, events, progress, report).await,
+    }
+}
+
+/// Sends `prepared` like `Mailer::deliver`, leaving out the recipients in `progress` as well
+async fn deliver<C: AsyncContent>(
+    config: &Config,
+    recorder: &SharedRecorder,
+    deadline: Option<Instant>,
+    prepared: &mut PreparedSend<C>,
+    events: (&broadcast::Sender<MailerEvent>, SendId),
+    progress: Progress<'_>,
+    report: &mut SendReport,
+) -> Result<(), Error> {
+    prepared.skip(|recipient| {
+        progress.delivered.iter().any(|delivered| delivered == recipient)
+            || report.recipients().any(|status| status.recipient == recipient)
+    });
+    let domains = prepared.by_domain.iter().map(|(domain, _)| domain.clone()).collect::<Vec<_>>();
+    let resolved = resolve_ahead(config, &domains).await;
+    let mut groups = Vec::new();
+    for (domain, indices) in &prepared.by_domain {
+        let mx_records = mail::resolve_mx(&resolved, recorder, domain)?;
+        mail::add_to_mx_group(&mut groups, mx_records, domain.clone(), indices.clone());
+    }
+    for group in groups {
+        let host = mail::primary_host(&group.mx_records).unwrap_or_default();
+        let _ = events.0.send(MailerEvent::Connecting { id: events.1, host });
+        let mut connection = open_session(config, &resolved, recorder, deadline, &group).await?;
+        for (domain, indices) in &group.domains {
+            let domain_recipients = indices.iter().map(|&i| prepared.recipients[i].clone()).collect::<Vec<_>>();
+            match transaction(&mut connection, domain, &prepared.from, &domain_recipients, &mut prepared.content, progress.content_sent).await {
+                Ok(transaction) => report.transactions.push(transaction),
+                Err(e) => {
+                    if !matches!(e, Error::Timeout { .. } | Error::Stalled { .. }) { quit(&mut connection).await; }
+                    return Err(e);
+                }
+            }
+        }
+        quit(&mut connection).await;
+    }
+    Ok(())
+}
+
+/// `config` with the MX and address records of `domains` looked up on the runtime,
+/// so the rest of the send never waits for DNS on a blocking call. A resolver set in
+/// `config.resolver` (without an async one) is queried on the blocking pool.
+pub(crate) async fn resolve_ahead(config: &Config, domains: &[String]) -> Config {
+    let resolver: Arc<dyn AsyncResolver> = match (&config.async_resolver, &config.resolver) {
+        (Some(resolver), _) => resolver.clone(),
+        // A relay or socket given directly needs no lookups
+        _ if config.unix_socket.is_some() || config.direct_target.is_some() => return config.clone(),
+        (None, Some(resolver)) => Arc::new(OnBlockingPool(resolver.clone())),
+        (None, None) if !config.test_mode => Arc::new(MicroDnsResolver),
+        (None, None) => return config.clone(),
+    };
+    let mut resolved = dns::resolve_ahead(resolver.as_ref(), domains, config).await;
+    resolved.fallback = match (&config.async_resolver, &config.resolver) {
+        (_, Some(resolver)) => Some(resolver.clone()),
+        (None, None) => Some(Arc::new(MicroDnsResolver)),
+        // Plaintext queries would leak what a custom async resolver (e.g. over DoT) hides
+        (Some(_), None) => None,
+    };
+    let mut config = config.clone();
+    config.resolver = Some(Arc::new(resolved));
+    config
+}
+
+/// Connects to the first reachable MX host of `group` and runs EHLO, STARTTLS and AUTH,
+/// like `Mailer::open_session`. Addresses come from `resolved`, everything else from `config`.
+pub(crate) async fn open_session(config: &Config, resolved: &Config, recorder: &SharedRecorder, deadline: Option<Instant>, group: &MxGroup) -> Result<AsyncConnection, Error> {
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(Error::Timeout { phase: SmtpPhase::Connect, elapsed: Duration::ZERO });
+    }
+    let mut connection = async_io::connect(&group.mx_records, resolved, deadline, recorder)
+        .instrument(trace::connect_span()).await
+        .ok_or(Error::ConnectionFailed)?;
+    connection.deadline = deadline;
+    let starttls_available = async_io::send_ehlo(&mut connection, config.ehlo_name(), false).await?.starttls && connection.can_starttls();
+    let domains = group.domains.iter().map(|(domain, _)| domain.clone()).collect();
+    let dane = match async_io::dane_lookup(&connection, config, resolved, domains).await {
+        Ok(dane) => dane,
+        Err(e) => { quit(&mut connection).await; return Err(e); }
+    };
+    let endpoints = (connection.local_addr(), connection.address);
+    let use_tls = match mail::use_starttls(config, recorder, starttls_available, connection.mx_host.as_deref(), endpoints, dane.as_ref()) {
+        Ok(use_tls) => use_tls,
+        Err(e) => { quit(&mut connection).await; return Err(e); }
+    };
+    if use_tls {
+        let span = trace::starttls_span(connection.mx_host.as_deref());
+        let started = Instant::now();
+        let result = async_io::establish_tls(connection, config, dane).instrument(span).await;
+        metrics::with(&config.metrics, |m| m.tls_handshake(result.as_ref().map(|_| started.elapsed())));
+        let (new_connection, upgraded) = result?;
+        connection = new_connection;
+        if upgraded { async_io::send_ehlo(&mut connection, config.ehlo_name(), true).await?; }
+    }
+    if let Err(e) = login(&mut connection, config).await {
+        if !matches!(e, Error::Timeout { .. } | Error::Stalled { .. }) { quit(&mut connection).await; }
+        return Err(e);
+    }
+    Ok(connection)
+}
+
+/// Authenticates with the credentials of `config`, if it has any
+async fn login(connection: &mut AsyncConnection, config: &Config) -> Result<(), Error> {
+    let Some(auth) = credentials(config).await? else { return Ok(()) };
+    match authenticate(connection, &auth.username, &auth.password).instrument(trace::auth_span(&auth.username)).await {
+        Err(Error::AuthError { code: Some(535), .. }) if config.auth_provider.is_some() => {
+            // The credentials may have been rotated since they were fetched
+            let auth = credentials(config).await?.unwrap_or(auth);
+            authenticate(connection, &auth.username, &auth.password).instrument(trace::auth_span(&auth.username)).await
+        }
+        result => result,
+    }
+}
+
+/// `config.credentials()`, with the provider run on the blocking pool since it may wait
+/// on a secrets store or token endpoint
+async fn credentials(config: &Config) -> Result<Option<Auth>, Error> {
+    let Some(provider) = config.auth_provider.clone() else { return config.credentials() };
+    tokio::task::spawn_blocking(move || provider.credentials())
         .await
-        .unwrap_or_else(|e| Err(Error::Other(format!("Tokio task error: {}", e))))
+        .map_err(|e| Error::Other(format!("credentials provider failed: {}", e)))?
+        .map(Some)
+}
+
+async fn authenticate(connection: &mut AsyncConnection, username: &str, password: &str) -> Result<(), Error> {
+    let mut login = smtp::AuthLogin::new(username, password);
+    let mut next = Some(login.start());
+    while let Some(action) = next {
+        next = match login.reply(connection.exchange(action).await?) {
+            Ok(next) => next,
+            Err(e) => {
+                metrics::with(&connection.metrics, |m| m.auth_failed(&e));
+                return Err(e);
+            }
+        };
     }
-}
\ No newline at end of file
+    connection.emit(SendEvent::Authenticated);
+    Ok(())
+}
+
+pub(crate) async fn quit(connection: &mut AsyncConnection) {
+    connection.enter_phase(SmtpPhase::Quit);
+    if connection.send("QUIT\r\n").await.is_ok() {
+        let _ = connection.read().await;
+    }
+}
+
+/// One mail transaction, like `Mailer::process_mail_internal`. `content_sent` is called
+/// once the content went out completely, from when on the server may have accepted it.
+pub(crate) async fn transaction(connection: &mut AsyncConnection, domain: &str, from: &str, recipients: &[String], content: &mut dyn AsyncContent, content_sent: &(dyn Fn() + Sync)) -> Result<TransactionReport, Error> {
+    let mut transaction = smtp::Transaction::new(domain, from, recipients, connection.dsn.as_ref(), &connection.capabilities, connection.lmtp);
+    let mut next = Some(transaction.start());
+    while let Some(action) = next {
+        next = match action {
+            smtp::Action::Data => {
+                connection.enter_phase(SmtpPhase::DataTransfer);
+                content.send(connection).await?;
+                content_sent();
+                transaction.data_sent()?
+            }
+            action => transaction.reply(connection.exchange(action).await?)?,
+        };
+    }
+    let report = transaction.report(&connection.capabilities);
+    connection.emit(SendEvent::Accepted { code: report.data_code });
+    metrics::with(&connection.metrics, |m| m.delivered(domain, report.recipients.iter().filter(|s| s.accepted).count()));
+    Ok(report)
+}
@@ -0,0 +1,140 @@
+//! Files sent along with a message
+//!
+//! With the `mime-detection` feature, the content type of an attachment is
+//! inferred from its magic bytes (via `infer`) or else from the file extension
+//! (via `mime_guess`). Without it, or if neither matches, attachments are sent
+//! as `application/octet-stream` unless a type is given explicitly.
+
+use std::fmt;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+
+use crate::error::Error;
+
+/// Content type of attachments whose type is unknown
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// A file attached to a [`Mail`](crate::Mail)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attachment {
+    /// File name shown to the recipient
+    pub filename: String,
+    /// MIME type, e.g. `application/pdf`
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    /// An attachment whose content type is guessed from `data` and `filename`
+    pub fn new<S: Into<String>>(filename: S, data: Vec<u8>) -> Self {
+        let filename = filename.into();
+        let content_type = guess_content_type(&filename, &data);
+        Self { filename, content_type, data }
+    }
+
+    /// Reads the file at `path`, naming the attachment after the file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let filename = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok(Self::new(filename, std::fs::read(path)?))
+    }
+
+    /// Overrides the guessed content type
+    pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Self { self.content_type = content_type.into(); self }
+
+    /// Writes the MIME entity for a `multipart/mixed` body, base64 encoded in lines of 76 characters
+    pub(crate) fn write_into<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        // Quotes and line breaks would end the parameter early
+        let filename = self.filename.replace(['"', '\r', '\n'], "");
+        if filename.is_ascii() {
+            write!(
+                out,
+                "Content-Type: {}; name=\"{}\"\r\nContent-Disposition: attachment; filename=\"{}\"\r\n",
+                self.content_type, filename, filename,
+            )?;
+        } else {
+            // RFC 2231 for the filename, plus the RFC 2047 encoded name older clients read
+            write!(
+                out,
+                "Content-Type: {};\r\n name=\"{}\"\r\nContent-Disposition: attachment;\r\n {}\r\n",
+                self.content_type, encoded_words(&filename), extended_parameter("filename", &filename),
+            )?;
+        }
+        out.write_str("Content-Transfer-Encoding: base64\r\n\r\n")?;
+        // 57 bytes encode to exactly one line
+        let mut line = [0u8; 76];
+        for chunk in self.data.chunks(57) {
+            let len = BASE64_STANDARD.encode_slice(chunk, &mut line).map_err(|_| fmt::Error)?;
+            out.write_str(std::str::from_utf8(&line[..len]).map_err(|_| fmt::Error)?)?;
+            out.write_str("\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// `value` as RFC 2047 encoded words of at most 75 characters, folded onto separate lines
+fn encoded_words(value: &str) -> String {
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        // 39 bytes encode to 52 characters, which leaves room for `name="=?UTF-8?B?...?="`
+        if chunk.len() + c.len_utf8() > 39 {
+            words.push(format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(&chunk)));
+    words.join("\r\n ")
+}
+
+/// The RFC 2231 parameter `name*=UTF-8''<percent-encoded value>`, split into numbered
+/// continuations (`name*0*=...; name*1*=...`) when it would make for a long line
+fn extended_parameter(name: &str, value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if encoded.len() <= 45 {
+        return format!("{}*=UTF-8''{}", name, encoded);
+    }
+    let mut sections = Vec::new();
+    let mut rest = encoded.as_str();
+    while !rest.is_empty() {
+        // Never split a %XX escape
+        let mut end = rest.len().min(45);
+        if end < rest.len() {
+            if let Some(percent) = rest[end - 2..end].find('%') {
+                end = end - 2 + percent;
+            }
+        }
+        let charset = if sections.is_empty() { "UTF-8''" } else { "" };
+        sections.push(format!("{}*{}*={}{}", name, sections.len(), charset, &rest[..end]));
+        rest = &rest[end..];
+    }
+    sections.join(";\r\n ")
+}
+
+/// The MIME type of a file named `filename` with the contents `data`, [`OCTET_STREAM`]
+/// if it can't be told (always, without the `mime-detection` feature)
+#[cfg_attr(not(feature = "mime-detection"), allow(unused_variables))]
+pub fn guess_content_type(filename: &str, data: &[u8]) -> String {
+    #[cfg(feature = "mime-detection")]
+    {
+        // The contents are harder to get wrong than the name
+        if let Some(kind) = infer::get(data) {
+            return kind.mime_type().to_string();
+        }
+        if let Some(mime) = mime_guess::from_path(filename).first_raw() {
+            return mime.to_string();
+        }
+    }
+    OCTET_STREAM.to_string()
+}
@@ -0,0 +1,208 @@
+//! Parsing of bounce messages
+//!
+//! [`parse`] turns a delivery status notification (RFC 3464) into a [`Bounce`]
+//! listing every affected recipient with its status. Bounces that are not
+//! proper DSNs, like the plain-text reports of qmail or older Exim setups, are
+//! recognized by looking for SMTP replies next to recipient addresses.
+
+use crate::error::EnhancedStatus;
+use crate::mime::Part;
+
+/// What happened to a recipient, from the DSN `Action` field (RFC 3464 section 2.3.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DsnAction {
+    /// Delivery failed permanently
+    Failed,
+    /// Delivery is still being attempted
+    Delayed,
+    /// Delivered to the recipient's mailbox
+    Delivered,
+    /// Passed on to a system that does not issue DSNs
+    Relayed,
+    /// Delivered and forwarded to further addresses
+    Expanded,
+}
+
+impl DsnAction {
+    fn parse(value: &str) -> Option<Self> {
+        // Extensions like "failed (bad address)" carry a comment after the keyword
+        match value.split_whitespace().next()?.to_lowercase().as_str() {
+            "failed" => Some(DsnAction::Failed),
+            "delayed" => Some(DsnAction::Delayed),
+            "delivered" => Some(DsnAction::Delivered),
+            "relayed" => Some(DsnAction::Relayed),
+            "expanded" => Some(DsnAction::Expanded),
+            _ => None,
+        }
+    }
+}
+
+/// Report for one recipient of the bounced message
+#[derive(Debug, Clone, PartialEq)]
+pub struct BouncedRecipient {
+    /// Address delivery was attempted to (`Final-Recipient`)
+    pub recipient: String,
+    /// Address as originally given by the sender, if the report includes it
+    pub original_recipient: Option<String>,
+    pub action: Option<DsnAction>,
+    /// Enhanced status code, e.g. 5.1.1 for an unknown mailbox
+    pub status: Option<EnhancedStatus>,
+    /// Basic SMTP reply code found in the diagnostic
+    pub smtp_code: Option<u16>,
+    /// The receiving server's explanation, without the `smtp;` type prefix
+    pub diagnostic: Option<String>,
+    /// Server that reported the failure
+    pub remote_mta: Option<String>,
+}
+
+impl BouncedRecipient {
+    /// Whether delivery to this recipient failed for good and should not be retried
+    pub fn is_permanent(&self) -> bool {
+        match (self.action, self.status, self.smtp_code) {
+            (Some(DsnAction::Failed), _, _) => true,
+            (Some(_), _, _) => false,
+            (None, Some(status), _) => status.is_permanent(),
+            (None, None, Some(code)) => (500..600).contains(&code),
+            (None, None, None) => false,
+        }
+    }
+}
+
+/// A parsed bounce message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bounce {
+    /// MTA that generated the report
+    pub reporting_mta: Option<String>,
+    /// Message-ID of the bounced message, if the report quotes its headers
+    pub original_message_id: Option<String>,
+    pub recipients: Vec<BouncedRecipient>,
+    /// Whether the bounce was a standard DSN rather than recognized heuristically
+    pub is_dsn: bool,
+}
+
+/// Parses a raw bounce message. Returns None if the message does not look like a bounce.
+pub fn parse(raw: impl AsRef<[u8]>) -> Option<Bounce> {
+    let message = Part::parse(&String::from_utf8_lossy(raw.as_ref()));
+    let parts = message.walk();
+
+    let original_message_id = parts.iter()
+        .find(|p| matches!(p.mime_type().as_str(), "message/rfc822" | "text/rfc822-headers"))
+        .and_then(|p| Part::parse(&p.body).header("Message-ID").map(String::from));
+
+    if let Some(status_part) = parts.iter().find(|p| p.mime_type() == "message/delivery-status") {
+        let mut groups = field_groups(&status_part.body).into_iter();
+        let per_message = groups.next().unwrap_or_default();
+        let recipients = groups.filter_map(|fields| parse_recipient_fields(&fields)).collect::<Vec<_>>();
+        if !recipients.is_empty() {
+            return Some(Bounce {
+                reporting_mta: per_message.header("Reporting-MTA").map(strip_type),
+                original_message_id,
+                recipients,
+                is_dsn: true,
+            });
+        }
+    }
+
+    let text = parts.iter()
+        .find(|p| p.parts.is_empty() && p.mime_type() == "text/plain")
+        .map(|p| p.body.as_str())
+        .unwrap_or("");
+    let recipients = scan_plain_text(text);
+    if recipients.is_empty() {
+        return None;
+    }
+    Some(Bounce { reporting_mta: None, original_message_id, recipients, is_dsn: false })
+}
+
+/// The blank-line separated field groups of a message/delivery-status body
+fn field_groups(body: &str) -> Vec<Part> {
+    body.split("\n\n")
+        .filter(|group| !group.trim().is_empty())
+        .map(|group| Part::parse(group.trim_start_matches('\n')))
+        .collect()
+}
+
+fn parse_recipient_fields(fields: &Part) -> Option<BouncedRecipient> {
+    let recipient = fields.header("Final-Recipient").map(strip_type)?;
+    let diagnostic = fields.header("Diagnostic-Code").map(strip_type);
+    Some(BouncedRecipient {
+        recipient: unbracket(&recipient),
+        original_recipient: fields.header("Original-Recipient").map(|r| unbracket(&strip_type(r))),
+        action: fields.header("Action").and_then(DsnAction::parse),
+        status: fields.header("Status").and_then(EnhancedStatus::parse),
+        smtp_code: diagnostic.as_deref().and_then(leading_reply_code),
+        diagnostic,
+        remote_mta: fields.header("Remote-MTA").map(strip_type),
+    })
+}
+
+/// Recognizes reports like `<user@example.com>: ... Remote host said: 550 5.1.1 ...`
+/// by pairing each SMTP error reply with the closest address mentioned before it
+fn scan_plain_text(text: &str) -> Vec<BouncedRecipient> {
+    let mut recipients: Vec<BouncedRecipient> = Vec::new();
+    let mut last_address: Option<String> = None;
+    for line in text.lines() {
+        if let Some((code, reply)) = find_error_reply(line) {
+            let address = find_address(reply).or_else(|| find_address(line)).or_else(|| last_address.clone());
+            if let Some(address) = address {
+                if recipients.iter().any(|r| r.recipient.eq_ignore_ascii_case(&address)) {
+                    continue;
+                }
+                recipients.push(BouncedRecipient {
+                    recipient: address,
+                    original_recipient: None,
+                    action: None,
+                    status: EnhancedStatus::parse(&reply[4..]),
+                    smtp_code: Some(code),
+                    diagnostic: Some(reply.trim().to_string()),
+                    remote_mta: None,
+                });
+            }
+        } else if let Some(address) = find_address(line) {
+            last_address = Some(address);
+        }
+    }
+    recipients
+}
+
+/// Finds a 4xx/5xx SMTP reply in `line`, returning its code and the text starting at the code
+fn find_error_reply(line: &str) -> Option<(u16, &str)> {
+    let bytes = line.as_bytes();
+    (0..bytes.len().saturating_sub(3)).find_map(|i| {
+        let at_word_start = i == 0 || (!bytes[i - 1].is_ascii_alphanumeric() && bytes[i - 1] != b'.');
+        let code = &bytes[i..i + 3];
+        let followed_by_space = matches!(bytes[i + 3], b' ' | b'-');
+        if at_word_start && followed_by_space && matches!(code[0], b'4' | b'5') && code[1..].iter().all(u8::is_ascii_digit) {
+            let code = line[i..i + 3].parse().ok()?;
+            Some((code, &line[i..]))
+        } else {
+            None
+        }
+    })
+}
+
+/// First thing in `text` shaped like an email address
+fn find_address(text: &str) -> Option<String> {
+    text.split(|c: char| c.is_whitespace() || "<>()[];:,\"'".contains(c))
+        .find(|token| match token.split_once('@') {
+            Some((local, domain)) => !local.is_empty() && domain.contains('.') && !domain.ends_with('.'),
+            None => false,
+        })
+        .map(String::from)
+}
+
+fn leading_reply_code(diagnostic: &str) -> Option<u16> {
+    diagnostic.get(..3)?.parse::<u16>().ok().filter(|code| (200..600).contains(code))
+}
+
+/// Drops the address or diagnostic type of a DSN field: `rfc822; user@example.com`
+fn strip_type(value: &str) -> String {
+    match value.split_once(';') {
+        Some((_, rest)) => rest.trim().to_string(),
+        None => value.trim().to_string(),
+    }
+}
+
+fn unbracket(address: &str) -> String {
+    address.trim().trim_start_matches('<').trim_end_matches('>').to_string()
+}
@@ -3,33 +3,151 @@
 use std::cell::RefCell;
 use std::ffi::{c_char, c_int, CStr, CString};
 use std::ptr;
+use std::time::UNIX_EPOCH;
+
+use crate::{Config, Error, Mail, Mailer, SendOptions, TlsPolicy, TranscriptEvent};
+
+/// Category of the last error, returned by `micromail_get_last_error_code` so callers
+/// can branch on failures without parsing the message. The values are stable, new
+/// categories are only ever added at the end.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The last call succeeded
+    Ok = 0,
+    /// A null pointer or a string that is not UTF-8 was passed in
+    InvalidArgument = 1,
+    /// The recipient domain has no MX (or address) record, or a null MX
+    NoMx = 2,
+    /// No MX server could be reached, or the connection broke
+    ConnFailed = 3,
+    /// The server answered with a temporary (4xx) failure, retrying later may succeed
+    Smtp4xx = 4,
+    /// The server answered with a permanent (5xx) failure
+    Smtp5xx = 5,
+    /// The server rejected the credentials
+    Auth = 6,
+    /// TLS negotiation failed
+    Tls = 7,
+    /// A timeout or stall limit was hit
+    Timeout = 8,
+    /// DNS resolution failed
+    Dns = 9,
+    /// The mail cannot be sent as it is
+    InvalidMail = 10,
+    /// The recipient is excluded by the recipient policy of the config
+    RecipientBlocked = 11,
+    /// The config contains contradictory settings
+    InvalidConfig = 12,
+    /// A mail provider's HTTP API refused the message
+    HttpApi = 13,
+    /// DKIM signing failed
+    Signing = 14,
+    /// Anything else
+    Other = 15,
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::NoMxRecords | Error::DomainDoesNotAcceptMail(_) => ErrorCode::NoMx,
+            Error::ConnectionFailed | Error::IoError(_) => ErrorCode::ConnFailed,
+            Error::SmtpError { reply, .. } if reply.is_transient() => ErrorCode::Smtp4xx,
+            Error::SmtpError { .. } => ErrorCode::Smtp5xx,
+            Error::AuthError { .. } => ErrorCode::Auth,
+            Error::TlsError(_) => ErrorCode::Tls,
+            Error::Timeout { .. } | Error::Stalled { .. } => ErrorCode::Timeout,
+            Error::DnsError(_) => ErrorCode::Dns,
+            Error::InvalidMailContent(_) => ErrorCode::InvalidMail,
+            Error::RecipientBlocked(_) => ErrorCode::RecipientBlocked,
+            Error::InvalidConfig(_) => ErrorCode::InvalidConfig,
+            Error::HttpApiError { .. } => ErrorCode::HttpApi,
+            #[cfg(feature = "signing")]
+            Error::SigningError(_) => ErrorCode::Signing,
+            _ => ErrorCode::Other,
+        }
+    }
+}
 
-use crate::{Config, Error, Mail, Mailer};
+/// The error of the last failed call on this thread
+struct LastError {
+    message: CString,
+    code: ErrorCode,
+    /// Reply code of the server answer that caused the error, 0 if there was none
+    smtp_code: u16,
+}
 
 thread_local! {
-    static LAST_ERROR_MESSAGE: RefCell<Option<CString>> = RefCell::new(None);
+    static LAST_ERROR: RefCell<Option<LastError>> = RefCell::new(None);
 }
 
 fn update_last_error(err: &Error) {
-    LAST_ERROR_MESSAGE.with(|prev| {
-        *prev.borrow_mut() = Some(CString::new(err.to_string()).unwrap());
+    let smtp_code = match err {
+        Error::AuthError { code, .. } => code.unwrap_or(0),
+        _ => err.reply().map_or(0, |reply| reply.code),
+    };
+    set_last_error(err.to_string(), ErrorCode::from(err), smtp_code);
+}
+
+/// Records a null pointer or non-UTF-8 string passed in by the caller
+fn invalid_argument(message: String) {
+    set_last_error(message, ErrorCode::InvalidArgument, 0);
+}
+
+fn set_last_error(message: String, code: ErrorCode, smtp_code: u16) {
+    // Error messages never contain NUL bytes, but an argument echoed back might
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|prev| {
+        *prev.borrow_mut() = Some(LastError { message, code, smtp_code });
     });
 }
 
 fn clear_last_error() {
-    LAST_ERROR_MESSAGE.with(|prev| {
+    LAST_ERROR.with(|prev| {
         *prev.borrow_mut() = None;
     });
 }
 
+/// Which way a transcript entry went, see `micromail_mailer_log_entry_at`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogDirection {
+    /// Note of the client itself: DNS results, connection, TLS
+    Info = 0,
+    /// Command or message content sent to the server
+    Sent = 1,
+    /// Reply received from the server
+    Received = 2,
+}
+
+/// One entry of a Mailer's transcript, filled in by `micromail_mailer_log_entry_at`
+#[repr(C)]
+#[derive(Debug)]
+pub struct LogEntry {
+    pub direction: LogDirection,
+    /// Reply code of a received entry, 0 for the others
+    pub code: c_int,
+    /// The entry's lines joined with newlines, to be freed with `micromail_free_string`
+    pub text: *mut c_char,
+    /// When the entry was recorded, in milliseconds since the UNIX epoch
+    pub timestamp_ms: i64,
+}
+
 /// Opaque pointer to a Config object
 pub type ConfigPtr = *mut Config;
 
 /// Opaque pointer to a Mailer object
 pub type MailerPtr = *mut Mailer;
 
+/// A mail built through the C API, with the envelope settings that go with it
+pub struct CMail {
+    mail: Mail,
+    /// MAIL FROM address, the From address if None
+    envelope_from: Option<String>,
+}
+
 /// Opaque pointer to a Mail object
-pub type MailPtr = *mut Mail;
+pub type MailPtr = *mut CMail;
 
 /// Create a new Config
 #[no_mangle]
@@ -37,14 +155,14 @@ pub extern "C" fn micromail_config_new(domain: *const c_char) -> ConfigPtr {
     clear_last_error();
     let domain_str = unsafe {
         if domain.is_null() {
-            update_last_error(&Error::Other("Invalid domain pointer".to_string()));
+            invalid_argument("Invalid domain pointer".to_string());
             return ptr::null_mut();
         }
 
         match CStr::from_ptr(domain).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid domain string: {}", e)));
+                invalid_argument(format!("Invalid domain string: {}", e));
                 return ptr::null_mut();
             }
         }
@@ -70,7 +188,7 @@ pub extern "C" fn micromail_config_free(config: ConfigPtr) {
 pub extern "C" fn micromail_config_enable_test_mode(config: ConfigPtr, enable: c_int) -> c_int {
     clear_last_error();
     if config.is_null() {
-        update_last_error(&Error::Other("Invalid config pointer".to_string()));
+        invalid_argument("Invalid config pointer".to_string());
         // Removed duplicate update_last_error call
         return -1;
     }
@@ -87,7 +205,7 @@ pub extern "C" fn micromail_config_enable_test_mode(config: ConfigPtr, enable: c
 pub extern "C" fn micromail_config_set_timeout(config: ConfigPtr, timeout_secs: c_int) -> c_int {
     clear_last_error();
     if config.is_null() {
-        update_last_error(&Error::Other("Invalid config pointer".to_string()));
+        invalid_argument("Invalid config pointer".to_string());
         return -1;
     }
 
@@ -104,13 +222,13 @@ pub extern "C" fn micromail_config_set_timeout(config: ConfigPtr, timeout_secs:
 pub extern "C" fn micromail_config_set_use_tls(config: ConfigPtr, use_tls: c_int) -> c_int {
     clear_last_error();
     if config.is_null() {
-        update_last_error(&Error::Other("Invalid config pointer".to_string()));
+        invalid_argument("Invalid config pointer".to_string());
         return -1;
     }
 
     unsafe {
         let config = &mut *config;
-        config.use_tls = use_tls != 0;
+        config.tls_policy = if use_tls != 0 { TlsPolicy::Opportunistic } else { TlsPolicy::None };
     }
 
     0
@@ -125,15 +243,15 @@ pub extern "C" fn micromail_config_set_auth(
 ) -> c_int {
     clear_last_error();
     if config.is_null() {
-        update_last_error(&Error::Other("Invalid config pointer".to_string()));
+        invalid_argument("Invalid config pointer".to_string());
         return -1;
     }
     if username.is_null() {
-        update_last_error(&Error::Other("Invalid username pointer".to_string()));
+        invalid_argument("Invalid username pointer".to_string());
         return -1;
     }
     if password.is_null() {
-        update_last_error(&Error::Other("Invalid password pointer".to_string()));
+        invalid_argument("Invalid password pointer".to_string());
         return -1;
     }
 
@@ -143,7 +261,7 @@ pub extern "C" fn micromail_config_set_auth(
         let username_str = match CStr::from_ptr(username).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid username string: {}", e)));
+                invalid_argument(format!("Invalid username string: {}", e));
                 return -1;
             }
         };
@@ -151,7 +269,7 @@ pub extern "C" fn micromail_config_set_auth(
         let password_str = match CStr::from_ptr(password).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid password string: {}", e)));
+                invalid_argument(format!("Invalid password string: {}", e));
                 return -1;
             }
         };
@@ -170,7 +288,7 @@ pub extern "C" fn micromail_config_set_auth(
 pub extern "C" fn micromail_mailer_new(config: ConfigPtr) -> MailerPtr {
     clear_last_error();
     if config.is_null() {
-        update_last_error(&Error::Other("Invalid config pointer".to_string()));
+        invalid_argument("Invalid config pointer".to_string());
         return ptr::null_mut();
     }
 
@@ -196,7 +314,7 @@ pub extern "C" fn micromail_mailer_free(mailer: MailerPtr) {
 #[no_mangle]
 pub extern "C" fn micromail_mail_new() -> MailPtr {
     clear_last_error();
-    let mail = Mail::new();
+    let mail = CMail { mail: Mail::new(), envelope_from: None };
     Box::into_raw(Box::new(mail))
 }
 
@@ -216,21 +334,21 @@ pub extern "C" fn micromail_mail_free(mail: MailPtr) {
 pub extern "C" fn micromail_mail_set_from(mail: MailPtr, from: *const c_char) -> c_int {
     clear_last_error();
     if mail.is_null() {
-        update_last_error(&Error::Other("Invalid mail pointer".to_string()));
+        invalid_argument("Invalid mail pointer".to_string());
         return -1;
     }
     if from.is_null() {
-        update_last_error(&Error::Other("Invalid from pointer".to_string()));
+        invalid_argument("Invalid from pointer".to_string());
         return -1;
     }
 
     unsafe {
-        let mail = &mut *mail;
+        let mail = &mut (*mail).mail;
 
         let from_str = match CStr::from_ptr(from).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid from string: {}", e)));
+                invalid_argument(format!("Invalid from string: {}", e));
                 return -1;
             }
         };
@@ -246,21 +364,21 @@ pub extern "C" fn micromail_mail_set_from(mail: MailPtr, from: *const c_char) ->
 pub extern "C" fn micromail_mail_set_to(mail: MailPtr, to: *const c_char) -> c_int {
     clear_last_error();
     if mail.is_null() {
-        update_last_error(&Error::Other("Invalid mail pointer".to_string()));
+        invalid_argument("Invalid mail pointer".to_string());
         return -1;
     }
     if to.is_null() {
-        update_last_error(&Error::Other("Invalid to pointer".to_string()));
+        invalid_argument("Invalid to pointer".to_string());
         return -1;
     }
 
     unsafe {
-        let mail = &mut *mail;
+        let mail = &mut (*mail).mail;
 
         let to_str = match CStr::from_ptr(to).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid to string: {}", e)));
+                invalid_argument(format!("Invalid to string: {}", e));
                 return -1;
             }
         };
@@ -276,21 +394,21 @@ pub extern "C" fn micromail_mail_set_to(mail: MailPtr, to: *const c_char) -> c_i
 pub extern "C" fn micromail_mail_set_subject(mail: MailPtr, subject: *const c_char) -> c_int {
     clear_last_error();
     if mail.is_null() {
-        update_last_error(&Error::Other("Invalid mail pointer".to_string()));
+        invalid_argument("Invalid mail pointer".to_string());
         return -1;
     }
     if subject.is_null() {
-        update_last_error(&Error::Other("Invalid subject pointer".to_string()));
+        invalid_argument("Invalid subject pointer".to_string());
         return -1;
     }
 
     unsafe {
-        let mail = &mut *mail;
+        let mail = &mut (*mail).mail;
 
         let subject_str = match CStr::from_ptr(subject).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid subject string: {}", e)));
+                invalid_argument(format!("Invalid subject string: {}", e));
                 return -1;
             }
         };
@@ -306,21 +424,21 @@ pub extern "C" fn micromail_mail_set_subject(mail: MailPtr, subject: *const c_ch
 pub extern "C" fn micromail_mail_set_body(mail: MailPtr, body: *const c_char) -> c_int {
     clear_last_error();
     if mail.is_null() {
-        update_last_error(&Error::Other("Invalid mail pointer".to_string()));
+        invalid_argument("Invalid mail pointer".to_string());
         return -1;
     }
     if body.is_null() {
-        update_last_error(&Error::Other("Invalid body pointer".to_string()));
+        invalid_argument("Invalid body pointer".to_string());
         return -1;
     }
 
     unsafe {
-        let mail = &mut *mail;
+        let mail = &mut (*mail).mail;
 
         let body_str = match CStr::from_ptr(body).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid body string: {}", e)));
+                invalid_argument(format!("Invalid body string: {}", e));
                 return -1;
             }
         };
@@ -340,25 +458,25 @@ pub extern "C" fn micromail_mail_add_header(
 ) -> c_int {
     clear_last_error();
     if mail.is_null() {
-        update_last_error(&Error::Other("Invalid mail pointer".to_string()));
+        invalid_argument("Invalid mail pointer".to_string());
         return -1;
     }
     if name.is_null() {
-        update_last_error(&Error::Other("Invalid header name pointer".to_string()));
+        invalid_argument("Invalid header name pointer".to_string());
         return -1;
     }
     if value.is_null() {
-        update_last_error(&Error::Other("Invalid header value pointer".to_string()));
+        invalid_argument("Invalid header value pointer".to_string());
         return -1;
     }
 
     unsafe {
-        let mail = &mut *mail;
+        let mail = &mut (*mail).mail;
 
         let name_str = match CStr::from_ptr(name).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid header name string: {}", e)));
+                invalid_argument(format!("Invalid header name string: {}", e));
                 return -1;
             }
         };
@@ -366,7 +484,7 @@ pub extern "C" fn micromail_mail_add_header(
         let value_str = match CStr::from_ptr(value).to_str() {
             Ok(s) => s,
             Err(e) => {
-                update_last_error(&Error::Other(format!("Invalid header value string: {}", e)));
+                invalid_argument(format!("Invalid header value string: {}", e));
                 return -1;
             }
         };
@@ -377,24 +495,84 @@ pub extern "C" fn micromail_mail_add_header(
     0
 }
 
+/// Add a To recipient to a Mail object, after the ones already set
+#[no_mangle]
+pub extern "C" fn micromail_mail_add_to(mail: MailPtr, to: *const c_char) -> c_int {
+    update_mail(mail, to, "to", |mail, to| {
+        if !mail.mail.to.is_empty() {
+            mail.mail.to.push_str(", ");
+        }
+        mail.mail.to.push_str(to);
+    })
+}
+
+/// Add a Cc recipient to a Mail object
+#[no_mangle]
+pub extern "C" fn micromail_mail_add_cc(mail: MailPtr, cc: *const c_char) -> c_int {
+    update_mail(mail, cc, "cc", |mail, cc| mail.mail.cc.push(cc.to_string()))
+}
+
+/// Add a Bcc recipient to a Mail object, who gets the mail without appearing in its headers
+#[no_mangle]
+pub extern "C" fn micromail_mail_add_bcc(mail: MailPtr, bcc: *const c_char) -> c_int {
+    update_mail(mail, bcc, "bcc", |mail, bcc| mail.mail.bcc.push(bcc.to_string()))
+}
+
+/// Set the envelope sender (MAIL FROM) of a Mail object, e.g. a bounce address,
+/// instead of its From address
+#[no_mangle]
+pub extern "C" fn micromail_mail_set_envelope_from(mail: MailPtr, address: *const c_char) -> c_int {
+    update_mail(mail, address, "envelope from", |mail, address| mail.envelope_from = Some(address.to_string()))
+}
+
+/// Checks the pointers of a string setter and applies `update` to the mail
+fn update_mail(mail: MailPtr, value: *const c_char, name: &str, update: impl FnOnce(&mut CMail, &str)) -> c_int {
+    clear_last_error();
+    if mail.is_null() {
+        invalid_argument("Invalid mail pointer".to_string());
+        return -1;
+    }
+    if value.is_null() {
+        invalid_argument(format!("Invalid {} pointer", name));
+        return -1;
+    }
+
+    unsafe {
+        match CStr::from_ptr(value).to_str() {
+            Ok(value) => {
+                update(&mut *mail, value);
+                0
+            }
+            Err(e) => {
+                invalid_argument(format!("Invalid {} string: {}", name, e));
+                -1
+            }
+        }
+    }
+}
+
 /// Send a mail using a Mailer
 #[no_mangle]
 pub extern "C" fn micromail_mailer_send(mailer: MailerPtr, mail: MailPtr) -> c_int {
     clear_last_error();
     if mailer.is_null() {
-        update_last_error(&Error::Other("Invalid mailer pointer".to_string()));
+        invalid_argument("Invalid mailer pointer".to_string());
         return -1;
     }
     if mail.is_null() {
-        update_last_error(&Error::Other("Invalid mail pointer".to_string()));
+        invalid_argument("Invalid mail pointer".to_string());
         return -1;
     }
 
     unsafe {
         let mailer = &mut *mailer;
         let mail = &*mail;
+        let result = match &mail.envelope_from {
+            Some(address) => mailer.send_with(mail.mail.clone(), SendOptions::new().envelope_from(address.clone())),
+            None => mailer.send_sync(mail.mail.clone()),
+        };
 
-        match mailer.send_sync(mail.clone()) {
+        match result {
             Ok(_) => 0,
             Err(e) => {
                 update_last_error(&e);
@@ -404,25 +582,61 @@ pub extern "C" fn micromail_mailer_send(mailer: MailerPtr, mail: MailPtr) -> c_i
     }
 }
 
-/// Get the last error message
+/// Get the message of the last error on this thread, an empty string if the last call
+/// succeeded. The pointer stays valid until the next call on this thread that can fail
+/// or `micromail_clear_last_error`; use `micromail_copy_last_error` to keep the message.
 #[no_mangle]
 pub extern "C" fn micromail_get_last_error() -> *const c_char {
-    LAST_ERROR_MESSAGE.with(|prev| match *prev.borrow() {
-        Some(ref e) => e.as_ptr(),
-        None => {
-            // Should not happen if update_last_error is called correctly
-            let unknown_err = CString::new("Unknown error").unwrap();
-            unknown_err.as_ptr()
+    LAST_ERROR.with(|prev| match *prev.borrow() {
+        Some(ref e) => e.message.as_ptr(),
+        None => c"".as_ptr(),
+    })
+}
+
+/// Copy the message of the last error on this thread into `buf`, truncated to fit
+/// `len` bytes including the terminating NUL. Returns the full length of the message,
+/// so a result of `len` or more means it was cut; `buf` may be null when `len` is 0.
+#[no_mangle]
+pub extern "C" fn micromail_copy_last_error(buf: *mut c_char, len: usize) -> c_int {
+    LAST_ERROR.with(|prev| {
+        let prev = prev.borrow();
+        let message = prev.as_ref().map_or(&[][..], |e| e.message.as_bytes());
+        if !buf.is_null() && len > 0 {
+            let copied = message.len().min(len - 1);
+            unsafe {
+                ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buf, copied);
+                *buf.add(copied) = 0;
+            }
         }
+        message.len() as c_int
     })
 }
 
+/// Forget the last error on this thread, so the getters report success again
+#[no_mangle]
+pub extern "C" fn micromail_clear_last_error() {
+    clear_last_error();
+}
+
+/// Get the category of the last error on this thread, `ErrorCode::Ok` if the last call succeeded
+#[no_mangle]
+pub extern "C" fn micromail_get_last_error_code() -> ErrorCode {
+    LAST_ERROR.with(|prev| prev.borrow().as_ref().map_or(ErrorCode::Ok, |e| e.code))
+}
+
+/// Get the SMTP reply code of the server answer behind the last error, 0 if the error
+/// did not come from a server reply
+#[no_mangle]
+pub extern "C" fn micromail_get_last_smtp_code() -> c_int {
+    LAST_ERROR.with(|prev| prev.borrow().as_ref().map_or(0, |e| e.smtp_code as c_int))
+}
+
 /// Get the log messages from a Mailer
 #[no_mangle]
 pub extern "C" fn micromail_mailer_get_log(mailer: MailerPtr) -> *mut c_char {
     clear_last_error();
     if mailer.is_null() {
-        update_last_error(&Error::Other("Invalid mailer pointer".to_string()));
+        invalid_argument("Invalid mailer pointer".to_string());
         return ptr::null_mut();
     }
 
@@ -440,7 +654,57 @@ pub extern "C" fn micromail_mailer_get_log(mailer: MailerPtr) -> *mut c_char {
     }
 }
 
-/// Free a string returned by micromail_mailer_get_log
+/// Get the number of entries in the transcript of the Mailer's last send, -1 on error
+#[no_mangle]
+pub extern "C" fn micromail_mailer_log_count(mailer: MailerPtr) -> c_int {
+    clear_last_error();
+    if mailer.is_null() {
+        invalid_argument("Invalid mailer pointer".to_string());
+        return -1;
+    }
+
+    unsafe { (*mailer).with_transcript(|transcript| transcript.len() as c_int) }
+}
+
+/// Fill `entry` with entry `index` of the transcript of the Mailer's last send.
+/// The caller frees `entry->text` with `micromail_free_string`.
+#[no_mangle]
+pub extern "C" fn micromail_mailer_log_entry_at(mailer: MailerPtr, index: c_int, entry: *mut LogEntry) -> c_int {
+    clear_last_error();
+    if mailer.is_null() {
+        invalid_argument("Invalid mailer pointer".to_string());
+        return -1;
+    }
+    if entry.is_null() {
+        invalid_argument("Invalid log entry pointer".to_string());
+        return -1;
+    }
+
+    unsafe {
+        (*mailer).with_transcript(|transcript| {
+            let Some(recorded) = usize::try_from(index).ok().and_then(|index| transcript.entries().get(index)) else {
+                invalid_argument(format!("Log entry index {} out of range (0..{})", index, transcript.len()));
+                return -1;
+            };
+            let (direction, code, text) = match &recorded.event {
+                TranscriptEvent::Note(message) => (LogDirection::Info, 0, message.clone()),
+                TranscriptEvent::Connected { address } => (LogDirection::Info, 0, format!("Connected to {}", address)),
+                TranscriptEvent::TlsNegotiated(info) => (LogDirection::Info, 0, crate::connection::tls_note(info)),
+                TranscriptEvent::TlsEstablished => (LogDirection::Info, 0, "TLS established".to_string()),
+                TranscriptEvent::CommandSent(command) => (LogDirection::Sent, 0, command.clone()),
+                TranscriptEvent::ResponseReceived { code, lines } => (LogDirection::Received, *code as c_int, lines.join("\n")),
+                TranscriptEvent::DataSent { content, .. } => (LogDirection::Sent, 0, content.clone()),
+            };
+            let timestamp_ms = recorded.time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64);
+            // Server replies and message content may contain NUL bytes, which C strings cannot
+            let text = CString::new(text.replace('\0', "")).unwrap().into_raw();
+            *entry = LogEntry { direction, code, text, timestamp_ms };
+            0
+        })
+    }
+}
+
+/// Free a string returned by micromail_mailer_get_log or the text of a LogEntry
 #[no_mangle]
 pub extern "C" fn micromail_free_string(s: *mut c_char) {
     clear_last_error();
@@ -0,0 +1,127 @@
+//! Mail merge: one personalized copy of a template per recipient
+//!
+//! The subject, body and header values of the template may contain placeholders
+//! like `{{first_name}}`, which are replaced by the recipient's variables. The
+//! variable `email` is always set to the recipient's address. Values are inserted
+//! as they are, so escape them beforehand for HTML bodies. A value with a line break
+//! is refused in the subject and headers, where it would start a header of its own.
+
+use std::collections::HashMap;
+
+use crate::{
+    error::Error,
+    mail::{Mail, Mailer},
+    report::SendReport,
+};
+
+/// A template [`Mail`] and the recipients to personalize it for
+///
+/// ```no_run
+/// use micromail::{Campaign, Config, Mail, Mailer};
+///
+/// let template = Mail::new()
+///     .from("news@example.com")
+///     .subject("Hello {{name}}")
+///     .body("Your code is {{code}}.");
+/// let campaign = Campaign::new(template)
+///     .recipient("ann@example.org", [("name", "Ann"), ("code", "A-1")])
+///     .recipient("bob@example.net", [("name", "Bob"), ("code", "B-2")]);
+/// let mut mailer = Mailer::new(Config::new("example.com"));
+/// for (recipient, result) in campaign.send(&mut mailer) {
+///     println!("{}: {:?}", recipient, result);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Campaign {
+    template: Mail,
+    recipients: Vec<(String, HashMap<String, String>)>,
+}
+
+impl Campaign {
+    pub fn new(template: Mail) -> Self { Self { template, recipients: Vec::new() } }
+
+    /// Adds a recipient with the values of its placeholders
+    pub fn recipient<S, I, K, V>(mut self, address: S, variables: I) -> Self
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let variables = variables.into_iter().map(|(name, value)| (name.into(), value.into())).collect();
+        self.recipients.push((address.into(), variables));
+        self
+    }
+
+    /// Addresses of the recipients, in the order they were added
+    pub fn recipients(&self) -> impl Iterator<Item = &str> {
+        self.recipients.iter().map(|(address, _)| address.as_str())
+    }
+
+    /// The personalized mail for every recipient, in the order they were added.
+    ///
+    /// Like [`Mailer::send_individually`], each copy has only its recipient in `To`
+    /// and a fresh Message-ID. A placeholder without a value, or with a line break in the
+    /// subject or a header, is an [`Error::InvalidMailContent`] for that recipient.
+    pub fn render(&self) -> Vec<(String, Result<Mail, Error>)> {
+        self.recipients.iter().map(|(address, variables)| (address.clone(), self.render_for(address, variables))).collect()
+    }
+
+    fn render_for(&self, address: &str, variables: &HashMap<String, String>) -> Result<Mail, Error> {
+        let lookup = |name: &str| match name {
+            "email" if !variables.contains_key("email") => Some(address),
+            _ => variables.get(name).map(String::as_str),
+        };
+        let mut mail = self.template.clone();
+        mail.to = address.to_string();
+        mail.cc.clear();
+        mail.bcc.clear();
+        mail.message_id = None;
+        mail.subject = merge(&mail.subject, lookup, true)?;
+        mail.body = merge(&mail.body, lookup, false)?;
+        for value in mail.headers.values_mut() {
+            *value = merge(value, lookup, true)?;
+        }
+        Ok(mail)
+    }
+
+    /// Sends every personalized copy, sharing one connection per MX host like
+    /// [`Mailer::send_individually`]. Results are in the order the recipients were added.
+    pub fn send(&self, mailer: &mut Mailer) -> Vec<(String, Result<SendReport, Error>)> {
+        mailer.send_copies(self.render())
+    }
+
+    /// Sends every personalized copy through `pool`, up to its connection limit at a time.
+    /// Results are in the order the recipients were added.
+    #[cfg(feature = "tokio-runtime")]
+    pub async fn send_pooled(&self, pool: &crate::pool::AsyncMailerPool) -> Vec<(String, Result<SendReport, Error>)> {
+        let sends = self.render().into_iter().map(|(recipient, mail)| async move {
+            let result = match mail {
+                Ok(mail) => pool.send(mail).await,
+                Err(e) => Err(e),
+            };
+            (recipient, result)
+        });
+        futures::future::join_all(sends).await
+    }
+}
+
+/// Replaces every `{{name}}` in `template` with its value. In a `header` a value must not
+/// contain CR or LF, which would end the header and let the value inject others.
+fn merge<'a, F: Fn(&str) -> Option<&'a str>>(template: &str, lookup: F, header: bool) -> Result<String, Error> {
+    let mut merged = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else { break };
+        let name = rest[start + 2..start + 2 + len].trim();
+        let value = lookup(name).ok_or_else(|| Error::InvalidMailContent(format!("no value for placeholder {{{{{}}}}}", name)))?;
+        if header && value.contains(['\r', '\n']) {
+            return Err(Error::InvalidMailContent(format!("the value for placeholder {{{{{}}}}} contains a line break", name)));
+        }
+        merged.push_str(&rest[..start]);
+        merged.push_str(value);
+        rest = &rest[start + 4 + len..];
+    }
+    merged.push_str(rest);
+    Ok(merged)
+}
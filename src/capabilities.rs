@@ -0,0 +1,72 @@
+//! SMTP service extensions a server advertises in its EHLO reply
+
+use crate::error::SmtpReply;
+
+/// What the server offered in its EHLO (or LHLO) reply. Empty if it only understood HELO.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// STARTTLS (RFC 3207)
+    pub starttls: bool,
+    /// SASL mechanisms offered with AUTH, upper-cased, e.g. `["LOGIN", "PLAIN"]`
+    pub auth: Vec<String>,
+    /// Largest message the server accepts in bytes (SIZE, RFC 1870; None = not announced or no limit)
+    pub size: Option<u64>,
+    /// PIPELINING (RFC 2920)
+    pub pipelining: bool,
+    /// 8BITMIME (RFC 6152)
+    pub eight_bit_mime: bool,
+    /// SMTPUTF8 (RFC 6531)
+    pub smtputf8: bool,
+    /// DSN (RFC 3461)
+    pub dsn: bool,
+    /// CHUNKING, i.e. BDAT (RFC 3030)
+    pub chunking: bool,
+    /// ENHANCEDSTATUSCODES (RFC 2034)
+    pub enhanced_status_codes: bool,
+    /// Every extension line as sent, including the ones without a field above
+    pub extensions: Vec<String>,
+}
+
+impl Capabilities {
+    /// Parses a positive EHLO reply, whose first line is the server's greeting
+    pub fn from_reply(reply: &SmtpReply) -> Self {
+        let mut capabilities = Capabilities::default();
+        for line in reply.lines.iter().skip(1) {
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else { continue };
+            let keyword = keyword.to_ascii_uppercase();
+            match keyword.as_str() {
+                "STARTTLS" => capabilities.starttls = true,
+                // `AUTH=LOGIN` is how some old servers announce it
+                _ if keyword == "AUTH" || keyword.starts_with("AUTH=") => {
+                    let listed = keyword.strip_prefix("AUTH=").into_iter().flat_map(|list| list.split(','));
+                    for mechanism in listed.chain(words).map(str::to_ascii_uppercase) {
+                        if !mechanism.is_empty() && !capabilities.auth.contains(&mechanism) {
+                            capabilities.auth.push(mechanism);
+                        }
+                    }
+                }
+                "SIZE" => capabilities.size = words.next().and_then(|size| size.parse().ok()).filter(|&size| size > 0),
+                "PIPELINING" => capabilities.pipelining = true,
+                "8BITMIME" => capabilities.eight_bit_mime = true,
+                "SMTPUTF8" => capabilities.smtputf8 = true,
+                "DSN" => capabilities.dsn = true,
+                "CHUNKING" => capabilities.chunking = true,
+                "ENHANCEDSTATUSCODES" => capabilities.enhanced_status_codes = true,
+                _ => {}
+            }
+            capabilities.extensions.push(line.clone());
+        }
+        capabilities
+    }
+
+    /// Whether the server announced the extension `keyword`, e.g. `"XCLIENT"`
+    pub fn supports(&self, keyword: &str) -> bool {
+        self.extensions.iter().any(|line| line.split_whitespace().next().is_some_and(|k| k.eq_ignore_ascii_case(keyword)))
+    }
+
+    /// Whether AUTH offers the SASL `mechanism`, e.g. `"PLAIN"`
+    pub fn supports_auth(&self, mechanism: &str) -> bool {
+        self.auth.iter().any(|m| m.eq_ignore_ascii_case(mechanism))
+    }
+}
@@ -1,33 +1,390 @@
 //! Configuration for the micromail crate.
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::time::Duration;
 use std::sync::Arc;
 use std::fmt;
 
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use crate::dane::DaneMode;
+use crate::dns::{DnsCache, MxRecord};
+use crate::dsn::Dsn;
+use crate::ids::{IdProvider, RandomIds};
+use crate::metrics::Metrics;
+use crate::resolver::Resolver;
+use crate::tls::{TlsPolicy, TlsSessionCache, TlsVersion};
+use crate::tlsrpt::TlsReportCollector;
+#[cfg(feature = "tokio-runtime")]
+use crate::resolver::AsyncResolver;
+#[cfg(feature = "hickory")]
+use crate::resolver::HickoryResolver;
+
 #[cfg(feature = "signing")]
 use mail_auth::common::crypto::{RsaKey, Sha256}; // As per successful subtask for 0.7.1
 
+/// Settings for sending mail.
+///
+/// With the `serialize` feature a config can be persisted and loaded again. Keys, resolvers,
+/// caches and collectors are runtime objects and are left out, a loaded config gets their
+/// defaults. The AUTH password is never written out, but is read if present.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(default))]
 pub struct Config {
     pub domain: String,
+    /// Name sent with EHLO/HELO, the FQDN of the sending host (None = `domain`)
+    pub ehlo_hostname: Option<String>,
+    /// Limit for establishing a connection, see [`Config::connect_timeout`]
     pub timeout: Duration,
-    pub use_tls: bool,
+    /// Whether to use STARTTLS and whether a send may go ahead without it
+    pub tls_policy: TlsPolicy,
     pub ports: Vec<u16>,
     pub auth: Option<Auth>,
+    /// Fetches credentials whenever a connection authenticates, taking precedence over `auth`
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub auth_provider: Option<AuthProvider>,
     #[cfg(feature = "signing")]
+    #[cfg_attr(feature = "serialize", serde(skip))]
     pub dkim_config: Option<Arc<DkimConfig>>,
     pub test_mode: bool,
+    /// Whether the AUTH exchange goes into the transcript verbatim; off by default, so the
+    /// base64 username and password are replaced by a placeholder
+    pub log_credentials: bool,
+    /// Whether formatting adds Date, Message-ID, Content-Type and X-Mailer headers the mail doesn't
+    /// have; on by default. Headers set with [`Mail::header`](crate::Mail::header) always win.
+    pub auto_headers: bool,
+    /// Value of the X-Mailer header added to every mail (None = no header)
+    pub user_agent: Option<String>,
+    /// Address given in MAIL FROM, where bounces go (None = the `From` of the mail)
+    pub envelope_from: Option<String>,
+    /// Delivery status notifications requested from servers that support them (None = none)
+    pub dsn: Option<Dsn>,
+    /// Whether [`SendReport::message`](crate::SendReport::message) gets a copy of the
+    /// message as sent (default false, the copy costs as much memory as the message)
+    pub keep_sent_message: bool,
+    /// Deliver all mail to this address instead of its recipients, who are listed in an
+    /// `X-Original-To` header (None = deliver normally). For staging environments.
+    pub sandbox_redirect: Option<String>,
+    /// Recipient patterns mail may go to (empty = any), see [`Config::allow_recipient`]
+    pub recipient_allowlist: Vec<String>,
+    /// Recipient patterns mail must never go to, checked before the allowlist
+    pub recipient_denylist: Vec<String>,
+    /// Generator for MIME boundaries and other per-message identifiers
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub id_provider: Arc<dyn IdProvider>,
+    /// Local address outbound connections are bound to (None = let the OS choose)
+    pub bind_addr: Option<IpAddr>,
+    /// Deliver everything to the server listening on this Unix socket instead of the
+    /// MX hosts of the recipients (None = connect over TCP)
+    pub unix_socket: Option<PathBuf>,
+    /// Connect to this address instead of looking up the MX hosts of the recipients
+    /// (None = resolve MX records). `ports` is ignored when set.
+    pub direct_target: Option<SocketAddr>,
+    /// Speak LMTP (RFC 2033) instead of SMTP: LHLO instead of EHLO, and a reply per
+    /// recipient after the message content
+    pub lmtp: bool,
+    /// Per-phase limits for waiting on the server
+    pub timeouts: Timeouts,
+    /// Upper bound for a whole send, from DNS lookup to the final reply (None = unlimited)
+    pub deadline: Option<Duration>,
+    /// Abort with `Error::Stalled` once no bytes were read or written for this long (None = off)
+    pub stall_timeout: Option<Duration>,
+    /// Automatic retries of failed sends (None = fail on the first error)
+    pub retry: Option<RetryPolicy>,
+    /// Wait this long and try once more when the server greylists the mail (None = fail)
+    pub greylist_delay: Option<Duration>,
+    /// Cache for MX and address lookups, shared by all clones of this config (None = always query)
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub dns_cache: Option<Arc<DnsCache>>,
+    /// Where MX and address records come from (None = built-in `MicroDnsResolver`, or
+    /// failing lookups if only `async_resolver` is set, rather than plaintext DNS behind its back).
+    /// Also consulted in test mode, instead of the fixed test-mode answers.
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Filters and reorders the MX hosts of each domain before they are tried (None = DNS order)
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub mx_policy: Option<MxPolicy>,
+    /// Resolver `AsyncMailer` looks up MX and address records with before connecting
+    /// (None = `resolver` if set, else `MicroDnsResolver` over tokio sockets). The blocking
+    /// `Mailer`, the diagnostics and the TXT and PTR lookups only use `resolver`.
+    #[cfg(feature = "tokio-runtime")]
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub async_resolver: Option<Arc<dyn AsyncResolver>>,
+    /// Whether to check server certificates against DNSSEC-validated TLSA records (RFC 7672)
+    pub dane: DaneMode,
+    /// Receives counts of connection attempts, handshakes, deliveries and bytes sent (None = not counted)
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Where DANE outcomes are recorded for TLS reporting (RFC 8460, None = not recorded)
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub tls_reports: Option<Arc<TlsReportCollector>>,
+    /// Skip verification of server certificates. Only for testing against servers with
+    /// self-signed certificates, it makes STARTTLS useless against active attackers.
+    pub accept_invalid_certs: bool,
+    /// Root certificates trusted in addition to (or with `tls_webpki_roots` off, instead of)
+    /// the Mozilla root store, e.g. the private CA of an internal relay
+    #[cfg_attr(feature = "serialize", serde(with = "der_certs"))]
+    pub tls_root_certs: Vec<CertificateDer<'static>>,
+    /// Whether to trust the Mozilla root store bundled with `webpki-roots`
+    pub tls_webpki_roots: bool,
+    /// Certificate presented to servers that request client authentication
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub tls_client_identity: Option<Arc<TlsIdentity>>,
+    /// Lowest TLS version to negotiate (None = TLS 1.2)
+    pub tls_min_version: Option<TlsVersion>,
+    /// Highest TLS version to negotiate (None = TLS 1.3)
+    pub tls_max_version: Option<TlsVersion>,
+    /// Cipher suites to offer by their IANA names, e.g. `TLS13_AES_256_GCM_SHA384`
+    /// (None = all suites of the crypto provider)
+    pub tls_cipher_suites: Option<Vec<String>>,
+    /// Name sent via SNI and checked against the server certificate, instead of the MX
+    /// host name, e.g. when a relay is reached through an address or an internal alias
+    pub tls_server_name: Option<String>,
+    /// TLS sessions resumed when reconnecting to a server, shared by all clones of this
+    /// config (None = always do a full handshake)
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub tls_session_cache: Option<Arc<TlsSessionCache>>,
+    /// SHA-256 hashes of the SubjectPublicKeyInfo of certificates the server must present
+    /// one of (empty = no pinning)
+    pub tls_pinned_spki: Vec<[u8; 32]>,
+    /// Verify certificates with the operating system's trust store and revocation checks
+    /// instead of the bundled Mozilla roots; `tls_root_certs` are trusted in addition
+    #[cfg(feature = "platform-verifier")]
+    pub tls_platform_verifier: bool,
+}
+
+/// Which failures a [`RetryPolicy`] retries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum RetryOn {
+    /// Connection failures, I/O errors and timeouts
+    ConnectionErrors,
+    /// Everything `Error::is_transient` reports, i.e. connection problems and 4xx replies
+    Transient,
+}
+
+/// Exponential backoff for retrying failed sends
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for each further one
+    pub base_delay: Duration,
+    /// Upper bound for a single delay
+    pub max_delay: Duration,
+    /// Random spread applied to each delay, as a fraction of it (0.0 to 1.0)
+    pub jitter: f64,
+    /// Which failures are retried
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            retry_on: RetryOn::Transient,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether `error` from attempt number `attempt` (counting from 1) should be retried
+    pub fn should_retry(&self, error: &crate::Error, attempt: u32) -> bool {
+        use crate::Error;
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        match self.retry_on {
+            RetryOn::ConnectionErrors => matches!(error, Error::ConnectionFailed | Error::IoError(_) | Error::Timeout { .. }),
+            RetryOn::Transient => error.is_transient(),
+        }
+    }
+
+    /// Delay to wait after attempt number `attempt` (counting from 1) failed
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        use rand::Rng;
+        let exponential = self.base_delay
+            .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return exponential;
+        }
+        let factor = 1.0 + jitter * rand::thread_rng().gen_range(-1.0..=1.0);
+        exponential.mul_f64(factor)
+    }
+}
+
+/// How long to wait for the server in each phase of the SMTP conversation.
+///
+/// Each limit applies to a single read or write, so a slow but steadily progressing
+/// transfer is not cut off. The defaults are the minimums from RFC 5321 section 4.5.3.2.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timeouts {
+    /// Waiting for the 220 greeting
+    pub greeting: Duration,
+    /// EHLO/HELO and STARTTLS replies
+    pub ehlo: Duration,
+    /// AUTH replies
+    pub auth: Duration,
+    /// MAIL FROM and RCPT TO replies
+    pub envelope: Duration,
+    /// The 354 reply to DATA
+    pub data_init: Duration,
+    /// Writing the message and waiting for the final reply
+    pub data_transfer: Duration,
 }
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            greeting: Duration::from_secs(5 * 60),
+            ehlo: Duration::from_secs(5 * 60),
+            auth: Duration::from_secs(5 * 60),
+            envelope: Duration::from_secs(5 * 60),
+            data_init: Duration::from_secs(2 * 60),
+            data_transfer: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl Timeouts {
+    /// The limit that applies while in `phase` (connecting itself is governed by `Config::timeout`)
+    pub(crate) fn for_phase(&self, phase: crate::error::SmtpPhase) -> Duration {
+        use crate::error::SmtpPhase;
+        match phase {
+            SmtpPhase::Dns | SmtpPhase::Connect | SmtpPhase::Greeting => self.greeting,
+            SmtpPhase::Ehlo | SmtpPhase::StartTls | SmtpPhase::Quit => self.ehlo,
+            SmtpPhase::Auth => self.auth,
+            SmtpPhase::MailFrom | SmtpPhase::RcptTo => self.envelope,
+            SmtpPhase::DataInit => self.data_init,
+            SmtpPhase::DataTransfer => self.data_transfer,
+        }
+    }
+}
+/// Settings that differ for a single [`Mailer::send_with`](crate::Mailer::send_with)
+/// from the mailer's config. Unset options keep the config's value.
+///
+/// ```no_run
+/// use micromail::{Config, Dsn, DsnNotify, Mail, Mailer, SendOptions, TlsPolicy};
+///
+/// let mut mailer = Mailer::new(Config::new("example.com"));
+/// let options = SendOptions::new()
+///     .tls_policy(TlsPolicy::Required)
+///     .envelope_from("bounces+4711@example.com")
+///     .dsn(Dsn::new().notify(DsnNotify::Failure).envid("4711"));
+/// let mail = Mail::new().from("shop@example.com").to("ann@example.org").subject("Your order");
+/// mailer.send_with(mail, options).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    pub tls_policy: Option<TlsPolicy>,
+    pub timeouts: Option<Timeouts>,
+    pub deadline: Option<Duration>,
+    /// Server to hand the mail to instead of the MX hosts, see `Config::direct_target`
+    pub relay: Option<SocketAddr>,
+    pub dsn: Option<Dsn>,
+    pub envelope_from: Option<String>,
+}
+
+impl SendOptions {
+    pub fn new() -> Self { Self::default() }
+    pub fn tls_policy(mut self, policy: TlsPolicy) -> Self { self.tls_policy = Some(policy); self }
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self { self.timeouts = Some(timeouts); self }
+    pub fn deadline(mut self, deadline: Duration) -> Self { self.deadline = Some(deadline); self }
+    pub fn relay(mut self, relay: SocketAddr) -> Self { self.relay = Some(relay); self }
+    pub fn dsn(mut self, dsn: Dsn) -> Self { self.dsn = Some(dsn); self }
+    pub fn envelope_from<S: Into<String>>(mut self, address: S) -> Self { self.envelope_from = Some(address.into()); self }
+
+    /// `config` with these options applied
+    pub(crate) fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(policy) = self.tls_policy { config.tls_policy = policy; }
+        if let Some(timeouts) = &self.timeouts { config.timeouts = timeouts.clone(); }
+        if let Some(deadline) = self.deadline { config.deadline = Some(deadline); }
+        if let Some(relay) = self.relay { config.direct_target = Some(relay); }
+        if let Some(dsn) = &self.dsn { config.dsn = Some(dsn.clone()); }
+        if let Some(address) = &self.envelope_from { config.envelope_from = Some(address.clone()); }
+        config
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Auth {
     pub username: String,
+    /// Not serialized, so persisted configs never contain it
+    #[cfg_attr(feature = "serialize", serde(skip_serializing, default))]
     pub password: String,
 }
+/// Callback producing AUTH credentials, see [`Config::auth_provider`]
+#[derive(Clone)]
+pub struct AuthProvider(Arc<dyn Fn() -> Result<Auth, crate::Error> + Send + Sync>);
+impl AuthProvider {
+    pub fn credentials(&self) -> Result<Auth, crate::Error> { (self.0)() }
+}
+impl fmt::Debug for AuthProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("AuthProvider(<callback>)") }
+}
+/// Callback filtering and reordering the MX hosts of a domain, see [`Config::mx_policy`]
+#[derive(Clone)]
+pub struct MxPolicy(Arc<MxPolicyFn>);
+type MxPolicyFn = dyn Fn(&str, Vec<MxRecord>) -> Vec<MxRecord> + Send + Sync;
+impl MxPolicy {
+    pub fn apply(&self, domain: &str, records: Vec<MxRecord>) -> Vec<MxRecord> { (self.0)(domain, records) }
+}
+impl fmt::Debug for MxPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("MxPolicy(<callback>)") }
+}
+/// Root certificates as base64 DER strings
+#[cfg(feature = "serialize")]
+mod der_certs {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use rustls::pki_types::CertificateDer;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(certs: &[CertificateDer<'static>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(certs.iter().map(|cert| STANDARD.encode(cert)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<CertificateDer<'static>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|cert| STANDARD.decode(cert).map(CertificateDer::from).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// A client certificate chain (leaf first) and its private key
+#[derive(PartialEq, Eq)]
+pub struct TlsIdentity {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+impl fmt::Debug for TlsIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsIdentity")
+         .field("cert_chain", &self.cert_chain.len())
+         .field("key", &"<PRIVATE_KEY>")
+         .finish()
+    }
+}
 #[cfg(feature = "signing")]
 #[derive(Clone)]
 pub struct DkimConfig {
-    pub private_key: RsaKey<Sha256>,
+    /// Shared between the clones of the config, the key itself cannot be cloned
+    pub private_key: Arc<RsaKey<Sha256>>,
     pub selector: String,
     pub domain: String,
+    /// Agent or user identifier (`i=`), e.g. `@news.example.com` (None = `@` plus `domain`)
+    pub auid: Option<String>,
 }
 #[cfg(feature = "signing")]
 impl fmt::Debug for DkimConfig {
@@ -35,6 +392,7 @@ impl fmt::Debug for DkimConfig {
         f.debug_struct("DkimConfig")
          .field("selector", &self.selector)
          .field("domain", &self.domain)
+         .field("auid", &self.auid)
          .field("private_key", &"<RSA_KEY_SHA256>")
          .finish()
     }
@@ -44,30 +402,311 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             domain: "localhost".to_string(),
+            ehlo_hostname: None,
             timeout: Duration::from_secs(30),
-            use_tls: true,
+            tls_policy: TlsPolicy::Opportunistic,
             ports: vec![25, 587, 465, 2525],
             auth: None,
+            auth_provider: None,
             #[cfg(feature = "signing")]
             dkim_config: None,
             test_mode: false,
+            log_credentials: false,
+            auto_headers: true,
+            user_agent: Some(format!("micromail/{}", env!("CARGO_PKG_VERSION"))),
+            envelope_from: None,
+            dsn: None,
+            keep_sent_message: false,
+            sandbox_redirect: None,
+            recipient_allowlist: Vec::new(),
+            recipient_denylist: Vec::new(),
+            id_provider: Arc::new(RandomIds),
+            bind_addr: None,
+            unix_socket: None,
+            direct_target: None,
+            lmtp: false,
+            timeouts: Timeouts::default(),
+            deadline: None,
+            stall_timeout: None,
+            retry: None,
+            greylist_delay: None,
+            dns_cache: Some(Arc::new(DnsCache::new())),
+            resolver: None,
+            mx_policy: None,
+            #[cfg(feature = "tokio-runtime")]
+            async_resolver: None,
+            dane: DaneMode::Off,
+            metrics: None,
+            tls_reports: None,
+            accept_invalid_certs: false,
+            tls_root_certs: Vec::new(),
+            tls_webpki_roots: true,
+            tls_client_identity: None,
+            tls_min_version: None,
+            tls_max_version: None,
+            tls_cipher_suites: None,
+            tls_server_name: None,
+            tls_session_cache: Some(Arc::new(TlsSessionCache::default())),
+            tls_pinned_spki: Vec::new(),
+            #[cfg(feature = "platform-verifier")]
+            tls_platform_verifier: false,
         }
     }
 }
 
 impl Config {
     pub fn enable_test_mode(mut self, enable: bool) -> Self { self.test_mode = enable; self }
+    /// Record AUTH credentials in the transcript instead of redacting them, for debugging only
+    pub fn log_credentials(mut self, enable: bool) -> Self { self.log_credentials = enable; self }
+    /// Turn off the generated Date, Message-ID, Content-Type and X-Mailer headers for mail that brings
+    /// a complete header set. `date`, `message_id` and attachments are still formatted.
+    pub fn auto_headers(mut self, enable: bool) -> Self { self.auto_headers = enable; self }
+    /// Set the X-Mailer header, `micromail/<version>` by default; None leaves it out
+    pub fn user_agent(mut self, user_agent: Option<String>) -> Self { self.user_agent = user_agent; self }
+    /// Send MAIL FROM with `address` instead of the `From` of each mail, e.g. a bounce
+    /// mailbox that is processed automatically
+    pub fn envelope_from<S: Into<String>>(mut self, address: S) -> Self { self.envelope_from = Some(address.into()); self }
+    /// Request delivery status notifications (RFC 3461) from servers that announce DSN
+    pub fn dsn(mut self, dsn: Dsn) -> Self { self.dsn = Some(dsn); self }
+    /// Return the message as sent in [`SendReport::message`](crate::SendReport::message),
+    /// e.g. to file it in a "Sent" folder
+    pub fn keep_sent_message(mut self, keep: bool) -> Self { self.keep_sent_message = keep; self }
+    /// Send every mail to `address` instead of its recipients, so a staging system can't
+    /// reach real customers. The original recipients go into an `X-Original-To` header.
+    pub fn sandbox_redirect<S: Into<String>>(mut self, address: S) -> Self { self.sandbox_redirect = Some(address.into()); self }
+    /// Only send to recipients matching one of the allowed patterns. A pattern with an `@`
+    /// is a whole address, `example.com` a domain and `*.example.com` its subdomains;
+    /// case does not matter. Sends to anyone else fail with `Error::RecipientBlocked`.
+    pub fn allow_recipient<S: Into<String>>(mut self, pattern: S) -> Self { self.recipient_allowlist.push(pattern.into()); self }
+    /// Never send to recipients matching `pattern` (same syntax as `allow_recipient`),
+    /// even if they are allowed
+    pub fn deny_recipient<S: Into<String>>(mut self, pattern: S) -> Self { self.recipient_denylist.push(pattern.into()); self }
+    /// Whether the allow- and denylist let mail go to `address`
+    pub fn recipient_permitted(&self, address: &str) -> bool {
+        let address = crate::utils::bare_address(address).to_lowercase();
+        let domain = address.rsplit_once('@').map_or("", |(_, domain)| domain);
+        let matches = |pattern: &String| {
+            let pattern = pattern.trim().to_lowercase();
+            if pattern.contains('@') {
+                pattern == address
+            } else if let Some(parent) = pattern.strip_prefix("*.") {
+                domain.strip_suffix(parent).is_some_and(|sub| sub.ends_with('.'))
+            } else {
+                pattern == domain
+            }
+        };
+        !self.recipient_denylist.iter().any(matches)
+            && (self.recipient_allowlist.is_empty() || self.recipient_allowlist.iter().any(matches))
+    }
     pub fn new<S: Into<String>>(domain: S) -> Self { Self { domain: domain.into(), ..Default::default() } }
+    /// Introduce the client as `hostname` in EHLO instead of `domain`, which stays the
+    /// domain of Message-IDs. Receivers expect the FQDN of the sending host there.
+    pub fn ehlo_hostname<S: Into<String>>(mut self, hostname: S) -> Self { self.ehlo_hostname = Some(hostname.into()); self }
+    /// The name the client introduces itself with in EHLO/HELO
+    pub fn ehlo_name(&self) -> &str { self.ehlo_hostname.as_deref().unwrap_or(&self.domain) }
     pub fn timeout(mut self, timeout: Duration) -> Self { self.timeout = timeout; self }
-    pub fn use_tls(mut self, use_tls: bool) -> Self { self.use_tls = use_tls; self }
+    /// Limit for establishing a TCP connection (same as [`Config::timeout`])
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self { self.timeout = timeout; self }
+    /// Limit for the reply to each command, from the greeting up to the 354 reply to DATA
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        let Timeouts { greeting, ehlo, auth, envelope, data_init, .. } = &mut self.timeouts;
+        for limit in [greeting, ehlo, auth, envelope, data_init] { *limit = timeout; }
+        self
+    }
+    /// Limit for each write of the message content and for the final reply after it,
+    /// which has to allow for large attachments and slow content filters
+    pub fn data_timeout(mut self, timeout: Duration) -> Self { self.timeouts.data_transfer = timeout; self }
+    pub fn tls_policy(mut self, policy: TlsPolicy) -> Self { self.tls_policy = policy; self }
+    /// Shorthand for `TlsPolicy::Opportunistic` (true) or `TlsPolicy::None` (false)
+    pub fn use_tls(mut self, use_tls: bool) -> Self { self.tls_policy = if use_tls { TlsPolicy::Opportunistic } else { TlsPolicy::None }; self }
     pub fn ports(mut self, ports: Vec<u16>) -> Self { self.ports = ports; self }
+    /// Deliver to the server listening on the Unix socket at `path`, e.g. a local
+    /// LMTP server like Dovecot's `/var/run/dovecot/lmtp` (combine with [`Config::lmtp`])
+    pub fn unix_socket<P: Into<PathBuf>>(mut self, path: P) -> Self { self.unix_socket = Some(path.into()); self }
+    pub fn lmtp(mut self, lmtp: bool) -> Self { self.lmtp = lmtp; self }
+    /// Deliver everything to the server at `addr` without any DNS lookups, e.g. a local test
+    /// server or a relay in a network without DNS
+    pub fn direct_target(mut self, addr: SocketAddr) -> Self { self.direct_target = Some(addr); self }
     pub fn auth<S: Into<String>>(mut self, username: S, password: S) -> Self { self.auth = Some(Auth { username: username.into(), password: password.into() }); self }
+    /// Passes the MX hosts of every recipient domain, sorted by preference, through
+    /// `policy` before connecting. Hosts are tried in the order it returns them, and
+    /// a domain left without hosts fails with `Error::NoMxRecords`.
+    ///
+    /// ```
+    /// # use micromail::Config;
+    /// let config = Config::new("example.com").mx_policy(|_domain, mut records| {
+    ///     records.retain(|mx| mx.server != "mx3.broken.example");
+    ///     records.sort_by_key(|mx| !mx.server.ends_with(".eu.example"));
+    ///     records
+    /// });
+    /// ```
+    pub fn mx_policy<F: Fn(&str, Vec<MxRecord>) -> Vec<MxRecord> + Send + Sync + 'static>(mut self, policy: F) -> Self {
+        self.mx_policy = Some(MxPolicy(Arc::new(policy)));
+        self
+    }
+    /// Authenticate with credentials from `provider`, called for every connection, e.g. to
+    /// read a password from the OS keyring or a short-lived token from a secret manager.
+    /// When the server rejects them with 535 the provider is asked once more, so rotated
+    /// credentials are picked up without restarting.
+    pub fn auth_provider<F: Fn() -> Result<Auth, crate::Error> + Send + Sync + 'static>(mut self, provider: F) -> Self {
+        self.auth_provider = Some(AuthProvider(Arc::new(provider)));
+        self
+    }
+    /// The credentials to authenticate with, from the provider if there is one
+    pub(crate) fn credentials(&self) -> Result<Option<Auth>, crate::Error> {
+        match &self.auth_provider {
+            Some(provider) => provider.credentials().map(Some),
+            None => Ok(self.auth.clone()),
+        }
+    }
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self { self.timeouts = timeouts; self }
+    pub fn deadline(mut self, deadline: Duration) -> Self { self.deadline = Some(deadline); self }
+    pub fn stall_timeout(mut self, idle: Duration) -> Self { self.stall_timeout = Some(idle); self }
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self { self.retry = Some(policy); self }
+    pub fn retry_greylisted(mut self, delay: Duration) -> Self { self.greylist_delay = Some(delay); self }
+    pub fn dns_cache(mut self, cache: Option<Arc<DnsCache>>) -> Self { self.dns_cache = cache; self }
+    pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self { self.resolver = Some(Arc::new(resolver)); self }
+    #[cfg(feature = "tokio-runtime")]
+    pub fn async_resolver<R: AsyncResolver + 'static>(mut self, resolver: R) -> Self { self.async_resolver = Some(Arc::new(resolver)); self }
+    /// Uses `resolver` as both `resolver` and `async_resolver`, so that with DNS-over-TLS or
+    /// DNS-over-HTTPS no lookup of the blocking or async path goes out in plaintext
+    #[cfg(feature = "hickory")]
+    pub fn hickory_resolver(mut self, resolver: HickoryResolver) -> Self {
+        let resolver = Arc::new(resolver);
+        self.resolver = Some(resolver.clone());
+        self.async_resolver = Some(resolver);
+        self
+    }
+    pub fn dane(mut self, mode: DaneMode) -> Self { self.dane = mode; self }
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: M) -> Self { self.metrics = Some(Arc::new(metrics)); self }
+    pub fn tls_reports(mut self, collector: Arc<TlsReportCollector>) -> Self { self.tls_reports = Some(collector); self }
+    /// Accept any server certificate, see [`Config::accept_invalid_certs`]
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self { self.accept_invalid_certs = accept; self }
+    /// Trust only `certs` as roots, replacing the Mozilla root store
+    pub fn tls_root_certs(mut self, certs: Vec<CertificateDer<'static>>) -> Self { self.tls_root_certs = certs; self.tls_webpki_roots = false; self }
+    /// Trust the CA certificates in `pem` in addition to the configured roots
+    pub fn add_root_ca_pem<S: AsRef<str>>(mut self, pem: S) -> Result<Self, crate::Error> {
+        let certs = CertificateDer::pem_slice_iter(pem.as_ref().as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| crate::Error::TlsError(format!("Failed to parse root CA PEM: {}", e)))?;
+        if certs.is_empty() {
+            return Err(crate::Error::TlsError("Failed to parse root CA PEM: no certificate found".to_string()));
+        }
+        self.tls_root_certs.extend(certs);
+        Ok(self)
+    }
+    pub fn tls_min_version(mut self, version: TlsVersion) -> Self { self.tls_min_version = Some(version); self }
+    pub fn tls_max_version(mut self, version: TlsVersion) -> Self { self.tls_max_version = Some(version); self }
+    /// Offer only the named cipher suites; connecting fails if a name is unknown
+    pub fn tls_cipher_suites<I: IntoIterator<Item = S>, S: Into<String>>(mut self, names: I) -> Self {
+        self.tls_cipher_suites = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+    pub fn tls_server_name<S: Into<String>>(mut self, name: S) -> Self { self.tls_server_name = Some(name.into()); self }
+    pub fn tls_session_cache(mut self, cache: Option<Arc<TlsSessionCache>>) -> Self { self.tls_session_cache = cache; self }
+    #[cfg(feature = "platform-verifier")]
+    pub fn tls_platform_verifier(mut self, enable: bool) -> Self { self.tls_platform_verifier = enable; self }
+    /// Only accept servers presenting a certificate whose SubjectPublicKeyInfo has one of
+    /// the SHA-256 `fingerprints`, given in hex (colons allowed) or base64 as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256`.
+    ///
+    /// The chain is still verified against the roots; together with
+    /// `danger_accept_invalid_certs(true)` the pin alone decides, e.g. for a self-signed smarthost.
+    pub fn pin_server_cert<I: IntoIterator<Item = S>, S: AsRef<str>>(mut self, fingerprints: I) -> Result<Self, crate::Error> {
+        use base64::Engine;
+
+        for fingerprint in fingerprints {
+            let fingerprint = fingerprint.as_ref().trim();
+            let hex = fingerprint.replace(':', "");
+            let bytes = if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                (0..64).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+            } else {
+                base64::engine::general_purpose::STANDARD.decode(fingerprint.trim_start_matches("sha256/")).unwrap_or_default()
+            };
+            let pin = <[u8; 32]>::try_from(bytes)
+                .map_err(|_| crate::Error::TlsError(format!("Invalid SHA-256 fingerprint {}", fingerprint)))?;
+            self.tls_pinned_spki.push(pin);
+        }
+        Ok(self)
+    }
+    /// Present the certificate chain and private key (PKCS#8, PKCS#1 or SEC1) in the given
+    /// PEM strings to servers that require client certificates
+    pub fn tls_client_identity<S: AsRef<str>>(mut self, cert_chain_pem: S, key_pem: S) -> Result<Self, crate::Error> {
+        let cert_chain = CertificateDer::pem_slice_iter(cert_chain_pem.as_ref().as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| crate::Error::TlsError(format!("Failed to parse client certificate PEM: {}", e)))?;
+        if cert_chain.is_empty() {
+            return Err(crate::Error::TlsError("Failed to parse client certificate PEM: no certificate found".to_string()));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem.as_ref().as_bytes())
+            .map_err(|e| crate::Error::TlsError(format!("Failed to parse client key PEM: {}", e)))?;
+        crate::tls::crypto_provider().key_provider.load_private_key(key.clone_key())
+            .map_err(|e| crate::Error::TlsError(format!("Unsupported client key: {}", e)))?;
+        self.tls_client_identity = Some(Arc::new(TlsIdentity { cert_chain, key }));
+        Ok(self)
+    }
+    pub fn bind_addr(mut self, addr: IpAddr) -> Self { self.bind_addr = Some(addr); self }
+
+    /// Checks for settings that contradict each other or cannot work, so they show up
+    /// before the first send instead of as a failed delivery. Fails with
+    /// [`Error::InvalidConfig`](crate::Error::InvalidConfig) listing every problem and how to fix it.
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        let mut problems = Vec::new();
+        let fixed_server = self.direct_target.is_some() || self.unix_socket.is_some();
+        if self.ports.is_empty() && !fixed_server && !self.test_mode {
+            problems.push("No ports are configured, so no server can be reached. Add at least one with Config::ports, e.g. 25 for MX delivery or 587 for submission.".to_string());
+        }
+        if self.tls_policy == TlsPolicy::None && self.ports.contains(&465) && !fixed_server {
+            problems.push("Port 465 expects TLS from the first byte, but TLS is turned off. Remove 465 from Config::ports or enable TLS.".to_string());
+        }
+        if self.tls_policy.requires_tls() && self.unix_socket.is_some() {
+            problems.push(format!("The TLS policy {:?} requires STARTTLS, which a Unix socket does not support. Use TlsPolicy::None or TlsPolicy::Opportunistic.", self.tls_policy));
+        }
+        if self.tls_policy == TlsPolicy::Verified && self.accept_invalid_certs {
+            problems.push("TlsPolicy::Verified requires verified certificates, but danger_accept_invalid_certs is on. Turn one of them off.".to_string());
+        }
+        if (self.auth.is_some() || self.auth_provider.is_some()) && !fixed_server && self.resolver.is_none() && self.ports.contains(&25) && !self.test_mode {
+            problems.push("AUTH credentials are set, but mail goes straight to the recipients' MX hosts, which do not accept them. Send through a relay with Config::direct_target or a resolver pointing at it, on a submission port like 587.".to_string());
+        }
+        if let Some(sandbox) = &self.sandbox_redirect {
+            if crate::utils::extract_domain(sandbox).is_err() {
+                problems.push(format!("The sandbox address {:?} is not an email address, so no mail could be delivered. Use a full address like qa@example.com.", sandbox));
+            }
+        }
+        #[cfg(feature = "signing")]
+        if let Some(dkim) = &self.dkim_config {
+            let dkim_domain = dkim.domain.trim_end_matches('.').to_lowercase();
+            let domain = self.domain.trim_end_matches('.').to_lowercase();
+            if crate::diagnostics::organizational_domain(&dkim_domain) != crate::diagnostics::organizational_domain(&domain) {
+                problems.push(format!("The DKIM domain {} does not align with the sending domain {}, so the signature cannot satisfy DMARC. Sign with a key for {}.", dkim_domain, domain, domain));
+            }
+        }
+        if problems.is_empty() { Ok(()) } else { Err(crate::Error::InvalidConfig(problems)) }
+    }
+    pub fn id_provider<P: IdProvider + 'static>(mut self, provider: P) -> Self { self.id_provider = Arc::new(provider); self }
 
     #[cfg(feature = "signing")]
     pub fn dkim_rsa_key<S: AsRef<str>>(mut self, private_key_pem: S, selector: S, dkim_domain: S) -> Result<Self, crate::Error> {
         let key = RsaKey::<Sha256>::from_pkcs1_pem(private_key_pem.as_ref())
             .map_err(|e| crate::Error::SigningError(format!("Failed to parse RSA key from PKCS#1 PEM for DKIM: {}", e.to_string())))?;
-        self.dkim_config = Some(Arc::new(DkimConfig { private_key: key, selector: selector.as_ref().to_string(), domain: dkim_domain.as_ref().to_string() }));
+        self.dkim_config = Some(Arc::new(DkimConfig { private_key: Arc::new(key), selector: selector.as_ref().to_string(), domain: dkim_domain.as_ref().to_string(), auid: None }));
+        Ok(self)
+    }
+    /// Sets the agent or user identifier (`i=`) of the DKIM signature, e.g. `@news.example.com`
+    /// to keep the reputation of newsletters apart, or the address of a user. Its domain
+    /// must be the DKIM domain or a subdomain of it. Call after setting the key.
+    #[cfg(feature = "signing")]
+    pub fn dkim_auid<S: Into<String>>(mut self, auid: S) -> Result<Self, crate::Error> {
+        let auid = auid.into();
+        let dkim = self.dkim_config.as_mut().map(Arc::make_mut)
+            .ok_or_else(|| crate::Error::SigningError("Set the DKIM key before its identifier".to_string()))?;
+        let auid_domain = auid.rsplit_once('@').map(|(_, domain)| domain.trim_end_matches('.').to_lowercase())
+            .ok_or_else(|| crate::Error::SigningError(format!("The DKIM identifier {:?} has no @", auid)))?;
+        let dkim_domain = dkim.domain.trim_end_matches('.').to_lowercase();
+        if auid_domain != dkim_domain && !auid_domain.ends_with(&format!(".{}", dkim_domain)) {
+            return Err(crate::Error::SigningError(format!("The DKIM identifier {} is not in the DKIM domain {}", auid, dkim_domain)));
+        }
+        dkim.auid = Some(auid);
         Ok(self)
     }
     #[cfg(feature = "signing")]
@@ -75,7 +714,7 @@ impl Config {
         // RsaKey (rsa::RsaPrivateKey) from_pkcs8_der needs "pkcs8" feature on rsa crate.
         let key = RsaKey::<Sha256>::from_pkcs8_der(private_key_der)
             .map_err(|e| crate::Error::SigningError(format!("Failed to parse RSA key from PKCS#8 DER for DKIM: {}", e.to_string())))?;
-        self.dkim_config = Some(Arc::new(DkimConfig { private_key: key, selector: selector.as_ref().to_string(), domain: dkim_domain.as_ref().to_string() }));
+        self.dkim_config = Some(Arc::new(DkimConfig { private_key: Arc::new(key), selector: selector.as_ref().to_string(), domain: dkim_domain.as_ref().to_string(), auid: None }));
         Ok(self)
     }
 }
@@ -1,25 +1,33 @@
 //! Connection handling for SMTP servers
 
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::{
-    net::{IpAddr, SocketAddr, TcpStream},
-    time::Duration,
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    path::Path,
+    time::{Duration, Instant},
+    sync::{mpsc, Arc},
+    thread,
 };
 
-use rustls::{ClientConnection, StreamOwned};
+use rustls::{pki_types::ServerName, ClientConnection, StreamOwned};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
-    config::Config, // Added for test_mode
-    dns::{lookup_host, MxRecord},
-    error::Error,
-    io::{self, HttpStatusMessage, MockStream}, // Added MockStream
-    tls::create_insecure_tls_config,
+    config::{Config, Timeouts}, // Added for test_mode
+    dane::{DaneMode, DanePolicy},
+    dns::{lookup_host_addrs, MxRecord},
+    capabilities::Capabilities,
+    dsn::Dsn,
+    error::{Error, SmtpPhase},
+    io::{self, MockStream}, // Added MockStream
+    metrics::{self, Metrics},
+    tls::{client_config, create_dane_tls_config, create_probe_tls_config, DaneOutcome, TlsInfo, TlsPolicy},
+    trace,
+    tlsrpt::ResultType,
+    transcript::{self, SendEvent, SharedRecorder, TranscriptEvent},
 };
 
-/// STARTTLS feature availability
-#[derive(Default, Debug)]
-pub struct StartTlsAvailable(pub bool);
-
 // Define StreamWrapper here as it's closely tied to connection types
 /// Wraps different types of streams (real, mock, TLS)
 #[derive(Debug)]
@@ -27,6 +35,9 @@ pub enum StreamWrapper {
     Insecure(TcpStream),
     Secure(StreamOwned<ClientConnection, TcpStream>),
     Mock(MockStream),
+    /// Connection to a local server over a Unix socket, never upgraded to TLS
+    #[cfg(unix)]
+    Unix(UnixStream),
 }
 
 /// Represents an active connection to an SMTP server (real or mocked)
@@ -34,8 +45,37 @@ pub enum StreamWrapper {
 pub struct Connected {
     /// The underlying stream, which can be real (insecure/secure) or mocked
     pub stream: StreamWrapper, // Made public for io.rs access
-    /// The socket address of the remote server (nominal in test_mode)
+    /// The socket address of the remote server (nominal in test_mode and for Unix sockets)
     pub address: SocketAddr, // Made public
+    /// Name of the MX host the address belongs to
+    pub mx_host: Option<String>,
+    /// Phase of the SMTP conversation the connection is currently in
+    pub phase: SmtpPhase,
+    /// When the current phase began
+    pub phase_started: Instant,
+    /// Per-phase time limits for reads and writes
+    pub timeouts: Timeouts,
+    /// Instant after which every read or write fails with a timeout
+    pub deadline: Option<Instant>,
+    /// Longest allowed period without socket progress
+    pub stall_timeout: Option<Duration>,
+    /// When bytes were last read or written
+    pub last_progress: Instant,
+    /// Bytes received after the last complete reply
+    pub(crate) pending: Vec<u8>,
+    /// What the server advertised in its last EHLO reply
+    pub capabilities: Capabilities,
+    /// Whether the server speaks LMTP instead of SMTP
+    pub lmtp: bool,
+    /// What was negotiated in the TLS handshake (None before STARTTLS and for mock streams)
+    pub tls_info: Option<TlsInfo>,
+    /// Transcript and event listeners, shared with the owning Mailer
+    pub(crate) recorder: SharedRecorder,
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    /// Whether credentials are recorded verbatim, see `Config::log_credentials`
+    pub(crate) log_credentials: bool,
+    /// DSN parameters for MAIL FROM and RCPT TO, see `Config::dsn`
+    pub(crate) dsn: Option<Dsn>,
 }
 
 
@@ -46,6 +86,17 @@ impl Connected {
             StreamWrapper::Insecure(_) => false,
             StreamWrapper::Secure(_) => true,
             StreamWrapper::Mock(ms) => ms.tls_active,
+            #[cfg(unix)]
+            StreamWrapper::Unix(_) => false,
+        }
+    }
+
+    /// Whether the stream can be upgraded with STARTTLS, which a Unix socket cannot
+    pub fn can_starttls(&self) -> bool {
+        match &self.stream {
+            #[cfg(unix)]
+            StreamWrapper::Unix(_) => false,
+            _ => true,
         }
     }
 
@@ -53,6 +104,141 @@ impl Connected {
     pub fn addr(&self) -> SocketAddr {
         self.address
     }
+
+    /// Protocol version, cipher suite and certificates of the TLS session, if there is one
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls_info.as_ref()
+    }
+
+    /// What the server advertised in its last EHLO reply
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Local address of the socket (None for mock streams and Unix sockets)
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.stream {
+            StreamWrapper::Insecure(stream) => stream.local_addr().ok(),
+            StreamWrapper::Secure(stream) => stream.sock.local_addr().ok(),
+            StreamWrapper::Mock(_) => None,
+            #[cfg(unix)]
+            StreamWrapper::Unix(_) => None,
+        }
+    }
+
+    pub(crate) fn new(stream: StreamWrapper, address: SocketAddr, config: &Config, recorder: &SharedRecorder) -> Self {
+        trace::record_address(address);
+        recorder.lock().unwrap().transcript.push(TranscriptEvent::Connected { address });
+        transcript::emit(recorder, &SendEvent::Connected { address });
+        Self {
+            stream,
+            address,
+            mx_host: None,
+            phase: SmtpPhase::Connect,
+            phase_started: Instant::now(),
+            timeouts: config.timeouts.clone(),
+            deadline: None,
+            stall_timeout: config.stall_timeout,
+            last_progress: Instant::now(),
+            pending: Vec::new(),
+            capabilities: Capabilities::default(),
+            lmtp: config.lmtp,
+            tls_info: None,
+            recorder: recorder.clone(),
+            metrics: config.metrics.clone(),
+            log_credentials: config.log_credentials,
+            dsn: config.dsn.clone(),
+        }
+    }
+
+    /// Appends `event` to the transcript
+    pub(crate) fn record(&self, event: TranscriptEvent) {
+        self.recorder.lock().unwrap().transcript.push(event);
+    }
+
+    /// Hands `event` to the registered listeners
+    pub(crate) fn emit(&self, event: SendEvent) {
+        transcript::emit(&self.recorder, &event);
+    }
+
+    /// Move on to the next phase of the conversation, subsequent I/O uses its time limit
+    pub fn enter_phase(&mut self, phase: SmtpPhase) {
+        self.phase = phase;
+        self.phase_started = Instant::now();
+    }
+
+    /// Notes that bytes went over the socket, resetting the stall watchdog
+    pub fn record_progress(&mut self) {
+        self.last_progress = Instant::now();
+    }
+
+    /// The error for a read or write that ran into its time limit: `Stalled` if the
+    /// stall watchdog expired, otherwise a timeout of the current phase
+    pub fn timeout_error(&self) -> Error {
+        timeout_error(self.phase, self.phase_started, self.stall_timeout, self.last_progress)
+    }
+
+    /// Applies the current phase's time limit (capped by the deadline and the stall
+    /// watchdog) to the socket before the next read or write. Fails once the deadline
+    /// has passed or the connection has been idle for longer than the stall timeout.
+    pub fn arm_timeout(&mut self) -> Result<(), Error> {
+        let timeout = time_limit(&self.timeouts, self.phase, self.phase_started, self.deadline, self.stall_timeout, self.last_progress)?;
+        // A zero timeout would be rejected by the socket
+        let timeout = Some(timeout.max(Duration::from_millis(1)));
+        let tcp = match &self.stream {
+            StreamWrapper::Insecure(stream) => stream,
+            StreamWrapper::Secure(stream_owned) => &stream_owned.sock,
+            StreamWrapper::Mock(_) => return Ok(()),
+            #[cfg(unix)]
+            StreamWrapper::Unix(stream) => {
+                return stream.set_read_timeout(timeout)
+                    .and_then(|_| stream.set_write_timeout(timeout))
+                    .map_err(Error::IoError);
+            }
+        };
+        tcp.set_read_timeout(timeout)
+            .and_then(|_| tcp.set_write_timeout(timeout))
+            .map_err(Error::IoError)
+    }
+}
+
+/// How long the next read or write in `phase` may take: the phase's time limit, capped by
+/// the deadline and the stall watchdog. Fails once the deadline has passed or the
+/// connection has been idle for longer than the stall timeout.
+pub(crate) fn time_limit(
+    timeouts: &Timeouts,
+    phase: SmtpPhase,
+    phase_started: Instant,
+    deadline: Option<Instant>,
+    stall_timeout: Option<Duration>,
+    last_progress: Instant,
+) -> Result<Duration, Error> {
+    let mut timeout = timeouts.for_phase(phase);
+    if let Some(deadline) = deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::Timeout { phase, elapsed: phase_started.elapsed() });
+        }
+        timeout = timeout.min(remaining);
+    }
+    if let Some(stall) = stall_timeout {
+        let remaining = stall.saturating_sub(last_progress.elapsed());
+        if remaining.is_zero() {
+            return Err(timeout_error(phase, phase_started, stall_timeout, last_progress));
+        }
+        timeout = timeout.min(remaining);
+    }
+    Ok(timeout)
+}
+
+/// `Stalled` if nothing went over the socket for the stall timeout, otherwise a timeout of
+/// `phase`, which began at `phase_started`
+pub(crate) fn timeout_error(phase: SmtpPhase, phase_started: Instant, stall_timeout: Option<Duration>, last_progress: Instant) -> Error {
+    let idle = last_progress.elapsed();
+    match stall_timeout {
+        Some(stall) if idle >= stall => Error::Stalled { phase, idle },
+        _ => Error::Timeout { phase, elapsed: phase_started.elapsed() },
+    }
 }
 
 /// Tries to connect to MX servers on various ports
@@ -60,42 +246,59 @@ pub fn try_start_connection(
     mxr: &[MxRecord],
     ports: &[u16],
     config: &Config, // Changed timeout to config
-    log: &mut Vec<String>,
+    deadline: Option<Instant>,
+    recorder: &SharedRecorder,
 ) -> Option<Connected> {
+    let _span = trace::connect_span().entered();
     if config.test_mode {
-        log.push("TEST MODE: Using mock connection to localhost.testmode".to_string());
+        recorder.lock().unwrap().transcript.note("TEST MODE: Using mock connection to localhost.testmode");
         let mock_stream = MockStream::new();
         // The address here is nominal for test mode.
         let dummy_addr: SocketAddr = "127.0.0.1:25".parse().unwrap();
-        return Some(Connected {
-            stream: StreamWrapper::Mock(mock_stream),
-            address: dummy_addr,
-        });
+        let mut connection = Connected::new(StreamWrapper::Mock(mock_stream), dummy_addr, config, recorder);
+        connection.mx_host = mxr.first().map(|mx| mx.server.clone());
+        return Some(connection);
+    }
+    if let Some(path) = &config.unix_socket {
+        return connect_unix(path, config, recorder);
+    }
+    if let Some(addr) = config.direct_target {
+        return connect_direct(addr, config, deadline, recorder);
     }
 
     // Real connection logic (non-test mode)
     for current_mx_record in mxr.iter() {
-        let ip_address = match lookup_host(&current_mx_record.server) {
-            Some(s) => s,
-            None => continue,
-        };
+        let ip_addresses = lookup_host_addrs(&current_mx_record.server, config);
+        if ip_addresses.is_empty() {
+            continue;
+        }
 
         for port_num in ports.iter() {
-            let socket_addr_str = format!("{}:{}", ip_address, port_num);
-            let socket_addr = match socket_addr_str.parse::<SocketAddr>() {
-                Ok(o) => o,
-                Err(_) => continue,
+            let socket_addrs = ip_addresses.iter()
+                .map(|ip| SocketAddr::new(*ip, *port_num))
+                .collect::<Vec<_>>();
+
+            let connect_timeout = match deadline {
+                Some(deadline) => config.timeout.min(deadline.saturating_duration_since(Instant::now())),
+                None => config.timeout,
             };
+            if connect_timeout.is_zero() {
+                return None;
+            }
 
-            match start_insecure_connection_internal(&socket_addr, config.timeout) {
-                Ok(tcp_stream) => return Some(Connected {
-                    stream: StreamWrapper::Insecure(tcp_stream),
-                    address: socket_addr,
-                }),
+            let started = Instant::now();
+            let result = connect_happy_eyeballs(&socket_addrs, connect_timeout, config.bind_addr);
+            metrics::with(&config.metrics, |m| m.connect_attempt(&current_mx_record.server, result.as_ref().map(|_| started.elapsed())));
+            match result {
+                Ok((tcp_stream, socket_addr)) => {
+                    let mut connection = Connected::new(StreamWrapper::Insecure(tcp_stream), socket_addr, config, recorder);
+                    connection.mx_host = Some(current_mx_record.server.clone());
+                    return Some(connection);
+                }
                 Err(e) => {
-                    log.push(format!(
-                        "Could not connect to {} (IP {}) port {}: {}",
-                        current_mx_record.server, ip_address, port_num, e
+                    recorder.lock().unwrap().transcript.note(format!(
+                        "Could not connect to {} port {}: {}",
+                        current_mx_record.server, port_num, e
                     ));
                 }
             }
@@ -104,13 +307,131 @@ pub fn try_start_connection(
     None // If no connection succeeded
 }
 
-/// Starts an insecure connection from an IP:Port address
+/// Connects to `addr` without any DNS lookups
+fn connect_direct(addr: SocketAddr, config: &Config, deadline: Option<Instant>, recorder: &SharedRecorder) -> Option<Connected> {
+    let connect_timeout = match deadline {
+        Some(deadline) => config.timeout.min(deadline.saturating_duration_since(Instant::now())),
+        None => config.timeout,
+    };
+    if connect_timeout.is_zero() {
+        return None;
+    }
+    let started = Instant::now();
+    let result = connect_happy_eyeballs(&[addr], connect_timeout, config.bind_addr);
+    metrics::with(&config.metrics, |m| m.connect_attempt(&addr.to_string(), result.as_ref().map(|_| started.elapsed())));
+    match result {
+        Ok((tcp_stream, socket_addr)) => Some(Connected::new(StreamWrapper::Insecure(tcp_stream), socket_addr, config, recorder)),
+        Err(e) => {
+            recorder.lock().unwrap().transcript.note(format!("Could not connect to {}: {}", addr, e));
+            None
+        }
+    }
+}
+
+/// Nominal address of a connection over a Unix socket
+pub(crate) const UNIX_SOCKET_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Connects to the server listening on the Unix socket at `path`
+#[cfg(unix)]
+fn connect_unix(path: &Path, config: &Config, recorder: &SharedRecorder) -> Option<Connected> {
+    match UnixStream::connect(path) {
+        Ok(stream) => {
+            recorder.lock().unwrap().transcript.note(format!("Connected to Unix socket {}", path.display()));
+            Some(Connected::new(StreamWrapper::Unix(stream), UNIX_SOCKET_ADDR, config, recorder))
+        }
+        Err(e) => {
+            recorder.lock().unwrap().transcript.note(format!("Could not connect to Unix socket {}: {}", path.display(), e));
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn connect_unix(path: &Path, _config: &Config, recorder: &SharedRecorder) -> Option<Connected> {
+    recorder.lock().unwrap().transcript.note(format!("Could not connect to Unix socket {}: not supported on this platform", path.display()));
+    None
+}
+
+/// Delay before racing the next address while an earlier attempt is still pending (RFC 8305 section 5)
+pub(crate) const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to the first of `addrs` that answers, racing attempts Happy Eyeballs style (RFC 8305).
+///
+/// Attempts start in the given order, each one `CONNECTION_ATTEMPT_DELAY` after the previous,
+/// or immediately once the previous attempt has failed. The first established connection wins
+/// and the remaining attempts are abandoned.
+pub fn connect_happy_eyeballs(
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    bind_addr: Option<IpAddr>,
+) -> Result<(TcpStream, SocketAddr), Error> {
+    // A bound source address pins the address family
+    let addrs = addrs.iter()
+        .copied()
+        .filter(|addr| bind_addr.is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4()))
+        .collect::<Vec<_>>();
+
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    let mut started = 0;
+    let mut failed = 0;
+    let mut last_error = Error::ConnectionFailed;
+
+    while failed < addrs.len() {
+        if started < addrs.len() {
+            let addr = addrs[started];
+            let tx = tx.clone();
+            let attempt_timeout = deadline.saturating_duration_since(Instant::now());
+            thread::spawn(move || {
+                // The receiver is gone once another attempt has won, the stream is simply dropped then
+                let _ = tx.send((addr, start_insecure_connection_internal(&addr, attempt_timeout, bind_addr)));
+            });
+            started += 1;
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let wait = if started < addrs.len() { CONNECTION_ATTEMPT_DELAY.min(remaining) } else { remaining };
+
+        match rx.recv_timeout(wait) {
+            Ok((addr, Ok(tcp_stream))) => return Ok((tcp_stream, addr)),
+            Ok((_, Err(e))) => {
+                failed += 1;
+                last_error = e;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Starts an insecure connection from an IP:Port address, optionally bound to a local IP first
 pub fn start_insecure_connection_internal(
     addr: &SocketAddr, 
-    timeout: Duration
+    timeout: Duration,
+    bind_addr: Option<IpAddr>,
 ) -> Result<TcpStream, Error> {
-    let tcp = TcpStream::connect_timeout(addr, timeout)
-        .map_err(|e| Error::ConnectionFailed)?;
+    let tcp = match bind_addr {
+        None => TcpStream::connect_timeout(addr, timeout)
+            .map_err(|_| Error::ConnectionFailed)?,
+        Some(local_ip) => {
+            // A v4 source cannot reach a v6 destination (and vice versa), skip early
+            if local_ip.is_ipv4() != addr.is_ipv4() {
+                return Err(Error::ConnectionFailed);
+            }
+            let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))
+                .map_err(Error::IoError)?;
+            socket.bind(&SocketAddr::new(local_ip, 0).into())
+                .map_err(Error::IoError)?;
+            socket.connect_timeout(&(*addr).into(), timeout)
+                .map_err(|_| Error::ConnectionFailed)?;
+            socket.into()
+        }
+    };
 
     tcp.set_nonblocking(false) // For simplicity, keeping blocking for real streams after connect
         .map_err(|e| Error::IoError(e))?;
@@ -122,74 +443,72 @@ pub fn start_insecure_connection_internal(
 pub fn send_ehlo(
     connection: &mut Connected,
     source_domain: &str,
-    log: &mut Vec<String>,
     is_reconnect: bool,
-) -> Result<StartTlsAvailable, Error> {
+) -> Result<Capabilities, Error> {
     if !is_reconnect {
         // wait for "220 HELO"
+        connection.enter_phase(SmtpPhase::Greeting);
         let response = io::secure_read(connection)?;
 
-        if !response.is_http_ok() {
-            return Err(Error::SmtpError{
-                code: response.code,
-                message: format!("Server did not send welcome message: {}", response.message)
-            });
+        if !response.is_positive_completion() {
+            return Err(response.into_error(SmtpPhase::Greeting));
         }
     }
 
-    // Try EHLO first, then fallback to HELO
-    let msgs = &["EHLO", "HELO"];
+    // Try EHLO first, then fallback to HELO. LMTP only knows LHLO.
+    connection.enter_phase(SmtpPhase::Ehlo);
+    let msgs: &[&str] = if connection.lmtp { &["LHLO"] } else { &["EHLO", "HELO"] };
     for ty in msgs.iter() {
         let helo = format!("{ty} {source_domain}\r\n");
         if let Err(_) = io::secure_send(connection, &helo) {
             continue;
         }
 
-        match io::secure_read_qued(connection) {
-            Ok(messages) => {
-                let has_starttls = messages.iter().any(|s| s.is_starttls());
-                return Ok(StartTlsAvailable(has_starttls));
+        match io::secure_read(connection) {
+            Ok(reply) if reply.is_positive_completion() => {
+                connection.capabilities = if *ty == "HELO" { Capabilities::default() } else { Capabilities::from_reply(&reply) };
+                return Ok(connection.capabilities.clone());
             }
+            Ok(_) => continue,
+            // A silent server will not answer HELO either
+            Err(e @ (Error::Timeout { .. } | Error::Stalled { .. })) => return Err(e),
             Err(_) => continue,
         }
     }
 
-    Ok(StartTlsAvailable(false))
+    connection.capabilities = Capabilities::default();
+    Ok(Capabilities::default())
 }
 
-/// Upgrades connection to TLS if available
-pub fn establish_tls(mut connection: Connected) -> Result<(Connected, bool), Error> {
+/// Upgrades connection to TLS if available and completes the handshake, verifying the
+/// certificate against the MX host name or `config.tls_server_name`. With a DANE `policy`,
+/// the certificate is checked against its TLSA records instead.
+pub(crate) fn establish_tls(mut connection: Connected, config: &Config, dane: Option<DanePolicy>) -> Result<(Connected, bool), Error> {
     if connection.is_secure() { // checks mock_stream.tls_active too
         return Ok((connection, false)); // Already secure (or simulated secure)
     }
 
     // Send STARTTLS command
+    let _span = trace::starttls_span(connection.mx_host.as_deref()).entered();
+    connection.enter_phase(SmtpPhase::StartTls);
     io::secure_send(&mut connection, "STARTTLS\r\n")?;
     let response = io::secure_read(&mut connection)?; // Server should respond with 220
 
-    if !response.is_http_ok() || response.code != 220 {
-         return Err(Error::SmtpError{
-            code: response.code,
-            message: format!("STARTTLS command failed or got unexpected response: {}", response.message)
-        });
+    if response.code != 220 {
+         return Err(response.into_error(SmtpPhase::StartTls));
     }
+    // Anything sent after the 220 was not protected by TLS and must not be read as a reply (RFC 3207)
+    connection.pending.clear();
 
     // Update stream based on its current type
-    let current_address = connection.address;
+    let mut dane_outcome = None;
     let new_stream_wrapper = match connection.stream {
         StreamWrapper::Insecure(tcp_stream) => {
             // Real TLS handshake
-            let tls_config = create_insecure_tls_config();
-            let server_name_str = lookup_host(&current_address.ip().to_string())
-                .unwrap_or_else(|| current_address.ip().to_string());
-
-            // Attempt to parse as ServerName, fallback or handle error if it's not a valid DNS name (e.g. IP)
-            let server_name = match rustls::pki_types::ServerName::try_from(server_name_str.as_str()) {
-                 Ok(name) => name.to_owned(),
-                 Err(_) => return Err(Error::TlsError("Invalid server name for TLS".to_string())),
-            };
-
-            match rustls::ClientConnection::new(Arc::new(tls_config), server_name) {
+            let (tls_config, outcome) = tls_client_config(config, dane.as_ref())?;
+            dane_outcome = outcome;
+            let server_name = tls_server_name(config, connection.mx_host.as_deref(), connection.address)?;
+            match rustls::ClientConnection::new(tls_config, server_name) {
                 Ok(tls_client_conn) => {
                     StreamWrapper::Secure(rustls::StreamOwned::new(tls_client_conn, tcp_stream))
                 }
@@ -207,8 +526,119 @@ pub fn establish_tls(mut connection: Connected) -> Result<(Connected, bool), Err
              // Should not happen if initial is_secure() check is correct
             return Ok((connection, false));
         }
+        #[cfg(unix)]
+        StreamWrapper::Unix(_) => return Err(Error::TlsError("STARTTLS is not supported over a Unix socket".to_string())),
     };
 
     connection.stream = new_stream_wrapper;
+    if let StreamWrapper::Secure(_) = connection.stream {
+        // Handshake before anything is sent, so a rejected certificate never leaks the envelope
+        connection.arm_timeout()?;
+        if let StreamWrapper::Secure(stream) = &mut connection.stream {
+            while stream.conn.is_handshaking() {
+                if let Err(e) = stream.conn.complete_io(&mut stream.sock) {
+                    let e = Error::TlsError(e.to_string());
+                    if let Some(policy) = &dane {
+                        policy.report(connection.local_addr(), connection.address, Err((ResultType::ValidationFailure, &e)));
+                    }
+                    return Err(e);
+                }
+            }
+            connection.tls_info = TlsInfo::from_connection(&stream.conn);
+        }
+        if let Some(info) = &connection.tls_info {
+            connection.record(TranscriptEvent::TlsNegotiated(info.clone()));
+        }
+    }
+    if let (Some(outcome), Some(policy)) = (dane_outcome, &dane) {
+        let note = dane_verdict(&outcome, policy, config, connection.local_addr(), connection.address)?;
+        connection.record(TranscriptEvent::Note(note));
+    } else if dane.is_some() {
+        connection.record(TranscriptEvent::Note("TEST MODE: skipping DANE certificate check".to_string()));
+    }
+    connection.record(TranscriptEvent::TlsEstablished);
+    connection.emit(SendEvent::TlsEstablished);
     Ok((connection, true)) // Indicate that TLS was established (or simulated)
-}
\ No newline at end of file
+}
+
+/// STARTTLS for a diagnostics probe. The handshake completes even if the certificate would
+/// be rejected; whether a send with `config` accepts it is returned alongside.
+pub(crate) fn probe_starttls(mut connection: Connected, config: &Config) -> Result<(Connected, Result<(), String>), Error> {
+    let not_tcp = || Error::TlsError("Only TCP connections can be probed for STARTTLS".to_string());
+    if !matches!(connection.stream, StreamWrapper::Insecure(_)) {
+        return Err(not_tcp());
+    }
+    connection.enter_phase(SmtpPhase::StartTls);
+    io::secure_send(&mut connection, "STARTTLS\r\n")?;
+    let response = io::secure_read(&mut connection)?;
+    if response.code != 220 {
+        return Err(response.into_error(SmtpPhase::StartTls));
+    }
+    connection.pending.clear();
+    let (tls_config, outcome) = create_probe_tls_config(config)?;
+    let server_name = tls_server_name(config, connection.mx_host.as_deref(), connection.address)?;
+    let tls_client_conn = ClientConnection::new(Arc::new(tls_config), server_name).map_err(|e| Error::TlsError(e.to_string()))?;
+    connection.stream = match connection.stream {
+        StreamWrapper::Insecure(tcp_stream) => StreamWrapper::Secure(StreamOwned::new(tls_client_conn, tcp_stream)),
+        _ => return Err(not_tcp()),
+    };
+    connection.arm_timeout()?;
+    if let StreamWrapper::Secure(stream) = &mut connection.stream {
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).map_err(|e| Error::TlsError(e.to_string()))?;
+        }
+        connection.tls_info = TlsInfo::from_connection(&stream.conn);
+    }
+    let verdict = outcome.lock().unwrap().take().unwrap_or(Ok(()));
+    Ok((connection, verdict))
+}
+
+/// The rustls config for a STARTTLS upgrade, with the slot the DANE check fills in if there is a `dane` policy
+pub(crate) fn tls_client_config(config: &Config, dane: Option<&DanePolicy>) -> Result<(Arc<rustls::ClientConfig>, Option<DaneOutcome>), Error> {
+    match dane {
+        Some(policy) => {
+            let (tls_config, outcome) = create_dane_tls_config(policy.clone(), config)?;
+            Ok((Arc::new(tls_config), Some(outcome)))
+        }
+        None => Ok((client_config(config)?, None)),
+    }
+}
+
+/// Name the certificate has to be valid for: `config.tls_server_name`, else the MX host,
+/// and only when connecting without one, the address
+pub(crate) fn tls_server_name(config: &Config, mx_host: Option<&str>, address: SocketAddr) -> Result<ServerName<'static>, Error> {
+    let name = match (&config.tls_server_name, mx_host) {
+        (Some(name), _) => name.trim_end_matches('.').to_string(),
+        (None, Some(name)) => name.trim_end_matches('.').to_string(),
+        (None, None) => address.ip().to_string(),
+    };
+    ServerName::try_from(name).map_err(|_| Error::TlsError("Invalid server name for TLS".to_string()))
+}
+
+/// Transcript note describing the negotiated TLS session
+pub(crate) fn tls_note(info: &TlsInfo) -> String {
+    format!(
+        "TLS: {} with {}, server presented {} certificate(s){}",
+        info.protocol_version.map_or("unknown version".to_string(), |v| v.to_string()), info.cipher_suite, info.peer_certificates.len(),
+        if info.resumed { ", session resumed" } else { "" },
+    )
+}
+
+/// Reports the outcome of the DANE check made during the handshake, and fails if the
+/// policy (or `TlsPolicy::Verified`) does not allow continuing after a mismatch.
+/// Returns the transcript note for the outcome otherwise.
+pub(crate) fn dane_verdict(
+    outcome: &DaneOutcome,
+    policy: &DanePolicy,
+    config: &Config,
+    local_addr: Option<SocketAddr>,
+    address: SocketAddr,
+) -> Result<String, Error> {
+    let result = outcome.lock().unwrap().take().unwrap_or_else(|| Err(Error::TlsError("DANE: certificate was not checked".to_string())));
+    policy.report(local_addr, address, result.as_ref().map_err(|e| (ResultType::ValidationFailure, e)).copied());
+    match result {
+        Ok(()) => Ok(format!("DANE: certificate of {} matches its TLSA records", policy.mx_host)),
+        Err(e) if policy.mode == DaneMode::Enforce || config.tls_policy == TlsPolicy::Verified => Err(e),
+        Err(e) => Ok(format!("{} (log only, continuing)", e)),
+    }
+}
@@ -0,0 +1,299 @@
+//! DANE verification of SMTP server certificates (RFC 7672)
+//!
+//! When the MX host publishes DNSSEC-validated TLSA records at `_25._tcp.<mx host>`,
+//! the certificate presented during STARTTLS is checked against them instead of
+//! against a CA. Only the usages RFC 7672 allows for SMTP are honored: DANE-EE(3),
+//! which pins the server's own certificate or key, and DANE-TA(2), which pins a
+//! trust anchor the server must include in its chain.
+//!
+//! DNSSEC validation is taken from the AD bit of the resolver's answers, which only a
+//! trusted resolver can vouch for, see [`ValidatingResolver`](crate::ValidatingResolver).
+//! The MX records that named the host have to be validated as well.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{config::Config, connection::Connected, error::Error, resolver::DnsAnswer, transcript::TranscriptEvent};
+use crate::tlsrpt::{Policy, PolicyType, ResultType, TlsFailure, TlsReportCollector};
+
+/// What to do with the outcome of DANE verification
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum DaneMode {
+    /// Do not look up TLSA records
+    #[default]
+    Off,
+    /// Verify and note the outcome in the transcript, but deliver regardless
+    LogOnly,
+    /// Refuse to deliver when the certificate does not match or STARTTLS is unavailable
+    Enforce,
+}
+
+/// TLSA record (RFC 6698 section 2.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsaRecord {
+    /// 0 PKIX-TA, 1 PKIX-EE, 2 DANE-TA, 3 DANE-EE
+    pub usage: u8,
+    /// 0 full certificate, 1 SubjectPublicKeyInfo
+    pub selector: u8,
+    /// 0 exact match, 1 SHA-256, 2 SHA-512
+    pub matching_type: u8,
+    /// Certificate association data
+    pub data: Vec<u8>,
+}
+
+impl TlsaRecord {
+    /// Parses the RDATA of a TLSA record
+    pub fn from_rdata(rdata: &[u8]) -> Option<Self> {
+        match rdata {
+            [usage, selector, matching_type, data @ ..] if !data.is_empty() => Some(Self {
+                usage: *usage,
+                selector: *selector,
+                matching_type: *matching_type,
+                data: data.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether an SMTP client may use the record: DANE-TA or DANE-EE with a known
+    /// selector and matching type (RFC 7672 section 3.1)
+    pub fn is_usable(&self) -> bool {
+        matches!(self.usage, 2 | 3) && self.selector <= 1 && self.matching_type <= 2
+    }
+
+    /// Whether the record's association data matches the DER certificate `cert`
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        let content = match self.selector {
+            0 => cert,
+            1 => match subject_public_key_info(cert) {
+                Some(spki) => spki,
+                None => return false,
+            },
+            _ => return false,
+        };
+        match self.matching_type {
+            0 => content == self.data.as_slice(),
+            1 => Sha256::digest(content).as_slice() == self.data.as_slice(),
+            2 => Sha512::digest(content).as_slice() == self.data.as_slice(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for TlsaRecord {
+    /// Presentation format, e.g. `3 1 1 0C72AC70...`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {} ", self.usage, self.selector, self.matching_type)?;
+        self.data.iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+/// Checks the certificate chain a server presented (leaf first) against the TLSA records
+/// of `mx_host`.
+///
+/// A DANE-EE record only has to match the leaf; its names and validity period are not
+/// checked. A DANE-TA record has to match a certificate in the chain, which must then
+/// validate from that anchor to a leaf valid for `mx_host`.
+pub fn verify(records: &[TlsaRecord], chain: &[CertificateDer<'_>], mx_host: &str, now: UnixTime) -> Result<(), Error> {
+    let leaf = chain.first().ok_or_else(|| Error::TlsError("DANE: the server presented no certificate".to_string()))?;
+    let usable = records.iter().filter(|r| r.is_usable()).collect::<Vec<_>>();
+    if usable.iter().any(|r| r.usage == 3 && r.matches(leaf)) {
+        return Ok(());
+    }
+    for anchor in chain {
+        if usable.iter().any(|r| r.usage == 2 && r.matches(anchor)) && chains_to(anchor, chain, mx_host, now) {
+            return Ok(());
+        }
+    }
+    Err(Error::TlsError(format!("DANE: no TLSA record of {} matches the server certificate", mx_host)))
+}
+
+fn chains_to(anchor: &CertificateDer<'_>, chain: &[CertificateDer<'_>], mx_host: &str, now: UnixTime) -> bool {
+    use rustls::client::danger::ServerCertVerifier;
+
+    let mut roots = rustls::RootCertStore::empty();
+    if roots.add(anchor.clone().into_owned()).is_err() {
+        return false;
+    }
+    let verifier = match rustls::client::WebPkiServerVerifier::builder_with_provider(Arc::new(roots), crate::tls::crypto_provider()).build() {
+        Ok(verifier) => verifier,
+        Err(_) => return false,
+    };
+    let name = match ServerName::try_from(mx_host.trim_end_matches('.').to_string()) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    verifier.verify_server_cert(&chain[0], &chain[1..], &name, &[], now).is_ok()
+}
+
+/// TLSA records that apply to a connection, looked up before STARTTLS
+#[derive(Debug, Clone)]
+pub(crate) struct DanePolicy {
+    pub mx_host: String,
+    pub records: Vec<TlsaRecord>,
+    pub mode: DaneMode,
+    /// Recipient domains delivered over the connection, the policy domains in TLS reports
+    pub domains: Vec<String>,
+    pub reports: Option<Arc<TlsReportCollector>>,
+}
+
+impl DanePolicy {
+    /// Records the outcome of the session for TLS reporting, once per recipient domain
+    pub fn report(&self, local_addr: Option<SocketAddr>, address: SocketAddr, outcome: Result<(), (ResultType, &Error)>) {
+        let Some(reports) = &self.reports else { return };
+        for domain in &self.domains {
+            let policy = Policy {
+                policy_type: PolicyType::Tlsa,
+                policy_strings: self.records.iter().map(|r| r.to_string()).collect(),
+                policy_domain: domain.clone(),
+                mx_host: Some(self.mx_host.clone()),
+            };
+            match &outcome {
+                Ok(()) => reports.record_success(&policy),
+                Err((result_type, error)) => reports.record_failure(&policy, TlsFailure {
+                    result_type: *result_type,
+                    sending_mta_ip: local_addr.map(|addr| addr.ip()),
+                    receiving_mx_hostname: Some(self.mx_host.clone()),
+                    receiving_ip: Some(address.ip()),
+                    additional_information: Some(error.to_string()),
+                }),
+            }
+        }
+    }
+}
+
+/// Looks up the TLSA records of the MX host `connection` is talking to for delivery to
+/// `domains`. Returns None if DANE is off, the MX records or the host's TLSA records are not
+/// DNSSEC-validated, or there are no usable records. When enforcing, a failed or
+/// unvalidated TLSA lookup fails with a transient [`Error::DnsError`] instead.
+pub(crate) fn lookup(connection: &Connected, config: &Config, domains: Vec<String>) -> Result<Option<DanePolicy>, Error> {
+    if config.dane == DaneMode::Off {
+        return Ok(None);
+    }
+    let Some(mx_host) = connection.mx_host.clone() else { return Ok(None) };
+    let note = |message| connection.record(TranscriptEvent::Note(message));
+    if !mx_secure(config, &mx_host, &domains, note) {
+        return Ok(None);
+    }
+    let resolver = crate::resolver::blocking(config);
+    let answer = resolver.tlsa_records(&tlsa_name(&mx_host, connection.address.port()));
+    policy_from_answer(answer, mx_host, config, domains, note)
+}
+
+/// Whether `mx_host` came from DNSSEC-validated MX records of one of `domains`, noting
+/// why DANE does not apply otherwise. `config` answers the MX lookups.
+pub(crate) fn mx_secure(config: &Config, mx_host: &str, domains: &[String], note: impl Fn(String)) -> bool {
+    if domains.iter().any(|domain| crate::dns::mx_authenticated(domain, config)) {
+        return true;
+    }
+    note(format!("DANE: MX records pointing to {} are not DNSSEC-validated, not using TLSA records", mx_host));
+    false
+}
+
+/// Owner name of the TLSA records for port `port` of `mx_host`
+pub(crate) fn tlsa_name(mx_host: &str, port: u16) -> String {
+    format!("_{}._tcp.{}", port, mx_host.trim_end_matches('.'))
+}
+
+/// The policy for a connection to `mx_host` given the answer to its TLSA lookup, noting
+/// why there is none
+pub(crate) fn policy_from_answer(
+    answer: Result<DnsAnswer<TlsaRecord>, Error>,
+    mx_host: String,
+    config: &Config,
+    domains: Vec<String>,
+    note: impl Fn(String),
+) -> Result<Option<DanePolicy>, Error> {
+    let enforce = config.dane == DaneMode::Enforce;
+    match answer {
+        // Without validation an empty answer may be a spoofed NXDOMAIN
+        Ok(answer) if !answer.authenticated && enforce => {
+            Err(Error::DnsError(format!("DANE: TLSA lookup for {} was not DNSSEC-validated", mx_host)))
+        }
+        Ok(answer) if !answer.authenticated => {
+            if !answer.records.is_empty() {
+                note(format!("DANE: ignoring TLSA records of {} that are not DNSSEC-validated", mx_host));
+            }
+            Ok(None)
+        }
+        Ok(answer) => {
+            let records = answer.records.into_iter().filter(TlsaRecord::is_usable).collect::<Vec<_>>();
+            if records.is_empty() {
+                return Ok(None);
+            }
+            note(format!("DANE: {} has {} usable TLSA record(s)", mx_host, records.len()));
+            Ok(Some(DanePolicy { mx_host, records, mode: config.dane, domains, reports: config.tls_reports.clone() }))
+        }
+        Err(e) if enforce => Err(Error::DnsError(format!("DANE: {}", e))),
+        Err(e) => {
+            note(format!("DANE: {}", e));
+            Ok(None)
+        }
+    }
+}
+
+/// The SubjectPublicKeyInfo of a DER certificate, as a complete DER element
+pub(crate) fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+    // The version is an optional [0] field
+    if tbs.first() == Some(&0xA0) {
+        tbs = der_element(tbs)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    Some(der_element(tbs)?.0)
+}
+
+/// The notBefore and notAfter dates of a DER certificate
+pub(crate) fn certificate_validity(cert: &[u8]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+    if tbs.first() == Some(&0xA0) {
+        tbs = der_element(tbs)?.2;
+    }
+    // serialNumber, signature, issuer
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, validity, _) = der_element(tbs)?;
+    let (not_before, _, rest) = der_element(validity)?;
+    let (not_after, _, _) = der_element(rest)?;
+    Some((der_time(not_before)?, der_time(not_after)?))
+}
+
+/// A UTCTime or GeneralizedTime element in the `Z` form certificates use (RFC 5280 section 4.1.2.5)
+fn der_time(element: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, contents, _) = der_element(element)?;
+    let text = std::str::from_utf8(contents).ok()?;
+    let text = match element[0] {
+        0x17 => format!("{}{}", if text.get(..2)? < "50" { "20" } else { "19" }, text),
+        0x18 => text.to_string(),
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(&text, "%Y%m%d%H%M%SZ").ok().map(|time| time.and_utc())
+}
+
+/// Splits off the DER element at the start of `input`: (whole element, contents, rest)
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *input.get(1)?;
+    let (len, header) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let count = (first & 0x7F) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let len = input.get(2..2 + count)?.iter().fold(0usize, |len, b| len << 8 | *b as usize);
+        (len, 2 + count)
+    };
+    let end = header.checked_add(len)?;
+    Some((input.get(..end)?, input.get(header..end)?, input.get(end..)?))
+}
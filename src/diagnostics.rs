@@ -0,0 +1,998 @@
+//! Pre-flight checks of the sending setup
+//!
+//! Receivers evaluate the DNS records of the sending domain before deciding whether to
+//! accept mail. The checks here run the same evaluation from the sender's side, so a
+//! misconfiguration shows up before the first message is rejected or filed as spam.
+//! [`probe`] looks at the receiving side instead, at what the MX hosts of a domain offer.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use chrono::{DateTime, Utc};
+
+use crate::capabilities::Capabilities;
+use crate::config::Config;
+use crate::connection::{self, Connected, StreamWrapper};
+use crate::dns::{lookup_host_addrs, MxRecord};
+use crate::error::SmtpPhase;
+use crate::io;
+use crate::resolver::{MicroDnsResolver, Resolver};
+use crate::tls::TlsInfo;
+use crate::transcript::SharedRecorder;
+
+/// Outcome of an SPF evaluation (RFC 7208 section 2.6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpfResult {
+    /// The domain publishes no SPF record
+    None,
+    /// The record makes no statement about the IP
+    Neutral,
+    /// The IP is authorized
+    Pass,
+    /// The IP is not authorized, receivers are expected to reject
+    Fail,
+    /// The IP is probably not authorized, receivers usually mark the mail as spam
+    SoftFail,
+    /// A DNS lookup failed, the check may succeed later
+    TempError,
+    /// The record is invalid and cannot be evaluated
+    PermError,
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        })
+    }
+}
+
+/// Result of [`check_spf`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpfCheck {
+    pub domain: String,
+    pub sending_ip: IpAddr,
+    pub result: SpfResult,
+    /// The SPF record of `domain`, if one was found
+    pub record: Option<String>,
+    /// The term that decided the result, e.g. `ip4:192.0.2.0/24` or `-all`
+    pub matched: Option<String>,
+    /// Why the result is what it is, for errors and results without a matching term
+    pub reason: Option<String>,
+}
+
+impl SpfCheck {
+    /// Whether receivers will consider the IP authorized
+    pub fn is_pass(&self) -> bool {
+        self.result == SpfResult::Pass
+    }
+
+    /// A warning describing how receivers are likely to treat mail from the IP, or None
+    /// if SPF passes
+    pub fn warning(&self) -> Option<String> {
+        let consequence = match self.result {
+            SpfResult::Pass => return None,
+            SpfResult::Fail => "receivers will likely reject mail from it",
+            SpfResult::SoftFail => "receivers will likely mark mail from it as spam",
+            SpfResult::Neutral => "the record does not vouch for it",
+            SpfResult::None => "the domain publishes no SPF record, receivers may treat its mail as suspicious",
+            SpfResult::TempError => "the record could not be evaluated right now",
+            SpfResult::PermError => "the record is invalid, receivers may treat it as failing",
+        };
+        let detail = match (&self.matched, &self.reason) {
+            (Some(term), _) => format!(" (matched {})", term),
+            (None, Some(reason)) => format!(" ({})", reason),
+            (None, None) => String::new(),
+        };
+        Some(format!("SPF {} for {} sending as {}{}: {}", self.result, self.sending_ip, self.domain, detail, consequence))
+    }
+}
+
+/// Evaluates the SPF record of `domain` (the domain of the envelope sender) for mail sent
+/// from `sending_ip`, e.g. the address set with [`Config::bind_addr`](crate::Config::bind_addr)
+/// or the public address of the NAT gateway.
+///
+/// Uses the built-in [`MicroDnsResolver`]; see [`check_spf_with`] for another resolver.
+pub fn check_spf(domain: &str, sending_ip: IpAddr) -> SpfCheck {
+    check_spf_with(&MicroDnsResolver, domain, sending_ip)
+}
+
+/// Like [`check_spf`], looking up records through `resolver`
+pub fn check_spf_with(resolver: &dyn Resolver, domain: &str, sending_ip: IpAddr) -> SpfCheck {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let mut evaluation = Evaluation { resolver, ip: sending_ip, sender_domain: domain.clone(), lookups: 0, void_lookups: 0 };
+    let (result, record, matched, reason) = match evaluation.check_host(&domain) {
+        Ok(outcome) => (outcome.result, outcome.record, outcome.matched, outcome.reason),
+        Err((result, reason)) => (result, None, None, Some(reason)),
+    };
+    SpfCheck { domain, sending_ip, result, record, matched, reason }
+}
+
+/// DNS-querying mechanisms and modifiers allowed per evaluation (RFC 7208 section 4.6.4)
+const MAX_LOOKUPS: usize = 10;
+/// Lookups with empty answers allowed per evaluation
+const MAX_VOID_LOOKUPS: usize = 2;
+
+struct Outcome {
+    result: SpfResult,
+    record: Option<String>,
+    matched: Option<String>,
+    reason: Option<String>,
+}
+
+/// An error result and its reason
+type Abort = (SpfResult, String);
+
+struct Evaluation<'a> {
+    resolver: &'a dyn Resolver,
+    ip: IpAddr,
+    sender_domain: String,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl Evaluation<'_> {
+    /// The check_host() function of RFC 7208 section 4
+    fn check_host(&mut self, domain: &str) -> Result<Outcome, Abort> {
+        let answer = self.resolver.txt_records(domain).map_err(|e| (SpfResult::TempError, e.to_string()))?;
+        let mut records = answer.records.into_iter().filter(|txt| is_spf_record(txt));
+        let record = match (records.next(), records.next()) {
+            (None, _) => return Ok(Outcome { result: SpfResult::None, record: None, matched: None, reason: Some(format!("{} has no SPF record", domain)) }),
+            (Some(_), Some(_)) => return Err((SpfResult::PermError, format!("{} has more than one SPF record", domain))),
+            (Some(record), None) => record,
+        };
+        let mut redirect = None;
+        for term in record.split_ascii_whitespace().skip(1) {
+            if let Some((name, value)) = modifier(term) {
+                if name.eq_ignore_ascii_case("redirect") {
+                    if redirect.is_some() {
+                        return Err((SpfResult::PermError, format!("{} has more than one redirect", domain)));
+                    }
+                    redirect = Some(value.to_string());
+                }
+                // exp= and unknown modifiers do not affect the result
+                continue;
+            }
+            let (qualifier, mechanism) = match term.as_bytes()[0] {
+                b'+' => (SpfResult::Pass, &term[1..]),
+                b'-' => (SpfResult::Fail, &term[1..]),
+                b'~' => (SpfResult::SoftFail, &term[1..]),
+                b'?' => (SpfResult::Neutral, &term[1..]),
+                _ => (SpfResult::Pass, term),
+            };
+            if self.matches(domain, mechanism)? {
+                return Ok(Outcome { result: qualifier, record: Some(record.clone()), matched: Some(term.to_string()), reason: None });
+            }
+        }
+        if let Some(target) = redirect {
+            self.count_lookup()?;
+            let target = self.expand(&target, domain)?;
+            let outcome = self.check_host(&target)?;
+            if outcome.result == SpfResult::None {
+                return Err((SpfResult::PermError, format!("redirect target {} has no SPF record", target)));
+            }
+            return Ok(Outcome { record: Some(record), ..outcome });
+        }
+        Ok(Outcome { result: SpfResult::Neutral, record: Some(record), matched: None, reason: Some("no mechanism matched".to_string()) })
+    }
+
+    /// Whether `mechanism` (without qualifier) of the record of `domain` matches the IP
+    fn matches(&mut self, domain: &str, mechanism: &str) -> Result<bool, Abort> {
+        let (name, argument) = match mechanism.find([':', '/']) {
+            Some(i) => (&mechanism[..i], &mechanism[i..]),
+            None => (mechanism, ""),
+        };
+        let invalid = || (SpfResult::PermError, format!("invalid term {} in the SPF record of {}", mechanism, domain));
+        match name.to_ascii_lowercase().as_str() {
+            "all" if argument.is_empty() => Ok(true),
+            "include" => {
+                self.count_lookup()?;
+                let target = self.expand(argument.strip_prefix(':').ok_or_else(invalid)?, domain)?;
+                match self.check_host(&target)? {
+                    Outcome { result: SpfResult::Pass, .. } => Ok(true),
+                    Outcome { result: SpfResult::None, .. } => Err((SpfResult::PermError, format!("included domain {} has no SPF record", target))),
+                    _ => Ok(false),
+                }
+            }
+            "a" | "mx" => {
+                self.count_lookup()?;
+                let (target, v4_len, v6_len) = self.target_and_cidr(argument, domain).ok_or_else(invalid)?;
+                let target = self.expand(&target, domain)?;
+                let hosts = if name.eq_ignore_ascii_case("mx") {
+                    let answer = self.resolver.mx_records(&target).map_err(|e| (SpfResult::TempError, e.to_string()))?;
+                    if answer.records.len() > MAX_LOOKUPS {
+                        return Err((SpfResult::PermError, format!("{} has more than {} MX records", target, MAX_LOOKUPS)));
+                    }
+                    self.count_void(answer.records.is_empty())?;
+                    answer.records.into_iter().map(|mx| mx.server).collect()
+                } else {
+                    vec![target]
+                };
+                for host in hosts {
+                    let answer = self.resolver.host_addrs(&host).map_err(|e| (SpfResult::TempError, e.to_string()))?;
+                    self.count_void(answer.records.is_empty())?;
+                    if answer.records.iter().any(|addr| in_network(self.ip, *addr, v4_len, v6_len)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "ip4" | "ip6" => {
+                let argument = argument.strip_prefix(':').ok_or_else(invalid)?;
+                let (addr, len) = match argument.split_once('/') {
+                    Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+                    None => (argument, None),
+                };
+                let network = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+                let (v4_len, v6_len) = (len.unwrap_or(32), len.unwrap_or(128));
+                match network {
+                    IpAddr::V4(_) if name.eq_ignore_ascii_case("ip4") && v4_len <= 32 => Ok(in_network(self.ip, network, v4_len, v6_len)),
+                    IpAddr::V6(_) if name.eq_ignore_ascii_case("ip6") && v6_len <= 128 => Ok(in_network(self.ip, network, v4_len, v6_len)),
+                    _ => Err(invalid()),
+                }
+            }
+            "exists" => {
+                self.count_lookup()?;
+                let target = self.expand(argument.strip_prefix(':').ok_or_else(invalid)?, domain)?;
+                let answer = self.resolver.host_addrs(&target).map_err(|e| (SpfResult::TempError, e.to_string()))?;
+                let exists = answer.records.iter().any(IpAddr::is_ipv4);
+                self.count_void(!exists)?;
+                Ok(exists)
+            }
+            // Deprecated (RFC 7208 section 5.5) and needs reverse lookups; receivers that
+            // still evaluate it may match, but a record should not rely on it
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Splits the argument of `a` and `mx` into its domain and the two prefix lengths
+    fn target_and_cidr(&self, argument: &str, domain: &str) -> Option<(String, u8, u8)> {
+        let (target, cidr) = match argument.strip_prefix(':') {
+            Some(rest) => match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, ""),
+            },
+            None => (domain, argument),
+        };
+        let (v4, v6) = match cidr.find("//") {
+            Some(i) => (&cidr[..i], Some(&cidr[i + 2..])),
+            None => (cidr, None),
+        };
+        let v4_len = match v4.strip_prefix('/') {
+            Some(len) => len.parse().ok().filter(|len| *len <= 32)?,
+            None if v4.is_empty() => 32,
+            None => return None,
+        };
+        let v6_len = match v6 {
+            Some(len) => len.parse().ok().filter(|len| *len <= 128)?,
+            None => 128,
+        };
+        (!target.is_empty()).then(|| (target.to_string(), v4_len, v6_len))
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Abort> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err((SpfResult::PermError, format!("more than {} DNS lookups", MAX_LOOKUPS)));
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self, void: bool) -> Result<(), Abort> {
+        if void {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err((SpfResult::PermError, format!("more than {} lookups without an answer", MAX_VOID_LOOKUPS)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands the macros of a domain-spec (RFC 7208 section 7). The local part of the
+    /// sender is not known ahead of a send, `postmaster` stands in for it.
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Abort> {
+        let invalid = || (SpfResult::PermError, format!("invalid macro in {}", spec));
+        let mut out = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out.push_str("%20"),
+                Some('{') => {
+                    let body = chars.by_ref().take_while(|c| *c != '}').collect::<String>();
+                    let mut body_chars = body.chars();
+                    let letter = body_chars.next().ok_or_else(invalid)?.to_ascii_lowercase();
+                    let value = match letter {
+                        's' => format!("postmaster@{}", self.sender_domain),
+                        'l' => "postmaster".to_string(),
+                        'o' => self.sender_domain.clone(),
+                        'd' => domain.to_string(),
+                        'i' => match self.ip {
+                            IpAddr::V4(ip) => ip.to_string(),
+                            IpAddr::V6(ip) => ip.octets().iter().map(|b| format!("{:x}.{:x}", b >> 4, b & 0xF)).collect::<Vec<_>>().join("."),
+                        },
+                        'v' => if self.ip.is_ipv4() { "in-addr" } else { "ip6" }.to_string(),
+                        'h' => self.sender_domain.clone(),
+                        _ => return Err(invalid()),
+                    };
+                    let rest = body_chars.as_str();
+                    let digits = rest.chars().take_while(char::is_ascii_digit).collect::<String>();
+                    let rest = &rest[digits.len()..];
+                    let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+                        Some(delimiters) => (true, delimiters),
+                        None => (false, rest),
+                    };
+                    if delimiters.chars().any(|c| !".-+,/_=".contains(c)) {
+                        return Err(invalid());
+                    }
+                    let delimiters = if delimiters.is_empty() { "." } else { delimiters };
+                    let mut parts = value.split(|c| delimiters.contains(c)).collect::<Vec<_>>();
+                    if reverse {
+                        parts.reverse();
+                    }
+                    if !digits.is_empty() {
+                        let keep = digits.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(invalid)?;
+                        parts = parts.split_off(parts.len().saturating_sub(keep));
+                    }
+                    out.push_str(&parts.join("."));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// What a domain asks receivers to do with mail failing DMARC (RFC 7489 section 6.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmarcPolicy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+/// How closely an authenticated domain has to match the From domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// Same organizational domain, e.g. `mail.example.com` for `example.com`
+    Relaxed,
+    /// Exactly the same domain
+    Strict,
+}
+
+/// A parsed DMARC record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmarcRecord {
+    /// `p=`
+    pub policy: DmarcPolicy,
+    /// `sp=`, the policy for subdomains if it differs
+    pub subdomain_policy: Option<DmarcPolicy>,
+    /// `adkim=`
+    pub dkim_alignment: Alignment,
+    /// `aspf=`
+    pub spf_alignment: Alignment,
+    /// `pct=`, the share of failing mail the policy applies to
+    pub percent: u8,
+    /// `rua=`, where aggregate reports go
+    pub aggregate_reports: Vec<String>,
+    /// `ruf=`, where failure reports go
+    pub failure_reports: Vec<String>,
+}
+
+impl DmarcRecord {
+    /// Parses the text of a `_dmarc` TXT record; None if it is not a DMARC record or has no policy
+    pub fn parse(txt: &str) -> Option<Self> {
+        let mut tags = txt.split(';').map(str::trim).filter(|tag| !tag.is_empty())
+            .filter_map(|tag| tag.split_once('=').map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim())));
+        match tags.next() {
+            Some((name, version)) if name == "v" && version == "DMARC1" => {}
+            _ => return None,
+        }
+        let policy = |value: &str| match value.to_ascii_lowercase().as_str() {
+            "none" => Some(DmarcPolicy::None),
+            "quarantine" => Some(DmarcPolicy::Quarantine),
+            "reject" => Some(DmarcPolicy::Reject),
+            _ => None,
+        };
+        let alignment = |value: &str| if value.eq_ignore_ascii_case("s") { Alignment::Strict } else { Alignment::Relaxed };
+        let uris = |value: &str| value.split(',').map(|uri| uri.trim().to_string()).filter(|uri| !uri.is_empty()).collect();
+        let mut record = DmarcRecord {
+            policy: DmarcPolicy::None,
+            subdomain_policy: None,
+            dkim_alignment: Alignment::Relaxed,
+            spf_alignment: Alignment::Relaxed,
+            percent: 100,
+            aggregate_reports: Vec::new(),
+            failure_reports: Vec::new(),
+        };
+        let mut has_policy = false;
+        for (name, value) in tags {
+            match name.as_str() {
+                "p" => { record.policy = policy(value)?; has_policy = true; }
+                "sp" => record.subdomain_policy = policy(value),
+                "adkim" => record.dkim_alignment = alignment(value),
+                "aspf" => record.spf_alignment = alignment(value),
+                "pct" => record.percent = value.parse::<u8>().ok().filter(|pct| *pct <= 100).unwrap_or(100),
+                "rua" => record.aggregate_reports = uris(value),
+                "ruf" => record.failure_reports = uris(value),
+                _ => {}
+            }
+        }
+        has_policy.then_some(record)
+    }
+}
+
+/// Result of [`check_dmarc`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmarcCheck {
+    /// Domain of the From header
+    pub from_domain: String,
+    /// The DMARC record that applies, if any
+    pub record: Option<DmarcRecord>,
+    /// Domain the record was found at: the From domain or its organizational domain
+    pub record_domain: Option<String>,
+    /// The policy that applies to the From domain, taking `sp=` into account
+    pub effective_policy: Option<DmarcPolicy>,
+    /// Whether the DKIM domain of the config aligns with the From domain (None without DKIM)
+    pub dkim_aligned: Option<bool>,
+    /// Whether the configured selector publishes a key (None without DKIM or if the lookup failed)
+    pub dkim_key_published: Option<bool>,
+    /// Whether the envelope sender's domain aligns with the From domain
+    pub spf_aligned: bool,
+    /// Problems found, each a complete sentence
+    pub warnings: Vec<String>,
+}
+
+impl DmarcCheck {
+    /// Whether at least one identifier aligns, which DMARC requires (on top of the
+    /// corresponding SPF or DKIM check passing)
+    pub fn is_aligned(&self) -> bool {
+        self.spf_aligned || (self.dkim_aligned == Some(true) && self.dkim_key_published != Some(false))
+    }
+}
+
+/// Looks up the DMARC policy for mail with a From header in `from_domain` and checks
+/// whether the DKIM key of `config` and the `envelope_sender` (an address or a domain)
+/// produce identifiers aligned with it.
+///
+/// Lookups go through `config.resolver`, or the built-in [`MicroDnsResolver`] unless only
+/// an async resolver is set. The
+/// organizational domain is approximated as the last two labels of a domain, which is
+/// wrong for public suffixes like `co.uk`.
+pub fn check_dmarc(config: &Config, from_domain: &str, envelope_sender: &str) -> DmarcCheck {
+    let resolver = crate::resolver::blocking(config);
+    let from_domain = from_domain.trim_end_matches('.').to_lowercase();
+    let envelope_domain = envelope_sender.rsplit('@').next().unwrap_or_default().trim_end_matches('.').to_lowercase();
+    let mut warnings = Vec::new();
+
+    let mut found = None;
+    let org_domain = organizational_domain(&from_domain);
+    for domain in std::iter::once(from_domain.as_str()).chain((org_domain != from_domain).then_some(org_domain)) {
+        match resolver.txt_records(&format!("_dmarc.{}", domain)) {
+            Ok(answer) => {
+                let mut records = answer.records.iter().filter_map(|txt| DmarcRecord::parse(txt));
+                match (records.next(), records.next()) {
+                    (Some(record), None) => { found = Some((record, domain.to_string())); break; }
+                    (Some(_), Some(_)) => { warnings.push(format!("_dmarc.{} has more than one DMARC record, receivers will ignore them", domain)); break; }
+                    (None, _) => {}
+                }
+            }
+            Err(e) => { warnings.push(format!("DMARC lookup failed: {}", e)); break; }
+        }
+    }
+    let (record, record_domain) = match found {
+        Some((record, domain)) => (Some(record), Some(domain)),
+        None => (None, None),
+    };
+    let effective_policy = record.as_ref().map(|record| match (&record_domain, record.subdomain_policy) {
+        (Some(domain), Some(subdomain_policy)) if *domain != from_domain => subdomain_policy,
+        _ => record.policy,
+    });
+    if record.is_none() && warnings.is_empty() {
+        warnings.push(format!("{} publishes no DMARC record, some providers reject or junk mail from domains without one", from_domain));
+    }
+
+    let spf_alignment = record.as_ref().map_or(Alignment::Relaxed, |record| record.spf_alignment);
+    let spf_aligned = aligned(&envelope_domain, &from_domain, spf_alignment);
+    if !spf_aligned {
+        warnings.push(format!("The envelope sender domain {} does not align with the From domain {}, so SPF cannot satisfy DMARC", envelope_domain, from_domain));
+    }
+
+    let (dkim_aligned, dkim_key_published) = match dkim_identity(config) {
+        Some((selector, domain)) => {
+            let dkim_alignment = record.as_ref().map_or(Alignment::Relaxed, |record| record.dkim_alignment);
+            let is_aligned = aligned(&domain, &from_domain, dkim_alignment);
+            if !is_aligned {
+                warnings.push(format!("The DKIM domain {} does not align with the From domain {}, so DKIM cannot satisfy DMARC", domain, from_domain));
+            }
+            let key_name = format!("{}._domainkey.{}", selector, domain);
+            let published = match resolver.txt_records(&key_name) {
+                Ok(answer) => Some(answer.records.iter().any(|txt| txt.split(';').any(|tag| tag.trim().starts_with("p=") && tag.trim().len() > 2))),
+                Err(e) => { warnings.push(format!("DKIM key lookup failed: {}", e)); None }
+            };
+            if published == Some(false) {
+                warnings.push(format!("No DKIM key is published at {}, receivers cannot verify the signatures", key_name));
+            }
+            (Some(is_aligned), published)
+        }
+        None => {
+            warnings.push("No DKIM key is configured, DMARC relies on SPF alone, which breaks when mail is forwarded".to_string());
+            (None, None)
+        }
+    };
+    DmarcCheck { from_domain, record, record_domain, effective_policy, dkim_aligned, dkim_key_published, spf_aligned, warnings }
+}
+
+#[cfg(feature = "signing")]
+fn dkim_identity(config: &Config) -> Option<(String, String)> {
+    config.dkim_config.as_ref().map(|dkim| (dkim.selector.clone(), dkim.domain.trim_end_matches('.').to_lowercase()))
+}
+
+#[cfg(not(feature = "signing"))]
+fn dkim_identity(_config: &Config) -> Option<(String, String)> {
+    None
+}
+
+/// Identifier alignment (RFC 7489 section 3.1)
+fn aligned(domain: &str, from_domain: &str, alignment: Alignment) -> bool {
+    match alignment {
+        Alignment::Strict => domain == from_domain,
+        Alignment::Relaxed => organizational_domain(domain) == organizational_domain(from_domain),
+    }
+}
+
+/// The last two labels of `domain`, standing in for a public suffix list lookup
+pub(crate) fn organizational_domain(domain: &str) -> &str {
+    match domain.rmatch_indices('.').nth(1) {
+        Some((i, _)) => &domain[i + 1..],
+        None => domain,
+    }
+}
+
+/// Result of [`check_reverse_dns`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReverseDnsCheck {
+    /// The source address that was checked
+    pub ip: IpAddr,
+    /// Names the PTR records of `ip` point to
+    pub ptr_names: Vec<String>,
+    /// Those of `ptr_names` that resolve back to `ip` (forward-confirmed reverse DNS)
+    pub confirmed_names: Vec<String>,
+    /// Whether the name sent in EHLO is among `confirmed_names`
+    pub matches_domain: bool,
+    /// Problems found, each a complete sentence
+    pub warnings: Vec<String>,
+}
+
+impl ReverseDnsCheck {
+    /// Whether the address has forward-confirmed reverse DNS matching the EHLO name
+    pub fn is_ok(&self) -> bool {
+        !self.confirmed_names.is_empty() && self.matches_domain
+    }
+}
+
+/// Checks that the address mail will be sent from has forward-confirmed reverse DNS
+/// (FCrDNS) matching the EHLO name, [`Config::ehlo_name`]. Large providers reject mail from addresses without it.
+///
+/// The address is `config.bind_addr`, or else the local address the OS would use to reach
+/// the internet. Behind NAT that is a private address, pass the public one to
+/// [`check_reverse_dns_of`] instead.
+pub fn check_reverse_dns(config: &Config) -> Result<ReverseDnsCheck, crate::Error> {
+    let ip = match config.bind_addr {
+        Some(ip) => ip,
+        None => outbound_addr()?,
+    };
+    Ok(check_reverse_dns_of(config, ip))
+}
+
+/// Like [`check_reverse_dns`], for the given source address. Lookups go through
+/// `config.resolver`, or the built-in [`MicroDnsResolver`] unless only an async resolver is set.
+pub fn check_reverse_dns_of(config: &Config, ip: IpAddr) -> ReverseDnsCheck {
+    let resolver = crate::resolver::blocking(config);
+    let domain = config.ehlo_name().trim_end_matches('.').to_lowercase();
+    let mut warnings = Vec::new();
+    if is_private(ip) {
+        warnings.push(format!("{} is not a public address, receivers see the address of the NAT gateway instead", ip));
+    }
+    let ptr_names = match resolver.ptr_records(ip) {
+        Ok(answer) if answer.records.is_empty() => {
+            warnings.push(format!("{} has no PTR record, many receivers reject mail from it", ip));
+            Vec::new()
+        }
+        Ok(answer) => answer.records.into_iter().map(|name| name.trim_end_matches('.').to_lowercase()).collect::<Vec<_>>(),
+        Err(e) => {
+            warnings.push(format!("PTR lookup failed: {}", e));
+            Vec::new()
+        }
+    };
+    let mut confirmed_names = Vec::new();
+    for name in &ptr_names {
+        match resolver.host_addrs(name) {
+            Ok(answer) if answer.records.contains(&ip) => confirmed_names.push(name.clone()),
+            Ok(_) => warnings.push(format!("{} points to {}, which does not resolve back to it", ip, name)),
+            Err(e) => warnings.push(format!("Address lookup of {} failed: {}", name, e)),
+        }
+    }
+    let matches_domain = confirmed_names.contains(&domain);
+    if !confirmed_names.is_empty() && !matches_domain {
+        warnings.push(format!("{} resolves to {} but the EHLO name is {}, set Config::ehlo_hostname to match", ip, confirmed_names.join(", "), domain));
+    }
+    ReverseDnsCheck { ip, ptr_names, confirmed_names, matches_domain, warnings }
+}
+
+/// What [`probe`] found on one port of an MX host
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PortProbe {
+    pub port: u16,
+    /// Address the connection was made to (None if no connection could be made)
+    pub address: Option<SocketAddr>,
+    /// Why the probe of the port ended early, e.g. a refused connection or a 554 greeting
+    pub error: Option<String>,
+    /// Text of the greeting
+    pub banner: Option<String>,
+    /// Whether the server offers STARTTLS
+    pub starttls: bool,
+    /// Reply to EHLO, after STARTTLS if the upgrade succeeded
+    pub capabilities: Option<Capabilities>,
+    /// The session negotiated with STARTTLS
+    pub tls: Option<TlsInfo>,
+    /// The certificate presented in the STARTTLS handshake
+    pub certificate: Option<CertificateProbe>,
+}
+
+/// The certificate of a server as seen by [`probe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateProbe {
+    /// Why a send with the probing config would reject the certificate (None = accepted)
+    pub error: Option<String>,
+    /// Validity period of the leaf certificate (None if it could not be parsed)
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: Option<DateTime<Utc>>,
+    /// Number of certificates the server presented
+    pub chain_len: usize,
+}
+
+impl CertificateProbe {
+    pub fn is_valid(&self) -> bool { self.error.is_none() }
+
+    /// Time left until the leaf certificate expires, negative once it has
+    pub fn expires_in(&self) -> Option<chrono::Duration> {
+        self.not_after.map(|not_after| not_after - Utc::now())
+    }
+}
+
+/// One MX host of a [`ProbeReport`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostProbe {
+    pub host: String,
+    pub priority: u16,
+    /// Addresses the host resolved to
+    pub addresses: Vec<IpAddr>,
+    /// Every port of `Config::ports`, in that order
+    pub ports: Vec<PortProbe>,
+}
+
+/// Result of [`probe`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeReport {
+    pub domain: String,
+    /// MX hosts in the order a send tries them
+    pub hosts: Vec<HostProbe>,
+    /// Problems found, each a complete sentence
+    pub warnings: Vec<String>,
+}
+
+impl ProbeReport {
+    /// Whether some port of some MX host greeted with a 2xx reply
+    pub fn is_reachable(&self) -> bool {
+        self.hosts.iter().flat_map(|host| &host.ports).any(|port| port.capabilities.is_some())
+    }
+}
+
+/// Certificates expiring sooner than this are reported
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+/// Connects to every MX host of `domain` on the default ports and reports what each one
+/// offers: the greeting, STARTTLS, the certificate and its expiry, and the EHLO extensions
+/// including AUTH mechanisms. Nothing is sent, every session ends after EHLO with QUIT.
+pub fn probe(domain: &str) -> Result<ProbeReport, crate::Error> {
+    probe_with(&Config::default(), domain)
+}
+
+/// Like [`probe`], with the resolver, ports, EHLO name, timeouts and trusted certificates
+/// of `config`. Certificates are judged as a send with `config` would judge them.
+/// Fails only if the MX hosts cannot be determined.
+pub fn probe_with(config: &Config, domain: &str) -> Result<ProbeReport, crate::Error> {
+    let recorder = SharedRecorder::default();
+    let mx_records = crate::mail::resolve_mx(config, &recorder, domain)?;
+    let mut warnings = Vec::new();
+    let mut hosts = Vec::new();
+    for mx in mx_records {
+        let addresses = lookup_host_addrs(&mx.server, config);
+        let ports = config.ports.iter().map(|&port| probe_port(config, &recorder, &mx.server, &addresses, port)).collect::<Vec<PortProbe>>();
+        for port in &ports {
+            let at = format!("{} port {}", mx.server, port.port);
+            if let Some(error) = &port.error {
+                warnings.push(format!("{}: {}", at, error));
+            } else if !port.starttls {
+                warnings.push(format!("{} does not offer STARTTLS, mail to it travels in plaintext", at));
+            }
+            if let Some(certificate) = &port.certificate {
+                if let Some(error) = &certificate.error {
+                    warnings.push(format!("{} presents a certificate that would be rejected: {}", at, error));
+                }
+                match certificate.expires_in() {
+                    Some(left) if left < chrono::Duration::zero() => warnings.push(format!("The certificate of {} has expired", at)),
+                    Some(left) if left < chrono::Duration::days(EXPIRY_WARNING_DAYS) => warnings.push(format!("The certificate of {} expires in {} days", at, left.num_days())),
+                    _ => {}
+                }
+            }
+        }
+        hosts.push(HostProbe { host: mx.server, priority: mx.priority, addresses, ports });
+    }
+    Ok(ProbeReport { domain: domain.to_string(), hosts, warnings })
+}
+
+fn probe_port(config: &Config, recorder: &SharedRecorder, host: &str, addresses: &[IpAddr], port: u16) -> PortProbe {
+    let mut probe = PortProbe { port, ..Default::default() };
+    if addresses.is_empty() {
+        probe.error = Some("the host has no address records".to_string());
+        return probe;
+    }
+    let socket_addrs = addresses.iter().map(|ip| SocketAddr::new(*ip, port)).collect::<Vec<_>>();
+    let (tcp_stream, address) = match connection::connect_happy_eyeballs(&socket_addrs, config.timeout, config.bind_addr) {
+        Ok(connected) => connected,
+        Err(e) => {
+            probe.error = Some(e.to_string());
+            return probe;
+        }
+    };
+    probe.address = Some(address);
+    let mut connection = Connected::new(StreamWrapper::Insecure(tcp_stream), address, config, recorder);
+    connection.mx_host = Some(host.to_string());
+    if let Err(e) = probe_session(config, connection, &mut probe) {
+        probe.error = Some(e.to_string());
+    }
+    probe
+}
+
+/// Greeting, EHLO, STARTTLS and EHLO again, filling in `probe` as it goes
+fn probe_session(config: &Config, mut connection: Connected, probe: &mut PortProbe) -> Result<(), crate::Error> {
+    connection.enter_phase(SmtpPhase::Greeting);
+    let greeting = io::secure_read(&mut connection)?;
+    probe.banner = Some(greeting.text());
+    if !greeting.is_positive_completion() {
+        return Err(greeting.into_error(SmtpPhase::Greeting));
+    }
+    let capabilities = connection::send_ehlo(&mut connection, config.ehlo_name(), true)?;
+    probe.starttls = capabilities.starttls;
+    probe.capabilities = Some(capabilities);
+    if probe.starttls {
+        let (secure, verdict) = connection::probe_starttls(connection, config)?;
+        connection = secure;
+        probe.tls = connection.tls_info.clone();
+        let chain = probe.tls.as_ref().map_or(&[][..], |tls| &tls.peer_certificates[..]);
+        let validity = chain.first().and_then(|leaf| crate::dane::certificate_validity(leaf));
+        probe.certificate = Some(CertificateProbe {
+            error: verdict.err(),
+            not_before: validity.map(|(not_before, _)| not_before),
+            not_after: validity.map(|(_, not_after)| not_after),
+            chain_len: chain.len(),
+        });
+        probe.capabilities = Some(connection::send_ehlo(&mut connection, config.ehlo_name(), true)?);
+    }
+    connection.enter_phase(SmtpPhase::Quit);
+    if io::secure_send(&mut connection, "QUIT\r\n").is_ok() {
+        let _ = io::secure_read(&mut connection);
+    }
+    Ok(())
+}
+
+/// The part of the setup a [`Finding`] is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckKind {
+    /// MX records of the sending domain, where replies and bounces go
+    Mx,
+    Spf,
+    /// The published key of the configured DKIM selector
+    Dkim,
+    Dmarc,
+    /// MTA-STS (RFC 8461) policy of the sending domain
+    MtaSts,
+    /// Forward-confirmed reverse DNS of the sending address
+    ReverseDns,
+}
+
+/// How a [`Finding`] affects deliverability
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Pass,
+    /// Mail gets through, but some receivers may junk or reject it
+    Warn,
+    /// Receivers will likely reject or junk the mail
+    Fail,
+}
+
+/// One result of [`deliverability_report`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub check: CheckKind,
+    pub severity: Severity,
+    /// What was found, a complete sentence
+    pub message: String,
+}
+
+/// Result of [`deliverability_report`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliverabilityReport {
+    /// The sending domain, `Config::domain`
+    pub domain: String,
+    /// The address SPF and reverse DNS were checked for
+    pub sending_ip: IpAddr,
+    /// One or more findings per check, in the order of [`CheckKind`]
+    pub findings: Vec<Finding>,
+}
+
+impl DeliverabilityReport {
+    /// The most severe finding, `Severity::Pass` if everything passed
+    pub fn severity(&self) -> Severity {
+        self.findings.iter().map(|finding| finding.severity).max().unwrap_or(Severity::Pass)
+    }
+
+    /// Findings that are not a pass
+    pub fn problems(&self) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(|finding| finding.severity != Severity::Pass)
+    }
+}
+
+/// Checks everything receivers look at before accepting mail from `config`: the MX, SPF,
+/// DMARC and MTA-STS records of `config.domain`, the DKIM key of the configured selector,
+/// and the reverse DNS of the sending address. The envelope sender is
+/// `config.envelope_from`, or else the domain itself.
+///
+/// The sending address is chosen like in [`check_reverse_dns`]; behind NAT, pass the
+/// public address to [`deliverability_report_for`] instead.
+pub fn deliverability_report(config: &Config) -> Result<DeliverabilityReport, crate::Error> {
+    let ip = match config.bind_addr {
+        Some(ip) => ip,
+        None => outbound_addr()?,
+    };
+    Ok(deliverability_report_for(config, ip))
+}
+
+/// Like [`deliverability_report`], for mail sent from `sending_ip`. Lookups go through
+/// `config.resolver`, or the built-in [`MicroDnsResolver`] unless only an async resolver is set.
+pub fn deliverability_report_for(config: &Config, sending_ip: IpAddr) -> DeliverabilityReport {
+    let resolver = crate::resolver::blocking(config);
+    let domain = config.domain.trim_end_matches('.').to_lowercase();
+    let envelope_sender = config.envelope_from.clone().unwrap_or_else(|| domain.clone());
+    let mut findings = Vec::new();
+    let mut add = |check, severity, message: String| findings.push(Finding { check, severity, message });
+
+    match resolver.mx_records(&domain) {
+        Ok(answer) if answer.records.iter().any(MxRecord::is_null) => {
+            add(CheckKind::Mx, Severity::Warn, format!("{} publishes a null MX, so replies and bounces to it are lost", domain));
+        }
+        Ok(answer) if answer.records.is_empty() => {
+            add(CheckKind::Mx, Severity::Warn, format!("{} has no MX records, some receivers reject senders whose domain cannot receive mail", domain));
+        }
+        Ok(answer) => {
+            let hosts = answer.records.iter().map(|mx| mx.server.trim_end_matches('.')).collect::<Vec<_>>();
+            add(CheckKind::Mx, Severity::Pass, format!("{} receives mail at {}", domain, hosts.join(", ")));
+        }
+        Err(e) => add(CheckKind::Mx, Severity::Warn, format!("MX lookup for {} failed: {}", domain, e)),
+    }
+
+    let envelope_domain = envelope_sender.rsplit('@').next().unwrap_or_default();
+    let spf = check_spf_with(resolver, envelope_domain, sending_ip);
+    let severity = match spf.result {
+        SpfResult::Pass => Severity::Pass,
+        SpfResult::Fail | SpfResult::SoftFail | SpfResult::PermError => Severity::Fail,
+        SpfResult::None | SpfResult::Neutral | SpfResult::TempError => Severity::Warn,
+    };
+    let message = spf.warning().unwrap_or_else(|| format!("SPF of {} authorizes {}", spf.domain, sending_ip));
+    add(CheckKind::Spf, severity, message);
+
+    let dmarc = check_dmarc(config, &domain, &envelope_sender);
+    match (dkim_identity(config), dmarc.dkim_key_published) {
+        (None, _) => add(CheckKind::Dkim, Severity::Warn, "No DKIM key is configured, so mail is not signed".to_string()),
+        (Some((selector, dkim_domain)), Some(true)) => {
+            add(CheckKind::Dkim, Severity::Pass, format!("A DKIM key is published at {}._domainkey.{}", selector, dkim_domain));
+        }
+        (Some((selector, dkim_domain)), Some(false)) => {
+            add(CheckKind::Dkim, Severity::Fail, format!("No DKIM key is published at {}._domainkey.{}, receivers cannot verify the signatures", selector, dkim_domain));
+        }
+        (Some((selector, dkim_domain)), None) => {
+            add(CheckKind::Dkim, Severity::Warn, format!("The DKIM key at {}._domainkey.{} could not be looked up", selector, dkim_domain));
+        }
+    }
+
+    match (&dmarc.record, dmarc.effective_policy) {
+        (None, _) => add(CheckKind::Dmarc, Severity::Warn, format!("{} publishes no DMARC record, some providers reject or junk mail from domains without one", domain)),
+        (Some(_), Some(DmarcPolicy::None)) => add(CheckKind::Dmarc, Severity::Warn, format!("The DMARC policy of {} is p=none, which only monitors and does not protect the domain", domain)),
+        (Some(_), _) => add(CheckKind::Dmarc, Severity::Pass, format!("{} publishes an enforced DMARC policy", domain)),
+    }
+    if dmarc.record.is_some() && !dmarc.is_aligned() {
+        add(CheckKind::Dmarc, Severity::Fail, format!("Neither the envelope sender nor the DKIM domain align with {}, so mail fails DMARC", domain));
+    }
+
+    match resolver.txt_records(&format!("_mta-sts.{}", domain)) {
+        Ok(answer) => match answer.records.iter().find(|txt| txt.trim_start().starts_with("v=STSv1")) {
+            Some(txt) if txt.split(';').any(|tag| tag.trim().starts_with("id=")) => {
+                add(CheckKind::MtaSts, Severity::Pass, format!("{} announces an MTA-STS policy", domain));
+            }
+            Some(_) => add(CheckKind::MtaSts, Severity::Warn, format!("The MTA-STS record of {} has no id, receivers ignore it", domain)),
+            None => add(CheckKind::MtaSts, Severity::Warn, format!("{} publishes no MTA-STS policy, so mail sent to it can be downgraded to plaintext", domain)),
+        },
+        Err(e) => add(CheckKind::MtaSts, Severity::Warn, format!("MTA-STS lookup failed: {}", e)),
+    }
+
+    let reverse_dns = check_reverse_dns_of(config, sending_ip);
+    if reverse_dns.is_ok() {
+        add(CheckKind::ReverseDns, Severity::Pass, format!("{} has reverse DNS matching {}", sending_ip, config.ehlo_name()));
+    } else {
+        // No PTR record at all gets mail rejected outright by the large providers
+        let severity = if reverse_dns.ptr_names.is_empty() { Severity::Fail } else { Severity::Warn };
+        for warning in reverse_dns.warnings {
+            add(CheckKind::ReverseDns, severity, warning);
+        }
+    }
+
+    DeliverabilityReport { domain, sending_ip, findings }
+}
+
+/// The local address of the route to the internet, found by connecting a UDP socket,
+/// which sends nothing
+fn outbound_addr() -> std::io::Result<IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(("192.0.2.1", 53))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64,
+        // Unique local fc00::/7 and link-local fe80::/10
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified() || ip.segments()[0] & 0xFE00 == 0xFC00 || ip.segments()[0] & 0xFFC0 == 0xFE80,
+    }
+}
+
+fn is_spf_record(txt: &str) -> bool {
+    let txt = txt.trim_start();
+    txt.get(..6).is_some_and(|version| version.eq_ignore_ascii_case("v=spf1")) && (txt.len() == 6 || txt[6..].starts_with(' '))
+}
+
+/// Splits a `name=value` modifier; mechanisms have no `=` before their first `:` or `/`
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    (!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))).then_some((name, value))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, v4_len: u8, v6_len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - v4_len as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - v6_len as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
@@ -1,6 +1,9 @@
 //! DNS-related functionality
 
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 /// MX record representing a mail exchange server
 #[derive(Debug, Clone, PartialEq)]
@@ -11,69 +14,334 @@ pub struct MxRecord {
     pub server: String,
 } // Close MxRecord struct definition
 
+impl MxRecord {
+    /// Whether this is a null MX (`0 .`, RFC 7505), by which a domain declares it accepts no mail
+    pub fn is_null(&self) -> bool {
+        self.server.trim_end_matches('.').is_empty()
+    }
+}
+
 use crate::config::Config; // Moved import to a correct position
+use crate::resolver::{DnsAnswer, MicroDnsResolver, Resolver};
+use crate::error::Error;
+#[cfg(feature = "tokio-runtime")]
+use crate::resolver::{AsyncResolver, PreResolved};
+use crate::transcript::Transcript;
 
-/// Resolves the list of MX records via DNS lookup
-pub fn get_mx_records(domain: &str, config: &Config) -> Vec<MxRecord> {
-    if config.test_mode {
-        return vec![MxRecord {
+/// Resolves the list of MX records via the configured [`Resolver`](crate::Resolver).
+/// Only a definite answer (no records, NXDOMAIN) counts as no records. A failed lookup
+/// (SERVFAIL, REFUSED, network error) is a transient [`Error::DnsError`], a timeout is
+/// returned as such.
+pub fn get_mx_records(domain: &str, config: &Config) -> Result<Vec<MxRecord>, Error> {
+    if config.test_mode && config.resolver.is_none() {
+        // Lets tests exercise the implicit MX fallback and null MX
+        if domain.starts_with("no-mx.") {
+            return Ok(Vec::new());
+        }
+        if domain.starts_with("null-mx.") {
+            return Ok(vec![MxRecord { priority: 0, server: ".".to_string() }]);
+        }
+        return Ok(vec![MxRecord {
             priority: 10,
             server: "localhost.testmode".to_string(), // Dummy MX record for test mode
-        }];
+        }]);
     }
 
     // Existing localhost check can remain as a fallback or be removed if test_mode is comprehensive
-    if domain.contains("localhost") {
-        return vec![MxRecord {
+    if domain.contains("localhost") && config.resolver.is_none() {
+        return Ok(vec![MxRecord {
             priority: 10,
             server: "127.0.0.1".to_string(),
-        }];
+        }]);
     }
 
-    match microdns::lookup_mx_records(domain) {
-        Ok(records) => records
-            .into_iter()
-            .map(|r| MxRecord {
-                priority: r.priority,
-                server: r.server,
-            })
-            .collect(),
-        Err(_) => Vec::new(),
+    if let Some(records) = config.dns_cache.as_ref().and_then(|cache| cache.mx(domain)) {
+        return Ok(records);
+    }
+    match resolver(config).mx_records(domain) {
+        Ok(mut answer) => {
+            answer.records.sort_by_key(|mx| mx.priority);
+            if let Some(cache) = &config.dns_cache {
+                cache.insert_mx_answer(domain, &answer);
+            }
+            Ok(answer.records)
+        }
+        Err(e @ (Error::Timeout { .. } | Error::DnsError(_))) => Err(e),
+        Err(e) => Err(Error::DnsError(format!("MX lookup for {} failed: {}", domain, e))),
+    }
+}
+
+/// Whether the MX records of `domain` were DNSSEC-validated, without which its MX hosts'
+/// TLSA records say nothing about the domain (RFC 7672 section 2.2.1)
+pub(crate) fn mx_authenticated(domain: &str, config: &Config) -> bool {
+    if config.resolver.is_none() && (config.test_mode || domain.contains("localhost")) {
+        return false;
+    }
+    if let Some(answer) = config.dns_cache.as_ref().and_then(|cache| cache.mx_answer(domain)) {
+        return answer.authenticated;
+    }
+    resolver(config).mx_records(domain).is_ok_and(|answer| answer.authenticated)
+}
+
+fn resolver(config: &Config) -> &dyn Resolver {
+    crate::resolver::blocking(config)
+}
+
+/// Implicit MX for a domain that publishes no MX records (RFC 5321 section 5.1):
+/// the domain itself, provided it has an A or AAAA record
+pub fn implicit_mx(domain: &str, config: &Config) -> Option<MxRecord> {
+    if (config.test_mode && config.resolver.is_none()) || !lookup_host_addrs(domain, config).is_empty() {
+        Some(MxRecord { priority: 0, server: domain.to_string() })
+    } else {
+        None
+    }
+}
+
+/// Looks up everything a send to `domains` needs with the async `resolver`: the MX records,
+/// the address records of the MX hosts, and those of domains without MX for the implicit MX.
+/// Cached answers are used and fresh ones added to the cache. A failed MX lookup is kept
+/// so the send fails with it, failed address lookups are left out.
+#[cfg(feature = "tokio-runtime")]
+pub(crate) async fn resolve_ahead(resolver: &dyn AsyncResolver, domains: &[String], config: &Config) -> PreResolved {
+    let mut resolved = PreResolved::default();
+    for domain in domains {
+        let domain = domain.to_lowercase();
+        if resolved.mx.contains_key(&domain) {
+            continue;
+        }
+        let answer = match config.dns_cache.as_ref().and_then(|cache| cache.mx_answer(&domain)) {
+            Some(answer) => answer,
+            None => match resolver.mx_records(&domain).await {
+                Ok(answer) => {
+                    if let Some(cache) = &config.dns_cache {
+                        cache.insert_mx_answer(&domain, &answer);
+                    }
+                    answer
+                }
+                Err(e) => {
+                    resolved.mx_failures.insert(domain, e);
+                    continue;
+                }
+            },
+        };
+        let mut hosts = answer.records.iter().filter(|mx| !mx.is_null()).map(|mx| mx.server.to_lowercase()).collect::<Vec<_>>();
+        if answer.records.is_empty() {
+            hosts.push(domain.clone());
+        }
+        resolved.mx.insert(domain, DnsAnswer::new(answer.records, Duration::ZERO).authenticated(answer.authenticated));
+        for host in hosts {
+            if resolved.hosts.contains_key(&host) || host.parse::<IpAddr>().is_ok() {
+                continue;
+            }
+            let addrs = match config.dns_cache.as_ref().and_then(|cache| cache.host(&host)) {
+                Some(addrs) => addrs,
+                None => match resolver.host_addrs(&host).await {
+                    Ok(answer) => {
+                        if let Some(cache) = &config.dns_cache {
+                            cache.insert_host(&host, answer.records.clone(), answer.ttl);
+                        }
+                        answer.records
+                    }
+                    Err(_) => continue,
+                },
+            };
+            resolved.hosts.insert(host, DnsAnswer::new(addrs, Duration::ZERO));
+        }
     }
+    resolved
 }
 
 /// Logs MX records for debugging purposes
-pub fn log_mx_records(mxrecords: &[MxRecord], log: &mut Vec<String>) {
-    log.push(format!("OK got DNS MX records:"));
-    log.push(String::new());
+pub fn log_mx_records(mxrecords: &[MxRecord], log: &mut Transcript) {
+    log.note("OK got DNS MX records:");
+    log.note("");
     for mxr in mxrecords.iter() {
-        log.push(format!(
+        log.note(format!(
             "    {} = priority {}",
             mxr.server, mxr.priority
         ));
     }
-    log.push(String::new());
+    log.note("");
 }
 
-/// Given the server name, returns an IP address
-pub fn lookup_host(domain: &str) -> Option<String> {
-    // First check if it's already a socket address
-    if let Ok(addr) = domain.parse::<SocketAddr>() {
-        return Some(addr.to_string());
+/// Resolves all A and AAAA records of a host, ordered for Happy Eyeballs connection racing.
+///
+/// Following RFC 8305 section 4, the address families are interleaved starting with IPv6,
+/// so a dead family only ever delays the connection by one attempt.
+pub fn lookup_host_addrs(domain: &str, config: &Config) -> Vec<IpAddr> {
+    if let Ok(ip) = domain.parse::<IpAddr>() {
+        return vec![ip];
+    }
+    if let Some(ips) = config.dns_cache.as_ref().and_then(|cache| cache.host(domain)) {
+        return ips;
     }
 
-    // Try to resolve using microdns
-    match microdns::lookup_ip_addresses(domain) {
-        Ok(ips) => {
-            // Prefer IPv4 addresses
-            let ip = ips
-                .iter()
-                .find(|ip| ip.is_ipv4())
-                .or_else(|| ips.first())
-                .cloned();
+    match resolver(config).host_addrs(domain) {
+        Ok(answer) => {
+            // Cached in connection order, so a cache hit needs no reordering
+            let ips = interleave_families(answer.records);
+            if let Some(cache) = &config.dns_cache {
+                cache.insert_host(domain, ips.clone(), answer.ttl);
+            }
+            ips
+        }
+        Err(_) => Vec::new(),
+    }
+}
 
-            ip.map(|ip| ip.to_string())
+/// [`lookup_host_addrs`] for the async send path. A blocking resolver from `config.resolver`
+/// is queried on tokio's blocking pool, so it never holds up the runtime.
+#[cfg(feature = "tokio-runtime")]
+pub(crate) async fn lookup_host_addrs_async(domain: &str, config: &Config) -> Vec<IpAddr> {
+    if let Ok(ip) = domain.parse::<IpAddr>() {
+        return vec![ip];
+    }
+    if let Some(ips) = config.dns_cache.as_ref().and_then(|cache| cache.host(domain)) {
+        return ips;
+    }
+    let answer = match config.resolver.clone() {
+        Some(resolver) => {
+            let host = domain.to_string();
+            spawn_lookup(move || resolver.host_addrs(&host)).await
         }
-        Err(_) => None,
+        None => AsyncResolver::host_addrs(&MicroDnsResolver, domain).await,
+    };
+    match answer {
+        Ok(answer) => {
+            // Cached in connection order, so a cache hit needs no reordering
+            let ips = interleave_families(answer.records);
+            if let Some(cache) = &config.dns_cache {
+                cache.insert_host(domain, ips.clone(), answer.ttl);
+            }
+            ips
+        }
+        Err(_) => Vec::new(),
     }
-}
\ No newline at end of file
+}
+
+/// Runs a lookup of a blocking [`Resolver`](crate::Resolver) on tokio's blocking pool
+#[cfg(feature = "tokio-runtime")]
+pub(crate) async fn spawn_lookup<T, F>(lookup: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(lookup).await.unwrap_or_else(|e| Err(Error::DnsError(format!("lookup task failed: {}", e))))
+}
+
+fn interleave_families(ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = ips.into_iter().partition(|ip| ip.is_ipv6());
+    let mut interleaved = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => {
+                interleaved.extend(a);
+                interleaved.extend(b);
+            }
+        }
+    }
+    interleaved
+}
+
+struct CacheEntry<T> {
+    value: T,
+    expires: Instant,
+    /// Whether the resolver DNSSEC-validated the records
+    authenticated: bool,
+}
+
+/// Cache of MX and address lookups that honors the TTLs of the records.
+///
+/// Every [`Config`] starts with its own cache, shared by all of its clones, so a
+/// [`Mailer`](crate::Mailer), [`AsyncMailer`](crate::AsyncMailer) or queue built from one
+/// configuration resolves each domain once per TTL. Lookups that found no records are
+/// remembered for the negative TTL; timeouts and network errors are not cached.
+#[derive(Default)]
+pub struct DnsCache {
+    mx: Mutex<HashMap<String, CacheEntry<Vec<MxRecord>>>>,
+    hosts: Mutex<HashMap<String, CacheEntry<Vec<IpAddr>>>>,
+    negative_ttl: Duration,
+    max_ttl: Duration,
+}
+
+impl DnsCache {
+    /// Cache that keeps negative results for 30 seconds and nothing longer than a day
+    pub fn new() -> Self {
+        Self::default().negative_ttl(Duration::from_secs(30)).max_ttl(Duration::from_secs(24 * 60 * 60))
+    }
+
+    /// How long to remember that a domain has no records
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self { self.negative_ttl = ttl; self }
+
+    /// Upper bound for the TTL of any entry
+    pub fn max_ttl(mut self, ttl: Duration) -> Self { self.max_ttl = ttl; self }
+
+    /// Cached MX records of `domain`, empty if the domain is known to have none
+    pub fn mx(&self, domain: &str) -> Option<Vec<MxRecord>> {
+        Self::get(&self.mx, domain).map(|(records, _)| records)
+    }
+
+    /// Cached MX records of `domain` with whether they were DNSSEC-validated
+    pub(crate) fn mx_answer(&self, domain: &str) -> Option<DnsAnswer<MxRecord>> {
+        Self::get(&self.mx, domain).map(|(records, authenticated)| DnsAnswer::new(records, Duration::ZERO).authenticated(authenticated))
+    }
+
+    /// Cached addresses of `host`, empty if the host is known to have none
+    pub fn host(&self, host: &str) -> Option<Vec<IpAddr>> {
+        Self::get(&self.hosts, host).map(|(addrs, _)| addrs)
+    }
+
+    /// Remembers the MX records of `domain` for `ttl`, e.g. to pin a domain to a relay.
+    /// An empty list is remembered for the negative TTL instead.
+    pub fn insert_mx(&self, domain: &str, records: Vec<MxRecord>, ttl: Duration) {
+        self.insert(&self.mx, domain, records, ttl, false);
+    }
+
+    /// Remembers a resolver's answer for the MX records of `domain`
+    pub(crate) fn insert_mx_answer(&self, domain: &str, answer: &DnsAnswer<MxRecord>) {
+        self.insert(&self.mx, domain, answer.records.clone(), answer.ttl, answer.authenticated);
+    }
+
+    /// Remembers the addresses of `host` for `ttl`, an empty list for the negative TTL
+    pub fn insert_host(&self, host: &str, addrs: Vec<IpAddr>, ttl: Duration) {
+        self.insert(&self.hosts, host, addrs, ttl, false);
+    }
+
+    /// Drops all entries
+    pub fn clear(&self) {
+        self.mx.lock().unwrap().clear();
+        self.hosts.lock().unwrap().clear();
+    }
+
+    fn get<T: Clone>(map: &Mutex<HashMap<String, CacheEntry<T>>>, name: &str) -> Option<(T, bool)> {
+        let mut map = map.lock().unwrap();
+        let key = name.trim_end_matches('.').to_lowercase();
+        match map.get(&key) {
+            Some(entry) if entry.expires > Instant::now() => Some((entry.value.clone(), entry.authenticated)),
+            Some(_) => { map.remove(&key); None }
+            None => None,
+        }
+    }
+
+    fn insert<T>(&self, map: &Mutex<HashMap<String, CacheEntry<Vec<T>>>>, name: &str, value: Vec<T>, ttl: Duration, authenticated: bool) {
+        let ttl = if value.is_empty() { self.negative_ttl } else { ttl }.min(self.max_ttl);
+        if ttl.is_zero() {
+            return;
+        }
+        let key = name.trim_end_matches('.').to_lowercase();
+        map.lock().unwrap().insert(key, CacheEntry { value, expires: Instant::now() + ttl, authenticated });
+    }
+}
+
+impl std::fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DnsCache")
+            .field("mx", &self.mx.lock().unwrap().len())
+            .field("hosts", &self.hosts.lock().unwrap().len())
+            .field("negative_ttl", &self.negative_ttl)
+            .field("max_ttl", &self.max_ttl)
+            .finish()
+    }
+}
@@ -0,0 +1,81 @@
+//! Delivery status notification requests (RFC 3461)
+//!
+//! A [`Dsn`] adds `RET`/`ENVID` to MAIL FROM and `NOTIFY` to every RCPT TO, asking
+//! the receiving servers to report success, failure or delay back to the envelope
+//! sender. The parameters are only sent to servers that announce DSN in EHLO.
+
+/// When the envelope sender wants to hear about a recipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum DsnNotify {
+    /// Never, not even on failure. Excludes the others.
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// How much of the message a failure report returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum DsnReturn {
+    Full,
+    Headers,
+}
+
+/// DSN parameters of a send
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dsn {
+    /// NOTIFY for every recipient (empty = the server's default, usually failures only)
+    pub notify: Vec<DsnNotify>,
+    /// RET (None = the server's default)
+    pub ret: Option<DsnReturn>,
+    /// ENVID, an identifier of the message quoted back in reports
+    pub envid: Option<String>,
+}
+
+impl Dsn {
+    pub fn new() -> Self { Self::default() }
+    pub fn notify(mut self, notify: DsnNotify) -> Self { self.notify.push(notify); self }
+    pub fn ret(mut self, ret: DsnReturn) -> Self { self.ret = Some(ret); self }
+    pub fn envid<S: Into<String>>(mut self, envid: S) -> Self { self.envid = Some(envid.into()); self }
+
+    /// Parameters appended to MAIL FROM, each with a leading space
+    pub(crate) fn mail_params(&self) -> String {
+        let mut params = String::new();
+        match self.ret {
+            Some(DsnReturn::Full) => params.push_str(" RET=FULL"),
+            Some(DsnReturn::Headers) => params.push_str(" RET=HDRS"),
+            None => {}
+        }
+        if let Some(envid) = &self.envid {
+            params.push_str(" ENVID=");
+            params.push_str(&xtext(envid));
+        }
+        params
+    }
+
+    /// Parameters appended to RCPT TO, each with a leading space
+    pub(crate) fn rcpt_params(&self) -> String {
+        if self.notify.is_empty() {
+            return String::new();
+        }
+        if self.notify.contains(&DsnNotify::Never) {
+            return " NOTIFY=NEVER".to_string();
+        }
+        let mut conditions = Vec::new();
+        for (notify, name) in [(DsnNotify::Success, "SUCCESS"), (DsnNotify::Failure, "FAILURE"), (DsnNotify::Delay, "DELAY")] {
+            if self.notify.contains(&notify) { conditions.push(name); }
+        }
+        format!(" NOTIFY={}", conditions.join(","))
+    }
+}
+
+/// `value` as xtext (RFC 3461 section 4): `+`, `=` and anything outside printable ASCII hex-escaped
+fn xtext(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'!'..=b'~' if b != b'+' && b != b'=' => (b as char).to_string(),
+        _ => format!("+{:02X}", b),
+    }).collect()
+}
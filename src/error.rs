@@ -1,24 +1,195 @@
 //! Error types for the micromail crate.
 
+use std::fmt;
+use std::time::Duration;
+
 use thiserror::Error;
 
+use crate::report::parse_queue_id;
+use crate::transcript::Transcript;
+
 /// SMTP error code type
 pub type SmtpErrorCode = u16;
 
+/// Step of the SMTP conversation, used to tell where a send got stuck
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpPhase {
+    /// Looking up the MX records of the recipient domain
+    Dns,
+    /// Establishing the TCP connection
+    Connect,
+    /// Waiting for the server's 220 greeting
+    Greeting,
+    /// EHLO/HELO exchange
+    Ehlo,
+    /// STARTTLS command and TLS handshake
+    StartTls,
+    /// AUTH exchange
+    Auth,
+    /// MAIL FROM command
+    MailFrom,
+    /// RCPT TO command
+    RcptTo,
+    /// DATA command, waiting for the 354 go-ahead
+    DataInit,
+    /// Transferring the message content and waiting for the final reply
+    DataTransfer,
+    /// QUIT command
+    Quit,
+}
+
+impl fmt::Display for SmtpPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SmtpPhase::Dns => "DNS lookup",
+            SmtpPhase::Connect => "connect",
+            SmtpPhase::Greeting => "greeting",
+            SmtpPhase::Ehlo => "EHLO",
+            SmtpPhase::StartTls => "STARTTLS",
+            SmtpPhase::Auth => "AUTH",
+            SmtpPhase::MailFrom => "MAIL FROM",
+            SmtpPhase::RcptTo => "RCPT TO",
+            SmtpPhase::DataInit => "DATA",
+            SmtpPhase::DataTransfer => "message transfer",
+            SmtpPhase::Quit => "QUIT",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Enhanced mail system status code (RFC 3463), e.g. `5.1.1` for an unknown mailbox
+/// or `5.7.1` for a policy rejection. Only parsed when the server advertises
+/// ENHANCEDSTATUSCODES (RFC 2034).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EnhancedStatus {
+    /// 2 (success), 4 (persistent transient failure) or 5 (permanent failure)
+    pub class: u8,
+    /// Broad category, e.g. 1 for addressing or 7 for security/policy
+    pub subject: u16,
+    /// Specific condition within the subject
+    pub detail: u16,
+}
+
+impl EnhancedStatus {
+    /// Parses the leading `class.subject.detail` token of a reply text
+    pub fn parse(text: &str) -> Option<Self> {
+        let token = text.split_whitespace().next()?;
+        let mut fields = token.splitn(3, '.');
+        let class = fields.next()?.parse::<u8>().ok()?;
+        let subject = fields.next()?.parse::<u16>().ok()?;
+        let detail = fields.next()?.parse::<u16>().ok()?;
+        if !matches!(class, 2 | 4 | 5) || subject > 999 || detail > 999 {
+            return None;
+        }
+        Some(EnhancedStatus { class, subject, detail })
+    }
+
+    /// Whether the status reports a permanent failure (class 5)
+    pub fn is_permanent(&self) -> bool {
+        self.class == 5
+    }
+
+    /// Whether the status reports a transient failure (class 4)
+    pub fn is_transient(&self) -> bool {
+        self.class == 4
+    }
+}
+
+impl fmt::Display for EnhancedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// A complete server reply: the code, the enhanced status code if there was one, and the
+/// text of every line, without the codes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpReply {
+    pub code: SmtpErrorCode,
+    /// Only parsed when the server advertised ENHANCEDSTATUSCODES
+    pub enhanced: Option<EnhancedStatus>,
+    /// One entry per line of a multi-line reply
+    pub lines: Vec<String>,
+}
+
+impl SmtpReply {
+    /// A single-line reply, e.g. for a test double
+    pub fn new<S: Into<String>>(code: SmtpErrorCode, text: S) -> Self {
+        SmtpReply { code, enhanced: None, lines: vec![text.into()] }
+    }
+
+    /// The text of all lines, joined with newlines
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// Whether the command succeeded (2xx)
+    pub fn is_positive_completion(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    /// Whether the server waits for more input, e.g. 354 after DATA or 334 during AUTH (3xx)
+    pub fn is_positive_intermediate(&self) -> bool {
+        (300..400).contains(&self.code)
+    }
+
+    /// Whether the code reports a temporary failure (4xx)
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code)
+    }
+
+    /// Whether the code reports a permanent failure (5xx)
+    pub fn is_permanent(&self) -> bool {
+        (500..600).contains(&self.code)
+    }
+
+    /// How long a deferring server (4xx) asked to wait before the next attempt, if its
+    /// reply says so, e.g. "try again in 5 minutes" or "Retry-After: 300"
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !self.is_transient() { return None; }
+        retry_hint(&self.text())
+    }
+
+    /// Queue ID the server assigned to an accepted message, see [`parse_queue_id`],
+    /// from whichever line of the reply carries it
+    pub fn queue_id(&self) -> Option<String> {
+        self.lines.iter().find_map(|line| parse_queue_id(line))
+    }
+
+    /// Turn a failed reply to the command of `phase` into an [`Error::SmtpError`]
+    pub fn into_error(self, phase: SmtpPhase) -> Error {
+        Error::SmtpError { phase, reply: self }
+    }
+}
+
+impl fmt::Display for SmtpReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        if let Some(enhanced) = self.enhanced {
+            write!(f, " {}", enhanced)?;
+        }
+        write!(f, " {}", self.lines.join(" "))
+    }
+}
+
 /// Errors that can occur when using the micromail crate.
 #[derive(Error, Debug)]
 pub enum Error {
-    /// No MX records found for the domain.
+    /// The domain has neither MX records nor an address record to fall back to.
     #[error("no MX records found for domain")]
     NoMxRecords,
     
+    /// The domain published a null MX (RFC 7505): it does not accept mail at all.
+    #[error("domain {0} does not accept mail (null MX)")]
+    DomainDoesNotAcceptMail(String),
+
     /// Could not establish a connection to any of the MX servers.
     #[error("could not connect to any MX server")]
     ConnectionFailed,
     
-    /// SMTP protocol error.
-    #[error("SMTP protocol error (code: {code:?}): {message}")]
-    SmtpError { code: SmtpErrorCode, message: String },
+    /// The server rejected the command of `phase` (MAIL FROM, RCPT TO, DATA, ...) with `reply`.
+    #[error("{phase} rejected by the server: {reply}")]
+    SmtpError { phase: SmtpPhase, reply: SmtpReply },
     
     /// TLS negotiation failed.
     #[error("TLS negotiation failed: {0}")]
@@ -28,13 +199,20 @@ pub enum Error {
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
     
-    /// DNS resolution error.
+    /// DNS resolution error, e.g. SERVFAIL or no resolver answering. Transient, unlike a
+    /// domain that has no MX records.
     #[error("DNS resolution error: {0}")]
     DnsError(String),
     
-    /// Mail sending timeout.
-    #[error("mail sending timeout")]
-    Timeout,
+    /// Mail sending timeout, either of a single phase or of the overall send deadline.
+    /// `elapsed` is how long the phase had been running when the limit hit, so a server
+    /// that stalls after DATA can be told apart from one that cannot be reached.
+    #[error("mail sending timeout during {phase} after {elapsed:?}")]
+    Timeout { phase: SmtpPhase, elapsed: Duration },
+
+    /// No bytes were read or written for longer than the configured stall timeout.
+    #[error("connection stalled during {phase}: no socket progress for {idle:?}")]
+    Stalled { phase: SmtpPhase, idle: Duration },
     
     /// Invalid mail content.
     #[error("invalid mail content: {0}")]
@@ -42,8 +220,32 @@ pub enum Error {
     
     /// Authentication error.
     #[error("authentication error (code: {code:?}): {message}")]
-    AuthError { code: Option<u16>, message: String },
+    AuthError { code: Option<u16>, message: String, enhanced: Option<EnhancedStatus> },
     
+    /// The recipient is not allowed by `Config::allow_recipient`/`deny_recipient`, nothing was sent.
+    #[error("recipient {0} is blocked by the recipient policy")]
+    RecipientBlocked(String),
+
+    /// Contradictory settings found by `Config::validate`, each a complete sentence.
+    #[error("invalid configuration: {}", .0.join(" "))]
+    InvalidConfig(Vec<String>),
+
+    /// A mail provider's HTTP API refused the message.
+    #[error("HTTP API error (status: {status}): {message}")]
+    HttpApiError { status: u16, message: String },
+
+    /// The queue already holds as many messages as its capacity allows.
+    #[error("the queue is full")]
+    QueueFull,
+
+    /// The mailer, pool or queue is shutting down and takes no new work.
+    #[error("shutting down, no new work is accepted")]
+    ShuttingDown,
+
+    /// A `shutdown` deadline passed while `in_flight` sends were still running.
+    #[error("shutdown deadline passed with {in_flight} sends in flight")]
+    ShutdownTimeout { in_flight: usize },
+
     #[cfg(feature = "signing")]
     /// Signing error.
     #[error("signing error: {0}")]
@@ -54,6 +256,100 @@ pub enum Error {
     Other(String),
 }
 
+/// A failed send together with the transcript of the session that led to it, returned by
+/// [`Mailer::send_traced`](crate::Mailer::send_traced) so the protocol context travels with
+/// the error instead of having to be fetched with a separate `get_log()` call
+#[derive(Error, Debug)]
+#[error("{error}")]
+pub struct SendFailure {
+    /// The error the send failed with, as `send_sync` would return it
+    #[source]
+    pub error: Error,
+    /// Everything recorded during the send, including earlier attempts when it was retried
+    pub transcript: Transcript,
+}
+
+impl From<SendFailure> for Error {
+    fn from(failure: SendFailure) -> Self {
+        failure.error
+    }
+}
+
+/// Phrases greylisting servers (postgrey, Exim, rspamd, ...) put into their deferral replies
+const GREYLIST_HINTS: &[&str] = &["greylist", "graylist", "grey-list", "gray-list", "try again later", "please retry later"];
+
+impl Error {
+    /// Whether the failure is temporary and the same mail may succeed when retried later
+    /// (4xx replies, connection problems, timeouts, HTTP 429 and 5xx).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::SmtpError { reply, .. } => reply.is_transient(),
+            Error::AuthError { code: Some(code), .. } => (400..500).contains(code),
+            Error::HttpApiError { status, .. } => *status == 429 || (500..600).contains(status),
+            Error::ConnectionFailed | Error::DnsError(_) | Error::Timeout { .. } | Error::Stalled { .. } | Error::IoError(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the server deferred the mail because of greylisting, i.e. it turns away
+    /// unknown senders once and accepts the same mail when it is retried a few minutes later
+    pub fn is_greylisting(&self) -> bool {
+        match self {
+            Error::SmtpError { reply, .. } if matches!(reply.code, 421 | 450 | 451) => {
+                let message = reply.text().to_lowercase();
+                GREYLIST_HINTS.iter().any(|hint| message.contains(hint))
+            }
+            _ => false,
+        }
+    }
+
+    /// The wait the server asked for before retrying, see [`SmtpReply::retry_after`]
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.reply().and_then(SmtpReply::retry_after)
+    }
+
+    /// Enhanced status code of the server reply that caused the error, if the server sent one
+    pub fn enhanced_status(&self) -> Option<EnhancedStatus> {
+        match self {
+            Error::SmtpError { reply, .. } => reply.enhanced,
+            Error::AuthError { enhanced, .. } => *enhanced,
+            _ => None,
+        }
+    }
+
+    /// The server reply that caused the error, for errors the server caused with one
+    pub fn reply(&self) -> Option<&SmtpReply> {
+        match self {
+            Error::SmtpError { reply, .. } => Some(reply),
+            _ => None,
+        }
+    }
+}
+
+/// The waiting time named in a reply text: a number with a unit ("300 seconds", "5min",
+/// "1 hour"), or seconds after "Retry-After". Numbers without a unit are ignored.
+pub(crate) fn retry_hint(text: &str) -> Option<Duration> {
+    let text = text.to_ascii_lowercase();
+    let words = text.split(|c: char| !c.is_ascii_alphanumeric()).filter(|word| !word.is_empty()).collect::<Vec<_>>();
+    for (i, word) in words.iter().enumerate() {
+        let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let Ok(value) = digits.parse::<u64>() else { continue };
+        let unit = match &word[digits.len()..] {
+            "" => words.get(i + 1).copied().unwrap_or(""),
+            suffix => suffix,
+        };
+        let seconds = match unit {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 60 * 60,
+            _ if i >= 2 && words[i - 2..i] == ["retry", "after"] => 1,
+            _ => continue,
+        };
+        return Some(Duration::from_secs(value.saturating_mul(seconds)));
+    }
+    None
+}
+
 impl From<String> for Error {
     fn from(s: String) -> Self {
         Error::Other(s)
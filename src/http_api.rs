@@ -0,0 +1,560 @@
+//! Delivery through the HTTP APIs of mail providers
+//!
+//! An [`HttpTransport`] takes the same [`Mail`] as [`Mailer`](crate::Mailer) and hands it to
+//! Amazon SES, SendGrid or Mailgun over HTTPS, so an application can fail over between
+//! direct SMTP and a provider without converting messages. SES and Mailgun receive the
+//! formatted (and DKIM-signed, if configured) message as it would go out over SMTP;
+//! SendGrid builds the message itself from the fields of the mail.
+//!
+//! Requests are plain HTTP/1.1 over TLS, verified against the bundled web PKI roots.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rustls::pki_types::ServerName;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::Config,
+    error::Error,
+    mail::{self, Mail, PreparedSend},
+    mime::Part,
+    report::{RecipientStatus, SendReport, TransactionReport},
+    tls,
+    transcript::SharedRecorder,
+    utils::{self, json_string},
+};
+
+/// A mail provider's HTTP API and the credentials for it
+#[derive(Debug, Clone)]
+pub enum ApiProvider {
+    /// Amazon SES v2 `SendEmail` with the raw message, signed with AWS Signature Version 4
+    Ses { region: String, access_key_id: String, secret_access_key: String },
+    /// SendGrid v3 `mail/send`
+    SendGrid { api_key: String },
+    /// Mailgun `messages.mime` with the raw message, sent from `domain`
+    Mailgun { domain: String, api_key: String },
+}
+
+impl ApiProvider {
+    pub fn ses<S: Into<String>>(region: S, access_key_id: S, secret_access_key: S) -> Self {
+        ApiProvider::Ses { region: region.into(), access_key_id: access_key_id.into(), secret_access_key: secret_access_key.into() }
+    }
+
+    pub fn sendgrid<S: Into<String>>(api_key: S) -> Self {
+        ApiProvider::SendGrid { api_key: api_key.into() }
+    }
+
+    pub fn mailgun<S: Into<String>>(domain: S, api_key: S) -> Self {
+        ApiProvider::Mailgun { domain: domain.into(), api_key: api_key.into() }
+    }
+
+    /// Name of the provider, e.g. for logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApiProvider::Ses { .. } => "Amazon SES",
+            ApiProvider::SendGrid { .. } => "SendGrid",
+            ApiProvider::Mailgun { .. } => "Mailgun",
+        }
+    }
+
+    /// Where requests go unless [`HttpTransport::endpoint`] says otherwise
+    pub fn default_endpoint(&self) -> String {
+        match self {
+            ApiProvider::Ses { region, .. } => format!("https://email.{}.amazonaws.com", region),
+            ApiProvider::SendGrid { .. } => "https://api.sendgrid.com".to_string(),
+            ApiProvider::Mailgun { .. } => "https://api.mailgun.net".to_string(),
+        }
+    }
+}
+
+/// Sends mail through a provider's HTTP API instead of SMTP
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    provider: ApiProvider,
+    config: Config,
+    endpoint: Option<String>,
+}
+
+impl HttpTransport {
+    /// A transport for `provider`. `config` formats and signs the mail and sets the
+    /// timeout of the requests; its SMTP and TLS settings are not used.
+    pub fn new(provider: ApiProvider, config: Config) -> Self {
+        Self { provider, config, endpoint: None }
+    }
+
+    /// Sends requests to `url` (scheme, host and optional port) instead of the provider's
+    /// public endpoint, e.g. `https://api.eu.mailgun.net` or a local mock over `http://`
+    pub fn endpoint<S: Into<String>>(mut self, url: S) -> Self {
+        self.endpoint = Some(url.into());
+        self
+    }
+
+    pub fn provider(&self) -> &ApiProvider {
+        &self.provider
+    }
+
+    /// Hands `mail` to the provider. Every recipient counts as accepted once the
+    /// provider took the message, with the provider's message id as the queue id.
+    pub fn send(&self, mail: Mail) -> Result<SendReport, Error> {
+        let (prepared, request) = self.prepare(mail)?;
+        let response = request.send_blocking(&self.config)?;
+        self.report(prepared, response)
+    }
+
+    fn prepare(&self, mail: Mail) -> Result<(PreparedSend, HttpRequest), Error> {
+        let prepared = mail::prepare_send(&self.config, &SharedRecorder::default(), mail.clone())?;
+        let endpoint = self.endpoint.clone().unwrap_or_else(|| self.provider.default_endpoint());
+        let mut request = match &self.provider {
+            ApiProvider::Ses { region, access_key_id, secret_access_key } => {
+                let body = format!(
+                    "{{\"FromEmailAddress\":{},\"Destination\":{{\"ToAddresses\":[{}]}},\"Content\":{{\"Raw\":{{\"Data\":{}}}}}}}",
+                    json_string(&utils::bare_address(&prepared.from)),
+                    json_list(&prepared.recipients),
+                    json_string(&BASE64_STANDARD.encode(&prepared.content)),
+                );
+                let mut request = HttpRequest::post(&endpoint, "/v2/email/outbound-emails", "application/json", body.into_bytes())?;
+                sign_aws_v4(&mut request, self.config.id_provider.now(), region, "ses", access_key_id, secret_access_key);
+                request
+            }
+            ApiProvider::SendGrid { api_key } => {
+                let mut request = HttpRequest::post(&endpoint, "/v3/mail/send", "application/json", sendgrid_body(&mail, &prepared).into_bytes())?;
+                request.headers.push(("Authorization".to_string(), format!("Bearer {}", api_key)));
+                request
+            }
+            ApiProvider::Mailgun { domain, api_key } => {
+                let boundary = self.config.id_provider.boundary();
+                let mut body = Vec::new();
+                for recipient in &prepared.recipients {
+                    body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\n{}\r\n", boundary, recipient).as_bytes());
+                }
+                body.extend_from_slice(format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"message\"; filename=\"message.mime\"\r\nContent-Type: message/rfc822\r\n\r\n",
+                    boundary,
+                ).as_bytes());
+                body.extend_from_slice(prepared.content.as_bytes());
+                body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+                let content_type = format!("multipart/form-data; boundary={}", boundary);
+                let mut request = HttpRequest::post(&endpoint, &format!("/v3/{}/messages.mime", domain), &content_type, body)?;
+                let credentials = BASE64_STANDARD.encode(format!("api:{}", api_key));
+                request.headers.push(("Authorization".to_string(), format!("Basic {}", credentials)));
+                request
+            }
+        };
+        if request.tls {
+            request.tls_config = Some(tls::https_client_config()?);
+        }
+        Ok((prepared, request))
+    }
+
+    fn report(&self, prepared: PreparedSend, response: HttpResponse) -> Result<SendReport, Error> {
+        let body = String::from_utf8_lossy(&response.body).into_owned();
+        if !(200..300).contains(&response.status) {
+            let message = json_field(&body, "message").unwrap_or_else(|| body.trim().to_string());
+            return Err(Error::HttpApiError { status: response.status, message });
+        }
+        let message_id = match self.provider {
+            ApiProvider::Ses { .. } => json_field(&body, "MessageId"),
+            ApiProvider::SendGrid { .. } => response.header("X-Message-Id").map(str::to_string),
+            ApiProvider::Mailgun { .. } => json_field(&body, "id").map(|id| id.trim_matches(|c| c == '<' || c == '>').to_string()),
+        };
+        let data_message = format!("{} accepted the message (HTTP {})", self.provider.name(), response.status);
+        let transactions = prepared.by_domain.into_iter().map(|(domain, indices)| TransactionReport {
+            domain,
+            recipients: indices.iter().map(|&i| RecipientStatus {
+                recipient: prepared.recipients[i].clone(),
+                code: 250,
+                message: "OK".to_string(),
+                enhanced: None,
+                accepted: true,
+            }).collect(),
+            data_code: 250,
+            queue_id: message_id.clone(),
+            capabilities: None,
+            data_message: data_message.clone(),
+        }).collect();
+        // SendGrid builds the message from the JSON fields itself
+        let message = match self.provider {
+            ApiProvider::SendGrid { .. } => Vec::new(),
+            _ => mail::sent_message(&self.config, prepared.content),
+        };
+        Ok(SendReport { transactions, message })
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+#[async_trait::async_trait]
+impl crate::async_mail::AsyncMailSender for HttpTransport {
+    async fn send(&mut self, mail: Mail) -> Result<SendReport, Error> {
+        let (prepared, request) = self.prepare(mail)?;
+        let response = request.send_async(&self.config).await?;
+        self.report(prepared, response)
+    }
+}
+
+/// The SendGrid request for the `prepared` send of `mail`. SendGrid assembles the message
+/// itself and has no raw upload, so this carries the fields of the mail and the headers
+/// of the prepared message (Message-ID, Date, Resent-* and the caller's) rather than the
+/// formatted message. SendGrid signs the message it assembles with the account's own
+/// domain authentication, a DKIM-Signature made here would not match it.
+fn sendgrid_body(mail: &Mail, prepared: &PreparedSend) -> String {
+    let listed = |list: &[String], recipient: &str| list.iter()
+        .flat_map(|addresses| utils::split_address_list(addresses))
+        .any(|address| utils::bare_address(address).eq_ignore_ascii_case(recipient));
+    // A recipient goes to cc or bcc as the mail lists it, anything else (like the sandbox) to to
+    let (mut to, mut cc, mut bcc) = (Vec::new(), Vec::new(), Vec::new());
+    let mut seen = std::collections::HashSet::new();
+    for recipient in prepared.recipients.iter().filter(|recipient| seen.insert(recipient.to_ascii_lowercase())) {
+        let field = if listed(std::slice::from_ref(&mail.to), recipient) {
+            &mut to
+        } else if listed(&mail.cc, recipient) {
+            &mut cc
+        } else if listed(&mail.bcc, recipient) {
+            &mut bcc
+        } else {
+            &mut to
+        };
+        field.push(format!("{{\"email\":{}}}", json_string(recipient)));
+    }
+    let mut personalization = format!("\"to\":[{}]", to.join(","));
+    if !cc.is_empty() {
+        personalization.push_str(&format!(",\"cc\":[{}]", cc.join(",")));
+    }
+    if !bcc.is_empty() {
+        personalization.push_str(&format!(",\"bcc\":[{}]", bcc.join(",")));
+    }
+    let content_type = mail.content_type.split(';').next().unwrap_or("text/plain").trim();
+    let mut body = format!(
+        "{{\"personalizations\":[{{{}}}],\"from\":{},\"subject\":{},\"content\":[{{\"type\":{},\"value\":{}}}]",
+        personalization,
+        sendgrid_address(&mail.from),
+        json_string(&mail.subject),
+        json_string(content_type),
+        json_string(&mail.body),
+    );
+    let head = prepared.content.split("\r\n\r\n").next().unwrap_or_default();
+    let mut headers = Vec::new();
+    let mut names = std::collections::HashSet::new();
+    for (name, value) in Part::parse(head).headers {
+        if name.eq_ignore_ascii_case("Reply-To") {
+            body.push_str(&format!(",\"reply_to\":{}", sendgrid_address(&value)));
+        } else if !SENDGRID_RESERVED_HEADERS.iter().any(|reserved| name.eq_ignore_ascii_case(reserved)) && names.insert(name.to_ascii_lowercase()) {
+            headers.push(format!("{}:{}", json_string(&name), json_string(&value)));
+        }
+    }
+    if !headers.is_empty() {
+        body.push_str(&format!(",\"headers\":{{{}}}", headers.join(",")));
+    }
+    if !mail.attachments.is_empty() {
+        let attachments = mail.attachments.iter()
+            .map(|attachment| format!(
+                "{{\"content\":{},\"filename\":{},\"type\":{}}}",
+                json_string(&BASE64_STANDARD.encode(&attachment.data)),
+                json_string(&attachment.filename),
+                json_string(&attachment.content_type),
+            ))
+            .collect::<Vec<_>>()
+            .join(",");
+        body.push_str(&format!(",\"attachments\":[{}]", attachments));
+    }
+    body.push('}');
+    body
+}
+
+/// Headers SendGrid sets itself from the other fields of the request and refuses in `headers`
+const SENDGRID_RESERVED_HEADERS: &[&str] = &[
+    "From", "To", "Cc", "Bcc", "Subject", "Reply-To", "Content-Type", "Content-Transfer-Encoding",
+    "MIME-Version", "DKIM-Signature", "Received", "X-SG-ID", "X-SG-EID",
+];
+
+/// A SendGrid email object for an address like `Shop <shop@example.com>`
+fn sendgrid_address(address: &str) -> String {
+    let email = json_string(&utils::bare_address(address));
+    match address.split_once('<').map(|(name, _)| name.trim().trim_matches('"')) {
+        Some(name) if !name.is_empty() => format!("{{\"email\":{},\"name\":{}}}", email, json_string(name)),
+        _ => format!("{{\"email\":{}}}", email),
+    }
+}
+
+fn json_list(values: &[String]) -> String {
+    values.iter().map(|value| json_string(value)).collect::<Vec<_>>().join(",")
+}
+
+/// Value of the first string member `name` in a JSON document, good enough for the flat
+/// responses of the providers
+fn json_field(json: &str, name: &str) -> Option<String> {
+    let key = json_string(name);
+    let rest = &json[json.find(&key)? + key.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+    let mut value = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Adds the `Authorization` header of AWS Signature Version 4 (and the headers it signs)
+/// for a request made at `now`
+fn sign_aws_v4(request: &mut HttpRequest, now: DateTime<Utc>, region: &str, service: &str, access_key_id: &str, secret_access_key: &str) {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    request.headers.push(("X-Amz-Date".to_string(), amz_date.clone()));
+    let content_type = request.header("Content-Type").unwrap_or_default().to_string();
+    let canonical_request = format!(
+        "POST\n{}\n\ncontent-type:{}\nhost:{}\nx-amz-date:{}\n\ncontent-type;host;x-amz-date\n{}",
+        request.path, content_type, request.host_header(), amz_date, hex(&Sha256::digest(&request.body)),
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+    let key = [date.as_str(), region, service, "aws4_request"].iter()
+        .fold(format!("AWS4{}", secret_access_key).into_bytes(), |key, part| hmac_sha256(&key, part.as_bytes()).to_vec());
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+    request.headers.push((
+        "Authorization".to_string(),
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=content-type;host;x-amz-date, Signature={}", access_key_id, scope, signature),
+    ));
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<_>>();
+    let inner = Sha256::new().chain_update(pad(0x36)).chain_update(message).finalize();
+    Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A POST request, sent on a connection of its own
+pub(crate) struct HttpRequest {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+    /// Headers besides Host, Content-Length and Connection
+    pub headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// TLS settings for an https URL, those of the config it is sent with if None
+    pub tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// A POST of `body` to the full `url`, e.g. `https://example.com/hooks/mail`
+    pub fn post_url(url: &str, content_type: &str, body: Vec<u8>) -> Result<Self, Error> {
+        let authority_start = url.find("://").map_or(0, |i| i + 3);
+        let (endpoint, path) = match url[authority_start..].find('/') {
+            Some(i) => url.split_at(authority_start + i),
+            None => (url, "/"),
+        };
+        Self::post(endpoint, path, content_type, body)
+    }
+
+    /// A POST of `body` to `path` below the `endpoint` URL
+    fn post(endpoint: &str, path: &str, content_type: &str, body: Vec<u8>) -> Result<Self, Error> {
+        let invalid = || Error::Other(format!("Invalid HTTP API endpoint: {}", endpoint));
+        let (tls, rest) = match endpoint.split_once("://") {
+            Some(("https", rest)) => (true, rest),
+            Some(("http", rest)) => (false, rest),
+            _ => return Err(invalid()),
+        };
+        let authority = rest.trim_end_matches('/');
+        // An IPv6 literal is bracketed, so its colons never precede the port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, port.parse().map_err(|_| invalid())?),
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        Ok(Self {
+            tls,
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            path: path.to_string(),
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+            tls_config: None,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn host_header(&self) -> String {
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => self.host.clone(),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("POST {} HTTP/1.1\r\nHost: {}\r\n", self.path, self.host_header());
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()));
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    fn server_name(&self) -> Result<ServerName<'static>, Error> {
+        ServerName::try_from(self.host.clone()).map_err(|e| Error::TlsError(format!("Invalid server name {}: {}", self.host, e)))
+    }
+
+    fn client_config(&self, config: &Config) -> Result<Arc<rustls::ClientConfig>, Error> {
+        match &self.tls_config {
+            Some(tls_config) => Ok(tls_config.clone()),
+            None => tls::client_config(config),
+        }
+    }
+
+    fn send_blocking(&self, config: &Config) -> Result<HttpResponse, Error> {
+        let addresses = (self.host.as_str(), self.port).to_socket_addrs()?.collect::<Vec<_>>();
+        if addresses.is_empty() {
+            return Err(Error::DnsError(format!("address lookup for {} failed", self.host)));
+        }
+        // Like a browser, try every address the name resolves to until one answers
+        let mut tcp = addresses.iter()
+            .find_map(|address| TcpStream::connect_timeout(address, config.timeout).ok())
+            .ok_or(Error::ConnectionFailed)?;
+        tcp.set_read_timeout(Some(config.timeout))?;
+        tcp.set_write_timeout(Some(config.timeout))?;
+        let mut response = Vec::new();
+        let clean = if self.tls {
+            let connection = rustls::ClientConnection::new(self.client_config(config)?, self.server_name()?)
+                .map_err(|e| Error::TlsError(e.to_string()))?;
+            let mut stream = rustls::StreamOwned::new(connection, tcp);
+            stream.write_all(&self.to_bytes())?;
+            closed(stream.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut response))?
+        } else {
+            tcp.write_all(&self.to_bytes())?;
+            closed(tcp.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut response))?
+        };
+        HttpResponse::parse(&response, clean)
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    pub async fn send_async(&self, config: &Config) -> Result<HttpResponse, Error> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let exchange = async {
+            let tcp = tokio::net::TcpStream::connect((self.host.as_str(), self.port)).await.map_err(|_| Error::ConnectionFailed)?;
+            let mut response = Vec::new();
+            let clean = if self.tls {
+                let connector = tokio_rustls::TlsConnector::from(self.client_config(config)?);
+                let mut stream = connector.connect(self.server_name()?, tcp).await
+                    .map_err(|e| Error::TlsError(e.to_string()))?;
+                stream.write_all(&self.to_bytes()).await?;
+                closed(stream.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut response).await)?
+            } else {
+                let mut tcp = tcp;
+                tcp.write_all(&self.to_bytes()).await?;
+                closed(tcp.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut response).await)?
+            };
+            HttpResponse::parse(&response, clean)
+        };
+        tokio::time::timeout(config.timeout, exchange).await
+            .map_err(|_| Error::Other(format!("HTTP API request to {} timed out", self.host)))?
+    }
+}
+
+/// Most bytes read of a response, headers included. The providers answer with a short
+/// JSON document, anything larger is not one of their responses.
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// The result of reading a response until the server closed the connection: whether it
+/// closed cleanly. A TLS peer that closes without close_notify may have been cut off, so
+/// its response only counts if its length shows it is complete.
+fn closed(result: std::io::Result<usize>) -> Result<bool, Error> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(Error::IoError(e)),
+    }
+}
+
+impl HttpResponse {
+    /// Parses the response in `bytes`, read until the server closed the connection,
+    /// `clean`ly or not
+    fn parse(bytes: &[u8], clean: bool) -> Result<Self, Error> {
+        let invalid = || Error::Other("Invalid HTTP response from the API".to_string());
+        let truncated = || Error::Other("The HTTP response of the API was cut off".to_string());
+        if bytes.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(Error::Other(format!("The HTTP response of the API is larger than {} bytes", MAX_RESPONSE_SIZE)));
+        }
+        let end = bytes.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(invalid)?;
+        let head = String::from_utf8_lossy(&bytes[..end]);
+        let mut lines = head.split("\r\n");
+        let status = lines.next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(invalid)?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect::<Vec<_>>();
+        let mut response = Self { status, headers, body: bytes[end + 4..].to_vec() };
+        let content_length = response.header("Content-Length").map(|length| length.parse::<usize>().map_err(|_| invalid())).transpose()?;
+        if response.header("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+            // The last chunk marks the end, a body cut off before it does not decode
+            response.body = dechunk(&response.body).ok_or_else(truncated)?;
+        } else if let Some(length) = content_length {
+            if response.body.len() < length {
+                return Err(truncated());
+            }
+            response.body.truncate(length);
+        } else if !clean && !matches!(status, 204 | 304) {
+            return Err(truncated());
+        }
+        Ok(response)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Decodes a chunked transfer-encoding body (RFC 9112 section 7.1)
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
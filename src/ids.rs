@@ -0,0 +1,79 @@
+//! Pluggable generators for the unique identifiers and dates placed into outgoing mail
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+
+use crate::utils;
+
+/// Source of the unique identifiers and the time micromail writes into formatted messages.
+///
+/// The default [`RandomIds`] draws from the thread RNG. Install a deterministic
+/// implementation via [`Config::id_provider`](crate::Config::id_provider) when
+/// identical input must produce byte-identical output, e.g. for golden-file tests.
+pub trait IdProvider: fmt::Debug + Send + Sync {
+    /// Returns a boundary string separating the parts of a multipart body
+    fn boundary(&self) -> String;
+
+    /// Returns a Message-ID for mail from `domain`, angle brackets included
+    fn message_id(&self, domain: &str) -> String {
+        utils::generate_message_id(domain)
+    }
+
+    /// Returns the time written into the Date header and used to sign HTTP API requests
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Random identifiers (the default)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomIds;
+
+impl IdProvider for RandomIds {
+    fn boundary(&self) -> String {
+        utils::generate_boundary()
+    }
+}
+
+/// Deterministic identifiers of the form `<prefix>-<n>`, counting up from zero, and a
+/// clock that stands still (at the Unix epoch unless set with [`SequentialIds::at`])
+#[derive(Debug)]
+pub struct SequentialIds {
+    prefix: String,
+    next: AtomicU64,
+    now: DateTime<Utc>,
+}
+
+impl SequentialIds {
+    /// Create a provider whose identifiers all start with `prefix`
+    pub fn new<S: Into<String>>(prefix: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+            next: AtomicU64::new(0),
+            now: DateTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Date every message at `time`
+    pub fn at(mut self, time: DateTime<Utc>) -> Self { self.now = time; self }
+
+    fn next_id(&self) -> String {
+        format!("{}-{}", self.prefix, self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl IdProvider for SequentialIds {
+    fn boundary(&self) -> String {
+        self.next_id()
+    }
+
+    fn message_id(&self, domain: &str) -> String {
+        format!("<{}@{}>", self.next_id(), domain)
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.now
+    }
+}
@@ -1,12 +1,13 @@
 //! I/O utilities for SMTP communication
 
-use std::{
-    io::{Read, Write},
-    time::Duration,
-};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use crate::connection::{Connected, StreamWrapper}; // Will define StreamWrapper here or in connection.rs
-use crate::error::Error;
+use crate::error::{EnhancedStatus, Error, SmtpReply};
+use crate::metrics;
+use crate::smtp;
+use crate::transcript::{SendEvent, TranscriptEvent};
+use crate::utils;
 use std::collections::VecDeque;
 use std::io::{Cursor}; // Keep Read, Write from std::io
 
@@ -35,6 +36,8 @@ pub struct MockStream {
     _client_write_log: Vec<u8>,
     server_responses: VecDeque<Vec<u8>>,
     smtp_state: SmtpState,
+    data_buffer: Vec<u8>, // Message content received so far in the DATA phase
+    queued: u32, // Messages accepted so far, numbers the queue IDs
     pub tls_active: bool, // To simulate TLS being active
 }
 
@@ -46,6 +49,8 @@ impl MockStream {
             _client_write_log: Vec::new(),
             server_responses: initial_responses,
             smtp_state: SmtpState::Initial,
+            data_buffer: Vec::new(),
+            queued: 0,
             tls_active: false,
         }
     }
@@ -59,12 +64,11 @@ impl MockStream {
 
         match self.smtp_state {
             SmtpState::Initial if command.starts_with("EHLO") => {
-                self.server_responses.push_back(b"250-localhost.testmode Hello\r\n".to_vec());
-                self.server_responses.push_back(b"250-AUTH LOGIN PLAIN\r\n".to_vec());
+                // A multi-line reply is queued as one chunk, like a server writing it in one go
                 if !self.tls_active { // Only offer STARTTLS if not already active
-                    self.server_responses.push_back(b"250 STARTTLS\r\n".to_vec());
+                    self.server_responses.push_back(b"250-localhost.testmode Hello\r\n250-ENHANCEDSTATUSCODES\r\n250-AUTH LOGIN PLAIN\r\n250 STARTTLS\r\n".to_vec());
                 } else {
-                    self.server_responses.push_back(b"250 OK\r\n".to_vec()); // Generic OK if TLS already active
+                    self.server_responses.push_back(b"250-localhost.testmode Hello\r\n250-ENHANCEDSTATUSCODES\r\n250-AUTH LOGIN PLAIN\r\n250 OK\r\n".to_vec()); // Generic OK if TLS already active
                 }
                 self.smtp_state = SmtpState::EhloSent;
             }
@@ -74,8 +78,7 @@ impl MockStream {
             }
             SmtpState::StartTlsSent if command.starts_with("EHLO") => { // After STARTTLS, client sends EHLO again
                 self.tls_active = true; // Simulate TLS becoming active
-                self.server_responses.push_back(b"250-localhost.testmode Hello (TLS)\r\n".to_vec());
-                self.server_responses.push_back(b"250 AUTH LOGIN PLAIN\r\n".to_vec());
+                self.server_responses.push_back(b"250-localhost.testmode Hello (TLS)\r\n250-ENHANCEDSTATUSCODES\r\n250 AUTH LOGIN PLAIN\r\n".to_vec());
                 self.smtp_state = SmtpState::EhloSent; // Or a new state like TlsEhloDone
             }
             SmtpState::EhloSent if command.starts_with("AUTH LOGIN") => {
@@ -91,17 +94,23 @@ impl MockStream {
                 self.server_responses.push_back(b"235 Authentication succeeded\r\n".to_vec());
                 self.smtp_state = SmtpState::EhloSent; // Ready for MAIL FROM
             }
-            SmtpState::EhloSent if command.starts_with("MAIL FROM") => {
-                if command.contains("<trigger550@example.com>") { // Condition to trigger specific error
-                    self.server_responses.push_back(b"550 No such user\r\n".to_vec());
+            SmtpState::EhloSent | SmtpState::MessageReceived if command.starts_with("MAIL FROM") => { // New transaction on the same session
+                if command.contains("<TRIGGER550@EXAMPLE.COM>") { // Condition to trigger specific error
+                    self.server_responses.push_back(b"550 5.1.1 No such user\r\n".to_vec());
                 } else {
                     self.server_responses.push_back(b"250 OK\r\n".to_vec());
                 }
                 self.smtp_state = SmtpState::MailFromSent; // State still advances
             }
-            SmtpState::MailFromSent if command.starts_with("RCPT TO") => {
-                 if command.contains("<trigger551@example.com>") {
-                    self.server_responses.push_back(b"551 User not local\r\n".to_vec());
+            SmtpState::MailFromSent | SmtpState::RcptToSent if command.starts_with("RCPT TO") => { // One RCPT per recipient
+                 if command.contains("<TRIGGER551@EXAMPLE.COM>") {
+                    self.server_responses.push_back(b"551 5.1.6 User not local\r\n".to_vec());
+                } else if command.contains("<TRIGGER452@") { // Transient failure that is not greylisting
+                    self.server_responses.push_back(b"452 4.3.1 Insufficient system storage\r\n".to_vec());
+                } else if command.contains("<TRIGGER450@") { // Deferral that names its own backoff
+                    self.server_responses.push_back(b"450 4.2.1 Rate limited, try again in 90 seconds\r\n".to_vec());
+                } else if command.contains("<TRIGGER451@") { // Transient failure for any domain
+                    self.server_responses.push_back(b"451 4.7.1 Greylisted, please try again later\r\n".to_vec());
                 } else {
                     self.server_responses.push_back(b"250 OK\r\n".to_vec());
                 }
@@ -111,11 +120,21 @@ impl MockStream {
                 self.server_responses.push_back(b"354 End data with <CR><LF>.<CR><LF>\r\n".to_vec());
                 self.smtp_state = SmtpState::DataSent;
             }
-            SmtpState::DataSent if command.ends_with("\r\n.\r\n") => { // Simplified check for end of data
-                self.server_responses.push_back(b"250 OK: message queued\r\n".to_vec());
-                self.smtp_state = SmtpState::MessageReceived; // Or back to EhloSent if transactions are independent
+            SmtpState::DataSent => {
+                // The command string is trimmed, so look for the terminator in the raw data
+                self.data_buffer.extend_from_slice(input);
+                if self.data_buffer.ends_with(b"\r\n.\r\n") {
+                    let rejected = self.data_buffer.windows(10).any(|w| w.eq_ignore_ascii_case(b"TRIGGER554"));
+                    self.data_buffer.clear();
+                    if rejected { // Content the server refuses after the whole message was sent
+                        self.server_responses.push_back(b"554 5.6.0 Message content rejected\r\n".to_vec());
+                    } else {
+                        self.queued += 1;
+                        self.server_responses.push_back(format!("250 OK: message queued as MOCK{:04}\r\n", self.queued).into_bytes());
+                    }
+                    self.smtp_state = SmtpState::MessageReceived; // Or back to EhloSent if transactions are independent
+                }
             }
-            SmtpState::DataSent => { /* Consuming data lines, no specific response until CRLF.CRLF */ }
             SmtpState::MessageReceived if command.starts_with("QUIT") => {
                 self.server_responses.push_back(b"221 Bye\r\n".to_vec());
                 self.smtp_state = SmtpState::QuitSent;
@@ -124,6 +143,13 @@ impl MockStream {
                 self.server_responses.push_back(b"221 Bye\r\n".to_vec());
                 self.smtp_state = SmtpState::QuitSent;
             }
+            _ if command == "NOOP" => {
+                self.server_responses.push_back(b"250 OK\r\n".to_vec());
+            }
+            SmtpState::EhloSent | SmtpState::MailFromSent | SmtpState::RcptToSent | SmtpState::MessageReceived if command == "RSET" => {
+                self.server_responses.push_back(b"250 OK\r\n".to_vec());
+                self.smtp_state = SmtpState::EhloSent;
+            }
             _ => {
                  // Default: Echo back for unknown states or commands during data phase.
                  // Or push a 500 error. For DATA phase, no response until end.
@@ -171,130 +197,310 @@ impl Write for MockStream {
 // --- End MockStream Definition ---
 
 
-/// HTTP-like status message from SMTP server
-pub struct HttpStatusMessage {
-    /// Status code
-    pub code: u16,
-    /// Status message
-    pub message: String,
+/// Send a command over the connection
+pub fn secure_send(connection_wrapper: &mut Connected, m: &str) -> Result<(), Error> {
+    connection_wrapper.record(TranscriptEvent::CommandSent(utils::sanitize_string_lite(m.trim_end())));
+    write_raw(connection_wrapper, m.as_bytes())
 }
 
-impl std::fmt::Debug for HttpStatusMessage {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RESPONSE: {} {}", self.code, self.message)
+/// What the transcript shows in place of a credentials line
+pub(crate) const REDACTED: &str = "<credentials redacted, see Config::log_credentials>";
+
+/// Send a line of credentials, recorded as [`REDACTED`] unless `Config::log_credentials` is set
+pub fn secure_send_secret(connection_wrapper: &mut Connected, m: &str) -> Result<(), Error> {
+    if connection_wrapper.log_credentials {
+        return secure_send(connection_wrapper, m);
     }
+    connection_wrapper.record(TranscriptEvent::CommandSent(REDACTED.to_string()));
+    write_raw(connection_wrapper, m.as_bytes())
 }
 
-impl HttpStatusMessage {
-    /// Parse a status message from a string.
-    /// 
-    /// Example: "200 OK" => { code: 200, message: "OK" }
-    pub fn from_str(s: &str) -> Option<Self> {
-        let s = s.trim();
-        if s.len() < 4 {
-            return None;
+/// Performs a [`smtp::Action`] other than [`Action::Data`](smtp::Action::Data) and returns
+/// the reply it asked for
+pub(crate) fn exchange(connection_wrapper: &mut Connected, action: smtp::Action) -> Result<SmtpReply, Error> {
+    if let smtp::Action::Send { phase, line, secret } = action {
+        if let Some(phase) = phase {
+            connection_wrapper.enter_phase(phase);
         }
-        
-        let code = s.chars().take(3).collect::<String>().parse::<u16>().ok()?;
-        
-        Some(HttpStatusMessage {
-            code,
-            message: s.chars().skip(4).collect::<String>(),
-        })
+        if secret { secure_send_secret(connection_wrapper, &line)? } else { secure_send(connection_wrapper, &line)? }
     }
+    secure_read(connection_wrapper)
+}
 
-    /// Check if the status code indicates success (2xx-3xx)
-    pub fn is_http_ok(&self) -> bool {
-        self.code < 354 && self.code >= 200
+/// Send the message content after DATA, dot-stuffed and followed by the terminating
+/// `<CRLF>.<CRLF>`. `chunks` are written one at a time, so the content never has to be
+/// copied as a whole; `total` is their combined length, for the progress events.
+pub fn send_data<I, B>(connection_wrapper: &mut Connected, chunks: I, total: usize) -> Result<(), Error>
+where
+    I: IntoIterator<Item = B>,
+    B: AsRef<[u8]>,
+{
+    let mut chunks = chunks.into_iter().peekable();
+    let first = chunks.peek().map_or(&[][..], |chunk| chunk.as_ref());
+    connection_wrapper.record(data_event(first, total));
+    let mut data = DataWriter::new(total);
+    for chunk in chunks {
+        write_raw(connection_wrapper, data.stuff(chunk.as_ref()))?;
+        connection_wrapper.emit(data.progress());
     }
+    write_raw(connection_wrapper, data.terminator())
+}
 
-    /// Check if the status message indicates STARTTLS support
-    pub fn is_starttls(&self) -> bool {
-        self.is_http_ok() && self.message.to_uppercase().contains("STARTTLS")
+/// Message content the DATA phase sends from its start, once per transaction
+pub(crate) trait Content {
+    fn send(&mut self, connection_wrapper: &mut Connected) -> Result<(), Error>;
+}
+
+impl Content for String {
+    fn send(&mut self, connection_wrapper: &mut Connected) -> Result<(), Error> {
+        send_data(connection_wrapper, self.as_bytes().chunks(DATA_CHUNK_SIZE), self.len())
     }
 }
 
-/// Send a message over the connection
-pub fn secure_send(connection_wrapper: &mut Connected, m: &str) -> Result<(), Error> {
+/// An already formatted message, read in chunks and rewound for every transaction,
+/// so it never has to be in memory as a whole
+pub(crate) struct Reader<R>(pub R);
+
+impl<R: Read + Seek> Content for Reader<R> {
+    fn send(&mut self, connection_wrapper: &mut Connected) -> Result<(), Error> {
+        let total = self.0.seek(SeekFrom::End(0))? as usize;
+        self.0.rewind()?;
+        let mut chunk = vec![0; DATA_CHUNK_SIZE];
+        let mut data = DataWriter::new(total);
+        loop {
+            let read = read_chunk(&mut self.0, &mut chunk)?;
+            if data.written == 0 {
+                connection_wrapper.record(data_event(&chunk[..read], total));
+            }
+            if read == 0 {
+                break;
+            }
+            write_raw(connection_wrapper, data.stuff(&chunk[..read]))?;
+            connection_wrapper.emit(data.progress());
+        }
+        write_raw(connection_wrapper, data.terminator())
+    }
+}
+
+/// Fills `chunk` as far as the reader goes, returning how much was read (0 at the end)
+fn read_chunk<R: Read>(reader: &mut R, chunk: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < chunk.len() {
+        match reader.read(&mut chunk[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Message content is written in pieces of this size, reporting progress after each
+pub(crate) const DATA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The transcript entry for message content of `total` bytes starting with `first`.
+/// Only the first chunk is recorded, a copy of a large message would double its memory.
+pub(crate) fn data_event(first: &[u8], total: usize) -> TranscriptEvent {
+    let mut content = utils::sanitize_string_lite(&String::from_utf8_lossy(first));
+    if total > first.len() {
+        content.push_str(&format!("\r\n[{} more bytes not recorded]", total - first.len()));
+    }
+    TranscriptEvent::DataSent { bytes: total, content }
+}
+
+/// The DATA phase in progress: the content written so far, dot-stuffed chunk by chunk
+pub(crate) struct DataWriter {
+    stuffer: DotStuffer,
+    buffer: Vec<u8>,
+    /// Bytes of content (before stuffing) written so far
+    pub written: usize,
+    total: usize,
+}
+
+impl DataWriter {
+    pub fn new(total: usize) -> Self {
+        Self { stuffer: DotStuffer::default(), buffer: Vec::new(), written: 0, total }
+    }
+
+    /// `chunk` dot-stuffed, to be written next
+    pub fn stuff(&mut self, chunk: &[u8]) -> &[u8] {
+        self.buffer.clear();
+        self.stuffer.stuff(chunk, &mut self.buffer);
+        self.written += chunk.len();
+        &self.buffer
+    }
+
+    /// The event for the chunks written so far
+    pub fn progress(&self) -> SendEvent {
+        SendEvent::DataWritten { bytes: self.written, total: self.total }
+    }
+
+    pub fn terminator(&self) -> &'static [u8] {
+        self.stuffer.terminator()
+    }
+}
+
+/// Doubles the dot at the start of every line (RFC 5321 section 4.5.2), across chunk
+/// boundaries, so that no line of the content reads as the end of the data
+#[derive(Debug)]
+pub(crate) struct DotStuffer {
+    /// Whether the next byte starts a line
+    line_start: bool,
+    /// The last two bytes written
+    tail: [u8; 2],
+}
+
+impl Default for DotStuffer {
+    fn default() -> Self { Self { line_start: true, tail: [0; 2] } }
+}
+
+impl DotStuffer {
+    /// Appends `chunk` to `out`, dot-stuffed
+    pub(crate) fn stuff(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        out.reserve(chunk.len());
+        for &byte in chunk {
+            if self.line_start && byte == b'.' { out.push(b'.'); }
+            out.push(byte);
+            // A bare LF counts as well, some servers end lines there
+            self.line_start = byte == b'\n';
+            self.tail = [self.tail[1], byte];
+        }
+    }
+
+    /// What ends the data: the final `.` line, after a CRLF if the content lacks one
+    pub(crate) fn terminator(&self) -> &'static [u8] {
+        if self.tail == *b"\r\n" || self.tail == [0; 2] { b".\r\n" } else { b"\r\n.\r\n" }
+    }
+}
+
+fn write_raw(connection_wrapper: &mut Connected, m: &[u8]) -> Result<(), Error> {
+    connection_wrapper.arm_timeout()?;
     let stream_wrapper = &mut connection_wrapper.stream;
     match stream_wrapper {
-        StreamWrapper::Insecure(ref mut stream) => stream.write_all(m.as_bytes()), // Changed Real to Insecure
-        StreamWrapper::Secure(ref mut stream_owned) => stream_owned.write_all(m.as_bytes()),
-        StreamWrapper::Mock(ref mut mock_stream) => mock_stream.write_all(m.as_bytes()),
+        StreamWrapper::Insecure(ref mut stream) => stream.write_all(m), // Changed Real to Insecure
+        StreamWrapper::Secure(ref mut stream_owned) => stream_owned.write_all(m),
+        StreamWrapper::Mock(ref mut mock_stream) => mock_stream.write_all(m),
+        #[cfg(unix)]
+        StreamWrapper::Unix(ref mut stream) => stream.write_all(m),
+    }
+    .map_err(|e| map_io_error(e, connection_wrapper))?;
+    connection_wrapper.record_progress();
+    metrics::with(&connection_wrapper.metrics, |metrics| metrics.bytes_sent(m.len()));
+    Ok(())
+}
+
+/// Socket timeouts surface as WouldBlock or TimedOut depending on the platform
+fn map_io_error(e: std::io::Error, connection: &Connected) -> Error {
+    if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut {
+        connection.timeout_error()
+    } else {
+        Error::IoError(e)
     }
-    .map_err(|e| Error::IoError(e))
 }
 
 /// Read a single line from the connection
-pub fn secure_read(connection_wrapper: &mut Connected) -> Result<HttpStatusMessage, Error> {
+pub fn secure_read(connection_wrapper: &mut Connected) -> Result<SmtpReply, Error> {
     let response_str = secure_read_internal(connection_wrapper)?;
-    
-    response_str // Changed variable name for clarity
-        .lines()
-        .filter_map(|s| HttpStatusMessage::from_str(s))
-        .next()
+    parse_reply(&response_str, connection_wrapper.capabilities.enhanced_status_codes)
         .ok_or_else(|| Error::Other("Invalid response format from server".to_string())) // Changed SmtpError to Other
 }
 
-/// Read multiple lines from the connection
-pub fn secure_read_qued(connection_wrapper: &mut Connected) -> Result<Vec<HttpStatusMessage>, Error> {
-    Ok(secure_read_internal(connection_wrapper)?
-        .lines()
-        .filter_map(|s| HttpStatusMessage::from_str(s))
-        .collect::<Vec<_>>())
+/// Parses a complete reply into the code of its first line and the text of every line,
+/// picking up the enhanced status code only if the server announced them
+pub(crate) fn parse_reply(response: &str, enhanced_codes: bool) -> Option<SmtpReply> {
+    let mut code = None;
+    let mut enhanced = None;
+    let mut lines = Vec::new();
+    for line in response.lines() {
+        let line = line.trim();
+        let Some(line_code) = reply_code(line) else { continue };
+        code.get_or_insert(line_code);
+        let mut text = line.get(4..).unwrap_or_default();
+        if enhanced_codes {
+            if let Some(status) = EnhancedStatus::parse(text) {
+                let token_len = text.split_whitespace().next().map_or(0, str::len);
+                text = text[token_len..].trim_start();
+                enhanced.get_or_insert(status);
+            }
+        }
+        lines.push(text.to_string());
+    }
+    Some(SmtpReply { code: code?, enhanced, lines })
+}
+
+/// The three-digit code at the start of a reply line
+fn reply_code(line: &str) -> Option<u16> {
+    line.get(..3).filter(|code| code.bytes().all(|b| b.is_ascii_digit()))?.parse().ok()
 }
 
+/// Reads one complete reply, however the server's TCP segments split it: bytes are
+/// buffered until a line without `-` after the code arrives, and anything the server sent
+/// beyond that stays buffered for the next read
 fn secure_read_internal(connection_wrapper: &mut Connected) -> Result<String, Error> {
-    let stream_wrapper = &mut connection_wrapper.stream;
-    let mut collect = Vec::new();
     let mut buff = [0; 5000]; // Standard buffer size
 
-    loop {
+    let reply = loop {
+        if let Some(end) = reply_end(&connection_wrapper.pending) {
+            break connection_wrapper.pending.drain(..end).collect::<Vec<_>>();
+        }
+        // The time limit of the current phase (and the overall deadline) applies to every read
+        connection_wrapper.arm_timeout()?;
+        let stream_wrapper = &mut connection_wrapper.stream;
         let len = match stream_wrapper {
             StreamWrapper::Insecure(ref mut stream) => { // Changed Real to Insecure
-                // Assuming TcpStream is still used directly for insecure real connections
-                // Timeout logic might need to be associated with StreamWrapper or handled by caller
-                // For simplicity, let's assume timeout is handled if this path is taken by non-mock.
-                stream.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| Error::IoError(e))?;
                 stream.read(&mut buff)
             }
             StreamWrapper::Secure(ref mut stream_owned) => {
-                // rustls::StreamOwned does not have set_read_timeout directly.
-                // Timeout needs to be handled by the underlying TcpStream before TLS handshake,
-                // or by higher-level logic (e.g., select with timeout).
-                // For mock, this is not an issue. For real, this implies timeout config on TcpStream.
                 stream_owned.read(&mut buff)
             }
             StreamWrapper::Mock(ref mut mock_stream) => {
                 mock_stream.read(&mut buff)
             }
-        }
-        .map_err(|e| {
-            // Differentiate between actual timeout and other IO errors if possible
-            // For mock stream, read should not typically error with Timeout unless specifically designed.
-            if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut {
-                 Error::Timeout
-            } else {
-                 Error::IoError(e)
+            #[cfg(unix)]
+            StreamWrapper::Unix(ref mut stream) => {
+                stream.read(&mut buff)
             }
-        })?;
+        }
+        .map_err(|e| map_io_error(e, connection_wrapper))?;
 
 
         if len == 0 { // EOF or mock stream has no more responses for now
-            break;
+            break std::mem::take(&mut connection_wrapper.pending);
         }
+        connection_wrapper.record_progress();
+
+        connection_wrapper.pending.extend_from_slice(&buff[0..len]);
+    };
 
-        collect.extend_from_slice(&buff[0..len]);
+    let response = String::from_utf8(reply)
+        .map_err(|_| Error::Other("Server response was not valid UTF-8".to_string()))?; // Changed SmtpError to Other
+    if let Some(event) = response_event(&response) {
+        connection_wrapper.record(event);
+    }
+    Ok(response)
+}
 
-        // If mock stream, it might provide data in chunks.
-        // If real stream, and len < buff.len(), it's likely the end of current available data.
-        if len < buff.len() || matches!(stream_wrapper, StreamWrapper::Mock(_)) {
-             // For mock, assume one pop_front from server_responses is one "read" event.
-             // For real streams, if less than full buffer is read, assume that's all for now.
-            break;
+/// End of the first complete reply in `buffer`: the end of the first line whose code is
+/// not followed by `-` (RFC 5321 section 4.2.1)
+pub(crate) fn reply_end(buffer: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(newline) = buffer[start..].iter().position(|&b| b == b'\n') {
+        let end = start + newline + 1;
+        if buffer.get(start + 3) != Some(&b'-') {
+            return Some(end);
         }
+        start = end;
     }
+    None
+}
 
-    String::from_utf8(collect)
-        .map_err(|_| Error::Other("Server response was not valid UTF-8".to_string())) // Changed SmtpError to Other
+/// The transcript entry for a reply, None if it has no lines
+pub(crate) fn response_event(response: &str) -> Option<TranscriptEvent> {
+    let lines = response.lines().map(|l| l.trim_end().to_string()).filter(|l| !l.is_empty()).collect::<Vec<_>>();
+    if lines.is_empty() {
+        return None;
+    }
+    let code = lines.first().and_then(|l| reply_code(l)).unwrap_or(0);
+    Some(TranscriptEvent::ResponseReceived { code, lines })
 }
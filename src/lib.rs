@@ -2,30 +2,96 @@
 //! # micromail
 // ... (module docs) ...
 
+mod campaign;
+mod capabilities;
 mod config;
 mod connection;
 mod dns;
+mod dsn;
 mod error;
+mod ids;
 mod io;
 mod mail;
+mod metrics;
+mod mime;
+mod report;
+mod resolver;
+mod smtp;
 mod tls;
+mod trace;
+mod transcript;
 mod utils;
 
+pub mod attachment;
+pub mod bounce;
+pub mod dane;
+pub mod diagnostics;
+#[cfg(feature = "http-api")]
+pub mod http_api;
+pub mod testing;
+pub mod tlsrpt;
+
 #[cfg(feature = "signing")]
 mod signing;
 
 #[cfg(feature = "tokio-runtime")]
 pub mod async_mail;
+#[cfg(feature = "tokio-runtime")]
+mod async_io;
+#[cfg(feature = "tokio-runtime")]
+mod shutdown;
+#[cfg(feature = "tokio-runtime")]
+pub mod pool;
+#[cfg(feature = "tokio-runtime")]
+pub mod queue;
+#[cfg(feature = "tokio-runtime")]
+pub mod scheduler;
+#[cfg(feature = "tokio-runtime")]
+pub mod store;
+#[cfg(all(feature = "http-api", feature = "tokio-runtime"))]
+pub mod webhook;
 
-pub use config::Config;
-pub use error::Error;
-pub use mail::{Mail, Mailer};
+pub use attachment::Attachment;
+pub use campaign::Campaign;
+pub use capabilities::Capabilities;
+pub use config::{Auth, AuthProvider, Config, MxPolicy, RetryOn, RetryPolicy, SendOptions, Timeouts, TlsIdentity};
+pub use dsn::{Dsn, DsnNotify, DsnReturn};
+pub use dane::DaneMode;
+pub use error::{EnhancedStatus, Error, SendFailure, SmtpPhase, SmtpReply};
+#[cfg(feature = "http-api")]
+pub use http_api::{ApiProvider, HttpTransport};
+pub use ids::{IdProvider, RandomIds, SequentialIds};
+pub use mail::{Mail, Mailer, Resent};
+pub use metrics::Metrics;
+pub use report::{parse_queue_id, ConnectionCheck, RecipientStatus, SendReport, TransactionReport};
+pub use resolver::{DnsAnswer, MicroDnsResolver, Resolver, ValidatingResolver};
+pub use tls::{TlsInfo, TlsPolicy, TlsSessionCache, TlsVersion};
+pub use tlsrpt::{TlsReport, TlsReportCollector};
+pub use transcript::{SendEvent, Transcript, TranscriptEntry, TranscriptEvent};
 
 #[cfg(feature = "tokio-runtime")]
-pub use async_mail::{AsyncMailer, AsyncMailSender};
+pub use async_mail::{AsyncMailer, AsyncMailSender, MailerEvent};
+#[cfg(feature = "tokio-runtime")]
+pub use pool::AsyncMailerPool;
+#[cfg(feature = "tokio-runtime")]
+pub use queue::{DeliveryEvent, MailQueue};
+#[cfg(feature = "tokio-runtime")]
+pub use scheduler::Scheduler;
+#[cfg(feature = "tokio-runtime")]
+pub use store::{FileSpool, QueueStore};
+#[cfg(feature = "sqlite")]
+pub use store::SqliteStore;
+#[cfg(all(feature = "http-api", feature = "tokio-runtime"))]
+pub use webhook::Webhook;
+#[cfg(feature = "tokio-runtime")]
+pub use resolver::AsyncResolver;
+#[cfg(feature = "hickory")]
+pub use resolver::HickoryResolver;
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+pub use resolver::DnsProvider;
 
 pub use connection::Connected;
-pub use dns::MxRecord;
+pub use dns::{DnsCache, MxRecord};
 
 #[cfg(feature = "signing")]
 pub use mail::Signer; // This was in the original issue's lib.rs
@@ -1,13 +1,18 @@
 //! Mail creation, signing, and sending
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, hash_map::DefaultHasher};
+use std::fmt;
+use std::io::{Read, Seek, Write as _};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
 // Cow is only needed for DkimSelector/Domain construction if they were used.
 // #[cfg(feature="signing")]
 // use std::borrow::Cow;
 
-use crate::{config::Config, connection::{self, Connected}, dns::{self}, error::Error, io::{self}, utils};
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use crate::{attachment::Attachment, config::{Config, SendOptions}, connection::{self, Connected}, dane::{self, DaneMode, DanePolicy}, dns::{self, MxRecord}, error::{Error, SendFailure, SmtpPhase}, io, metrics, report::{ConnectionCheck, SendReport, TransactionReport}, smtp, tls::TlsPolicy, tlsrpt::ResultType, trace, transcript::{self, SendEvent, SharedRecorder, Transcript}, utils};
 
 // mail-auth 0.7.1 specific imports - Commented out due to persistent resolution issues
 // #[cfg(feature = "signing")]
@@ -22,19 +27,50 @@ use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 pub struct Mail {
     pub from: String,
     pub to: String,
+    pub cc: Vec<String>,
+    /// Recipients that get the message without appearing in any header
+    pub bcc: Vec<String>,
     pub subject: String,
     pub body: String,
     pub content_type: String,
     pub headers: HashMap<String, String>,
     pub message_id: Option<String>,
+    /// The Date header, the time of formatting if None
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub date: Option<DateTime<Utc>>,
+    /// Files sent after the body in a `multipart/mixed` message
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub attachments: Vec<Attachment>,
+    /// When [`MailQueue`](crate::MailQueue) and [`Scheduler`](crate::Scheduler) should
+    /// transmit the mail. Sending it directly ignores this.
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub send_at: Option<DateTime<Utc>>,
+    /// Resent-* headers for resubmitting the mail to new recipients
+    #[cfg_attr(feature = "serialize", serde(default))]
+    pub resent: Option<Resent>,
+}
+
+/// A resent block (RFC 5322 section 3.6.6), written above the original headers when a
+/// mail that was sent or received before is passed on unchanged
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Resent {
+    /// Who resends the mail, also the envelope sender
+    pub from: String,
+    /// The new recipients, who replace the original ones in the envelope
+    pub to: Vec<String>,
+    /// The Resent-Date header, the time of formatting if None
+    pub date: Option<DateTime<Utc>>,
+    /// The Resent-Message-ID header, generated if None
+    pub message_id: Option<String>,
 }
 
 impl Default for Mail {
     fn default() -> Self {
         Self {
-            from: String::new(), to: String::new(), subject: String::new(), body: String::new(),
+            from: String::new(), to: String::new(), cc: Vec::new(), bcc: Vec::new(), subject: String::new(), body: String::new(),
             content_type: "text/plain; charset=utf-8".to_string(),
-            headers: HashMap::new(), message_id: None,
+            headers: HashMap::new(), message_id: None, date: None, attachments: Vec::new(), send_at: None, resent: None,
         }
     }
 }
@@ -43,55 +79,158 @@ impl Mail {
     pub fn new() -> Self { Default::default() }
     pub fn from<S: Into<String>>(mut self, from: S) -> Self { self.from = from.into(); self }
     pub fn to<S: Into<String>>(mut self, to: S) -> Self { self.to = to.into(); self }
+    pub fn cc<S: Into<String>>(mut self, cc: S) -> Self { self.cc.push(cc.into()); self }
+    pub fn bcc<S: Into<String>>(mut self, bcc: S) -> Self { self.bcc.push(bcc.into()); self }
+
+    /// Addresses to deliver to: everyone in the address list `to`, then `cc` and `bcc`,
+    /// or only the Resent-To recipients of a resent mail.
+    /// Display names are dropped (`Jane <jane@example.com>` becomes `jane@example.com`).
+    pub fn envelope_recipients(&self) -> Vec<String> {
+        let lists: Vec<&str> = match self.resent.as_ref().filter(|resent| !resent.to.is_empty()) {
+            Some(resent) => resent.to.iter().map(String::as_str).collect(),
+            None => std::iter::once(self.to.as_str()).chain(self.cc.iter().map(String::as_str)).chain(self.bcc.iter().map(String::as_str)).collect(),
+        };
+        lists.into_iter()
+            .flat_map(utils::split_address_list)
+            .map(utils::bare_address)
+            .filter(|address| !address.is_empty())
+            .collect()
+    }
     pub fn subject<S: Into<String>>(mut self, subject: S) -> Self { self.subject = subject.into(); self }
     pub fn body<S: Into<String>>(mut self, body: S) -> Self { self.body = body.into(); self }
     pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Self { self.content_type = content_type.into(); self }
     pub fn header<S: Into<String>>(mut self, name: S, value: S) -> Self { self.headers.insert(name.into(), value.into()); self }
     pub fn message_id<S: Into<String>>(mut self, message_id: S) -> Self { self.message_id = Some(message_id.into()); self }
+    pub fn date(mut self, date: DateTime<Utc>) -> Self { self.date = Some(date); self }
+    pub fn attach(mut self, attachment: Attachment) -> Self { self.attachments.push(attachment); self }
+    /// Holds the mail back until `time` when it is handed to a queue or scheduler
+    pub fn send_at(mut self, time: DateTime<Utc>) -> Self { self.send_at = Some(time); self }
+    /// Resends the mail on behalf of `from`, keeping its original headers
+    pub fn resent_from<S: Into<String>>(mut self, from: S) -> Self { self.resent.get_or_insert_with(Resent::default).from = from.into(); self }
+    /// Resends the mail to `to` instead of its original recipients
+    pub fn resent_to<S: Into<String>>(mut self, to: S) -> Self { self.resent.get_or_insert_with(Resent::default).to.push(to.into()); self }
+    pub fn resent_date(mut self, date: DateTime<Utc>) -> Self { self.resent.get_or_insert_with(Resent::default).date = Some(date); self }
+    pub fn resent_message_id<S: Into<String>>(mut self, message_id: S) -> Self { self.resent.get_or_insert_with(Resent::default).message_id = Some(message_id.into()); self }
 
-    #[cfg_attr(not(feature = "signing"), allow(dead_code))]
-    #[cfg_attr(not(feature = "signing"), allow(unused_variables))]
-    fn format_for_signing(&self, config: &Config) -> String {
-        let mut temp_headers = self.headers.clone();
-        temp_headers.remove("DKIM-Signature");
-        let mut headers_str = String::new();
-        headers_str.push_str(&format!("From: {}\r\n", self.from));
-        headers_str.push_str(&format!("To: {}\r\n", self.to));
-        headers_str.push_str(&format!("Subject: {}\r\n", self.subject));
-        headers_str.push_str(&format!("Date: {}\r\n", utils::format_date()));
-        let mut msg_id_val = utils::generate_message_id(&config.domain);
-        if let Some(id) = &self.message_id { msg_id_val = id.clone(); }
-        if !msg_id_val.starts_with('<') { msg_id_val.insert(0, '<'); }
-        if !msg_id_val.ends_with('>') { msg_id_val.push('>'); }
-        headers_str.push_str(&format!("Message-ID: {}\r\n", msg_id_val));
-        headers_str.push_str(&format!("Content-Type: {}\r\n", self.content_type));
-        for (name, value) in &temp_headers { headers_str.push_str(&format!("{}: {}\r\n", name, value)); }
-        headers_str.push_str("\r\n");
-        headers_str.push_str(&utils::ensure_crlf(&self.body));
-        headers_str
+    /// The address MAIL FROM names unless the config overrides it
+    fn envelope_sender(&self) -> &str {
+        self.resent.as_ref().map(|resent| resent.from.as_str()).filter(|from| !from.is_empty()).unwrap_or(&self.from)
+    }
+
+    /// Writes the body with CRLF line endings, wrapped in `multipart/mixed` with the given
+    /// boundary if there are attachments
+    fn write_content<W: fmt::Write>(&self, out: &mut W, boundary: Option<&str>) -> fmt::Result {
+        let Some(boundary) = boundary else { return write_crlf(out, &self.body) };
+        write!(out, "--{}\r\nContent-Type: {}\r\n\r\n", boundary, self.content_type)?;
+        write_crlf(out, &self.body)?;
+        let ends_with_crlf = if self.body.contains("\r\n") { self.body.ends_with("\r\n") } else { self.body.ends_with('\n') };
+        if !ends_with_crlf { out.write_str("\r\n")?; }
+        for attachment in &self.attachments {
+            write!(out, "--{}\r\n", boundary)?;
+            attachment.write_into(out)?;
+        }
+        write!(out, "--{}--\r\n", boundary)
+    }
+
+    /// A boundary derived from the content, so that formatting the mail again gives the same bytes
+    fn boundary(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        (&self.content_type, &self.body).hash(&mut hasher);
+        for attachment in &self.attachments {
+            (&attachment.filename, &attachment.content_type, &attachment.data).hash(&mut hasher);
+        }
+        format!("=_micromail_{:016x}", hasher.finish())
     }
 
     pub fn format(&self, config: &Config) -> String {
-        let mut headers_str = String::new();
-        headers_str.push_str(&format!("From: {}\r\n", self.from));
-        headers_str.push_str(&format!("To: {}\r\n", self.to));
-        headers_str.push_str(&format!("Subject: {}\r\n", self.subject));
-        headers_str.push_str(&format!("Date: {}\r\n", utils::format_date()));
-        let mut msg_id_val = utils::generate_message_id(&config.domain);
-        if let Some(id) = &self.message_id { msg_id_val = id.clone(); }
-        if !msg_id_val.starts_with('<') { msg_id_val.insert(0, '<'); }
-        if !msg_id_val.ends_with('>') { msg_id_val.push('>'); }
-        headers_str.push_str(&format!("Message-ID: {}\r\n", msg_id_val));
-        headers_str.push_str(&format!("Content-Type: {}\r\n", self.content_type));
-        for (name, value) in &self.headers { headers_str.push_str(&format!("{}: {}\r\n", name, value)); }
-        headers_str.push_str("\r\n");
-        headers_str.push_str(&utils::ensure_crlf(&self.body));
-        headers_str
+        let mut formatted = String::with_capacity(self.estimated_size());
+        // Writing to a String cannot fail
+        let _ = self.format_into(&mut formatted, config);
+        formatted
+    }
+
+    /// Writes the formatted message to `out` like [`format_into`](Self::format_into), e.g.
+    /// to a file for [`Mailer::send_reader`] when the message is too large to format in memory
+    pub fn write_to<W: std::io::Write>(&self, out: W, config: &Config) -> std::io::Result<()> {
+        let mut writer = IoWriter { out: std::io::BufWriter::new(out), error: None };
+        if self.format_into(&mut writer, config).is_err() {
+            return Err(writer.error.unwrap_or_else(|| std::io::Error::other("the mail could not be formatted")));
+        }
+        writer.out.flush()
     }
 
+    /// Writes the formatted message to `out` piece by piece, without building it up in
+    /// memory first. Produces the same bytes as [`format`](Self::format).
+    pub fn format_into<W: fmt::Write>(&self, out: &mut W, config: &Config) -> fmt::Result {
+        // The newest resent block goes above the headers of the original message
+        if let Some(resent) = &self.resent {
+            if resent.date.is_some() || config.auto_headers {
+                write!(out, "Resent-Date: {}\r\n", utils::format_date_at(resent.date.unwrap_or_else(|| config.id_provider.now())))?;
+            }
+            write!(out, "Resent-From: {}\r\n", resent.from)?;
+            if !resent.to.is_empty() { write!(out, "Resent-To: {}\r\n", resent.to.join(", "))?; }
+            if resent.message_id.is_some() || config.auto_headers {
+                let id = resent.message_id.clone().unwrap_or_else(|| config.id_provider.message_id(&config.domain));
+                write!(out, "Resent-Message-ID: {}\r\n", angle_bracketed(id))?;
+            }
+        }
+        write!(out, "From: {}\r\nTo: {}\r\n", self.from, self.to)?;
+        if !self.cc.is_empty() { write!(out, "Cc: {}\r\n", self.cc.join(", "))?; }
+        write!(out, "Subject: {}\r\n", self.subject)?;
+        // Headers the caller set replace the ones made up here
+        let has_header = |name: &str| self.headers.keys().any(|key| key.eq_ignore_ascii_case(name));
+        if !has_header("Date") && (config.auto_headers || self.date.is_some()) {
+            write!(out, "Date: {}\r\n", utils::format_date_at(self.date.unwrap_or_else(|| config.id_provider.now())))?;
+        }
+        if !has_header("Message-ID") && (config.auto_headers || self.message_id.is_some()) {
+            let msg_id_val = self.message_id.clone().unwrap_or_else(|| config.id_provider.message_id(&config.domain));
+            write!(out, "Message-ID: {}\r\n", angle_bracketed(msg_id_val))?;
+        }
+        let boundary = (!self.attachments.is_empty()).then(|| self.boundary());
+        if boundary.is_some() && !has_header("MIME-Version") { out.write_str("MIME-Version: 1.0\r\n")?; }
+        // The multipart type is needed to read the body, so it takes precedence over the caller's
+        match &boundary {
+            Some(boundary) => write!(out, "Content-Type: multipart/mixed; boundary=\"{}\"\r\n", boundary)?,
+            None if config.auto_headers && !has_header("Content-Type") => write!(out, "Content-Type: {}\r\n", self.content_type)?,
+            None => {}
+        }
+        if let Some(user_agent) = config.user_agent.as_ref().filter(|_| config.auto_headers && !has_header("X-Mailer")) {
+            write!(out, "X-Mailer: {}\r\n", user_agent)?;
+        }
+        for (name, value) in &self.headers {
+            if boundary.is_some() && name.eq_ignore_ascii_case("Content-Type") { continue; }
+            write!(out, "{}: {}\r\n", name, value)?;
+        }
+        out.write_str("\r\n")?;
+        self.write_content(out, boundary.as_deref())
+    }
+
+    /// Roughly the length of the formatted message, to allocate it in one go
+    fn estimated_size(&self) -> usize {
+        let headers = self.headers.iter().map(|(name, value)| name.len() + value.len() + 4).sum::<usize>();
+        // Base64 grows attachments by a third, plus a CRLF every 76 characters
+        let attachments = self.attachments.iter().map(|a| a.data.len() * 4 / 3 * 78 / 76 + 256).sum::<usize>();
+        512 + headers + self.body.len() + self.body.len() / 32 + attachments
+    }
+
+    /// Adds a DKIM-Signature header made with the key of `config`, if it has one.
+    ///
+    /// Sets `date` and `message_id` first if they are missing, so that [`format`](Self::format)
+    /// produces the same message that was signed, plus the signature.
     #[cfg(feature = "signing")]
-    pub fn sign_with_dkim(&mut self, _config: &Config) -> Result<(), Error> {
-        // DKIM signing logic using mail-auth 0.7.1 commented out due to API resolution issues.
+    pub fn sign_with_dkim(&mut self, config: &Config) -> Result<(), Error> {
+        let Some(dkim) = &config.dkim_config else { return Ok(()) };
+        self.headers.remove("DKIM-Signature");
+        if config.auto_headers {
+            self.date.get_or_insert_with(|| config.id_provider.now());
+            if self.message_id.is_none() { self.message_id = Some(config.id_provider.message_id(&config.domain)); }
+            if let Some(resent) = &mut self.resent {
+                resent.date.get_or_insert_with(|| config.id_provider.now());
+                if resent.message_id.is_none() { resent.message_id = Some(config.id_provider.message_id(&config.domain)); }
+            }
+        }
+        let signature = crate::signing::dkim_signature(dkim, &self.format(config))?;
+        self.headers.insert("DKIM-Signature".to_string(), signature);
         Ok(())
     }
     #[cfg(not(feature = "signing"))]
@@ -114,88 +253,620 @@ impl Signer {
 
 pub struct Mailer {
     config: Config,
-    log: Vec<String>,
+    recorder: SharedRecorder,
+    /// End of the overall send deadline for the send in progress
+    deadline: Option<Instant>,
+    /// Whether the send in progress already waited out greylisting once
+    greylist_retried: bool,
 }
 impl Mailer {
-    pub fn new(config: Config) -> Self { Self { config, log: Vec::new() } }
-    pub fn get_log(&self) -> &[String] { &self.log }
-    pub fn clear_log(&mut self) { self.log.clear(); }
-    pub fn send_sync(&mut self, mut mail: Mail) -> Result<(), Error> {
+    pub fn new(config: Config) -> Self { Self { config, recorder: SharedRecorder::default(), deadline: None, greylist_retried: false } }
+    /// The transcript of the last send as plain lines
+    pub fn get_log(&self) -> Vec<String> { self.recorder.lock().unwrap().transcript.to_strings() }
+    /// Structured transcript of the last send
+    pub fn transcript(&self) -> Transcript { self.recorder.lock().unwrap().transcript.clone() }
+    /// Calls `f` with the transcript of the last send, without copying it
+    #[cfg(feature = "c-api")]
+    pub(crate) fn with_transcript<R>(&self, f: impl FnOnce(&Transcript) -> R) -> R { f(&self.recorder.lock().unwrap().transcript) }
+    /// The transcript of the last send as JSON, see [`Transcript::to_json`]
+    pub fn transcript_json(&self) -> String { self.recorder.lock().unwrap().transcript.to_json() }
+    pub fn clear_log(&mut self) { self.recorder.lock().unwrap().transcript.clear(); }
+    /// Registers `listener` to be called with every [`SendEvent`] of subsequent sends,
+    /// e.g. to show progress or feed an audit log. Listeners run on the sending thread.
+    pub fn on_event<F: FnMut(&SendEvent) + Send + 'static>(&mut self, listener: F) { self.recorder.lock().unwrap().add_listener(Box::new(listener)); }
+    /// Delivers `mail` to all its envelope recipients, one transaction per recipient domain.
+    /// Domains served by the same primary MX host share one connection.
+    ///
+    /// Fails if any transaction fails, including when a domain rejects all its recipients.
+    /// A retry leaves out the domains that already accepted the message.
+    pub fn send_sync(&mut self, mail: Mail) -> Result<SendReport, Error> {
+        self.send_using(None, mail)
+    }
+    /// Like [`Mailer::send_sync`], with `options` overriding parts of the config for this
+    /// mail only. The mailer's config is unchanged afterwards.
+    pub fn send_with(&mut self, mail: Mail, options: SendOptions) -> Result<SendReport, Error> {
+        let config = options.apply(&self.config);
+        self.send_using(Some(&config), mail)
+    }
+    /// Sends with `config` in place of the mailer's own, if given
+    fn send_using(&mut self, config: Option<&Config>, mail: Mail) -> Result<SendReport, Error> {
+        let _span = trace::send_span().entered();
+        self.begin_send(config.unwrap_or(&self.config).deadline);
+        self.with_retries(|mailer, report| mailer.send_attempt(config.unwrap_or(&mailer.config), mail.clone(), report))
+    }
+    /// Sends a message that is already formatted, e.g. an `.eml` file or one written with
+    /// [`Mail::write_to`], from `from` to `recipients`. The message is read and dot-stuffed
+    /// in chunks on its way out, so it never has to fit in memory; it is rewound for every
+    /// transaction and retry.
+    ///
+    /// The message goes out as it is: no headers are added, nothing is signed, and with
+    /// [`Config::sandbox_redirect`] only the envelope is redirected.
+    pub fn send_reader<R: Read + Seek>(&mut self, from: &str, recipients: &[String], message: R) -> Result<SendReport, Error> {
+        let _span = trace::send_span().entered();
+        self.begin_send(self.config.deadline);
+        let mut prepared = prepare_raw(&self.config, from, recipients, io::Reader(message))?;
+        self.with_retries(|mailer, report| mailer.deliver(&mailer.config, &mut prepared, report))
+    }
+    /// Runs `attempt` until it succeeds or the retry policy gives up, collecting the
+    /// transactions of all attempts in one report
+    fn with_retries(&mut self, mut attempt: impl FnMut(&Self, &mut SendReport) -> Result<(), Error>) -> Result<SendReport, Error> {
+        let mut report = SendReport::default();
+        let mut attempts = 1;
+        loop {
+            match attempt(self, &mut report) {
+                Err(e) => match self.retry_delay(&e, attempts) {
+                    Some(delay) => { std::thread::sleep(delay); attempts += 1; }
+                    None => return Err(e),
+                },
+                Ok(()) => return Ok(report),
+            }
+        }
+    }
+    /// Like [`Mailer::send_sync`], but a failure carries the transcript of the session
+    pub fn send_traced(&mut self, mail: Mail) -> Result<SendReport, SendFailure> {
+        self.send_sync(mail).map_err(|error| SendFailure { error, transcript: self.transcript() })
+    }
+    /// Resets the log and starts the clock for a new send that has `deadline` to complete
+    pub(crate) fn begin_send(&mut self, deadline: Option<Duration>) {
         self.clear_log();
+        self.start_deadline(deadline);
+        self.greylist_retried = false;
+    }
+    /// How long to wait before retrying after attempt number `attempt` failed with `error`,
+    /// or None if the retry policy (or the deadline) rules out another attempt
+    fn retry_delay(&mut self, error: &Error, attempt: u32) -> Option<std::time::Duration> {
+        retry_delay(&self.config, &self.recorder, self.deadline, &mut self.greylist_retried, error, attempt)
+    }
+    pub(crate) fn config(&self) -> &Config { &self.config }
+    /// A recorder for one send of its own, calling this mailer's listeners
+    pub(crate) fn send_recorder(&self) -> SharedRecorder { Arc::new(Mutex::new(self.recorder.lock().unwrap().for_send())) }
+    /// Makes the transcript of a send that recorded on its own the one of the last send
+    pub(crate) fn keep_transcript(&self, recorder: &SharedRecorder) {
+        let transcript = recorder.lock().unwrap().transcript.clone();
+        self.recorder.lock().unwrap().transcript = transcript;
+    }
+    /// A single delivery attempt with `config`, without retries, adding its transactions
+    /// to `report`. Recipients of the transactions already in `report` are left out.
+    fn send_attempt(&self, config: &Config, mail: Mail, report: &mut SendReport) -> Result<(), Error> {
+        let mut prepared = prepare_send(config, &self.recorder, mail)?;
+        self.deliver(config, &mut prepared, report)?;
+        report.message = sent_message(config, prepared.content);
+        Ok(())
+    }
+    /// Sends `prepared` to the recipients not in a transaction of `report` yet, one
+    /// transaction per domain, and adds the transactions to `report`
+    fn deliver<C: io::Content>(&self, config: &Config, prepared: &mut PreparedSend<C>, report: &mut SendReport) -> Result<(), Error> {
+        prepared.skip(|recipient| report.recipients().any(|status| status.recipient == recipient));
+        let mut groups = Vec::new();
+        for (domain, indices) in &prepared.by_domain {
+            let mx_records = resolve_mx(config, &self.recorder, domain)?;
+            add_to_mx_group(&mut groups, mx_records, domain.clone(), indices.clone());
+        }
+        for group in groups {
+            let mut connection = self.open_session(config, &group)?;
+            for (domain, indices) in &group.domains {
+                let domain_recipients = indices.iter().map(|&i| prepared.recipients[i].clone()).collect::<Vec<_>>();
+                let result = self.process_mail_internal(&mut connection, domain, &prepared.from, &domain_recipients, &mut prepared.content);
+                match result {
+                    Ok(transaction) => report.transactions.push(transaction),
+                    Err(e) => {
+                        // A server that timed out would only keep us waiting for its QUIT reply as well
+                        if !matches!(e, Error::Timeout { .. } | Error::Stalled { .. }) { self.quit(&mut connection); }
+                        return Err(e);
+                    }
+                }
+            }
+            self.quit(&mut connection);
+        }
+        Ok(())
+    }
+    /// Connects to the server mail for `domain` goes to (or the relay), runs EHLO, STARTTLS
+    /// and AUTH as a send would and quits without sending anything. Fails where a send
+    /// would fail before the first MAIL FROM, e.g. as a health check at startup.
+    pub fn verify_connection(&mut self, domain: &str) -> Result<ConnectionCheck, Error> {
+        let _span = trace::send_span().entered();
+        self.begin_send(self.config.deadline);
+        let mx_records = resolve_mx(&self.config, &self.recorder, domain)?;
+        let group = MxGroup { mx_records, domains: vec![(domain.to_string(), Vec::new())] };
+        let mut connection = self.open_session(&self.config, &group)?;
+        let check = ConnectionCheck {
+            mx_host: connection.mx_host.clone(),
+            address: connection.addr(),
+            capabilities: connection.capabilities.clone(),
+            secure: connection.is_secure(),
+            tls: connection.tls_info.clone(),
+            authenticated: self.config.auth.is_some() || self.config.auth_provider.is_some(),
+        };
+        self.quit(&mut connection);
+        Ok(check)
+    }
+    /// Sends a separate copy of `mail` to every recipient instead of one message with many RCPTs.
+    ///
+    /// Each copy gets its own envelope, a `To` header naming only that recipient and a fresh
+    /// Message-ID, so recipients never learn about each other. Recipients are grouped by the
+    /// primary MX host of their domain and share one connection per host. Results are
+    /// returned in the order of `recipients`.
+    pub fn send_individually<S: AsRef<str>>(&mut self, mail: Mail, recipients: &[S]) -> Vec<(String, Result<SendReport, Error>)> {
+        let copies = recipients.iter().map(|recipient| {
+            let mut copy = mail.clone();
+            copy.to = recipient.as_ref().to_string();
+            copy.cc.clear();
+            copy.bcc.clear();
+            copy.message_id = None;
+            (copy.to.clone(), Ok(copy))
+        });
+        self.send_copies(copies.collect())
+    }
+    /// Sends every mail to its recipient alone, like [`send_individually`](Self::send_individually).
+    /// A copy that is already an error is reported as such without being sent.
+    pub(crate) fn send_copies(&mut self, copies: Vec<(String, Result<Mail, Error>)>) -> Vec<(String, Result<SendReport, Error>)> {
+        self.begin_send(self.config.deadline);
+        let mut results = Vec::<(String, Option<Result<SendReport, Error>>)>::new();
+        let mut mails = Vec::new();
+        for (recipient, copy) in copies {
+            match copy {
+                Ok(mail) => { results.push((recipient, None)); mails.push(Some(mail)); }
+                Err(e) => { results.push((recipient, Some(Err(e)))); mails.push(None); }
+            }
+        }
+        let _span = trace::send_span().entered();
+        // With a sandbox, every copy goes to the sandbox's MX host
+        let addresses = results.iter().map(|(r, _)| self.config.sandbox_redirect.clone().unwrap_or_else(|| r.clone())).collect::<Vec<_>>();
+        let (by_domain, invalid) = group_by_domain(&addresses);
+        for (i, e) in invalid { results[i].1.get_or_insert(Err(e)); }
+        for (i, address) in addresses.iter().enumerate() {
+            if !self.config.recipient_permitted(address) { results[i].1.get_or_insert(Err(Error::RecipientBlocked(address.clone()))); }
+        }
+        let mut groups = Vec::new();
+        for (domain, mut indices) in by_domain {
+            indices.retain(|&i| mails[i].is_some() && results[i].1.is_none());
+            if indices.is_empty() { continue; }
+            match resolve_mx(&self.config, &self.recorder, &domain) {
+                Ok(mx_records) => add_to_mx_group(&mut groups, mx_records, domain, indices),
+                Err(e) => {
+                    // Every recipient of the domain reports the failure of the one lookup
+                    for &i in &indices[1..] {
+                        results[i].1 = Some(Err(lookup_error(&e)));
+                    }
+                    results[indices[0]].1 = Some(Err(e));
+                }
+            }
+        }
+        for group in groups {
+            let mut connection: Option<Connected> = None;
+            for i in group.domains.iter().flat_map(|(_, indices)| indices.iter().copied()) {
+                let conn = match connection.as_mut() {
+                    Some(conn) => conn,
+                    None => match self.open_session(&self.config, &group) {
+                        Ok(conn) => connection.insert(conn),
+                        Err(e) => { results[i].1 = Some(Err(e)); continue; }
+                    },
+                };
+                let Some(copy) = mails[i].take() else { continue };
+                let result = self.send_copy(conn, copy);
+                if let Err(e) = &result {
+                    // A rejection leaves the session mid-transaction: RSET keeps it for the next copy,
+                    // anything else starts over on a fresh connection
+                    let recovered = matches!(e, Error::SmtpError { .. }) && self.reset(conn);
+                    if !recovered {
+                        if let Some(mut conn) = connection.take() {
+                            if !matches!(e, Error::Timeout { .. } | Error::Stalled { .. }) { self.quit(&mut conn); }
+                        }
+                    }
+                }
+                results[i].1 = Some(result);
+            }
+            if let Some(mut conn) = connection { self.quit(&mut conn); }
+        }
+        results.into_iter().map(|(recipient, result)| (recipient, result.unwrap_or(Err(Error::ConnectionFailed)))).collect()
+    }
+    fn send_copy(&self, connection: &mut Connected, mut mail: Mail) -> Result<SendReport, Error> {
+        let recipient = match &self.config.sandbox_redirect {
+            Some(sandbox) => {
+                let original = [mail.to.clone()];
+                redirect_to_sandbox(&mut mail, &original);
+                sandbox.clone()
+            }
+            None => mail.to.clone(),
+        };
         if self.config.dkim_config.is_some() {
             mail.sign_with_dkim(&self.config)?;
         }
-        let domain_to = self.extract_domain(&mail.to)?;
-        let mx_records = dns::get_mx_records(&domain_to, &self.config);
-        if mx_records.is_empty() { return Err(Error::NoMxRecords); }
-        dns::log_mx_records(&mx_records, &mut self.log);
-        let mut connection = connection::try_start_connection(&mx_records, &self.config.ports, &self.config, &mut self.log)
+        let mut formatted = mail.format(&self.config);
+        let domain = utils::extract_domain(&recipient)?;
+        let from = envelope_sender(&self.config, mail.envelope_sender())?;
+        let transaction = self.process_mail_internal(connection, &domain, &from, &[recipient], &mut formatted)?;
+        Ok(SendReport { transactions: vec![transaction], message: sent_message(&self.config, formatted) })
+    }
+    fn start_deadline(&mut self, limit: Option<Duration>) {
+        self.deadline = limit.map(|limit| Instant::now() + limit);
+    }
+    /// Connects to the first reachable MX host of `group` and runs EHLO, STARTTLS and AUTH
+    /// with `config`
+    fn open_session(&self, config: &Config, group: &MxGroup) -> Result<Connected, Error> {
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::Timeout { phase: SmtpPhase::Connect, elapsed: Duration::ZERO });
+        }
+        let mut connection = connection::try_start_connection(&group.mx_records, &config.ports, config, self.deadline, &self.recorder)
             .ok_or(Error::ConnectionFailed)?;
-        let starttls_available = connection::send_ehlo(&mut connection, &self.config.domain, &mut self.log, false)?.0;
-        if self.config.use_tls && starttls_available {
-            let (new_connection, reconnected) = connection::establish_tls(connection)?;
+        connection.deadline = self.deadline;
+        let starttls_available = connection::send_ehlo(&mut connection, config.ehlo_name(), false)?.starttls && connection.can_starttls();
+        let domains = group.domains.iter().map(|(domain, _)| domain.clone()).collect();
+        let dane = match dane::lookup(&connection, config, domains) {
+            Ok(dane) => dane,
+            Err(e) => { self.quit(&mut connection); return Err(e); }
+        };
+        let endpoints = (connection.local_addr(), connection.address);
+        let use_tls = match use_starttls(config, &self.recorder, starttls_available, connection.mx_host.as_deref(), endpoints, dane.as_ref()) {
+            Ok(use_tls) => use_tls,
+            Err(e) => { self.quit(&mut connection); return Err(e); }
+        };
+        if use_tls {
+            let started = Instant::now();
+            let result = connection::establish_tls(connection, config, dane);
+            metrics::with(&config.metrics, |m| m.tls_handshake(result.as_ref().map(|_| started.elapsed())));
+            let (new_connection, reconnected) = result?;
             connection = new_connection;
-            if reconnected { connection::send_ehlo(&mut connection, &self.config.domain, &mut self.log, true)?; }
+            if reconnected { connection::send_ehlo(&mut connection, config.ehlo_name(), true)?; }
         }
-        let auth_clone = self.config.auth.clone();
-        if let Some(auth_config) = auth_clone {
-            self.authenticate(&mut connection, &auth_config.username, &auth_config.password)?;
+        if let Err(e) = self.login(config, &mut connection) {
+            if !matches!(e, Error::Timeout { .. } | Error::Stalled { .. }) { self.quit(&mut connection); }
+            return Err(e);
         }
-        let formatted_mail_for_sending = mail.format(&self.config);
-        if self.config.test_mode && self.config.dkim_config.is_some() {
-             self.log.push(format!("BEGIN_SIGNED_MAIL_FOR_TEST_MODE\r\n{}\r\nEND_SIGNED_MAIL_FOR_TEST_MODE", formatted_mail_for_sending));
+        Ok(connection)
+    }
+    /// Authenticates with the configured credentials, if there are any
+    fn login(&self, config: &Config, connection: &mut Connected) -> Result<(), Error> {
+        let Some(auth) = config.credentials()? else { return Ok(()) };
+        match self.authenticate(connection, &auth.username, &auth.password) {
+            Err(Error::AuthError { code: Some(535), .. }) if config.auth_provider.is_some() => {
+                // The credentials may have been rotated since they were fetched
+                let auth = config.credentials()?.unwrap_or(auth);
+                self.authenticate(connection, &auth.username, &auth.password)
+            }
+            result => result,
         }
-        self.process_mail(&mut connection, &mail.from, &mail.to, &formatted_mail_for_sending)?;
-        Ok(())
     }
     pub fn extract_domain(&self, email: &str) -> Result<String, Error> {
-        email.split('@').nth(1).map(String::from).ok_or_else(|| Error::InvalidMailContent(format!("Invalid email address: {}", email)))
-    }
-    fn authenticate(&mut self, connection: &mut Connected, username: &str, password: &str) -> Result<(), Error> {
-        io::secure_send(connection, "AUTH LOGIN\r\n")?;
-        io::secure_read(connection)?;
-        let username_b64 = BASE64_STANDARD.encode(username);
-        io::secure_send(connection, &format!("{}\r\n", username_b64))?;
-        io::secure_read(connection)?;
-        let password_b64 = BASE64_STANDARD.encode(password);
-        io::secure_send(connection, &format!("{}\r\n", password_b64))?;
-        let response = io::secure_read(connection)?;
-        if !response.is_http_ok() { return Err(Error::AuthError{ code: Some(response.code), message: response.message }); }
-        Ok(())
+        utils::extract_domain(email)
     }
-    fn process_mail(&mut self, connection: &mut Connected, from: &str, to: &str, mail_content: &str) -> Result<(), Error> {
-        let result = self.process_mail_internal(connection, from, to, mail_content);
-        let _ = io::secure_send(connection, "QUIT\r\n");
-        self.log.push("QUIT".to_string());
-        result
-    }
-    fn process_mail_internal(&mut self, connection: &mut Connected, from: &str, to: &str, mail_content: &str) -> Result<(), Error> {
-        let msg_from = format!("MAIL FROM:<{}>\r\n", from);
-        self.log.push(utils::sanitize_string_lite(&msg_from));
-        io::secure_send(connection, &msg_from)?;
-        let resp_from = io::secure_read(connection)?;
-        self.log.push(format!("{:?}", resp_from));
-        if !resp_from.is_http_ok() { return Err(Error::SmtpError{ code: resp_from.code, message: format!("MAIL FROM failed: {}", resp_from.message) }); }
-        let msg_rcpt = format!("RCPT TO:<{}>\r\n", to);
-        self.log.push(utils::sanitize_string_lite(&msg_rcpt));
-        io::secure_send(connection, &msg_rcpt)?;
-        let resp_rcpt = io::secure_read(connection)?;
-        self.log.push(format!("{:?}", resp_rcpt));
-        if !resp_rcpt.is_http_ok() { return Err(Error::SmtpError{ code: resp_rcpt.code, message: format!("RCPT TO failed: {}", resp_rcpt.message) }); }
-        self.log.push("DATA".to_string());
-        io::secure_send(connection, "DATA\r\n")?;
-        let resp_data_cmd = io::secure_read(connection)?;
-        self.log.push(format!("{:?}", resp_data_cmd));
-        if resp_data_cmd.code != 354 { return Err(Error::SmtpError{ code: resp_data_cmd.code, message: format!("DATA command failed: {}", resp_data_cmd.message) }); }
-        let already_logged_signed_mail = self.config.test_mode && self.config.dkim_config.is_some() && self.log.last().map_or(false, |l| l.starts_with("BEGIN_SIGNED_MAIL_FOR_TEST_MODE"));
-        if !already_logged_signed_mail {
-            for l in mail_content.lines() { self.log.push(utils::sanitize_string_lite(l)); }
-        }
-        io::secure_send(connection, mail_content)?;
-        io::secure_send(connection, "\r\n.\r\n")?;
-        let resp_mail_sent = io::secure_read(connection)?;
-        self.log.push(format!("{:?}", resp_mail_sent));
-        if !resp_mail_sent.is_http_ok() { return Err(Error::SmtpError{ code: resp_mail_sent.code, message: format!("Mail content sending failed: {}", resp_mail_sent.message) }); }
+    fn authenticate(&self, connection: &mut Connected, username: &str, password: &str) -> Result<(), Error> {
+        let _span = trace::auth_span(username).entered();
+        let mut login = smtp::AuthLogin::new(username, password);
+        let mut next = Some(login.start());
+        while let Some(action) = next {
+            next = match login.reply(io::exchange(connection, action)?) {
+                Ok(next) => next,
+                Err(e) => {
+                    metrics::with(&connection.metrics, |m| m.auth_failed(&e));
+                    return Err(e);
+                }
+            };
+        }
+        connection.emit(SendEvent::Authenticated);
         Ok(())
     }
+    fn quit(&self, connection: &mut Connected) {
+        connection.enter_phase(SmtpPhase::Quit);
+        if io::secure_send(connection, "QUIT\r\n").is_ok() {
+            let _ = io::secure_read(connection);
+        }
+    }
+    /// Aborts the transaction in progress, returning whether the server accepted the RSET
+    fn reset(&self, connection: &mut Connected) -> bool {
+        connection.enter_phase(SmtpPhase::MailFrom);
+        io::secure_send(connection, "RSET\r\n").is_ok() && io::secure_read(connection).is_ok_and(|reply| reply.code == 250)
+    }
+    /// One mail transaction: MAIL FROM, a RCPT TO per recipient and DATA if any recipient was accepted
+    fn process_mail_internal(&self, connection: &mut Connected, domain: &str, from: &str, recipients: &[String], content: &mut dyn io::Content) -> Result<TransactionReport, Error> {
+        let mut transaction = smtp::Transaction::new(domain, from, recipients, connection.dsn.as_ref(), &connection.capabilities, connection.lmtp);
+        let mut next = Some(transaction.start());
+        while let Some(action) = next {
+            next = match action {
+                smtp::Action::Data => {
+                    connection.enter_phase(SmtpPhase::DataTransfer);
+                    content.send(connection)?;
+                    transaction.data_sent()?
+                }
+                action => transaction.reply(io::exchange(connection, action)?)?,
+            };
+        }
+        let report = transaction.report(&connection.capabilities);
+        connection.emit(SendEvent::Accepted { code: report.data_code });
+        metrics::with(&connection.metrics, |m| m.delivered(domain, report.recipients.iter().filter(|s| s.accepted).count()));
+        Ok(report)
+    }
+}
+
+/// A mail ready to go out on every attempt of a send
+pub(crate) struct PreparedSend<C = String> {
+    pub from: String,
+    pub recipients: Vec<String>,
+    /// Indices into `recipients` by recipient domain
+    pub by_domain: DomainGroups,
+    /// The signed and formatted message, or where to read it from
+    pub content: C,
+}
+
+impl<C> PreparedSend<C> {
+    /// Leaves out the recipients `done` says already have the mail, e.g. from an earlier attempt
+    pub fn skip(&mut self, done: impl Fn(&str) -> bool) {
+        let recipients = &self.recipients;
+        self.by_domain.retain_mut(|(_, indices)| {
+            indices.retain(|&i| !done(&recipients[i]));
+            !indices.is_empty()
+        });
+    }
+}
+
+/// Checks `mail` against `config`, signs and formats it
+/// How long a send recording into `recorder` waits before retrying after attempt number
+/// `attempt` failed with `error`, or None if the retry policy (or the `deadline`) rules out
+/// another attempt. `greylist_retried` tells whether the send already waited out greylisting.
+pub(crate) fn retry_delay(config: &Config, recorder: &SharedRecorder, deadline: Option<Instant>, greylist_retried: &mut bool, error: &Error, attempt: u32) -> Option<Duration> {
+    let greylist_delay = config.greylist_delay.filter(|_| !*greylist_retried && error.is_greylisting());
+    let delay = match greylist_delay {
+        Some(delay) => delay,
+        None => {
+            let policy = config.retry.as_ref()?;
+            if !policy.should_retry(error, attempt) { return None; }
+            // A server that says how long to wait knows better than the backoff
+            error.retry_after().map_or_else(|| policy.delay_for(attempt), |hint| hint.min(policy.max_delay))
+        }
+    };
+    if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) { return None; }
+    let note = if greylist_delay.is_some() {
+        *greylist_retried = true;
+        format!("Greylisted by the server ({}), retrying in {:?}", error, delay)
+    } else {
+        format!("Attempt {} failed: {}, retrying in {:?}", attempt, error, delay)
+    };
+    recorder.lock().unwrap().transcript.note(note);
+    transcript::emit(recorder, &SendEvent::Retrying { attempt, delay });
+    metrics::with(&config.metrics, |m| m.deferred(error, delay));
+    Some(delay)
+}
+
+pub(crate) fn prepare_send(config: &Config, recorder: &SharedRecorder, mut mail: Mail) -> Result<PreparedSend, Error> {
+    let original = mail.envelope_recipients();
+    let envelope = prepare_raw(config, mail.envelope_sender(), &original, ())?;
+    if config.sandbox_redirect.is_some() {
+        redirect_to_sandbox(&mut mail, &original);
+    }
+    if config.dkim_config.is_some() {
+        mail.sign_with_dkim(config)?;
+    }
+    let content = mail.format(config);
+    trace::record_message_id(&content);
+    if config.test_mode && config.dkim_config.is_some() {
+        recorder.lock().unwrap().transcript.note(format!("BEGIN_SIGNED_MAIL_FOR_TEST_MODE\r\n{}\r\nEND_SIGNED_MAIL_FOR_TEST_MODE", content));
+    }
+    Ok(PreparedSend { from: envelope.from, recipients: envelope.recipients, by_domain: envelope.by_domain, content })
+}
+
+/// Checks the envelope of a message from `from` to `recipients` against `config`, for
+/// `content` that is already formatted
+pub(crate) fn prepare_raw<C>(config: &Config, from: &str, recipients: &[String], content: C) -> Result<PreparedSend<C>, Error> {
+    if config.tls_policy == TlsPolicy::Verified && config.accept_invalid_certs {
+        return Err(Error::TlsError("TLS policy requires verified certificates, but invalid certificates are accepted".to_string()));
+    }
+    let mut recipients = recipients.iter().map(|recipient| utils::bare_address(recipient)).filter(|address| !address.is_empty()).collect::<Vec<_>>();
+    if recipients.is_empty() { return Err(Error::InvalidMailContent("Mail has no recipients".to_string())); }
+    if let Some(sandbox) = &config.sandbox_redirect {
+        recipients = vec![sandbox.clone()];
+    }
+    if let Some(blocked) = recipients.iter().find(|recipient| !config.recipient_permitted(recipient)) {
+        return Err(Error::RecipientBlocked(blocked.clone()));
+    }
+    let (by_domain, mut invalid) = group_by_domain(&recipients);
+    if !invalid.is_empty() { return Err(invalid.remove(0).1); }
+    let from = envelope_sender(config, from)?;
+    Ok(PreparedSend { from, recipients, by_domain, content })
+}
+
+/// The MAIL FROM address for a message from `sender`: `config.envelope_from`, or else `sender`
+fn envelope_sender(config: &Config, sender: &str) -> Result<String, Error> {
+    // Either would end the MAIL FROM command early, or smuggle another one in
+    let invalid = |from: &str| from.contains(['\r', '\n', '>']);
+    match config.envelope_from.as_deref() {
+        Some(from) if invalid(from) => Err(Error::InvalidConfig(vec![format!("The envelope sender {:?} contains a line break or '>'. Use a plain address like bounces@example.com.", from)])),
+        Some(from) => Ok(from.to_string()),
+        None => {
+            // The address of a sender like `Shop <shop@example.com>`
+            let from = utils::bare_address(sender);
+            if invalid(&from) {
+                return Err(Error::InvalidMailContent(format!("Invalid envelope sender {:?}", from)));
+            }
+            Ok(from)
+        }
+    }
+}
+
+/// `content` for [`SendReport::message`], if the config asks to keep it
+pub(crate) fn sent_message(config: &Config, content: String) -> Vec<u8> {
+    if config.keep_sent_message { content.into_bytes() } else { Vec::new() }
+}
+
+/// A [`fmt::Write`] over an [`std::io::Write`], keeping the error that stopped it
+struct IoWriter<W> {
+    out: W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: std::io::Write> fmt::Write for IoWriter<W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.out.write_all(text.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// Writes `text` with every bare LF turned into CRLF, or unchanged if it already has CRLFs
+fn write_crlf<W: fmt::Write>(out: &mut W, text: &str) -> fmt::Result {
+    if text.contains("\r\n") { return out.write_str(text); }
+    let mut lines = text.split('\n');
+    if let Some(first) = lines.next() { out.write_str(first)?; }
+    for line in lines {
+        out.write_str("\r\n")?;
+        out.write_str(line)?;
+    }
+    Ok(())
+}
+
+/// `id` in the angle brackets of a Message-ID header
+fn angle_bracketed(mut id: String) -> String {
+    if !id.starts_with('<') { id.insert(0, '<'); }
+    if !id.ends_with('>') { id.push('>'); }
+    id
+}
+
+/// Records the recipients `mail` would have gone to without the sandbox
+fn redirect_to_sandbox(mail: &mut Mail, original: &[String]) {
+    mail.headers.insert("X-Original-To".to_string(), original.join(", "));
+}
+
+/// Looks up the MX hosts of `domain_to` and records them. A domain without MX records
+/// is its own mail server if it has an address record, one with a null MX refuses mail.
+pub(crate) fn resolve_mx(config: &Config, recorder: &SharedRecorder, domain_to: &str) -> Result<Vec<MxRecord>, Error> {
+    if config.unix_socket.is_some() || config.direct_target.is_some() {
+        // Every domain goes to the one configured server
+        return Ok(Vec::new());
+    }
+    let _span = trace::dns_span(domain_to).entered();
+    let mut mx_records = dns::get_mx_records(domain_to, config).inspect_err(|e| {
+        recorder.lock().unwrap().transcript.note(format!("MX lookup for {} failed: {}", domain_to, e));
+    })?;
+    if mx_records.iter().any(MxRecord::is_null) {
+        // A null MX is meant to be the only record, never try to deliver to "."
+        mx_records.retain(|mx| !mx.is_null());
+        if mx_records.is_empty() {
+            recorder.lock().unwrap().transcript.note(format!("{} publishes a null MX and accepts no mail", domain_to));
+            return Err(Error::DomainDoesNotAcceptMail(domain_to.to_string()));
+        }
+    }
+    if mx_records.is_empty() {
+        let implicit = dns::implicit_mx(domain_to, config).ok_or(Error::NoMxRecords)?;
+        recorder.lock().unwrap().transcript.note(format!("No MX records for {}, falling back to its address record", domain_to));
+        mx_records.push(implicit);
+    }
+    if let Some(policy) = &config.mx_policy {
+        mx_records = policy.apply(domain_to, mx_records);
+        if mx_records.is_empty() {
+            recorder.lock().unwrap().transcript.note(format!("The MX policy left no hosts to try for {}", domain_to));
+            return Err(Error::NoMxRecords);
+        }
+    }
+    dns::log_mx_records(&mx_records, &mut recorder.lock().unwrap().transcript);
+    transcript::emit(recorder, &SendEvent::DnsResolved {
+        domain: domain_to.to_string(),
+        hosts: mx_records.iter().map(|mx| mx.server.clone()).collect(),
+    });
+    Ok(mx_records)
+}
+
+/// Whether to upgrade the connection to `mx_host` with STARTTLS, after EHLO told whether the
+/// server offers it. Fails when the TLS policy or an enforced DANE policy rules out going
+/// on in plaintext, the caller then quits. `endpoints` are the local and remote address.
+pub(crate) fn use_starttls(
+    config: &Config,
+    recorder: &SharedRecorder,
+    starttls_available: bool,
+    mx_host: Option<&str>,
+    endpoints: (Option<SocketAddr>, SocketAddr),
+    dane: Option<&DanePolicy>,
+) -> Result<bool, Error> {
+    let use_tls = config.tls_policy != TlsPolicy::None && starttls_available;
+    if !use_tls && config.tls_policy.requires_tls() {
+        let message = format!("TLS policy requires STARTTLS, but {} does not offer it", mx_host.unwrap_or("the server"));
+        if let Some(policy) = dane {
+            policy.report(endpoints.0, endpoints.1, Err((ResultType::StartTlsNotSupported, &Error::TlsError(message.clone()))));
+        }
+        return Err(Error::TlsError(message));
+    }
+    if let Some(policy) = dane.filter(|_| !use_tls) {
+        let message = format!("DANE: {} publishes TLSA records but the connection would not be encrypted", policy.mx_host);
+        policy.report(endpoints.0, endpoints.1, Err((ResultType::StartTlsNotSupported, &Error::TlsError(message.clone()))));
+        if policy.mode == DaneMode::Enforce {
+            return Err(Error::TlsError(message));
+        }
+        recorder.lock().unwrap().transcript.note(format!("{} (log only, continuing)", message));
+    }
+    Ok(use_tls)
+}
+
+/// A copy of `error`, which an MX lookup failed with, for another recipient of the domain
+fn lookup_error(error: &Error) -> Error {
+    match error {
+        Error::NoMxRecords => Error::NoMxRecords,
+        Error::DomainDoesNotAcceptMail(domain) => Error::DomainDoesNotAcceptMail(domain.clone()),
+        Error::Timeout { phase, elapsed } => Error::Timeout { phase: *phase, elapsed: *elapsed },
+        Error::DnsError(message) => Error::DnsError(message.clone()),
+        e => Error::DnsError(e.to_string()),
+    }
+}
+
+/// Lowercased recipient domains with the indices of their recipients, in order of first appearance
+pub(crate) type DomainGroups = Vec<(String, Vec<usize>)>;
+
+/// Groups addresses by lowercased domain in order of first appearance, as indices into
+/// `addresses`. Addresses without a domain are returned separately with their error.
+pub(crate) fn group_by_domain(addresses: &[String]) -> (DomainGroups, Vec<(usize, Error)>) {
+    let mut by_domain = DomainGroups::new();
+    let mut invalid = Vec::new();
+    for (i, address) in addresses.iter().enumerate() {
+        match utils::extract_domain(address) {
+            Ok(domain) => {
+                let domain = domain.to_lowercase();
+                match by_domain.iter_mut().find(|(d, _)| *d == domain) {
+                    Some((_, indices)) => indices.push(i),
+                    None => by_domain.push((domain, vec![i])),
+                }
+            }
+            Err(e) => invalid.push((i, e)),
+        }
+    }
+    (by_domain, invalid)
+}
+
+/// Recipient domains whose mail goes to the same MX host and therefore over one connection
+pub(crate) struct MxGroup {
+    pub mx_records: Vec<MxRecord>,
+    pub domains: DomainGroups,
+}
+
+/// Adds `domain` to the group of its most preferred MX host, e.g. all domains hosted
+/// by the same provider end up in one group. Groups keep the order of first appearance.
+pub(crate) fn add_to_mx_group(groups: &mut Vec<MxGroup>, mx_records: Vec<MxRecord>, domain: String, indices: Vec<usize>) {
+    let host = primary_host(&mx_records);
+    match groups.iter_mut().find(|group| primary_host(&group.mx_records) == host) {
+        Some(group) => group.domains.push((domain, indices)),
+        None => groups.push(MxGroup { mx_records, domains: vec![(domain, indices)] }),
+    }
+}
+
+/// Name of the host in `records` that is tried first, normalized for comparison
+pub(crate) fn primary_host(records: &[MxRecord]) -> Option<String> {
+    records.first()
+        .map(|mx| mx.server.trim_end_matches('.').to_lowercase())
 }
@@ -0,0 +1,41 @@
+//! Hooks for counting delivery outcomes
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// Receives counts and timings of what happens during sends, e.g. to update Prometheus
+/// counters and histograms without parsing transcripts. Install with
+/// [`Config::metrics`](crate::Config::metrics).
+///
+/// Every method does nothing by default, so an implementation only overrides what it
+/// records. Methods are called on the sending thread or task and should not block.
+pub trait Metrics: fmt::Debug + Send + Sync {
+    /// A connection attempt to `host` (MX host, address or socket path) succeeded after
+    /// `Ok(elapsed)` or failed with `Err(error)`
+    fn connect_attempt(&self, _host: &str, _result: Result<Duration, &Error>) {}
+
+    /// A STARTTLS handshake finished, successfully after `Ok(elapsed)` or with `Err(error)`
+    fn tls_handshake(&self, _result: Result<Duration, &Error>) {}
+
+    /// The server rejected the AUTH credentials
+    fn auth_failed(&self, _error: &Error) {}
+
+    /// The server for `domain` accepted the message for `recipients` recipients
+    fn delivered(&self, _domain: &str, _recipients: usize) {}
+
+    /// A send failed temporarily and is tried again after `retry_in`
+    fn deferred(&self, _error: &Error, _retry_in: Duration) {}
+
+    /// `bytes` more were written to the server, commands and message content alike
+    fn bytes_sent(&self, _bytes: usize) {}
+}
+
+/// Calls `record` with the installed metrics, if any
+pub(crate) fn with(metrics: &Option<Arc<dyn Metrics>>, record: impl FnOnce(&dyn Metrics)) {
+    if let Some(metrics) = metrics {
+        record(metrics.as_ref());
+    }
+}
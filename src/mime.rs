@@ -0,0 +1,153 @@
+//! Minimal MIME parser for inspecting formatted and received messages
+
+/// One entity of a MIME message: its headers, raw body and, for multipart
+/// entities, the parsed sub-parts.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Part {
+    /// Unfolded headers in order of appearance
+    pub headers: Vec<(String, String)>,
+    /// Body with LF line endings, not transfer-decoded
+    pub body: String,
+    /// Sub-parts of a multipart entity
+    pub parts: Vec<Part>,
+}
+
+impl Part {
+    /// Parses a complete message or entity, accepting both CRLF and bare LF line endings
+    pub fn parse(raw: &str) -> Part {
+        let raw = raw.replace("\r\n", "\n");
+        let (head, body) = match raw.find("\n\n") {
+            Some(pos) => (&raw[..pos], &raw[pos + 2..]),
+            None if raw.starts_with('\n') => ("", &raw[1..]),
+            None => (raw.as_str(), ""),
+        };
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in head.lines() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let mut part = Part { headers, body: body.to_string(), parts: Vec::new() };
+        if part.mime_type().starts_with("multipart/") {
+            if let Some(boundary) = part.header("Content-Type").and_then(|ct| header_param(ct, "boundary")) {
+                part.parts = split_multipart(body, &boundary).iter().map(|p| Part::parse(p)).collect();
+            }
+        }
+        part
+    }
+
+    /// First value of the header `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// All values of the header `name` (case-insensitive)
+    pub fn headers_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Lowercased media type without parameters, `text/plain` if none is given
+    pub fn mime_type(&self) -> String {
+        self.header("Content-Type")
+            .and_then(|ct| ct.split(';').next())
+            .map(|t| t.trim().to_lowercase())
+            .unwrap_or_else(|| "text/plain".to_string())
+    }
+
+    /// File name from Content-Disposition (RFC 2231 encoded or plain), falling back to the
+    /// Content-Type `name` parameter
+    pub fn filename(&self) -> Option<String> {
+        self.header("Content-Disposition")
+            .and_then(|cd| extended_param(cd, "filename").or_else(|| header_param(cd, "filename")))
+            .or_else(|| self.header("Content-Type").and_then(|ct| header_param(ct, "name")))
+    }
+
+    /// This part followed by all nested parts, depth first
+    pub fn walk(&self) -> Vec<&Part> {
+        let mut all = vec![self];
+        for part in &self.parts {
+            all.extend(part.walk());
+        }
+        all
+    }
+}
+
+/// Value of the parameter `name` in a structured header like `text/plain; charset="utf-8"`
+pub(crate) fn header_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, val) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(val.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Value of the RFC 2231 parameter `name*=charset'lang'value` or its continuations
+/// `name*0*=...; name*1*=...`, percent-decoded as UTF-8
+pub(crate) fn extended_param(value: &str, name: &str) -> Option<String> {
+    let single = header_param(value, &format!("{}*", name));
+    let encoded = match single {
+        Some(encoded) => encoded,
+        None => {
+            let mut sections = Vec::new();
+            while let Some(section) = header_param(value, &format!("{}*{}*", name, sections.len()))
+                .or_else(|| header_param(value, &format!("{}*{}", name, sections.len())))
+            {
+                sections.push(section);
+            }
+            if sections.is_empty() { return None; }
+            sections.concat()
+        }
+    };
+    // Charset and language come first, only UTF-8 (and its ASCII subset) is supported
+    let (_, text) = encoded.split_once('\'').and_then(|(_, rest)| rest.split_once('\''))?;
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let [first, tail @ ..] = rest {
+        match (first, tail) {
+            (b'%', [high, low, after @ ..]) => {
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok()?);
+                rest = after;
+            }
+            _ => {
+                bytes.push(*first);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Splits a multipart body into its raw entities, dropping preamble and epilogue
+fn split_multipart(body: &str, boundary: &str) -> Vec<String> {
+    let delimiter = format!("--{}", boundary);
+    let closing = format!("{}--", delimiter);
+    let mut parts = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in body.lines() {
+        let trimmed = line.trim_end();
+        if trimmed == delimiter || trimmed == closing {
+            if let Some(lines) = current.take() {
+                parts.push(lines.join("\n"));
+            }
+            if trimmed == closing {
+                break;
+            }
+            current = Some(Vec::new());
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    parts
+}
@@ -3,7 +3,7 @@
 use neon::prelude::*;
 use std::sync::{Arc, Mutex};
 
-use crate::{Config, Error, Mail, Mailer};
+use crate::{Config, Error, Mail, Mailer, TlsPolicy};
 
 /// Node.js wrapper for Config
 struct JsConfig {
@@ -75,7 +75,7 @@ fn js_config_set_use_tls(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let config = cx.argument::<JsBox<JsConfig>>(0)?;
     let use_tls = cx.argument::<JsBoolean>(1)?.value(&mut cx);
     
-    config.inner.use_tls = use_tls;
+    config.inner.tls_policy = if use_tls { TlsPolicy::Opportunistic } else { TlsPolicy::None };
     
     Ok(cx.undefined())
 }
@@ -202,9 +202,8 @@ fn js_mailer_send(mut cx: FunctionContext) -> JsResult<JsBoolean> {
 /// Send a mail asynchronously using a Mailer
 /// Note: This "async" implementation currently uses `std::thread::spawn`
 /// to run the blocking `send_sync` method in a separate thread.
-/// It does not leverage a full async Rust runtime (e.g., Tokio) directly within Neon's event loop
-/// for the send operation itself, as the underlying `AsyncMailer` in the core library
-/// also uses `tokio::task::spawn_blocking`.
+/// It does not run a Tokio runtime within Neon's event loop, so the native
+/// async path of `AsyncMailer` is not used here.
 fn js_mailer_send_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let mailer = cx.argument::<JsBox<JsMailer>>(0)?;
     let mail = cx.argument::<JsBox<JsMail>>(1)?;
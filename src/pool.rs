@@ -0,0 +1,330 @@
+//! Pool of warm SMTP connections for high-throughput sending
+//!
+//! [`AsyncMailerPool`] keeps connections open after a send, already past EHLO,
+//! STARTTLS and AUTH, and hands them to the next send for the same MX host (or
+//! smarthost). Before a connection is reused it is checked with NOOP; one that
+//! went stale is dropped and replaced by a fresh connection without the caller
+//! noticing. [`AsyncMailerPool::keepalive`] also pings idle connections
+//! periodically, so servers with short idle timers don't close them in between,
+//! and [`AsyncMailerPool::idle_timeout`] closes connections that sat unused for too long.
+//!
+//! A server may still drop a session right after answering NOOP. If a reused
+//! connection fails with 421 or a dead socket before the message was sent, the
+//! transaction is repeated once on a fresh connection.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    async_io::AsyncConnection,
+    async_mail::{self, AsyncMailSender},
+    config::Config,
+    error::{Error, SmtpPhase},
+    mail::{self, Mail, MxGroup},
+    report::SendReport,
+    shutdown::Drain,
+    trace::{self, Instrument},
+    transcript::SharedRecorder,
+};
+
+struct PoolInner {
+    config: Config,
+    max_conns: usize,
+    /// One permit per open connection, whether idle or in use
+    permits: Arc<Semaphore>,
+    /// Connections waiting for their next send, by MX host, oldest first
+    idle: Mutex<Vec<IdleConnection>>,
+    /// Wakes the sends waiting for a connection when one is returned to the pool
+    returned: Notify,
+    /// Number of [`AsyncMailerPool::close`] calls, so a ping that had a connection out
+    /// while the pool was closed closes it as well
+    closes: AtomicU64,
+    /// How long a connection may wait before it is closed (None = until it goes stale)
+    idle_timeout: Mutex<Option<Duration>>,
+    /// Sends in flight, for [`AsyncMailerPool::shutdown`]
+    drain: Arc<Drain>,
+}
+
+struct IdleConnection {
+    host: String,
+    connection: AsyncConnection,
+    since: Instant,
+    permit: OwnedSemaphorePermit,
+}
+
+/// Sends mail over at most `max_conns` connections, reusing them between sends
+///
+/// Clones share the pool. Sends are not retried, wrap the pool in a queue for that.
+/// The transcript of a send is not kept.
+#[derive(Clone)]
+pub struct AsyncMailerPool {
+    inner: Arc<PoolInner>,
+}
+
+impl AsyncMailerPool {
+    /// A pool of up to `max_conns` connections (at least one) opened with `config`
+    pub fn new(config: Config, max_conns: usize) -> Self {
+        let max_conns = max_conns.max(1);
+        Self {
+            inner: Arc::new(PoolInner {
+                config,
+                max_conns,
+                permits: Arc::new(Semaphore::new(max_conns)),
+                idle: Mutex::new(Vec::new()),
+                returned: Notify::new(),
+                closes: AtomicU64::new(0),
+                idle_timeout: Mutex::new(None),
+                drain: Arc::default(),
+            }),
+        }
+    }
+
+    /// Most connections the pool keeps open
+    pub fn max_conns(&self) -> usize {
+        self.inner.max_conns
+    }
+
+    /// Connections currently open and waiting for a send
+    pub fn idle_conns(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// Delivers `mail` like [`AsyncMailer`](crate::AsyncMailer), but on pooled connections.
+    /// Waits while all `max_conns` connections are busy; idle connections to other hosts
+    /// are closed to make room.
+    pub async fn send(&self, mail: Mail) -> Result<SendReport, Error> {
+        let _in_flight = self.inner.drain.enter()?;
+        self.deliver(mail).instrument(trace::send_span()).await
+    }
+
+    async fn deliver(&self, mail: Mail) -> Result<SendReport, Error> {
+        let config = &self.inner.config;
+        let recorder = SharedRecorder::default();
+        let deadline = config.deadline.map(|limit| Instant::now() + limit);
+        let mut prepared = mail::prepare_send(config, &recorder, mail)?;
+        let domains = prepared.by_domain.iter().map(|(domain, _)| domain.clone()).collect::<Vec<_>>();
+        let resolved = async_mail::resolve_ahead(config, &domains).await;
+        let mut groups = Vec::new();
+        for (domain, indices) in prepared.by_domain {
+            let mx_records = mail::resolve_mx(&resolved, &recorder, &domain)?;
+            mail::add_to_mx_group(&mut groups, mx_records, domain, indices);
+        }
+        let mut report = SendReport::default();
+        for group in groups {
+            let host = mail::primary_host(&group.mx_records).unwrap_or_default();
+            let (mut connection, permit, mut reused) = self.checkout(&host, &resolved, &recorder, deadline, &group).await?;
+            for (domain, indices) in &group.domains {
+                let domain_recipients = indices.iter().map(|&i| prepared.recipients[i].clone()).collect::<Vec<_>>();
+                let mut result = async_mail::transaction(&mut connection, domain, &prepared.from, &domain_recipients, &mut prepared.content, &|| {}).await;
+                if reused && result.as_ref().is_err_and(|e| went_stale(e, connection.phase)) {
+                    connection = async_mail::open_session(config, &resolved, &recorder, deadline, &group).await?;
+                    result = async_mail::transaction(&mut connection, domain, &prepared.from, &domain_recipients, &mut prepared.content, &|| {}).await;
+                }
+                // Once a transaction went through, the session is known to be alive
+                reused = false;
+                match result {
+                    Ok(transaction) => report.transactions.push(transaction),
+                    Err(e) => {
+                        // Only a server that answered can take the connection back to a clean state
+                        if matches!(e, Error::SmtpError { .. }) && reset(&mut connection).await {
+                            self.release(host, connection, permit).await;
+                        } else if !matches!(e, Error::Timeout { .. } | Error::Stalled { .. }) {
+                            async_mail::quit(&mut connection).await;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            self.release(host, connection, permit).await;
+        }
+        report.message = mail::sent_message(config, prepared.content);
+        Ok(report)
+    }
+
+    /// Pings the idle connections with NOOP every `interval` from a background task.
+    /// The task ends once the last clone of the pool is dropped.
+    ///
+    /// Must be called from within a tokio runtime. Panics if `interval` is zero.
+    pub fn keepalive(self, interval: Duration) -> Self {
+        let pool = Arc::downgrade(&self.inner);
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        tokio::spawn(async move {
+            loop {
+                ticks.tick().await;
+                let Some(inner) = pool.upgrade() else { break };
+                AsyncMailerPool { inner }.ping().await;
+            }
+        });
+        self
+    }
+
+    /// Closes connections that waited longer than `timeout` for a send, the next time
+    /// the pool is used or pinged. Servers drop idle sessions on their own after a few
+    /// minutes (RFC 5321 suggests 5), so a shorter timeout avoids reusing dead ones.
+    pub fn idle_timeout(self, timeout: Duration) -> Self {
+        *self.inner.idle_timeout.lock().unwrap() = Some(timeout);
+        self
+    }
+
+    /// Sends NOOP on every idle connection and closes the ones that no longer answer,
+    /// returning how many are still open.
+    ///
+    /// Connections are taken out of the pool one at a time, so sends keep using the others
+    /// in the meantime.
+    pub async fn ping(&self) -> usize {
+        self.close_expired().await;
+        let closes = self.inner.closes.load(Ordering::SeqCst);
+        let count = self.inner.idle.lock().unwrap().len();
+        let mut alive = 0;
+        for _ in 0..count {
+            // The oldest first, each goes back to the end of the pool
+            let entry = {
+                let mut idle = self.inner.idle.lock().unwrap();
+                (!idle.is_empty()).then(|| idle.remove(0))
+            };
+            let Some(mut entry) = entry else { break };
+            // Nobody reads the transcript of a ping, and the last send's deadline has long passed
+            entry.connection.recorder = SharedRecorder::default();
+            entry.connection.deadline = None;
+            if !noop(&mut entry.connection).await {
+                continue;
+            }
+            if self.inner.closes.load(Ordering::SeqCst) != closes || self.inner.drain.is_closed() {
+                async_mail::quit(&mut entry.connection).await;
+                continue;
+            }
+            self.checkin(entry);
+            alive += 1;
+        }
+        alive
+    }
+
+    /// Sends QUIT on all idle connections and closes them. Connections in use are
+    /// returned to the pool as usual.
+    pub async fn close(&self) {
+        self.inner.closes.fetch_add(1, Ordering::SeqCst);
+        let idle = std::mem::take(&mut *self.inner.idle.lock().unwrap());
+        for mut entry in idle {
+            async_mail::quit(&mut entry.connection).await;
+        }
+    }
+
+    /// Stop taking new sends, wait up to `deadline` for the ones in flight to finish their
+    /// transactions, then QUIT every connection. Later sends fail with [`Error::ShuttingDown`].
+    ///
+    /// Connections still busy at the deadline are QUIT by their send once it ends.
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), Error> {
+        let drained = self.inner.drain.close(deadline).await;
+        self.close().await;
+        drained
+    }
+
+    /// Sends QUIT on the idle connections that outlived the idle timeout and closes them
+    async fn close_expired(&self) {
+        let Some(timeout) = *self.inner.idle_timeout.lock().unwrap() else { return };
+        let expired = {
+            let mut idle = self.inner.idle.lock().unwrap();
+            let (expired, fresh) = std::mem::take(&mut *idle).into_iter().partition::<Vec<_>, _>(|entry| entry.since.elapsed() > timeout);
+            *idle = fresh;
+            expired
+        };
+        for mut entry in expired {
+            entry.connection.recorder = SharedRecorder::default();
+            entry.connection.deadline = None;
+            async_mail::quit(&mut entry.connection).await;
+        }
+    }
+
+    /// An idle connection to `host` that still answers NOOP, or a new one, with the permit
+    /// it holds and whether the connection was reused
+    async fn checkout(&self, host: &str, resolved: &Config, recorder: &SharedRecorder, deadline: Option<Instant>, group: &MxGroup) -> Result<(AsyncConnection, OwnedSemaphorePermit, bool), Error> {
+        self.close_expired().await;
+        let permit = loop {
+            // Registered before looking, so a connection returned in between still wakes us
+            let returned = self.inner.returned.notified();
+            tokio::pin!(returned);
+            returned.as_mut().enable();
+            let entry = {
+                let mut idle = self.inner.idle.lock().unwrap();
+                idle.iter().position(|entry| entry.host == host).map(|i| idle.remove(i))
+            };
+            if let Some(mut entry) = entry {
+                entry.connection.recorder = recorder.clone();
+                entry.connection.deadline = deadline;
+                if noop(&mut entry.connection).await {
+                    return Ok((entry.connection, entry.permit, true));
+                }
+                continue;
+            }
+            if let Ok(permit) = self.inner.permits.clone().try_acquire_owned() {
+                break permit;
+            }
+            // Make room by closing the longest-idle connection, which goes to another host
+            let evicted = {
+                let mut idle = self.inner.idle.lock().unwrap();
+                (!idle.is_empty()).then(|| idle.remove(0))
+            };
+            if let Some(mut entry) = evicted {
+                entry.connection.recorder = SharedRecorder::default();
+                entry.connection.deadline = None;
+                async_mail::quit(&mut entry.connection).await;
+                break entry.permit;
+            }
+            // Every connection is in use, wait for one to be closed or returned
+            tokio::select! {
+                permit = self.inner.permits.clone().acquire_owned() => break permit.map_err(|_| Error::ConnectionFailed)?,
+                _ = returned => {}
+            }
+        };
+        Ok((async_mail::open_session(&self.inner.config, resolved, recorder, deadline, group).await?, permit, false))
+    }
+
+    /// Returns a connection after a send, or QUITs it if the pool is shutting down
+    async fn release(&self, host: String, mut connection: AsyncConnection, permit: OwnedSemaphorePermit) {
+        if self.inner.drain.is_closed() {
+            async_mail::quit(&mut connection).await;
+        } else {
+            self.checkin(IdleConnection { host, connection, since: Instant::now(), permit });
+        }
+    }
+
+    fn checkin(&self, entry: IdleConnection) {
+        self.inner.idle.lock().unwrap().push(entry);
+        self.inner.returned.notify_waiters();
+    }
+}
+
+#[async_trait]
+impl AsyncMailSender for AsyncMailerPool {
+    async fn send(&mut self, mail: Mail) -> Result<SendReport, Error> {
+        AsyncMailerPool::send(self, mail).await
+    }
+}
+
+/// Whether the server still answers on `connection`
+async fn noop(connection: &mut AsyncConnection) -> bool {
+    connection.enter_phase(SmtpPhase::Ehlo);
+    connection.send("NOOP\r\n").await.is_ok() && connection.read().await.is_ok_and(|reply| reply.code == 250)
+}
+
+/// Whether `e`, which ended a transaction on a reused connection in `phase`, means the
+/// server had dropped the session: a 421 or a dead socket before the message went out
+fn went_stale(e: &Error, phase: SmtpPhase) -> bool {
+    let before_data = matches!(phase, SmtpPhase::MailFrom | SmtpPhase::RcptTo | SmtpPhase::DataInit);
+    before_data && match e {
+        Error::SmtpError { reply, .. } => reply.code == 421,
+        // A closed socket reads as an empty, unparsable reply
+        Error::IoError(_) | Error::Other(_) => true,
+        _ => false,
+    }
+}
+
+/// Aborts the transaction in progress, returning whether the server accepted the RSET
+async fn reset(connection: &mut AsyncConnection) -> bool {
+    connection.enter_phase(SmtpPhase::MailFrom);
+    connection.send("RSET\r\n").await.is_ok() && connection.read().await.is_ok_and(|reply| reply.code == 250)
+}
@@ -1,14 +1,15 @@
 //! Python bindings for the micromail crate
 
 use pyo3::prelude::*;
-use pyo3::exceptions::{PyRuntimeError, PyValueError};
-use pyo3::types::{PyDict, PyList};
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyValueError};
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
 use pyo3::create_exception;
 
-use crate::{Config, Error, Mail, Mailer};
+use crate::{Attachment, Config, Error, Mail, Mailer, SendFailure, TlsPolicy, Transcript, TranscriptEvent};
 
-#[cfg(feature = "tokio-runtime")]
-use crate::async_mail::AsyncMailSender; // For AsyncMailer::send
 #[cfg(feature = "tokio-runtime")]
 use crate::AsyncMailer;
 
@@ -23,20 +24,112 @@ fn micromail(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyAsyncMailer>()?;
 
     // Custom Error types
-    m.add("MicromailError", _py.get_type::<PyRuntimeError>())?; // Changed to get_type
-    m.add("MicromailSmtpError", _py.get_type::<MicromailSmtpError>())?; // Changed to get_type
-    m.add("MicromailAuthError", _py.get_type::<MicromailAuthError>())?; // Changed to get_type
+    m.add("MicromailError", _py.get_type::<MicromailError>())?;
+    m.add("MicromailSmtpError", _py.get_type::<MicromailSmtpError>())?;
+    m.add("MicromailTransientError", _py.get_type::<MicromailTransientError>())?;
+    m.add("MicromailPermanentError", _py.get_type::<MicromailPermanentError>())?;
+    m.add("MicromailAuthError", _py.get_type::<MicromailAuthError>())?;
+    m.add("MicromailTlsError", _py.get_type::<MicromailTlsError>())?;
+    m.add("MicromailTimeoutError", _py.get_type::<MicromailTimeoutError>())?;
     
     Ok(())
 }
 
-// Define custom Python exception types
-create_exception!(micromail, MicromailSmtpError, PyRuntimeError);
-create_exception!(micromail, MicromailAuthError, PyRuntimeError);
+// Define custom Python exception types. Every failed send raises a MicromailError
+// (a RuntimeError) carrying `code`, `enhanced`, `phase` and `transcript` attributes.
+create_exception!(micromail, MicromailError, PyRuntimeError);
+create_exception!(micromail, MicromailSmtpError, MicromailError);
+create_exception!(micromail, MicromailTransientError, MicromailSmtpError);
+create_exception!(micromail, MicromailPermanentError, MicromailSmtpError);
+create_exception!(micromail, MicromailAuthError, MicromailError);
+create_exception!(micromail, MicromailTlsError, MicromailError);
+create_exception!(micromail, MicromailTimeoutError, MicromailError);
+
+/// Maps a failed send to the Python exception for it, with the details of the error
+/// and the transcript of the send as attributes
+fn to_py_err(failure: SendFailure) -> PyErr {
+    let SendFailure { error, transcript } = failure;
+    let (code, phase) = match &error {
+        Error::SmtpError { phase, reply } => (Some(reply.code), Some(phase.to_string())),
+        Error::AuthError { code, .. } => (*code, None),
+        Error::Timeout { phase, .. } | Error::Stalled { phase, .. } => (None, Some(phase.to_string())),
+        _ => (None, None),
+    };
+    let enhanced = error.enhanced_status().map(|status| status.to_string());
+    let err = match &error {
+        Error::SmtpError { phase, reply } => {
+            let args = (reply.code, format!("{}: {}", phase, reply.text()));
+            if reply.is_transient() { MicromailTransientError::new_err(args) } else { MicromailPermanentError::new_err(args) }
+        }
+        Error::AuthError { code, message, .. } => {
+            MicromailAuthError::new_err((code.map(|c| c.to_string()).unwrap_or_else(|| "N/A".to_string()), message.clone()))
+        }
+        Error::TlsError(_) => MicromailTlsError::new_err(format!("Failed to send mail: {}", error)),
+        Error::Timeout { .. } | Error::Stalled { .. } => MicromailTimeoutError::new_err(format!("Failed to send mail: {}", error)),
+        _ => MicromailError::new_err(format!("Failed to send mail: {}", error)),
+    };
+    Python::with_gil(|py| {
+        let value = err.value(py);
+        let attributes = [
+            ("code", code.into_py(py)),
+            ("enhanced", enhanced.into_py(py)),
+            ("phase", phase.into_py(py)),
+            ("transcript", transcript_to_list(py, &transcript).unwrap_or_else(|_| PyList::empty(py)).to_object(py)),
+        ];
+        for (name, attribute) in attributes {
+            // Attributes of a fresh exception instance can always be set
+            let _ = value.setattr(name, attribute);
+        }
+    });
+    err
+}
+
+/// The transcript as a list of dicts, each with the `time` in seconds since the epoch, a
+/// `type` and the fields of the event like in `Transcript::to_json`
+fn transcript_to_list<'py>(py: Python<'py>, transcript: &Transcript) -> PyResult<&'py PyList> {
+    let list = PyList::empty(py);
+    for entry in transcript.entries() {
+        let dict = PyDict::new(py);
+        dict.set_item("time", entry.time.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()))?;
+        match &entry.event {
+            TranscriptEvent::Note(message) => {
+                dict.set_item("type", "note")?;
+                dict.set_item("message", message)?;
+            }
+            TranscriptEvent::Connected { address } => {
+                dict.set_item("type", "connected")?;
+                dict.set_item("address", address.to_string())?;
+            }
+            TranscriptEvent::TlsNegotiated(info) => {
+                dict.set_item("type", "tls")?;
+                dict.set_item("protocol_version", info.protocol_version.map(|v| v.to_string()))?;
+                dict.set_item("cipher_suite", &info.cipher_suite)?;
+                dict.set_item("resumed", info.resumed)?;
+            }
+            TranscriptEvent::TlsEstablished => dict.set_item("type", "tls_established")?,
+            TranscriptEvent::CommandSent(command) => {
+                dict.set_item("type", "command")?;
+                dict.set_item("command", command)?;
+            }
+            TranscriptEvent::ResponseReceived { code, lines } => {
+                dict.set_item("type", "response")?;
+                dict.set_item("code", code)?;
+                dict.set_item("lines", lines)?;
+            }
+            TranscriptEvent::DataSent { bytes, content } => {
+                dict.set_item("type", "data")?;
+                dict.set_item("bytes", bytes)?;
+                dict.set_item("content", content)?;
+            }
+        }
+        list.append(dict)?;
+    }
+    Ok(list)
+}
 
 
 /// Python wrapper for Config
-#[pyclass]
+#[pyclass(name = "Config")]
 struct PyConfig {
     inner: Config,
 }
@@ -61,7 +154,7 @@ impl PyConfig {
     /// Set whether to use TLS
     #[pyo3(text_signature = "($self, use_tls)")]
     fn use_tls(&mut self, use_tls: bool) -> PyResult<()> {
-        self.inner.use_tls = use_tls;
+        self.inner.tls_policy = if use_tls { TlsPolicy::Opportunistic } else { TlsPolicy::None };
         Ok(())
     }
     
@@ -112,19 +205,58 @@ impl PyConfig {
 }
 
 /// Python wrapper for Mail
-#[pyclass]
+#[pyclass(name = "Mail")]
 struct PyMail {
     inner: Mail,
 }
 
 #[pymethods]
 impl PyMail {
-    /// Create a new mail
+    /// Create a new mail, with any of the fields `from_addr`, `to`, `cc`, `bcc`, `subject`,
+    /// `body`, `html`, `content_type`, `headers`, `message_id` and `attachments` as keyword
+    /// arguments. Recipients can be given as a string or a list of strings.
     #[new]
-    fn new() -> Self {
-        Self {
-            inner: Mail::new(),
+    #[pyo3(signature = (**fields))]
+    fn new(fields: Option<&PyDict>) -> PyResult<Self> {
+        let mut mail = Self { inner: Mail::new() };
+        if let Some(fields) = fields {
+            mail.update(fields)?;
         }
+        Ok(mail)
+    }
+
+    /// Create a mail from a dict with the keys `Mail()` takes as keyword arguments,
+    /// e.g. one returned by `to_dict`
+    #[staticmethod]
+    #[pyo3(text_signature = "(fields)")]
+    fn from_dict(fields: &PyDict) -> PyResult<Self> {
+        Self::new(Some(fields))
+    }
+
+    /// The fields of the mail as a dict that `from_dict` accepts
+    #[pyo3(text_signature = "($self)")]
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let mail = &self.inner;
+        let dict = PyDict::new(py);
+        dict.set_item("from_addr", &mail.from)?;
+        dict.set_item("to", crate::utils::split_address_list(&mail.to))?;
+        dict.set_item("cc", &mail.cc)?;
+        dict.set_item("bcc", &mail.bcc)?;
+        dict.set_item("subject", &mail.subject)?;
+        dict.set_item("body", &mail.body)?;
+        dict.set_item("content_type", &mail.content_type)?;
+        dict.set_item("headers", mail.headers.clone())?;
+        dict.set_item("message_id", &mail.message_id)?;
+        let attachments = PyList::empty(py);
+        for attachment in &mail.attachments {
+            let item = PyDict::new(py);
+            item.set_item("filename", &attachment.filename)?;
+            item.set_item("content_type", &attachment.content_type)?;
+            item.set_item("data", PyBytes::new(py, &attachment.data))?;
+            attachments.append(item)?;
+        }
+        dict.set_item("attachments", attachments)?;
+        Ok(dict)
     }
     
     /// Set the from address
@@ -162,14 +294,42 @@ impl PyMail {
         Ok(())
     }
     
+    /// Set an HTML body, replacing the body and content type
+    #[pyo3(text_signature = "($self, html)")]
+    fn html(&mut self, html: &str) -> PyResult<()> {
+        self.inner.body = html.to_string();
+        self.inner.content_type = "text/html; charset=utf-8".to_string();
+        Ok(())
+    }
+
     /// Add a header
     #[pyo3(text_signature = "($self, name, value)")]
     fn add_header(&mut self, name: &str, value: &str) -> PyResult<()> {
         self.inner.headers.insert(name.to_string(), value.to_string());
         Ok(())
     }
+
+    /// Attach `data` as a file named `filename`, the content type is guessed if not given
+    #[pyo3(signature = (filename, data, content_type=None), text_signature = "($self, filename, data, content_type=None)")]
+    fn attach(&mut self, filename: &str, data: &[u8], content_type: Option<&str>) -> PyResult<()> {
+        let mut attachment = Attachment::new(filename, data.to_vec());
+        if let Some(content_type) = content_type {
+            attachment = attachment.content_type(content_type);
+        }
+        self.inner.attachments.push(attachment);
+        Ok(())
+    }
+
+    /// Attach the file at `path`, named after the file
+    #[pyo3(text_signature = "($self, path)")]
+    fn attach_file(&mut self, path: &str) -> PyResult<()> {
+        let attachment = Attachment::from_file(path).map_err(|e| PyOSError::new_err(format!("Failed to read {}: {}", path, e)))?;
+        self.inner.attachments.push(attachment);
+        Ok(())
+    }
     
-    /// Get the from address
+    /// Get the from address. Subject, body and content type have no getters, the methods
+    /// setting them take their names; they are in `to_dict()`.
     #[getter]
     fn get_from(&self) -> String {
         self.inner.from.clone()
@@ -181,24 +341,12 @@ impl PyMail {
         self.inner.to.clone()
     }
     
-    /// Get the subject
+    /// Get the file names and content types of the attachments
     #[getter]
-    fn get_subject(&self) -> String {
-        self.inner.subject.clone()
+    fn get_attachments(&self) -> Vec<(String, String)> {
+        self.inner.attachments.iter().map(|a| (a.filename.clone(), a.content_type.clone())).collect()
     }
-    
-    /// Get the body
-    #[getter]
-    fn get_body(&self) -> String {
-        self.inner.body.clone()
-    }
-    
-    /// Get the content type
-    #[getter]
-    fn get_content_type(&self) -> String {
-        self.inner.content_type.clone()
-    }
-    
+
     /// Get all headers
     #[getter]
     fn get_headers<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
@@ -228,8 +376,57 @@ impl PyMail {
     }
 }
 
+impl PyMail {
+    /// Sets the fields named by the keys of `fields`, skipping `None` values
+    fn update(&mut self, fields: &PyDict) -> PyResult<()> {
+        let mail = &mut self.inner;
+        for (key, value) in fields {
+            if value.is_none() {
+                continue;
+            }
+            match key.extract::<&str>()? {
+                "from_addr" => mail.from = value.extract()?,
+                "to" => mail.to = addresses(value)?.join(", "),
+                "cc" => mail.cc = addresses(value)?,
+                "bcc" => mail.bcc = addresses(value)?,
+                "subject" => mail.subject = value.extract()?,
+                "body" => mail.body = value.extract()?,
+                "html" => {
+                    mail.body = value.extract()?;
+                    mail.content_type = "text/html; charset=utf-8".to_string();
+                }
+                "content_type" => mail.content_type = value.extract()?,
+                "headers" => mail.headers.extend(value.extract::<HashMap<String, String>>()?),
+                "message_id" => mail.message_id = Some(value.extract()?),
+                "attachments" => {
+                    for item in value.extract::<Vec<&PyDict>>()? {
+                        let field = |name: &str| item.get_item(name).ok().flatten().filter(|v| !v.is_none());
+                        let filename: String = field("filename").ok_or_else(|| PyValueError::new_err("Attachment without a filename"))?.extract()?;
+                        let data: &[u8] = field("data").ok_or_else(|| PyValueError::new_err(format!("Attachment {} without data", filename)))?.extract()?;
+                        let mut attachment = Attachment::new(filename, data.to_vec());
+                        if let Some(content_type) = field("content_type") {
+                            attachment = attachment.content_type(content_type.extract::<String>()?);
+                        }
+                        mail.attachments.push(attachment);
+                    }
+                }
+                other => return Err(PyValueError::new_err(format!("Unknown mail field: {}", other))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Recipients given as one string or a list of strings
+fn addresses(value: &PyAny) -> PyResult<Vec<String>> {
+    match value.extract::<String>() {
+        Ok(address) => Ok(vec![address]),
+        Err(_) => value.extract(),
+    }
+}
+
 /// Python wrapper for Mailer
-#[pyclass]
+#[pyclass(name = "Mailer")]
 struct PyMailer {
     inner: Mailer,
 }
@@ -247,15 +444,7 @@ impl PyMailer {
     /// Send a mail
     #[pyo3(text_signature = "($self, mail)")]
     fn send(&mut self, mail: &PyMail) -> PyResult<()> {
-        self.inner.send_sync(mail.inner.clone()).map_err(|e| match e {
-            Error::SmtpError { code, message } => {
-                MicromailSmtpError::new_err((code, message))
-            }
-            Error::AuthError { code, message } => {
-                MicromailAuthError::new_err((code.map(|c| c.to_string()).unwrap_or_else(|| "N/A".to_string()), message))
-            }
-            _ => PyRuntimeError::new_err(format!("Failed to send mail: {}", e)),
-        })
+        self.inner.send_traced(mail.inner.clone()).map(|_| ()).map_err(to_py_err)
     }
     
     /// Get the log messages
@@ -266,6 +455,12 @@ impl PyMailer {
         Ok(list)
     }
     
+    /// Get the transcript of the last send as a list of dicts, see `MicromailError.transcript`
+    #[pyo3(text_signature = "($self)")]
+    fn transcript<'py>(&self, py: Python<'py>) -> PyResult<&'py PyList> {
+        transcript_to_list(py, &self.inner.transcript())
+    }
+
     /// Clear the log messages
     #[pyo3(text_signature = "($self)")]
     fn clear_log(&mut self) -> PyResult<()> {
@@ -276,7 +471,7 @@ impl PyMailer {
 
 /// Python wrapper for AsyncMailer
 #[cfg(feature = "tokio-runtime")]
-#[pyclass]
+#[pyclass(name = "AsyncMailer")]
 struct PyAsyncMailer {
     inner: AsyncMailer,
 }
@@ -292,25 +487,15 @@ impl PyAsyncMailer {
         }
     }
     
-    /// Send a mail asynchronously
+    /// Send a mail, returning an awaitable. The send runs on the async SMTP client in
+    /// the tokio runtime, so no thread is blocked and concurrent sends can be gathered.
     #[pyo3(text_signature = "($self, mail)")]
-    fn send<'py>(&mut self, py: Python<'py>, mail: &PyMail) -> PyResult<&'py PyAny> {
-        let mail_clone = mail.inner.clone();
-        // self.inner is AsyncMailer, which is now Clone.
-        // The send method on AsyncMailer takes &mut self, but the Mailer within is Arc<Mutex<Mailer>>
-        // So, we clone the AsyncMailer (which clones the Arc) for this specific async operation.
-        let mut mailer_for_send = self.inner.clone();
-        
+    fn send<'py>(&self, py: Python<'py>, mail: &PyMail) -> PyResult<&'py PyAny> {
+        let mail = mail.inner.clone();
+        // A clone shares the Mailer, so the log of the send stays visible through self
+        let mut mailer = self.inner.clone();
         pyo3_asyncio::tokio::future_into_py(py, async move {
-            mailer_for_send.send(mail_clone).await.map_err(|e| match e {
-                Error::SmtpError { code, message } => {
-                    MicromailSmtpError::new_err((code, message))
-                }
-                Error::AuthError { code, message } => {
-                    MicromailAuthError::new_err((code.map(|c| c.to_string()).unwrap_or_else(|| "N/A".to_string()), message))
-                }
-                _ => PyRuntimeError::new_err(format!("Failed to send mail: {}", e)),
-            })
+            mailer.send_traced(mail).await.map(|_| ()).map_err(to_py_err)
         })
     }
 
@@ -324,6 +509,14 @@ impl PyAsyncMailer {
         Ok(list)
     }
 
+    /// Get the transcript of the last send as a list of dicts, see `MicromailError.transcript`
+    #[pyo3(text_signature = "($self)")]
+    fn transcript<'py>(&self, py: Python<'py>) -> PyResult<&'py PyList> {
+        let mailer_arc = self.inner.mailer(); // Gets Arc<Mutex<Mailer>>
+        let locked_mailer = mailer_arc.lock().map_err(|e| PyRuntimeError::new_err(format!("Failed to lock mailer: {}", e)))?;
+        transcript_to_list(py, &locked_mailer.transcript())
+    }
+
     /// Clear the log messages
     #[pyo3(text_signature = "($self)")]
     fn clear_log(&mut self) -> PyResult<()> {
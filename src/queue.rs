@@ -0,0 +1,861 @@
+//! In-memory delivery queue with per-domain backoff
+//!
+//! [`MailQueue`] accepts mail, delivers it through an [`AsyncMailer`] and keeps
+//! transiently failed messages around for another attempt. When a destination
+//! domain keeps answering with temporary (4xx) failures, the whole domain is
+//! backed off: none of its queued mail is attempted until the backoff expires,
+//! after which messages are released a few at a time until one is accepted again.
+//! A message goes by the domains of all its envelope recipients (To, Cc and Bcc),
+//! and once some of them took it, retries only go to the recipients still waiting.
+//!
+//! Messages travel in [`Priority`] lanes: transactional mail is always attempted
+//! before bulk mail, and each lane can be given its own [`RateLimit`], so a
+//! newsletter blast neither delays nor eats the budget of password resets.
+//!
+//! Every step of a message's lifecycle is published as a [`DeliveryEvent`],
+//! see [`MailQueue::events`].
+//!
+//! Queued mail lives in memory unless the queue is given a
+//! [`QueueStore`](crate::store::QueueStore), which keeps it across restarts.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use tokio::sync::{broadcast, mpsc, Notify};
+
+use crate::{
+    async_mail::{AsyncMailer, Outgoing, Progress},
+    config::Config,
+    error::Error,
+    mail::Mail,
+    metrics::{self, Metrics},
+    report::SendReport,
+    shutdown::Drain,
+    store::{Attempt, QueueStore, StoredJob},
+    utils,
+};
+
+/// Identifier of a queued message
+pub type JobId = u64;
+
+/// Delivery state of a queued message
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobStatus {
+    /// Waiting for its first attempt
+    Pending,
+    /// Failed temporarily, will be retried
+    Deferred { attempts: u32, last_error: String },
+    /// Accepted by the receiving server
+    Delivered,
+    /// Failed permanently or ran out of attempts
+    Failed(String),
+    /// Recovered from a [`QueueStore`] after the process stopped while the server was
+    /// answering the message. It may have been delivered, so it is held back until
+    /// [`MailQueue::resolve`] decides.
+    PossiblyDelivered,
+}
+
+/// Lane of a queued message. Lanes are drained in this order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Mail someone is waiting for, like password resets and receipts
+    #[default]
+    Transactional,
+    /// Newsletters and other mass mail
+    Bulk,
+}
+
+/// At most `messages` delivery attempts in any window of `per`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+impl RateLimit {
+    pub fn new(messages: u32, per: Duration) -> Self { Self { messages, per } }
+}
+
+/// Result of [`MailQueue::enqueue_idempotent`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submission {
+    /// The job holding the mail
+    pub id: JobId,
+    /// Its current status
+    pub status: JobStatus,
+    /// Whether the key was already known and no new job was created
+    pub duplicate: bool,
+}
+
+/// Snapshot of a message in the [`MailQueue`], see [`MailQueue::list`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedMail {
+    pub id: JobId,
+    /// Envelope recipient
+    pub recipient: String,
+    pub priority: Priority,
+    pub status: JobStatus,
+    /// When the next attempt is due, None if none will be made. A backed off domain
+    /// or a rate limit can push the attempt later still.
+    pub next_attempt: Option<SystemTime>,
+}
+
+/// A step in the delivery lifecycle of a queued message
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryEvent {
+    /// The message was accepted into the queue
+    Enqueued { id: JobId, recipient: String },
+    /// A delivery attempt is starting (`attempt` counts from 1)
+    Attempt { id: JobId, attempt: u32 },
+    /// The attempt failed temporarily, the message will be retried after `retry_in`.
+    /// `code` is the SMTP reply code, if the server sent one.
+    Deferred { id: JobId, attempts: u32, error: String, code: Option<u16>, retry_in: Duration },
+    /// The receiving server accepted the message
+    Delivered { id: JobId, attempts: u32 },
+    /// The receiving server rejected the message permanently, with the SMTP reply `code`
+    /// unless the failure happened before a server answered
+    Failed { id: JobId, error: String, code: Option<u16> },
+    /// The message left the queue undelivered and will not be attempted again
+    DeadLettered { id: JobId, reason: String },
+}
+
+/// Buffered events per subscriber before the slowest one starts missing events
+const EVENT_CAPACITY: usize = 1024;
+
+/// Delivered and failed messages kept by default, see [`MailQueue::keep_finished`]
+const KEEP_FINISHED: usize = 1000;
+
+/// Retry and per-domain backoff settings for a [`MailQueue`]
+#[derive(Debug, Clone)]
+pub struct BackoffPolicy {
+    /// Attempts per message before it is given up
+    pub max_attempts: u32,
+    /// Delay before retrying a single message after a temporary failure. A wait the server
+    /// names in its reply ([`Error::retry_after`]) replaces this and `greylist_delay`, up
+    /// to `domain_max_delay`.
+    pub retry_delay: Duration,
+    /// Delay before retrying a message the server greylisted, instead of `retry_delay`.
+    /// Greylisting does not count towards the domain backoff.
+    pub greylist_delay: Duration,
+    /// Consecutive temporary failures from a domain before the whole domain is backed off
+    pub domain_threshold: u32,
+    /// Backoff applied the first time a domain trips the threshold, doubled on each repeat
+    pub domain_base_delay: Duration,
+    /// Upper bound for the domain backoff
+    pub domain_max_delay: Duration,
+    /// Messages released per processing pass while a domain is recovering
+    pub release_batch: usize,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(60),
+            greylist_delay: Duration::from_secs(5 * 60),
+            domain_threshold: 3,
+            domain_base_delay: Duration::from_secs(60),
+            domain_max_delay: Duration::from_secs(60 * 60),
+            release_batch: 1,
+        }
+    }
+}
+
+struct Job {
+    mail: Mail,
+    /// The idempotency key it was submitted with
+    key: Option<String>,
+    /// Envelope recipients with their lowercased domain, which the backoff goes by
+    recipients: Vec<(String, String)>,
+    /// Recipients that got the mail in an attempt that failed for others
+    delivered: Vec<String>,
+    priority: Priority,
+    attempts: u32,
+    next_attempt: Instant,
+    status: JobStatus,
+}
+
+impl Job {
+    /// A freshly submitted message, due at its `send_at`
+    fn new(mail: Mail, recipients: Vec<(String, String)>, priority: Priority) -> Self {
+        let next_attempt = due_at(mail.send_at);
+        Self { mail, key: None, recipients, delivered: Vec::new(), priority, attempts: 0, next_attempt, status: JobStatus::Pending }
+    }
+
+    /// Domains of the recipients still waiting for the mail
+    fn pending_domains(&self) -> Vec<String> {
+        let mut domains = Vec::new();
+        for (recipient, domain) in &self.recipients {
+            if !self.delivered.contains(recipient) && !domains.contains(domain) {
+                domains.push(domain.clone());
+            }
+        }
+        domains
+    }
+}
+
+/// What [`MailQueue::claim`] took from the rate limit and the release batches for an attempt
+struct Reservation {
+    priority: Priority,
+    /// The start time pushed into the lane, if it is rate limited
+    started: Option<Instant>,
+    /// Recovering domains whose release batch counted the attempt
+    released: Vec<String>,
+}
+
+/// Backoff bookkeeping for one destination domain
+#[derive(Default)]
+struct DomainState {
+    consecutive_failures: u32,
+    level: u32,
+    blocked_until: Option<Instant>,
+    recovering: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: HashMap<JobId, Job>,
+    next_id: JobId,
+    domains: HashMap<String, DomainState>,
+    idempotency_keys: HashMap<String, JobId>,
+    /// Submissions being written to the store, they count against the capacity
+    storing: usize,
+    /// Idempotency keys of those submissions
+    storing_keys: HashSet<String>,
+    /// Start times of the attempts within the rate limit window, per lane
+    lanes: HashMap<Priority, VecDeque<Instant>>,
+    /// Delivered and failed jobs, oldest first, evicted beyond [`MailQueue::keep_finished`]
+    finished: VecDeque<JobId>,
+}
+
+/// Queue that delivers mail in the background and retries temporary failures
+#[derive(Clone)]
+pub struct MailQueue {
+    mailer: AsyncMailer,
+    policy: BackoffPolicy,
+    rate_limits: HashMap<Priority, RateLimit>,
+    state: Arc<Mutex<QueueState>>,
+    events: broadcast::Sender<DeliveryEvent>,
+    /// Subscribers that must see every event however far behind they are, e.g. webhooks
+    sinks: Arc<Mutex<Vec<mpsc::UnboundedSender<DeliveryEvent>>>>,
+    /// The metrics of the mailer's config, told about deferrals
+    metrics: Option<Arc<dyn Metrics>>,
+    /// Durable copy of the queued mail, which also hands out the job IDs
+    store: Option<Arc<dyn QueueStore>>,
+    /// Attempts in flight, for [`MailQueue::shutdown`]
+    drain: Arc<Drain>,
+    /// Most messages waiting at once (None = unbounded)
+    capacity: Option<usize>,
+    /// Wakes producers waiting for room when a message leaves the queue
+    room: Arc<Notify>,
+    /// Most delivered and failed messages kept for `status` and `list`
+    keep_finished: usize,
+}
+
+impl MailQueue {
+    /// Create a queue delivering through a new mailer with the given configuration
+    pub fn new(config: Config) -> Self {
+        Self::with_mailer(AsyncMailer::new(config))
+    }
+
+    /// Create a queue delivering through an existing mailer
+    pub fn with_mailer(mailer: AsyncMailer) -> Self {
+        let metrics = mailer.mailer().lock().unwrap().config().metrics.clone();
+        Self {
+            metrics,
+            mailer,
+            policy: BackoffPolicy::default(),
+            rate_limits: HashMap::new(),
+            state: Arc::new(Mutex::new(QueueState::default())),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            sinks: Arc::default(),
+            store: None,
+            drain: Arc::default(),
+            capacity: None,
+            room: Arc::default(),
+            keep_finished: KEEP_FINISHED,
+        }
+    }
+
+    /// Keep the queued mail in `store`, and queue the messages it still holds from a
+    /// previous run. Their schedule starts over: mail is due at its last retry time.
+    ///
+    /// Every attempt is journaled in the store. A message the previous run was sending
+    /// when it stopped is retried if its content had not gone out completely, and
+    /// becomes [`JobStatus::PossiblyDelivered`] otherwise.
+    pub fn store<S: QueueStore + 'static>(mut self, store: S) -> Result<Self, Error> {
+        let jobs = store.list()?;
+        {
+            let mut state = self.state.lock().unwrap();
+            for stored in jobs {
+                let StoredJob { id, mail, priority, attempts, last_error, retry_at, attempt, delivered, idempotency_key } = stored;
+                let recipients = recipient_domains(&mail)?;
+                let status = match (attempt, attempts) {
+                    (Attempt::ContentSent, _) => JobStatus::PossiblyDelivered,
+                    (_, 0) => JobStatus::Pending,
+                    _ => JobStatus::Deferred { attempts, last_error: last_error.unwrap_or_default() },
+                };
+                let next_attempt = due_at(retry_at.or(mail.send_at));
+                insert_job(&mut state, id, idempotency_key.as_deref(), Job { mail, key: None, recipients, delivered, priority, attempts, next_attempt, status });
+            }
+        }
+        let store: Arc<dyn QueueStore> = Arc::new(store);
+        self.store = Some(store);
+        Ok(self)
+    }
+
+    /// Replace the retry and backoff settings
+    pub fn backoff_policy(mut self, policy: BackoffPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Hold at most `capacity` undelivered messages. When the queue is full, [`enqueue`](Self::enqueue)
+    /// waits for room and [`try_enqueue`](Self::try_enqueue) fails with [`Error::QueueFull`],
+    /// so producers slow down instead of piling up mail while a provider is down.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Keep the last `count` delivered and failed messages (1000 by default) for
+    /// [`status`](Self::status), [`list`](Self::list) and idempotent resubmission, and
+    /// forget older ones so a long-running queue does not grow without bound
+    pub fn keep_finished(mut self, count: usize) -> Self {
+        self.keep_finished = count;
+        self
+    }
+
+    /// Limit the delivery attempts of the `priority` lane. Mail over the limit stays
+    /// queued for a later pass; the other lanes are not affected.
+    pub fn rate_limit(mut self, priority: Priority, limit: RateLimit) -> Self {
+        self.rate_limits.insert(priority, limit);
+        self
+    }
+
+    /// POST the outcome of every delivery attempt to `webhook` from a background task.
+    /// Must be called from within a tokio runtime.
+    #[cfg(feature = "http-api")]
+    pub fn webhook(self, webhook: crate::webhook::Webhook) -> Self {
+        let (sink, events) = mpsc::unbounded_channel();
+        self.sinks.lock().unwrap().push(sink);
+        webhook.spawn(events);
+        self
+    }
+
+    /// Subscribe to the lifecycle events of all messages in this queue.
+    ///
+    /// The stream only sees events published after this call. A subscriber that
+    /// falls more than 1024 events behind skips the oldest ones, with a warning in
+    /// the log, instead of slowing down delivery.
+    pub fn events(&self) -> impl Stream<Item = DeliveryEvent> {
+        futures::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!(target: "micromail", "queue event subscriber fell behind, skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Add a mail to the queue, it is attempted on the next processing pass
+    /// (or the first one after its [`Mail::send_at`] time)
+    /// in the [`Priority::Transactional`] lane. Waits while the queue is at its [`capacity`](Self::capacity).
+    pub async fn enqueue(&self, mail: Mail) -> Result<JobId, Error> {
+        self.enqueue_with_priority(mail, Priority::default()).await
+    }
+
+    /// Add a mail to the queue in the lane of `priority`, waiting for room if it is full
+    pub async fn enqueue_with_priority(&self, mail: Mail, priority: Priority) -> Result<JobId, Error> {
+        self.submit(None, mail, priority).await.map(|submission| submission.id)
+    }
+
+    /// Add a mail to the queue like [`enqueue`](Self::enqueue), but fail with
+    /// [`Error::QueueFull`] instead of waiting when the queue is at its capacity
+    pub fn try_enqueue(&self, mail: Mail) -> Result<JobId, Error> {
+        self.try_submit(None, &mut Some(mail), Priority::default()).map(|submission| submission.id)
+    }
+
+    /// Add a mail to the queue unless a mail with the same `key` was submitted before.
+    ///
+    /// Resubmitting a key returns the existing job and its current status instead of
+    /// queueing the mail a second time, which makes the call safe to repeat when the
+    /// caller itself is being retried (e.g. behind an HTTP endpoint). The key is
+    /// forgotten with its job once that is evicted, see [`keep_finished`](Self::keep_finished).
+    pub async fn enqueue_idempotent<K: Into<String>>(&self, key: K, mail: Mail) -> Result<Submission, Error> {
+        self.submit(Some(key.into()), mail, Priority::default()).await
+    }
+
+    async fn submit(&self, key: Option<String>, mail: Mail, priority: Priority) -> Result<Submission, Error> {
+        let mut mail = Some(mail);
+        loop {
+            // Registered before trying, so room made in between still wakes us
+            let room = self.room.notified();
+            match self.try_submit(key.as_deref(), &mut mail, priority) {
+                Err(Error::QueueFull) => room.await,
+                result => return result,
+            }
+        }
+    }
+
+    /// Queues the mail, taking it out of `mail`, unless the queue is full. A full queue
+    /// leaves `mail` in place for the next try. So does a submission whose key is still
+    /// being stored by another call, which the caller waits for like for room.
+    ///
+    /// The store is written with the state unlocked, a reservation holds the place of the
+    /// message meanwhile.
+    fn try_submit(&self, key: Option<&str>, mail: &mut Option<Mail>, priority: Priority) -> Result<Submission, Error> {
+        if self.drain.is_closed() {
+            return Err(Error::ShuttingDown);
+        }
+        let recipient = mail.as_ref().map(|mail| mail.to.clone()).unwrap_or_default();
+        let recipients = recipient_domains(mail.as_ref().expect("a mail to submit"))?;
+        let (mail, store) = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(existing) = key.and_then(|key| state.idempotency_keys.get(key)) {
+                if let Some(job) = state.jobs.get(existing) {
+                    return Ok(Submission { id: *existing, status: job.status.clone(), duplicate: true });
+                }
+            }
+            if key.is_some_and(|key| state.storing_keys.contains(key)) {
+                return Err(Error::QueueFull);
+            }
+            if self.capacity.is_some_and(|capacity| state.storing + state.jobs.values().filter(|job| is_undelivered(&job.status)).count() >= capacity) {
+                return Err(Error::QueueFull);
+            }
+            let mail = mail.take().expect("a mail to submit");
+            match &self.store {
+                Some(store) => {
+                    state.storing += 1;
+                    if let Some(key) = key {
+                        state.storing_keys.insert(key.to_string());
+                    }
+                    (mail, store)
+                }
+                None => {
+                    let id = state.next_id;
+                    state.next_id += 1;
+                    insert_job(&mut state, id, key, Job::new(mail, recipients, priority));
+                    drop(state);
+                    self.emit(DeliveryEvent::Enqueued { id, recipient });
+                    return Ok(Submission { id, status: JobStatus::Pending, duplicate: false });
+                }
+            }
+        };
+        let stored = store.enqueue(&mail, priority, key);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.storing -= 1;
+            if let Some(key) = key {
+                state.storing_keys.remove(key);
+            }
+            if let Ok(id) = stored {
+                insert_job(&mut state, id, key, Job::new(mail, recipients, priority));
+            }
+        }
+        // Wakes submissions of the same key, and producers waiting for the room a failed
+        // write gave back
+        self.room.notify_waiters();
+        let id = stored?;
+        self.emit(DeliveryEvent::Enqueued { id, recipient });
+        Ok(Submission { id, status: JobStatus::Pending, duplicate: false })
+    }
+
+    /// Current status of a queued message
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.state.lock().unwrap().jobs.get(&id).map(|job| job.status.clone())
+    }
+
+    /// Every message the queue knows about, including the delivered and failed ones kept
+    /// by [`keep_finished`](Self::keep_finished), in ID order
+    pub fn list(&self) -> Vec<QueuedMail> {
+        let now = (Instant::now(), SystemTime::now());
+        let state = self.state.lock().unwrap();
+        let mut list = state.jobs.iter()
+            .map(|(id, job)| QueuedMail {
+                id: *id,
+                recipient: job.mail.to.clone(),
+                priority: job.priority,
+                status: job.status.clone(),
+                next_attempt: is_waiting(&job.status).then(|| now.1 + job.next_attempt.saturating_duration_since(now.0)),
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|mail| mail.id);
+        list
+    }
+
+    /// Make a pending or deferred message due now, for the next processing pass.
+    /// Returns false if the message is not waiting for delivery.
+    pub fn retry_now(&self, id: JobId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.jobs.get_mut(&id) {
+            Some(job) if is_waiting(&job.status) => {
+                job.next_attempt = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove a message from the queue and its store, whatever its status. An attempt
+    /// already in flight still completes. Returns false if the message is unknown.
+    pub fn delete(&self, id: JobId) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(job) = state.jobs.remove(&id) else { return false };
+            if let Some(key) = job.key {
+                state.idempotency_keys.remove(&key);
+            }
+        }
+        self.update_store(id, |store| store.ack(id));
+        self.room.notify_waiters();
+        true
+    }
+
+    /// The message as an RFC 5322 `.eml` file, e.g. to inspect or resend dead-lettered
+    /// mail by hand. Date and Message-ID are generated now unless the mail sets them.
+    pub fn export_eml(&self, id: JobId) -> Option<String> {
+        let mail = self.state.lock().unwrap().jobs.get(&id)?.mail.clone();
+        let mailer = self.mailer.mailer();
+        let formatted = mail.format(mailer.lock().unwrap().config());
+        Some(formatted)
+    }
+
+    /// Number of messages still waiting for delivery
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().jobs.values().filter(|job| is_waiting(&job.status)).count()
+    }
+
+    /// Whether no messages are waiting for delivery
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Settle a [`JobStatus::PossiblyDelivered`] message, e.g. after looking up its
+    /// Message-ID in the receiving server's logs: `delivered` marks it delivered,
+    /// otherwise it is retried on the next pass, at the risk of a duplicate.
+    /// Returns false if the message was not waiting for this decision.
+    pub fn resolve(&self, id: JobId, delivered: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let job = match state.jobs.get_mut(&id) {
+            Some(job) if job.status == JobStatus::PossiblyDelivered => job,
+            _ => return false,
+        };
+        if delivered {
+            job.status = JobStatus::Delivered;
+            self.update_store(id, |store| store.ack(id));
+            self.room.notify_waiters();
+            retire(&mut state, id, self.keep_finished);
+        } else {
+            job.attempts += 1;
+            let last_error = "possibly delivered before the queue stopped".to_string();
+            self.update_store(id, |store| store.nack(id, &last_error, Utc::now()));
+            job.status = JobStatus::Deferred { attempts: job.attempts, last_error };
+            job.next_attempt = Instant::now();
+        }
+        true
+    }
+
+    /// If the domain is currently backed off, the time remaining until it is retried
+    pub fn domain_backoff(&self, domain: &str) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let blocked_until = state.domains.get(&domain.to_lowercase())?.blocked_until?;
+        blocked_until.checked_duration_since(Instant::now())
+    }
+
+    /// Attempt every message that is due, whose domain is not backed off and whose
+    /// lane is within its rate limit, higher priority lanes first.
+    ///
+    /// Domains are re-checked before every attempt, so a domain that trips its
+    /// backoff midway through a pass holds back the rest of its mail immediately.
+    /// Returns the number of delivery attempts made.
+    pub async fn process_due(&self) -> usize {
+        let now = Instant::now();
+        let mut released = HashMap::new();
+        let mut attempted = 0;
+        for id in self.due_ids(now) {
+            // A shutdown ends the pass, the remaining mail stays queued
+            let Ok(_in_flight) = self.drain.enter() else { break };
+            let (mail, delivered, reservation) = match self.claim(id, now, &mut released) {
+                Some(claimed) => claimed,
+                None => continue,
+            };
+            if let Some(store) = &self.store {
+                let dequeued = store.dequeue(id).unwrap_or_else(|e| {
+                    log::warn!(target: "micromail", "queue store failed to dequeue job {}: {}", id, e);
+                    None
+                });
+                // Missing if it was removed from the store behind the queue's back. No
+                // attempt is made, so the lane and the release batches get their slot back.
+                if dequeued.is_none() {
+                    self.unclaim(reservation, &mut released);
+                    continue;
+                }
+            }
+            attempted += 1;
+            self.emit(DeliveryEvent::Attempt { id, attempt: self.attempts(id) + 1 });
+            let content_sent = || self.update_store(id, |store| store.content_sent(id));
+            let mut report = SendReport::default();
+            let progress = Progress { delivered: &delivered, content_sent: &content_sent };
+            let result = self.mailer.send_observed(Outgoing::Mail(&mail), progress, &mut report).await;
+            let newly_delivered = report.recipients().map(|status| status.recipient.clone()).collect();
+            self.record_result(id, result, newly_delivered);
+        }
+        attempted
+    }
+
+    /// Process the queue, checking for due messages every `poll_interval`, until it
+    /// is shut down
+    pub async fn run(&self, poll_interval: Duration) {
+        while !self.drain.is_closed() {
+            self.process_due().await;
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Stop taking new mail and starting attempts, wait up to `deadline` for the
+    /// attempts in flight to finish, then flush the [`QueueStore`] if there is one.
+    /// Later submissions fail with [`Error::ShuttingDown`].
+    ///
+    /// Queued mail stays in the store for the next run. Without a store it is lost,
+    /// see [`MailQueue::len`] for how much.
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), Error> {
+        let closing = self.drain.close(deadline);
+        // Producers waiting for room find the queue closed instead
+        self.room.notify_waiters();
+        let drained = closing.await;
+        if let Some(store) = &self.store {
+            store.flush()?;
+        }
+        drained
+    }
+
+    fn attempts(&self, id: JobId) -> u32 {
+        self.state.lock().unwrap().jobs.get(&id).map_or(0, |job| job.attempts)
+    }
+
+    fn emit(&self, event: DeliveryEvent) {
+        self.sinks.lock().unwrap().retain(|sink| sink.send(event.clone()).is_ok());
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Apply a change to the store, if there is one. A failing store is logged and does
+    /// not hold up delivery; the in-memory queue stays authoritative for this run.
+    fn update_store(&self, id: JobId, update: impl FnOnce(&dyn QueueStore) -> Result<(), Error>) {
+        if let Some(store) = &self.store {
+            if let Err(e) = update(store.as_ref()) {
+                log::warn!(target: "micromail", "queue store failed to update job {}: {}", id, e);
+            }
+        }
+    }
+
+    fn due_ids(&self, now: Instant) -> Vec<JobId> {
+        let state = self.state.lock().unwrap();
+        let mut ids = state.jobs.iter()
+            .filter(|(_, job)| is_waiting(&job.status) && job.next_attempt <= now)
+            .map(|(id, job)| (job.priority, *id))
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Returns the mail for `id`, the recipients that already have it and the slot taken
+    /// for the attempt if the domains of the others and the lane currently admit one
+    fn claim(&self, id: JobId, now: Instant, released: &mut HashMap<String, usize>) -> Option<(Mail, Vec<String>, Reservation)> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let job = state.jobs.get(&id)?;
+        let domains = job.pending_domains();
+        let blocked = |domain: &DomainState| domain.blocked_until.is_some_and(|until| until > now);
+        if domains.iter().any(|domain| state.domains.get(domain).is_some_and(blocked)) {
+            return None;
+        }
+        let limit = self.rate_limits.get(&job.priority);
+        let lane = state.lanes.entry(job.priority).or_default();
+        if let Some(limit) = limit {
+            while lane.front().is_some_and(|&started| now.duration_since(started) >= limit.per) {
+                lane.pop_front();
+            }
+            if lane.len() >= limit.messages as usize {
+                return None;
+            }
+        }
+        let recovering = domains.into_iter()
+            .filter(|domain| state.domains.get(domain).is_some_and(|domain| domain.recovering))
+            .collect::<Vec<_>>();
+        if recovering.iter().any(|domain| released.get(domain).is_some_and(|&count| count >= self.policy.release_batch)) {
+            return None;
+        }
+        for domain in &recovering {
+            *released.entry(domain.clone()).or_default() += 1;
+        }
+        if limit.is_some() {
+            lane.push_back(now);
+        }
+        let reservation = Reservation { priority: job.priority, started: limit.map(|_| now), released: recovering };
+        Some((job.mail.clone(), job.delivered.clone(), reservation))
+    }
+
+    /// Gives back what `claim` reserved for an attempt that was not made
+    fn unclaim(&self, reservation: Reservation, released: &mut HashMap<String, usize>) {
+        for domain in &reservation.released {
+            if let Some(count) = released.get_mut(domain) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        let Some(started) = reservation.started else { return };
+        let mut state = self.state.lock().unwrap();
+        if let Some(lane) = state.lanes.get_mut(&reservation.priority) {
+            if let Some(position) = lane.iter().rposition(|&time| time == started) {
+                lane.remove(position);
+            }
+        }
+    }
+
+    /// Settles an attempt in which the transactions to `delivered` went through
+    fn record_result(&self, id: JobId, result: Result<(), Error>, delivered: Vec<String>) {
+        let now = Instant::now();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let job = match state.jobs.get_mut(&id) {
+            Some(job) => job,
+            None => return,
+        };
+        job.attempts += 1;
+        // Domains that took the mail are healthy again
+        for (_, domain) in job.recipients.iter().filter(|(recipient, _)| delivered.contains(recipient)) {
+            state.domains.remove(domain);
+        }
+
+        match result {
+            Ok(()) => {
+                job.status = JobStatus::Delivered;
+                self.update_store(id, |store| store.ack(id));
+                self.room.notify_waiters();
+                self.emit(DeliveryEvent::Delivered { id, attempts: job.attempts });
+            }
+            Err(e) if e.is_transient() => {
+                // Only the recipients that don't have the mail yet are retried
+                if !delivered.is_empty() {
+                    self.update_store(id, |store| store.delivered(id, &delivered));
+                    job.delivered.extend(delivered);
+                }
+                let retry_in = match e.retry_after() {
+                    Some(hint) => hint.min(self.policy.domain_max_delay),
+                    None if e.is_greylisting() => self.policy.greylist_delay,
+                    None => self.policy.retry_delay,
+                };
+                if job.attempts >= self.policy.max_attempts {
+                    let reason = format!("giving up after {} attempts: {}", job.attempts, e);
+                    job.status = JobStatus::Failed(reason.clone());
+                    self.update_store(id, |store| store.ack(id));
+                    self.room.notify_waiters();
+                    self.emit(DeliveryEvent::DeadLettered { id, reason });
+                } else {
+                    job.status = JobStatus::Deferred { attempts: job.attempts, last_error: e.to_string() };
+                    let retry_at = Utc::now() + chrono::Duration::from_std(retry_in).unwrap_or_else(|_| chrono::Duration::zero());
+                    self.update_store(id, |store| store.nack(id, &e.to_string(), retry_at));
+                    metrics::with(&self.metrics, |m| m.deferred(&e, retry_in));
+                    self.emit(DeliveryEvent::Deferred {
+                        id,
+                        attempts: job.attempts,
+                        error: e.to_string(),
+                        code: e.reply().map(|reply| reply.code),
+                        retry_in,
+                    });
+                }
+                job.next_attempt = now + retry_in;
+
+                // The failure counts against every domain still waiting for the mail, a
+                // greylisting server is doing as it should
+                let pending = if e.is_greylisting() { Vec::new() } else { job.pending_domains() };
+                for domain in pending {
+                    let domain = state.domains.entry(domain).or_default();
+                    domain.consecutive_failures += 1;
+                    // A domain that fails again while recovering goes straight back into backoff
+                    if domain.recovering || domain.consecutive_failures >= self.policy.domain_threshold {
+                        let delay = self.policy.domain_base_delay
+                            .saturating_mul(1u32 << domain.level.min(16))
+                            .min(self.policy.domain_max_delay);
+                        domain.level += 1;
+                        domain.consecutive_failures = 0;
+                        domain.blocked_until = Some(now + delay);
+                        domain.recovering = true;
+                    }
+                }
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed(e.to_string());
+                self.update_store(id, |store| store.ack(id));
+                self.room.notify_waiters();
+                self.emit(DeliveryEvent::Failed { id, error: e.to_string(), code: e.reply().map(|reply| reply.code) });
+                self.emit(DeliveryEvent::DeadLettered { id, reason: e.to_string() });
+            }
+        }
+        if state.jobs.get(&id).is_some_and(|job| !is_undelivered(&job.status)) {
+            retire(state, id, self.keep_finished);
+        }
+    }
+}
+
+/// The envelope recipients of `mail` with their lowercased domain
+fn recipient_domains(mail: &Mail) -> Result<Vec<(String, String)>, Error> {
+    let recipients = mail.envelope_recipients();
+    if recipients.is_empty() {
+        return Err(Error::InvalidMailContent("Mail has no recipients".to_string()));
+    }
+    recipients.into_iter()
+        .map(|recipient| {
+            let domain = utils::extract_domain(&recipient)?.to_lowercase();
+            Ok((recipient, domain))
+        })
+        .collect()
+}
+
+/// Adds a submitted job, remembering its idempotency key
+fn insert_job(state: &mut QueueState, id: JobId, key: Option<&str>, mut job: Job) {
+    job.key = key.map(str::to_string);
+    state.jobs.insert(id, job);
+    if let Some(key) = key {
+        state.idempotency_keys.insert(key.to_string(), id);
+    }
+}
+
+/// Records that `id` was delivered or failed, and forgets the oldest finished jobs
+/// beyond the `keep` most recent, with their idempotency keys
+fn retire(state: &mut QueueState, id: JobId, keep: usize) {
+    state.finished.push_back(id);
+    while state.finished.len() > keep {
+        let Some(oldest) = state.finished.pop_front() else { break };
+        // Deleted by hand in the meantime, or retried after all
+        if state.jobs.get(&oldest).is_none_or(|job| is_undelivered(&job.status)) {
+            continue;
+        }
+        if let Some(key) = state.jobs.remove(&oldest).and_then(|job| job.key) {
+            state.idempotency_keys.remove(&key);
+        }
+    }
+}
+
+/// The instant a mail to be sent at `send_at` is due, now if that time has passed
+fn due_at(send_at: Option<DateTime<Utc>>) -> Instant {
+    let now = Instant::now();
+    send_at.and_then(|time| (time - Utc::now()).to_std().ok()).map_or(now, |delay| now + delay)
+}
+
+fn is_waiting(status: &JobStatus) -> bool {
+    matches!(status, JobStatus::Pending | JobStatus::Deferred { .. })
+}
+
+/// Whether the message still takes up room in the queue
+fn is_undelivered(status: &JobStatus) -> bool {
+    is_waiting(status) || *status == JobStatus::PossiblyDelivered
+}
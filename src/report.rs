@@ -0,0 +1,135 @@
+//! What the receiving servers answered during a send
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::capabilities::Capabilities;
+use crate::error::EnhancedStatus;
+use crate::tls::TlsInfo;
+
+/// The server's answer to one RCPT TO
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipientStatus {
+    /// Envelope address
+    pub recipient: String,
+    /// Reply code to RCPT TO
+    pub code: u16,
+    /// Reply text
+    pub message: String,
+    pub enhanced: Option<EnhancedStatus>,
+    /// Whether the server accepted the recipient (2xx)
+    pub accepted: bool,
+}
+
+impl RecipientStatus {
+    /// How long the server asked to wait before retrying a deferred (4xx) recipient,
+    /// see [`SmtpReply::retry_after`](crate::SmtpReply::retry_after)
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !(400..500).contains(&self.code) { return None; }
+        crate::error::retry_hint(&self.message)
+    }
+}
+
+/// One mail transaction, i.e. the recipients of one domain delivered together
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionReport {
+    /// Recipient domain the transaction delivered to
+    pub domain: String,
+    /// Replies to every RCPT TO, in envelope order
+    pub recipients: Vec<RecipientStatus>,
+    /// Reply code after the end of DATA
+    pub data_code: u16,
+    /// Reply text after the end of DATA
+    pub data_message: String,
+    /// Queue ID the server assigned to the message, for correlating with its logs
+    pub queue_id: Option<String>,
+    /// What the server advertised in its EHLO reply (None when not sent over SMTP)
+    pub capabilities: Option<Capabilities>,
+}
+
+/// What [`Mailer::verify_connection`](crate::Mailer::verify_connection) found out about a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionCheck {
+    /// MX host that answered (None for a relay given by address)
+    pub mx_host: Option<String>,
+    pub address: SocketAddr,
+    /// What the server advertised in its last EHLO reply
+    pub capabilities: Capabilities,
+    /// Whether the session was encrypted with STARTTLS
+    pub secure: bool,
+    /// The negotiated TLS session (None without STARTTLS and in test mode)
+    pub tls: Option<TlsInfo>,
+    /// Whether the configured credentials were accepted (false if there are none)
+    pub authenticated: bool,
+}
+
+/// Outcome of a successful send.
+///
+/// A send is successful once every transaction delivered the message to at least one
+/// recipient; individual recipients may still have been rejected, see [`rejected`](Self::rejected).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SendReport {
+    /// One entry per recipient domain, in the order the domains first appear in the envelope
+    pub transactions: Vec<TransactionReport>,
+    /// The message exactly as transmitted, DKIM-Signature included, e.g. to file it
+    /// in a "Sent" folder. Only kept with [`Config::keep_sent_message`](crate::Config::keep_sent_message),
+    /// empty otherwise and if the transport doesn't send a MIME message (SendGrid).
+    pub message: Vec<u8>,
+}
+
+impl SendReport {
+    /// Replies to every RCPT TO across all transactions
+    pub fn recipients(&self) -> impl Iterator<Item = &RecipientStatus> {
+        self.transactions.iter().flat_map(|t| t.recipients.iter())
+    }
+
+    /// Recipients the servers accepted
+    pub fn accepted(&self) -> impl Iterator<Item = &RecipientStatus> {
+        self.recipients().filter(|r| r.accepted)
+    }
+
+    /// Recipients the servers turned down while the message went to the others
+    pub fn rejected(&self) -> impl Iterator<Item = &RecipientStatus> {
+        self.recipients().filter(|r| !r.accepted)
+    }
+
+    /// Queue ID of the first transaction, the only one when all recipients share a domain
+    pub fn queue_id(&self) -> Option<&str> {
+        self.transactions.iter().find_map(|t| t.queue_id.as_deref())
+    }
+
+    /// Whether every recipient was accepted
+    pub fn all_accepted(&self) -> bool {
+        self.recipients().all(|r| r.accepted)
+    }
+}
+
+/// Extracts the queue ID an MTA put into its reply to the end of DATA.
+///
+/// Recognizes the formats of the common MTAs: `queued as 4Bx1` (Postfix),
+/// `id=1a2b3c-000abc-XY` (Exim), `InternalId=123` (Exchange),
+/// `x9ABC012345 Message accepted for delivery` (Sendmail) and
+/// `OK 1700000000 d2si123.45 - gsmtp` (Gmail).
+pub fn parse_queue_id(reply_text: &str) -> Option<String> {
+    let words = reply_text.split_whitespace().collect::<Vec<_>>();
+    let clean = |word: &str| Some(word.trim_matches(|c: char| ",;[]()<>".contains(c)).to_string()).filter(|id| !id.is_empty());
+
+    if let Some(pos) = words.windows(2).position(|w| w[0].eq_ignore_ascii_case("queued") && w[1].eq_ignore_ascii_case("as")) {
+        return words.get(pos + 2).and_then(|id| clean(id));
+    }
+    for word in &words {
+        let word = word.trim_start_matches('[');
+        for key in ["id=", "InternalId="] {
+            if word.get(..key.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(key)) {
+                return clean(&word[key.len()..]);
+            }
+        }
+    }
+    if let Some(pos) = words.windows(3).position(|w| w[1].eq_ignore_ascii_case("Message") && w[2].eq_ignore_ascii_case("accepted")) {
+        return clean(words[pos]);
+    }
+    if words.last().is_some_and(|w| w.eq_ignore_ascii_case("gsmtp")) && words.len() >= 4 {
+        return clean(words[words.len() - 3]);
+    }
+    None
+}
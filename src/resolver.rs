@@ -0,0 +1,946 @@
+//! Pluggable DNS resolution
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tokio-runtime")]
+use async_trait::async_trait;
+
+use crate::dane::TlsaRecord;
+use crate::dns::MxRecord;
+use crate::error::{Error, SmtpPhase};
+
+/// Records returned by a [`Resolver`], with how long they may be cached
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsAnswer<T> {
+    /// The records found, empty if the name exists but has none of the requested type
+    /// or does not exist at all
+    pub records: Vec<T>,
+    /// Lowest TTL of the records (ignored for empty answers, which are cached for the
+    /// cache's negative TTL)
+    pub ttl: Duration,
+    /// Whether the resolver validated the answer with DNSSEC (the AD bit)
+    pub authenticated: bool,
+}
+
+impl<T> DnsAnswer<T> {
+    pub fn new(records: Vec<T>, ttl: Duration) -> Self {
+        Self { records, ttl, authenticated: false }
+    }
+
+    /// Marks the answer as DNSSEC-validated
+    pub fn authenticated(mut self, authenticated: bool) -> Self {
+        self.authenticated = authenticated;
+        self
+    }
+}
+
+/// Source of the MX and address records micromail needs for delivery.
+///
+/// The default [`MicroDnsResolver`] sends plain UDP queries to public resolvers. Install
+/// another implementation via [`Config::resolver`](crate::Config::resolver) to use the
+/// system or a corporate split-horizon resolver, a [`ValidatingResolver`] for DANE, or
+/// fixed answers in tests.
+///
+/// Return an empty answer when the name has no such records, and an error only when the
+/// lookup itself failed (timeout, network error, SERVFAIL), so that it is not cached and
+/// the send is deferred instead of failing for lack of MX records.
+/// A timed out MX lookup should be an [`Error::Timeout`] of [`SmtpPhase::Dns`], which the
+/// send then fails with instead of reporting missing MX records.
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// MX records of `domain`, in any order
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error>;
+    /// A and AAAA records of `host`, in any order
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error>;
+    /// TLSA records at `name` (`_25._tcp.<mx host>`) for DANE. Only answers marked
+    /// [`authenticated`](DnsAnswer::authenticated) are used. The default finds none.
+    fn tlsa_records(&self, _name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        Ok(DnsAnswer::new(Vec::new(), Duration::ZERO))
+    }
+    /// TXT records at `name`, each with its strings joined, for SPF and DMARC checks.
+    /// The default finds none.
+    fn txt_records(&self, _name: &str) -> Result<DnsAnswer<String>, Error> {
+        Ok(DnsAnswer::new(Vec::new(), Duration::ZERO))
+    }
+    /// Host names `ip` points back to (PTR records), for reverse DNS checks. The default finds none.
+    fn ptr_records(&self, _ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        Ok(DnsAnswer::new(Vec::new(), Duration::ZERO))
+    }
+}
+
+/// Queries the public resolvers of the `microdns` crate over UDP, and over TCP for answers
+/// too large for a datagram (the default).
+///
+/// Its answers are never [`authenticated`](DnsAnswer::authenticated): anyone on the path
+/// to a public resolver could set the AD bit. Use a [`ValidatingResolver`] for DANE.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MicroDnsResolver;
+
+impl Resolver for MicroDnsResolver {
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        Upstream::public().mx_records(domain)
+    }
+
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        Upstream::public().host_addrs(host)
+    }
+
+    fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        Upstream::public().tlsa_records(name)
+    }
+
+    fn txt_records(&self, name: &str) -> Result<DnsAnswer<String>, Error> {
+        Upstream::public().txt_records(name)
+    }
+
+    fn ptr_records(&self, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        Upstream::public().ptr_records(ip)
+    }
+}
+
+/// Sends the same queries as the blocking implementation over tokio's sockets
+#[cfg(feature = "tokio-runtime")]
+#[async_trait]
+impl AsyncResolver for MicroDnsResolver {
+    async fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        Upstream::public().mx_records_async(domain).await
+    }
+
+    async fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        Upstream::public().host_addrs_async(host).await
+    }
+
+    async fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        Upstream::public().tlsa_records_async(name).await
+    }
+}
+
+/// Queries DNS servers that validate DNSSEC and are reached over a path the host trusts,
+/// typically a resolver such as unbound on localhost, and believes the AD bit of their
+/// answers. This is what makes [`DaneMode`](crate::DaneMode) usable.
+#[derive(Debug, Clone)]
+pub struct ValidatingResolver {
+    upstream: Upstream,
+}
+
+impl ValidatingResolver {
+    /// Resolver querying `servers` in order
+    pub fn new(servers: Vec<SocketAddr>) -> Self {
+        Self { upstream: Upstream { servers, trusted: true } }
+    }
+
+    /// Resolver at 127.0.0.1 port 53
+    pub fn localhost() -> Self {
+        Self::new(vec![SocketAddr::from(([127, 0, 0, 1], 53))])
+    }
+}
+
+impl Resolver for ValidatingResolver {
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        self.upstream.mx_records(domain)
+    }
+
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        self.upstream.host_addrs(host)
+    }
+
+    fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        self.upstream.tlsa_records(name)
+    }
+
+    fn txt_records(&self, name: &str) -> Result<DnsAnswer<String>, Error> {
+        self.upstream.txt_records(name)
+    }
+
+    fn ptr_records(&self, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        self.upstream.ptr_records(ip)
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+#[async_trait]
+impl AsyncResolver for ValidatingResolver {
+    async fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        self.upstream.mx_records_async(domain).await
+    }
+
+    async fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        self.upstream.host_addrs_async(host).await
+    }
+
+    async fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        self.upstream.tlsa_records_async(name).await
+    }
+}
+
+/// The DNS servers a resolver queries, and whether their AD bit is believed
+#[derive(Debug, Clone)]
+struct Upstream {
+    servers: Vec<SocketAddr>,
+    trusted: bool,
+}
+
+impl Upstream {
+    /// The public resolvers of the `microdns` crate, in the order they are tried
+    fn public() -> Self {
+        let servers = microdns::DEFAULT_DNS_SERVERS.iter().filter_map(|server| server.parse().ok()).map(|ip| SocketAddr::new(ip, 53)).collect();
+        Self { servers, trusted: false }
+    }
+
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        self.query(domain, microdns::DNS_TYPE_MX, microdns::parse_mx_records).map(mx_answer)
+    }
+
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        let v4 = self.query(host, microdns::DNS_TYPE_A, microdns::parse_ip_records);
+        let v6 = self.query(host, microdns::DNS_TYPE_AAAA, microdns::parse_ip_records);
+        merge_families(v4, v6)
+    }
+
+    fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        self.raw_query(name, DNS_TYPE_TLSA, |_, record| TlsaRecord::from_rdata(record.data))
+    }
+
+    fn txt_records(&self, name: &str) -> Result<DnsAnswer<String>, Error> {
+        self.raw_query(name, DNS_TYPE_TXT, |_, record| txt_from_rdata(record.data))
+    }
+
+    fn ptr_records(&self, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        self.raw_query(&reverse_name(ip), DNS_TYPE_PTR, |response, record| {
+            microdns::parse_dns_name(response, record.data_offset).ok().map(|name| name.trim_end_matches('.').to_string())
+        })
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    async fn mx_records_async(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        self.query_async(domain, microdns::DNS_TYPE_MX, microdns::parse_mx_records).await.map(mx_answer)
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    async fn host_addrs_async(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        let (v4, v6) = futures::join!(
+            self.query_async(host, microdns::DNS_TYPE_A, microdns::parse_ip_records),
+            self.query_async(host, microdns::DNS_TYPE_AAAA, microdns::parse_ip_records),
+        );
+        merge_families(v4, v6)
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    async fn tlsa_records_async(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        self.raw_query_async(name, DNS_TYPE_TLSA, |_, record| TlsaRecord::from_rdata(record.data)).await
+    }
+
+    fn query<T>(&self, name: &str, record_type: u16, parse: fn(&[u8]) -> Result<Vec<T>, microdns::Error>) -> Result<DnsAnswer<T>, Error> {
+        let started = Instant::now();
+        let mut response = Err(microdns::Error::Timeout);
+        if let Ok(query) = ad_query(name, record_type) {
+            response = self.servers.iter().find_map(|server| exchange(*server, &query).ok()).ok_or(microdns::Error::Timeout);
+        }
+        self.parsed_answer(name, record_type, response, parse, started)
+    }
+
+    /// [`query`](Self::query) over tokio's sockets
+    #[cfg(feature = "tokio-runtime")]
+    async fn query_async<T>(&self, name: &str, record_type: u16, parse: fn(&[u8]) -> Result<Vec<T>, microdns::Error>) -> Result<DnsAnswer<T>, Error> {
+        let started = Instant::now();
+        let mut response = Err(microdns::Error::Timeout);
+        if let Ok(query) = ad_query(name, record_type) {
+            for server in &self.servers {
+                if let Ok(answer) = exchange_async(*server, &query).await {
+                    response = Ok(answer);
+                    break;
+                }
+            }
+        }
+        self.parsed_answer(name, record_type, response, parse, started)
+    }
+
+    fn parsed_answer<T>(
+        &self,
+        name: &str,
+        record_type: u16,
+        response: Result<Vec<u8>, microdns::Error>,
+        parse: fn(&[u8]) -> Result<Vec<T>, microdns::Error>,
+        started: Instant,
+    ) -> Result<DnsAnswer<T>, Error> {
+        let authenticated = response.as_deref().is_ok_and(|response| self.authenticated(response));
+        match response.and_then(|response| Ok(DnsAnswer::new(parse(&response)?, min_answer_ttl(&response)))) {
+            Ok(answer) => Ok(answer.authenticated(authenticated)),
+            // RCODE 3 is NXDOMAIN: a definite answer that the name has no records
+            Err(microdns::Error::NoRecordsFound | microdns::Error::ServerError(3)) => Ok(DnsAnswer::new(Vec::new(), Duration::ZERO).authenticated(authenticated)),
+            // No DNS server answered in time
+            Err(microdns::Error::Timeout) => Err(Error::Timeout { phase: SmtpPhase::Dns, elapsed: started.elapsed() }),
+            Err(e) => Err(query_failed(name, record_type, &e)),
+        }
+    }
+
+    /// Queries record types `microdns` has no parser for
+    fn raw_query<T>(&self, name: &str, record_type: u16, parse: fn(&[u8], &AnswerRecord) -> Option<T>) -> Result<DnsAnswer<T>, Error> {
+        let query = ad_query(name, record_type)?;
+        let mut last_error = query_failed(name, record_type, &"no DNS server answered");
+        for server in &self.servers {
+            match exchange(*server, &query) {
+                Ok(response) => return self.raw_answer(name, record_type, &response, parse),
+                Err(e) => last_error = query_failed(name, record_type, &e),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// [`raw_query`](Self::raw_query) over tokio's sockets
+    #[cfg(feature = "tokio-runtime")]
+    async fn raw_query_async<T>(&self, name: &str, record_type: u16, parse: fn(&[u8], &AnswerRecord) -> Option<T>) -> Result<DnsAnswer<T>, Error> {
+        let query = ad_query(name, record_type)?;
+        let mut last_error = query_failed(name, record_type, &"no DNS server answered");
+        for server in &self.servers {
+            match exchange_async(*server, &query).await {
+                Ok(response) => return self.raw_answer(name, record_type, &response, parse),
+                Err(e) => last_error = query_failed(name, record_type, &e),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// The records of type `record_type` in `response`, parsed with `parse`
+    fn raw_answer<T>(&self, name: &str, record_type: u16, response: &[u8], parse: fn(&[u8], &AnswerRecord) -> Option<T>) -> Result<DnsAnswer<T>, Error> {
+        let authenticated = self.authenticated(response);
+        match microdns::parse_dns_header(response) {
+            Ok(_) => {}
+            Err(microdns::Error::ServerError(3)) => return Ok(DnsAnswer::new(Vec::new(), Duration::ZERO).authenticated(authenticated)),
+            Err(e) => return Err(query_failed(name, record_type, &e)),
+        }
+        let answers = answer_records(response).ok_or_else(|| query_failed(name, record_type, &"malformed response"))?;
+        let ttl = answers.iter().map(|a| a.ttl).min().unwrap_or(0);
+        let records = answers.iter()
+            .filter(|a| a.record_type == record_type)
+            .filter_map(|a| parse(response, a))
+            .collect();
+        Ok(DnsAnswer::new(records, Duration::from_secs(ttl as u64)).authenticated(authenticated))
+    }
+
+    /// Whether the server validated `response`: its AD bit, if the server is trusted to set it
+    fn authenticated(&self, response: &[u8]) -> bool {
+        self.trusted && response.get(3).is_some_and(|flags| flags & 0x20 != 0)
+    }
+}
+
+fn mx_answer(answer: DnsAnswer<microdns::MxRecord>) -> DnsAnswer<MxRecord> {
+    DnsAnswer::new(
+        answer.records.into_iter().map(|r| MxRecord { priority: r.priority, server: r.server }).collect(),
+        answer.ttl,
+    ).authenticated(answer.authenticated)
+}
+
+/// Combines the A and AAAA answers for a host, failing only if both lookups failed
+fn merge_families(v4: Result<DnsAnswer<IpAddr>, Error>, v6: Result<DnsAnswer<IpAddr>, Error>) -> Result<DnsAnswer<IpAddr>, Error> {
+    match (v4, v6) {
+        (Err(e), Err(_)) => Err(e),
+        (Ok(answer), Err(_)) | (Err(_), Ok(answer)) => Ok(answer),
+        (Ok(mut v4), Ok(v6)) => {
+            // An empty answer carries no TTL worth keeping
+            v4.ttl = match (v4.records.is_empty(), v6.records.is_empty()) {
+                (true, _) => v6.ttl,
+                (_, true) => v4.ttl,
+                _ => v4.ttl.min(v6.ttl),
+            };
+            v4.records.extend(v6.records);
+            Ok(v4)
+        }
+    }
+}
+
+fn query_failed(name: &str, record_type: u16, e: &dyn fmt::Display) -> Error {
+    Error::DnsError(format!("{} lookup for {} failed: {}", record_name(record_type), name, e))
+}
+
+/// A query for `name` with a random ID and the AD bit set, which asks the resolver to
+/// report whether it validated the answer (RFC 6840 section 5.7)
+fn ad_query(name: &str, record_type: u16) -> Result<Vec<u8>, Error> {
+    let mut query = microdns::build_dns_query(name, record_type).map_err(|e| query_failed(name, record_type, &e))?;
+    query[..2].copy_from_slice(&rand::random::<u16>().to_be_bytes());
+    query[3] |= 0x20;
+    Ok(query)
+}
+
+/// The `in-addr.arpa` or `ip6.arpa` name of `ip`
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let nibbles = ip.octets().iter().rev().map(|b| format!("{:x}.{:x}", b & 0xF, b >> 4)).collect::<Vec<_>>();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+/// A TXT record's character-strings, joined without separator (RFC 7208 section 3.3)
+fn txt_from_rdata(rdata: &[u8]) -> Option<String> {
+    let mut text = Vec::new();
+    let mut rest = rdata;
+    while let [len, tail @ ..] = rest {
+        let len = *len as usize;
+        text.extend_from_slice(tail.get(..len)?);
+        rest = &tail[len..];
+    }
+    Some(String::from_utf8_lossy(&text).into_owned())
+}
+
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_TYPE_TXT: u16 = 16;
+const DNS_TYPE_TLSA: u16 = 52;
+
+/// Sends `query` to `server` over UDP, and again over TCP if the answer did not fit
+/// into the datagram (TC bit, RFC 7766 section 5)
+fn exchange(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let response = udp_query(server, query)?;
+    if !truncated(&response) { return Ok(response); }
+    answer_to(query, tcp_query(server, query)?)
+}
+
+/// [`exchange`] over tokio's sockets
+#[cfg(feature = "tokio-runtime")]
+async fn exchange_async(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let response = udp_query_async(server, query).await?;
+    if !truncated(&response) { return Ok(response); }
+    let response = tokio::time::timeout(DNS_TIMEOUT, tcp_query_async(server, query))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    answer_to(query, response)
+}
+
+fn truncated(response: &[u8]) -> bool {
+    response.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// Whether `response` answers `query`: a response with the same ID and question (RFC 5452
+/// section 4.3). Anything else is a late answer to an earlier query, or spoofed.
+fn answers(query: &[u8], response: &[u8]) -> bool {
+    response.len() >= query.len()
+        && response[..2] == query[..2]
+        && response[2] & 0x80 != 0
+        && response[4..6] == query[4..6]
+        && response[12..query.len()].eq_ignore_ascii_case(&query[12..])
+}
+
+fn answer_to(query: &[u8], response: Vec<u8>) -> io::Result<Vec<u8>> {
+    if answers(query, &response) {
+        Ok(response)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "response does not match the query"))
+    }
+}
+
+fn udp_query(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = std::net::UdpSocket::bind(unspecified(server))?;
+    socket.connect(server)?;
+    socket.send(query)?;
+    let deadline = Instant::now() + DNS_TIMEOUT;
+    let mut buffer = [0; 1232];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let size = socket.recv(&mut buffer)?;
+        if answers(query, &buffer[..size]) {
+            return Ok(buffer[..size].to_vec());
+        }
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+async fn udp_query_async(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = tokio::net::UdpSocket::bind(unspecified(server)).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let receive = async {
+        let mut buffer = [0; 1232];
+        loop {
+            let size = socket.recv(&mut buffer).await?;
+            if answers(query, &buffer[..size]) {
+                return Ok(buffer[..size].to_vec());
+            }
+        }
+    };
+    tokio::time::timeout(DNS_TIMEOUT, receive)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Sends `query` over TCP, where messages are prefixed with their length
+fn tcp_query(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect_timeout(&server, DNS_TIMEOUT)?;
+    stream.set_read_timeout(Some(DNS_TIMEOUT))?;
+    stream.set_write_timeout(Some(DNS_TIMEOUT))?;
+    stream.write_all(&tcp_message(query))?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(feature = "tokio-runtime")]
+async fn tcp_query_async(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(server).await?;
+    stream.write_all(&tcp_message(query)).await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+fn tcp_message(query: &[u8]) -> Vec<u8> {
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    message
+}
+
+/// Local address to send queries to `server` from
+fn unspecified(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// How long to wait for each DNS server
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The blocking resolver of `config`: its `resolver`, else the built-in [`MicroDnsResolver`].
+/// With only an async resolver set, lookups fail instead of going out in plaintext.
+pub(crate) fn blocking(config: &crate::Config) -> &dyn Resolver {
+    #[cfg(feature = "tokio-runtime")]
+    if config.resolver.is_none() && config.async_resolver.is_some() {
+        return &AsyncOnly;
+    }
+    config.resolver.as_deref().unwrap_or(&MicroDnsResolver)
+}
+
+/// Stands in for the blocking resolver of a config that only has an async one
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug)]
+struct AsyncOnly;
+
+#[cfg(feature = "tokio-runtime")]
+impl Resolver for AsyncOnly {
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        Err(no_blocking_resolver(&format!("MX lookup for {}", domain)))
+    }
+
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        Err(no_blocking_resolver(&format!("address lookup for {}", host)))
+    }
+
+    fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        Err(no_blocking_resolver(&format!("TLSA lookup for {}", name)))
+    }
+
+    fn txt_records(&self, name: &str) -> Result<DnsAnswer<String>, Error> {
+        Err(no_blocking_resolver(&format!("TXT lookup for {}", name)))
+    }
+
+    fn ptr_records(&self, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        Err(no_blocking_resolver(&format!("PTR lookup for {}", ip)))
+    }
+}
+
+/// Async counterpart of [`Resolver`], used by [`AsyncMailer`](crate::AsyncMailer) so that
+/// DNS lookups do not block the runtime.
+///
+/// Install via [`Config::async_resolver`](crate::Config::async_resolver). [`MicroDnsResolver`]
+/// implements it over tokio's UDP sockets and is used when none is installed. With the
+/// `hickory` feature, [`HickoryResolver`] provides an implementation on top of `hickory-resolver`.
+#[cfg(feature = "tokio-runtime")]
+#[async_trait]
+pub trait AsyncResolver: fmt::Debug + Send + Sync {
+    /// MX records of `domain`, in any order
+    async fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error>;
+    /// A and AAAA records of `host`, in any order
+    async fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error>;
+    /// TLSA records at `name`, like [`Resolver::tlsa_records`]. The default finds none.
+    async fn tlsa_records(&self, _name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        Ok(DnsAnswer::new(Vec::new(), Duration::ZERO))
+    }
+}
+
+/// Resolves through `hickory-resolver` on the tokio runtime.
+///
+/// It is a blocking [`Resolver`] as well, running its lookups on a runtime of its own, so
+/// with [`Config::hickory_resolver`](crate::Config::hickory_resolver) the blocking
+/// [`Mailer`](crate::Mailer), DANE and the [`diagnostics`](crate::diagnostics) query
+/// through it too, e.g. over DNS-over-TLS.
+#[cfg(feature = "hickory")]
+#[derive(Debug)]
+pub struct HickoryResolver {
+    inner: hickory_resolver::TokioResolver,
+    /// Runs the blocking lookups, started by the first one
+    runtime: std::sync::OnceLock<Result<tokio::runtime::Runtime, String>>,
+}
+
+#[cfg(feature = "hickory")]
+impl HickoryResolver {
+    /// Resolver using the system configuration (`/etc/resolv.conf`, the registry on Windows)
+    pub fn from_system_conf() -> Result<Self, Error> {
+        let builder = hickory_resolver::TokioResolver::builder_tokio().map_err(|e| Error::DnsError(e.to_string()))?;
+        Ok(Self::new(builder.build()))
+    }
+
+    /// Wrap an already configured resolver
+    pub fn new(resolver: hickory_resolver::TokioResolver) -> Self {
+        Self { inner: resolver, runtime: std::sync::OnceLock::new() }
+    }
+
+    /// Resolver querying the name servers of `config`, e.g. a DoT server of your own
+    /// built with `ResolverConfig::from_parts`
+    pub fn with_config(config: hickory_resolver::config::ResolverConfig) -> Self {
+        let provider = hickory_resolver::name_server::TokioConnectionProvider::default();
+        Self::new(hickory_resolver::TokioResolver::builder_with_config(config, provider).build())
+    }
+
+    /// Resolver sending every query over DNS-over-TLS (port 853) to a public `provider`,
+    /// for networks where plaintext DNS on port 53 is blocked or monitored
+    #[cfg(feature = "dns-over-tls")]
+    pub fn dns_over_tls(provider: DnsProvider) -> Self {
+        use hickory_resolver::config::ResolverConfig;
+        Self::with_config(match provider {
+            DnsProvider::Cloudflare => ResolverConfig::cloudflare_tls(),
+            DnsProvider::Google => ResolverConfig::google_tls(),
+            DnsProvider::Quad9 => ResolverConfig::quad9_tls(),
+        })
+    }
+
+    /// Resolver sending every query over DNS-over-HTTPS to a public `provider`
+    #[cfg(feature = "dns-over-https")]
+    pub fn dns_over_https(provider: DnsProvider) -> Self {
+        use hickory_resolver::config::ResolverConfig;
+        Self::with_config(match provider {
+            DnsProvider::Cloudflare => ResolverConfig::cloudflare_https(),
+            DnsProvider::Google => ResolverConfig::google_https(),
+            DnsProvider::Quad9 => ResolverConfig::quad9_https(),
+        })
+    }
+}
+
+/// Public resolvers offering encrypted DNS
+#[cfg(any(feature = "dns-over-tls", feature = "dns-over-https"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsProvider {
+    /// 1.1.1.1 and 1.0.0.1
+    Cloudflare,
+    /// 8.8.8.8 and 8.8.4.4
+    Google,
+    /// 9.9.9.9 and 149.112.112.112
+    Quad9,
+}
+
+#[cfg(feature = "hickory")]
+impl HickoryResolver {
+    /// Runs `lookup` on the resolver's own runtime and waits for it. Unlike blocking on the
+    /// caller's runtime, this also works on a runtime thread and on the blocking pool.
+    fn block_on<T, F>(&self, lookup: impl FnOnce(hickory_resolver::TokioResolver) -> F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: std::future::Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        let runtime = self.runtime.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("micromail-dns")
+                .enable_all()
+                .build()
+                .map_err(|e| e.to_string())
+        });
+        let runtime = runtime.as_ref().map_err(|e| Error::DnsError(format!("could not start the DNS runtime: {}", e)))?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let lookup = lookup(self.inner.clone());
+        runtime.spawn(async move {
+            let _ = sender.send(lookup.await);
+        });
+        receiver.recv().unwrap_or_else(|_| Err(Error::DnsError("lookup task failed".to_string())))
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl Drop for HickoryResolver {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which panics when the last clone of a config goes
+        // away on an async task
+        if let Some(Ok(runtime)) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// How long a hickory answer stays valid
+#[cfg(feature = "hickory")]
+fn hickory_ttl(valid_until: Instant) -> Duration {
+    valid_until.saturating_duration_since(Instant::now())
+}
+
+#[cfg(feature = "hickory")]
+async fn hickory_mx(resolver: &hickory_resolver::TokioResolver, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+    match resolver.mx_lookup(domain).await {
+        Ok(lookup) => Ok(DnsAnswer::new(
+            lookup.iter()
+                .map(|mx| MxRecord { priority: mx.preference(), server: mx.exchange().to_utf8().trim_end_matches('.').to_string() })
+                .collect(),
+            hickory_ttl(lookup.valid_until()),
+        )),
+        Err(e) if e.is_no_records_found() => Ok(DnsAnswer::new(Vec::new(), Duration::ZERO)),
+        Err(e) => Err(Error::DnsError(format!("MX lookup for {} failed: {}", domain, e))),
+    }
+}
+
+#[cfg(feature = "hickory")]
+async fn hickory_host(resolver: &hickory_resolver::TokioResolver, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+    match resolver.lookup_ip(host).await {
+        Ok(lookup) => Ok(DnsAnswer::new(lookup.iter().collect(), hickory_ttl(lookup.valid_until()))),
+        Err(e) if e.is_no_records_found() => Ok(DnsAnswer::new(Vec::new(), Duration::ZERO)),
+        Err(e) => Err(Error::DnsError(format!("address lookup for {} failed: {}", host, e))),
+    }
+}
+
+/// TLSA records at `name`. hickory does not tell whether the upstream resolver validated
+/// them, so they are never [`authenticated`](DnsAnswer::authenticated).
+#[cfg(feature = "hickory")]
+async fn hickory_tlsa(resolver: &hickory_resolver::TokioResolver, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+    match resolver.tlsa_lookup(name).await {
+        Ok(lookup) => Ok(DnsAnswer::new(
+            lookup.iter()
+                .map(|tlsa| TlsaRecord {
+                    usage: tlsa.cert_usage().into(),
+                    selector: tlsa.selector().into(),
+                    matching_type: tlsa.matching().into(),
+                    data: tlsa.cert_data().to_vec(),
+                })
+                .collect(),
+            hickory_ttl(lookup.valid_until()),
+        )),
+        Err(e) if e.is_no_records_found() => Ok(DnsAnswer::new(Vec::new(), Duration::ZERO)),
+        Err(e) => Err(Error::DnsError(format!("TLSA lookup for {} failed: {}", name, e))),
+    }
+}
+
+#[cfg(feature = "hickory")]
+async fn hickory_txt(resolver: &hickory_resolver::TokioResolver, name: &str) -> Result<DnsAnswer<String>, Error> {
+    match resolver.txt_lookup(name).await {
+        Ok(lookup) => Ok(DnsAnswer::new(
+            lookup.iter()
+                .map(|txt| txt.txt_data().iter().map(|part| String::from_utf8_lossy(part)).collect())
+                .collect(),
+            hickory_ttl(lookup.valid_until()),
+        )),
+        Err(e) if e.is_no_records_found() => Ok(DnsAnswer::new(Vec::new(), Duration::ZERO)),
+        Err(e) => Err(Error::DnsError(format!("TXT lookup for {} failed: {}", name, e))),
+    }
+}
+
+#[cfg(feature = "hickory")]
+async fn hickory_ptr(resolver: &hickory_resolver::TokioResolver, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+    match resolver.reverse_lookup(ip).await {
+        Ok(lookup) => Ok(DnsAnswer::new(
+            lookup.iter().map(|ptr| ptr.0.to_utf8().trim_end_matches('.').to_string()).collect(),
+            hickory_ttl(lookup.valid_until()),
+        )),
+        Err(e) if e.is_no_records_found() => Ok(DnsAnswer::new(Vec::new(), Duration::ZERO)),
+        Err(e) => Err(Error::DnsError(format!("PTR lookup for {} failed: {}", ip, e))),
+    }
+}
+
+#[cfg(feature = "hickory")]
+#[async_trait]
+impl AsyncResolver for HickoryResolver {
+    async fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        hickory_mx(&self.inner, domain).await
+    }
+
+    async fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        hickory_host(&self.inner, host).await
+    }
+
+    async fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        hickory_tlsa(&self.inner, name).await
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl Resolver for HickoryResolver {
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        let domain = domain.to_string();
+        self.block_on(|resolver| async move { hickory_mx(&resolver, &domain).await })
+    }
+
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        let host = host.to_string();
+        self.block_on(|resolver| async move { hickory_host(&resolver, &host).await })
+    }
+
+    fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        let name = name.to_string();
+        self.block_on(|resolver| async move { hickory_tlsa(&resolver, &name).await })
+    }
+
+    fn txt_records(&self, name: &str) -> Result<DnsAnswer<String>, Error> {
+        let name = name.to_string();
+        self.block_on(|resolver| async move { hickory_txt(&resolver, &name).await })
+    }
+
+    fn ptr_records(&self, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        self.block_on(|resolver| async move { hickory_ptr(&resolver, ip).await })
+    }
+}
+
+/// A blocking [`Resolver`] run on tokio's blocking pool, for looking up ahead of an async
+/// send when only a blocking resolver is configured
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug)]
+pub(crate) struct OnBlockingPool(pub Arc<dyn Resolver>);
+
+#[cfg(feature = "tokio-runtime")]
+#[async_trait]
+impl AsyncResolver for OnBlockingPool {
+    async fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        let (resolver, domain) = (self.0.clone(), domain.to_string());
+        crate::dns::spawn_lookup(move || resolver.mx_records(&domain)).await
+    }
+
+    async fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        let (resolver, host) = (self.0.clone(), host.to_string());
+        crate::dns::spawn_lookup(move || resolver.host_addrs(&host)).await
+    }
+
+    async fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        let (resolver, name) = (self.0.clone(), name.to_string());
+        crate::dns::spawn_lookup(move || resolver.tlsa_records(&name)).await
+    }
+}
+
+/// Answers looked up ahead of a send, served to the send path without further
+/// queries. Names that were not looked up go to `fallback`. Without one, those lookups
+/// fail rather than leave the configured async resolver for plaintext DNS.
+#[derive(Debug, Default)]
+pub(crate) struct PreResolved {
+    pub mx: HashMap<String, DnsAnswer<MxRecord>>,
+    pub hosts: HashMap<String, DnsAnswer<IpAddr>>,
+    /// Domains whose MX lookup failed, with the error
+    pub mx_failures: HashMap<String, Error>,
+    pub fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl Resolver for PreResolved {
+    fn mx_records(&self, domain: &str) -> Result<DnsAnswer<MxRecord>, Error> {
+        match self.mx_failures.get(&domain.to_lowercase()) {
+            Some(&Error::Timeout { phase, elapsed }) => return Err(Error::Timeout { phase, elapsed }),
+            Some(Error::DnsError(message)) => return Err(Error::DnsError(message.clone())),
+            Some(e) => return Err(Error::DnsError(format!("MX lookup for {} failed: {}", domain, e))),
+            None => {}
+        }
+        match (self.mx.get(&domain.to_lowercase()), &self.fallback) {
+            (Some(answer), _) => Ok(answer.clone()),
+            (None, Some(fallback)) => fallback.mx_records(domain),
+            (None, None) => Err(Error::DnsError(format!("MX lookup for {} failed", domain))),
+        }
+    }
+
+    fn host_addrs(&self, host: &str) -> Result<DnsAnswer<IpAddr>, Error> {
+        match (self.hosts.get(&host.to_lowercase()), &self.fallback) {
+            (Some(answer), _) => Ok(answer.clone()),
+            (None, Some(fallback)) => fallback.host_addrs(host),
+            (None, None) => Err(Error::DnsError(format!("address lookup for {} failed", host))),
+        }
+    }
+
+    fn tlsa_records(&self, name: &str) -> Result<DnsAnswer<TlsaRecord>, Error> {
+        match &self.fallback {
+            Some(fallback) => fallback.tlsa_records(name),
+            None => Err(no_blocking_resolver(&format!("TLSA lookup for {}", name))),
+        }
+    }
+
+    fn txt_records(&self, name: &str) -> Result<DnsAnswer<String>, Error> {
+        match &self.fallback {
+            Some(fallback) => fallback.txt_records(name),
+            None => Err(no_blocking_resolver(&format!("TXT lookup for {}", name))),
+        }
+    }
+
+    fn ptr_records(&self, ip: IpAddr) -> Result<DnsAnswer<String>, Error> {
+        match &self.fallback {
+            Some(fallback) => fallback.ptr_records(ip),
+            None => Err(no_blocking_resolver(&format!("PTR lookup for {}", ip))),
+        }
+    }
+}
+
+/// A lookup that cannot be made with the async resolver alone
+fn no_blocking_resolver(lookup: &str) -> Error {
+    Error::DnsError(format!("{} needs a blocking resolver next to Config::async_resolver, see Config::resolver", lookup))
+}
+
+fn record_name(record_type: u16) -> &'static str {
+    match record_type {
+        microdns::DNS_TYPE_MX => "MX",
+        microdns::DNS_TYPE_AAAA => "AAAA",
+        DNS_TYPE_PTR => "PTR",
+        DNS_TYPE_TXT => "TXT",
+        DNS_TYPE_TLSA => "TLSA",
+        _ => "A",
+    }
+}
+
+/// One resource record of the answer section
+struct AnswerRecord<'a> {
+    record_type: u16,
+    ttl: u32,
+    data: &'a [u8],
+    /// Position of `data` in the response, for names compressed with pointers
+    data_offset: usize,
+}
+
+/// The records of the answer section of a DNS response, which `microdns` only exposes
+/// without their TTL and for the record types it knows
+fn answer_records(response: &[u8]) -> Option<Vec<AnswerRecord<'_>>> {
+    let header = microdns::parse_dns_header(response).ok()?;
+    let mut pos = 12;
+    for _ in 0..header.questions {
+        pos = microdns::skip_question(response, pos).ok()?;
+    }
+    let mut records = Vec::new();
+    for _ in 0..header.answers {
+        // Owner name: labels up to the root or a compression pointer
+        loop {
+            let len = *response.get(pos)? as usize;
+            if len == 0 { pos += 1; break; }
+            if len & 0xC0 == 0xC0 { pos += 2; break; }
+            pos += len + 1;
+        }
+        let fixed = response.get(pos..pos + 10)?;
+        let data_len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        records.push(AnswerRecord {
+            record_type: u16::from_be_bytes([fixed[0], fixed[1]]),
+            ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            data: response.get(pos + 10..pos + 10 + data_len)?,
+            data_offset: pos + 10,
+        });
+        pos += 10 + data_len;
+    }
+    Some(records)
+}
+
+/// Lowest TTL among the answer records of a DNS response
+fn min_answer_ttl(response: &[u8]) -> Duration {
+    let ttl = answer_records(response).and_then(|records| records.iter().map(|r| r.ttl).min());
+    Duration::from_secs(ttl.unwrap_or(0) as u64)
+}
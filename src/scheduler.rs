@@ -0,0 +1,171 @@
+//! In-memory scheduler for mail that should go out later
+//!
+//! [`Scheduler`] is the lightweight sibling of [`MailQueue`](crate::MailQueue): it
+//! holds mail until its `send_at` time, sends it through an [`AsyncMailer`] and
+//! retries temporary failures along a fixed schedule. There is no per-domain
+//! backoff and no event stream, only list, cancel and flush.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    async_mail::{AsyncMailSender, AsyncMailer},
+    config::Config,
+    error::Error,
+    mail::Mail,
+};
+
+/// Identifier of a scheduled mail
+pub type ScheduleId = u64;
+
+/// Snapshot of a mail waiting in the [`Scheduler`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledMail {
+    pub id: ScheduleId,
+    /// Envelope recipient
+    pub recipient: String,
+    /// When the next attempt is due
+    pub send_at: SystemTime,
+    /// Attempts made so far
+    pub attempts: u32,
+    /// Error of the last failed attempt
+    pub last_error: Option<String>,
+}
+
+struct Entry {
+    mail: Mail,
+    send_at: SystemTime,
+    /// Delays before the 2nd, 3rd, ... attempt
+    retries: Vec<Duration>,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    entries: BTreeMap<ScheduleId, Entry>,
+    next_id: ScheduleId,
+}
+
+/// Holds mail until it is due and sends it, retrying temporary failures
+#[derive(Clone)]
+pub struct Scheduler {
+    mailer: AsyncMailer,
+    retries: Vec<Duration>,
+    state: Arc<Mutex<SchedulerState>>,
+}
+
+impl Scheduler {
+    /// Create a scheduler sending through a new mailer with the given configuration
+    pub fn new(config: Config) -> Self {
+        Self::with_mailer(AsyncMailer::new(config))
+    }
+
+    /// Create a scheduler sending through an existing mailer
+    pub fn with_mailer(mailer: AsyncMailer) -> Self {
+        Self {
+            mailer,
+            retries: vec![Duration::from_secs(60), Duration::from_secs(5 * 60), Duration::from_secs(30 * 60)],
+            state: Arc::new(Mutex::new(SchedulerState::default())),
+        }
+    }
+
+    /// Replace the default retry schedule: the delays before each further attempt after
+    /// a temporary failure. An empty schedule means a single attempt.
+    pub fn retry_schedule(mut self, retries: Vec<Duration>) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Schedule `mail` for its [`Mail::send_at`] time, or right away if it has none
+    pub fn submit(&self, mail: Mail) -> ScheduleId {
+        let send_at = mail.send_at.map_or_else(SystemTime::now, SystemTime::from);
+        self.schedule(mail, send_at)
+    }
+
+    /// Schedule `mail` for `send_at` with the default retry schedule.
+    /// A time in the past makes the mail due immediately.
+    pub fn schedule(&self, mail: Mail, send_at: SystemTime) -> ScheduleId {
+        self.schedule_with_retries(mail, send_at, self.retries.clone())
+    }
+
+    /// Schedule `mail` for `send_at` with its own retry schedule
+    pub fn schedule_with_retries(&self, mail: Mail, send_at: SystemTime, retries: Vec<Duration>) -> ScheduleId {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.entries.insert(id, Entry { mail, send_at, retries, attempts: 0, last_error: None });
+        id
+    }
+
+    /// All mail still waiting, ordered by when it is due
+    pub fn pending(&self) -> Vec<ScheduledMail> {
+        let state = self.state.lock().unwrap();
+        let mut pending = state.entries.iter()
+            .map(|(id, entry)| ScheduledMail {
+                id: *id,
+                recipient: entry.mail.to.clone(),
+                send_at: entry.send_at,
+                attempts: entry.attempts,
+                last_error: entry.last_error.clone(),
+            })
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|mail| (mail.send_at, mail.id));
+        pending
+    }
+
+    /// Remove a mail before it is sent. Returns false if it is unknown, already
+    /// finished or currently being sent.
+    pub fn cancel(&self, id: ScheduleId) -> bool {
+        self.state.lock().unwrap().entries.remove(&id).is_some()
+    }
+
+    /// Send every mail that is due now.
+    ///
+    /// Returns the outcome of each attempt. A mail whose attempt failed temporarily
+    /// and that has retries left stays in [`pending`](Self::pending) for a later pass.
+    pub async fn run_due(&self) -> Vec<(ScheduleId, Result<(), Error>)> {
+        let now = SystemTime::now();
+        self.send_where(|entry| entry.send_at <= now).await
+    }
+
+    /// Send every pending mail right away, regardless of its `send_at` time
+    pub async fn flush(&self) -> Vec<(ScheduleId, Result<(), Error>)> {
+        self.send_where(|_| true).await
+    }
+
+    /// Send due mail forever, checking every `poll_interval`
+    pub async fn run(&self, poll_interval: Duration) {
+        loop {
+            self.run_due().await;
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    async fn send_where<F: Fn(&Entry) -> bool>(&self, select: F) -> Vec<(ScheduleId, Result<(), Error>)> {
+        let ids = {
+            let state = self.state.lock().unwrap();
+            state.entries.iter().filter(|(_, entry)| select(entry)).map(|(id, _)| *id).collect::<Vec<_>>()
+        };
+        let mut outcomes = Vec::new();
+        for id in ids {
+            // Taking the entry out keeps it from being sent twice by concurrent passes
+            let mut entry = match self.state.lock().unwrap().entries.remove(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let result = self.mailer.clone().send(entry.mail.clone()).await.map(|_| ());
+            if let Err(e) = &result {
+                if let Some(delay) = entry.retries.get(entry.attempts as usize).filter(|_| e.is_transient()) {
+                    entry.send_at = SystemTime::now() + *delay;
+                    entry.attempts += 1;
+                    entry.last_error = Some(e.to_string());
+                    self.state.lock().unwrap().entries.insert(id, entry);
+                }
+            }
+            outcomes.push((id, result));
+        }
+        outcomes
+    }
+}
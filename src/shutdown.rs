@@ -0,0 +1,66 @@
+//! Bookkeeping of in-flight work for the `shutdown` methods of the async types
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use crate::error::Error;
+
+/// Counts the work in flight and turns new work away once closed
+#[derive(Default)]
+pub(crate) struct Drain {
+    closed: AtomicBool,
+    active: AtomicUsize,
+    drained: Notify,
+}
+
+/// Marks one unit of work in flight until dropped
+pub(crate) struct DrainGuard(Arc<Drain>);
+
+impl Drain {
+    /// Register new work, or fail with [`Error::ShuttingDown`] once closed
+    pub fn enter(self: &Arc<Self>) -> Result<DrainGuard, Error> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        // Created first, so the guard gives the count back if closing raced with us
+        let guard = DrainGuard(self.clone());
+        if self.is_closed() {
+            return Err(Error::ShuttingDown);
+        }
+        Ok(guard)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Turn away new work right away, and wait up to `deadline` for the work in flight
+    /// to finish when awaited
+    pub fn close(&self, deadline: Duration) -> impl Future<Output = Result<(), Error>> + '_ {
+        self.closed.store(true, Ordering::SeqCst);
+        let drained = async move {
+            loop {
+                // Registered before the check, so a guard dropped in between still wakes us
+                let notified = self.drained.notified();
+                if self.active.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        async move {
+            tokio::time::timeout(deadline, drained).await
+                .map_err(|_| Error::ShutdownTimeout { in_flight: self.active.load(Ordering::SeqCst) })
+        }
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
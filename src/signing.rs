@@ -1,6 +1,10 @@
 //! DKIM signing utilities using mail-auth crate (version 0.7.1)
 #[cfg(feature = "signing")]
-use mail_auth::common::crypto::{RsaKey as MailAuthRsaKey, Sha256}; // Not used in this file anymore, but kept for context
+use mail_auth::common::crypto::{Algorithm, RsaKey, Sha256, SigningKey};
+#[cfg(feature = "signing")]
+use mail_auth::common::headers::{HeaderWriter, Writable};
+#[cfg(feature = "signing")]
+use mail_auth::dkim::DkimSigner;
 #[cfg(feature = "signing")]
 use rsa::{RsaPrivateKey, RsaPublicKey, pkcs1::{EncodeRsaPublicKey, EncodeRsaPrivateKey, LineEnding as RsaLineEnding}};
 #[cfg(feature = "signing")]
@@ -36,3 +40,40 @@ pub fn format_dkim_dns_record(public_key: &RsaPublicKey, selector: &str, domain:
     let public_key_base64 = BASE64_STANDARD.encode(&public_key_der);
     Ok(format!("{}._domainkey.{} IN TXT \"v=DKIM1; k=rsa; p={}\"", selector, domain, public_key_base64))
 }
+
+/// Headers covered by the signature, if the message has them
+#[cfg(feature = "signing")]
+const SIGNED_HEADERS: [&str; 8] = ["From", "To", "Cc", "Subject", "Date", "Message-ID", "Content-Type", "MIME-Version"];
+
+/// Signs with the key of a shared `DkimConfig` without taking it out
+#[cfg(feature = "signing")]
+struct BorrowedKey<'a>(&'a RsaKey<Sha256>);
+
+#[cfg(feature = "signing")]
+impl SigningKey for BorrowedKey<'_> {
+    type Hasher = Sha256;
+
+    fn sign(&self, input: impl Writable) -> mail_auth::Result<Vec<u8>> {
+        self.0.sign(input)
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        self.0.algorithm()
+    }
+}
+
+/// The value of the DKIM-Signature header for `message`, a complete formatted mail
+#[cfg(feature = "signing")]
+pub(crate) fn dkim_signature(dkim: &crate::config::DkimConfig, message: &str) -> Result<String, crate::Error> {
+    let mut signer = DkimSigner::from_key(BorrowedKey(&dkim.private_key))
+        .domain(dkim.domain.as_str())
+        .selector(dkim.selector.as_str())
+        .headers(SIGNED_HEADERS);
+    if let Some(auid) = &dkim.auid {
+        signer = signer.agent_user_identifier(auid.as_str());
+    }
+    let signature = signer.sign(message.as_bytes())
+        .map_err(|e| crate::Error::SigningError(format!("DKIM signing failed: {}", e)))?;
+    let header = signature.to_header();
+    Ok(header.trim_start_matches("DKIM-Signature:").trim().to_string())
+}
@@ -0,0 +1,222 @@
+//! The SMTP client dialogue without any I/O, shared by the blocking and the async send path
+//!
+//! A dialogue tells its driver what to do next as an [`Action`] and is fed the replies of
+//! the server. [`Mailer`](crate::Mailer) drives it over a blocking connection, the async
+//! mailer over a tokio one, so both speak the protocol the same way.
+
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+
+use crate::{
+    capabilities::Capabilities,
+    dsn::Dsn,
+    error::{Error, SmtpPhase, SmtpReply},
+    report::{RecipientStatus, TransactionReport},
+};
+
+/// What the driver of a dialogue does next
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Action {
+    /// Enter `phase` if there is one, send `line` and read the reply. A `secret` line
+    /// carries credentials and is kept out of the transcript.
+    Send { phase: Option<SmtpPhase>, line: String, secret: bool },
+    /// Send the message content in the DataTransfer phase and tell
+    /// [`Transaction::data_sent`] once it went out
+    Data,
+    /// Read another reply without sending anything
+    Read,
+}
+
+impl Action {
+    fn command(phase: Option<SmtpPhase>, line: String) -> Self {
+        Action::Send { phase, line, secret: false }
+    }
+}
+
+/// AUTH LOGIN with a username and password
+pub(crate) struct AuthLogin {
+    /// Lines still to send after the reply to the last one
+    lines: Vec<String>,
+}
+
+impl AuthLogin {
+    pub fn new(username: &str, password: &str) -> Self {
+        Self { lines: vec![BASE64_STANDARD.encode(password), BASE64_STANDARD.encode(username)] }
+    }
+
+    pub fn start(&self) -> Action {
+        Action::command(Some(SmtpPhase::Auth), "AUTH LOGIN\r\n".to_string())
+    }
+
+    /// The next line to send after `reply`, None once the server accepted the credentials
+    pub fn reply(&mut self, reply: SmtpReply) -> Result<Option<Action>, Error> {
+        if let Some(line) = self.lines.pop() {
+            return Ok(Some(Action::Send { phase: None, line: format!("{}\r\n", line), secret: true }));
+        }
+        if !reply.is_positive_completion() {
+            return Err(Error::AuthError { code: Some(reply.code), message: reply.text(), enhanced: reply.enhanced });
+        }
+        Ok(None)
+    }
+}
+
+enum Stage {
+    MailFrom,
+    /// Waiting for the reply to the RCPT TO of the recipient at this index
+    RcptTo(usize),
+    DataInit,
+    DataTransfer,
+    Done(SmtpReply),
+}
+
+/// One mail transaction: MAIL FROM, RCPT TO for every recipient, DATA and the content.
+/// Fails with the first rejecting reply unless it is a rejected recipient while others
+/// were accepted.
+pub(crate) struct Transaction<'a> {
+    domain: &'a str,
+    from: &'a str,
+    recipients: &'a [String],
+    /// DSN parameters, empty unless the server announced the extension
+    dsn: Dsn,
+    /// LMTP answers the end of DATA once per accepted recipient
+    lmtp: bool,
+    stage: Stage,
+    statuses: Vec<RecipientStatus>,
+    first_rejection: Option<SmtpReply>,
+    lmtp_replies: Vec<SmtpReply>,
+}
+
+impl<'a> Transaction<'a> {
+    /// DSN parameters in `dsn` are only sent if `capabilities` announce the extension,
+    /// they are a syntax error to servers that don't
+    pub fn new(domain: &'a str, from: &'a str, recipients: &'a [String], dsn: Option<&Dsn>, capabilities: &Capabilities, lmtp: bool) -> Self {
+        Self {
+            domain,
+            from,
+            recipients,
+            dsn: dsn.filter(|_| capabilities.dsn).cloned().unwrap_or_default(),
+            lmtp,
+            stage: Stage::MailFrom,
+            statuses: Vec::new(),
+            first_rejection: None,
+            lmtp_replies: Vec::new(),
+        }
+    }
+
+    pub fn start(&self) -> Action {
+        Action::command(Some(SmtpPhase::MailFrom), format!("MAIL FROM:<{}>{}\r\n", self.from, self.dsn.mail_params()))
+    }
+
+    /// What to do after `reply`, None once the server took the message
+    pub fn reply(&mut self, reply: SmtpReply) -> Result<Option<Action>, Error> {
+        match self.stage {
+            Stage::MailFrom => {
+                if !reply.is_positive_completion() {
+                    return Err(reply.into_error(SmtpPhase::MailFrom));
+                }
+                Ok(Some(self.next_recipient(0)))
+            }
+            Stage::RcptTo(index) => {
+                let accepted = reply.is_positive_completion();
+                self.statuses.push(RecipientStatus {
+                    recipient: self.recipients[index].clone(),
+                    code: reply.code,
+                    message: reply.text(),
+                    enhanced: reply.enhanced,
+                    accepted,
+                });
+                if !accepted && self.first_rejection.is_none() {
+                    self.first_rejection = Some(reply);
+                }
+                if index + 1 < self.recipients.len() {
+                    return Ok(Some(self.next_recipient(index + 1)));
+                }
+                if !self.statuses.iter().any(|s| s.accepted) {
+                    if let Some(rejection) = self.first_rejection.take() {
+                        return Err(rejection.into_error(SmtpPhase::RcptTo));
+                    }
+                }
+                self.stage = Stage::DataInit;
+                Ok(Some(Action::command(Some(SmtpPhase::DataInit), "DATA\r\n".to_string())))
+            }
+            Stage::DataInit => {
+                if reply.code != 354 {
+                    return Err(reply.into_error(SmtpPhase::DataInit));
+                }
+                self.stage = Stage::DataTransfer;
+                Ok(Some(Action::Data))
+            }
+            Stage::DataTransfer => {
+                self.lmtp_replies.push(reply);
+                self.finish_data()
+            }
+            Stage::Done(_) => Ok(None),
+        }
+    }
+
+    /// What to do once the content went out
+    pub fn data_sent(&mut self) -> Result<Option<Action>, Error> {
+        self.finish_data()
+    }
+
+    /// The report of the transaction the server took, once [`reply`](Self::reply) returned None
+    pub fn report(self, capabilities: &Capabilities) -> TransactionReport {
+        let Stage::Done(reply) = self.stage else { panic!("transaction reported before it completed") };
+        TransactionReport {
+            domain: self.domain.to_string(),
+            recipients: self.statuses,
+            data_code: reply.code,
+            queue_id: reply.queue_id(),
+            capabilities: Some(capabilities.clone()),
+            data_message: reply.text(),
+        }
+    }
+
+    fn next_recipient(&mut self, index: usize) -> Action {
+        let Some(to) = self.recipients.get(index) else {
+            self.stage = Stage::DataInit;
+            return Action::command(Some(SmtpPhase::DataInit), "DATA\r\n".to_string());
+        };
+        self.stage = Stage::RcptTo(index);
+        let phase = (index == 0).then_some(SmtpPhase::RcptTo);
+        Action::command(phase, format!("RCPT TO:<{}>{}\r\n", to, self.dsn.rcpt_params()))
+    }
+
+    /// Reads the replies after the content, one or (LMTP) one per accepted recipient
+    fn finish_data(&mut self) -> Result<Option<Action>, Error> {
+        let expected = if self.lmtp { self.statuses.iter().filter(|s| s.accepted).count() } else { 1 };
+        if self.lmtp_replies.len() < expected {
+            return Ok(Some(Action::Read));
+        }
+        let replies = std::mem::take(&mut self.lmtp_replies);
+        let reply = if self.lmtp {
+            apply_lmtp_replies(&mut self.statuses, replies)?
+        } else {
+            replies.into_iter().next().expect("the reply after DATA")
+        };
+        if !reply.is_positive_completion() {
+            return Err(reply.into_error(SmtpPhase::DataTransfer));
+        }
+        self.stage = Stage::Done(reply);
+        Ok(None)
+    }
+}
+
+/// Updates the status of each accepted recipient with its reply after DATA. Returns the
+/// first reply of a recipient that took the message, or the first rejection if none did.
+fn apply_lmtp_replies(statuses: &mut [RecipientStatus], replies: Vec<SmtpReply>) -> Result<SmtpReply, Error> {
+    let mut first_delivery = None;
+    let mut first_rejection = None;
+    for (status, reply) in statuses.iter_mut().filter(|s| s.accepted).zip(replies) {
+        status.code = reply.code;
+        status.message = reply.text();
+        status.enhanced = reply.enhanced;
+        status.accepted = reply.is_positive_completion();
+        if status.accepted {
+            first_delivery.get_or_insert(reply);
+        } else {
+            first_rejection.get_or_insert(reply);
+        }
+    }
+    first_delivery.or(first_rejection).ok_or_else(|| Error::Other("Invalid response format from server".to_string()))
+}
@@ -0,0 +1,464 @@
+//! Durable storage for the messages of a [`MailQueue`](crate::MailQueue)
+//!
+//! The queue itself keeps its schedule (due times, domain backoff, rate limits) in
+//! memory. A [`QueueStore`] only has to keep the messages so that a restarted
+//! process picks them up again. Two stores are built in: [`FileSpool`], one file
+//! per message in a directory, and `SqliteStore` with the `sqlite` feature. Redis,
+//! a database table or anything else can back the queue by implementing the trait.
+//!
+//! The queue calls the store as a message moves through it:
+//!
+//! - [`enqueue`](QueueStore::enqueue) when it is submitted,
+//! - [`dequeue`](QueueStore::dequeue) when it is handed to the sender,
+//! - [`content_sent`](QueueStore::content_sent) once the sender transmitted all of it,
+//! - [`ack`](QueueStore::ack) when it was delivered or given up,
+//! - [`delivered`](QueueStore::delivered) when the attempt failed but some recipients got the message,
+//! - [`nack`](QueueStore::nack) when the attempt failed temporarily,
+//! - [`list`](QueueStore::list) once, to recover the messages of a previous run,
+//! - [`flush`](QueueStore::flush) when the queue shuts down.
+//!
+//! Together these journal every attempt, so after a crash the queue can tell a message
+//! that is safe to retry ([`Attempt::Started`]) from one the server may already have
+//! accepted ([`Attempt::ContentSent`]), which it flags instead of sending it twice.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    attachment::Attachment,
+    error::Error,
+    mail::{Mail, Resent},
+    queue::{JobId, Priority},
+};
+
+/// A message as kept by a [`QueueStore`]
+#[derive(Debug, Clone)]
+pub struct StoredJob {
+    pub id: JobId,
+    pub mail: Mail,
+    pub priority: Priority,
+    /// Attempts that failed temporarily so far
+    pub attempts: u32,
+    /// Error of the last failed attempt
+    pub last_error: Option<String>,
+    /// When the next attempt is due (None: as soon as possible, or at [`Mail::send_at`])
+    pub retry_at: Option<DateTime<Utc>>,
+    /// How far the attempt in flight got, if there is one
+    pub attempt: Attempt,
+    /// Recipients that got the message in an attempt that failed for others.
+    /// Retries leave them out.
+    pub delivered: Vec<String>,
+    /// The key it was submitted with by [`MailQueue::enqueue_idempotent`](crate::MailQueue::enqueue_idempotent)
+    pub idempotency_key: Option<String>,
+}
+
+/// Journal entry of the delivery attempt of a stored message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Attempt {
+    /// Not being attempted
+    #[default]
+    Idle,
+    /// Handed to the sender, but the message content has not gone out completely.
+    /// The server cannot have accepted it, retrying is safe.
+    Started,
+    /// The whole content went out and the server may have accepted the message,
+    /// retrying might deliver it twice
+    ContentSent,
+}
+
+/// Storage backend of a [`MailQueue`](crate::MailQueue), see the [module docs](self)
+pub trait QueueStore: Send + Sync {
+    /// Persist a new message, with the idempotency key it was submitted with, and return
+    /// the ID it is known by from now on. The key comes back in [`list`](Self::list), so
+    /// a resubmission after a restart is still recognized.
+    fn enqueue(&self, mail: &Mail, priority: Priority, idempotency_key: Option<&str>) -> Result<JobId, Error>;
+    /// Mark the message as handed to the sender and return it, None if the store no
+    /// longer holds it
+    fn dequeue(&self, id: JobId) -> Result<Option<StoredJob>, Error>;
+    /// Journal that the content of the message in flight was transmitted completely
+    fn content_sent(&self, id: JobId) -> Result<(), Error>;
+    /// Remove a message that was delivered or will not be attempted again
+    fn ack(&self, id: JobId) -> Result<(), Error>;
+    /// Remember that `recipients` got the message while the attempt failed for others,
+    /// so retries after a restart leave them out. A store that doesn't keep this
+    /// delivers to them again.
+    fn delivered(&self, _id: JobId, _recipients: &[String]) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Return a message whose attempt failed temporarily, counting the attempt
+    fn nack(&self, id: JobId, error: &str, retry_at: DateTime<Utc>) -> Result<(), Error>;
+    /// Every stored message, in ID order
+    fn list(&self) -> Result<Vec<StoredJob>, Error>;
+    /// Make all changes so far durable, for stores that buffer writes. Called when the
+    /// queue shuts down; the built-in stores write through and have nothing to do.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A [`QueueStore`] keeping one file per message in a spool directory.
+///
+/// Every change is written and synced to a temporary file that is then renamed over the
+/// old one, so a crash leaves either the old or the new state of a message behind.
+pub struct FileSpool {
+    dir: PathBuf,
+    /// Serializes ID allocation and the read-modify-write of dequeue and nack
+    lock: Mutex<()>,
+}
+
+impl FileSpool {
+    /// Use `dir` as the spool, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, lock: Mutex::new(()) })
+    }
+
+    fn path(&self, id: JobId) -> PathBuf {
+        self.dir.join(format!("{:016}.job", id))
+    }
+
+    fn read(&self, id: JobId) -> Result<Option<StoredJob>, Error> {
+        let path = self.path(id);
+        match fs::read(&path) {
+            Ok(bytes) => decode_job(id, &bytes).map(Some).ok_or_else(|| corrupt(&path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, job: &StoredJob) -> Result<(), Error> {
+        replace_file(&self.path(job.id), &encode_job(job))
+    }
+}
+
+impl QueueStore for FileSpool {
+    fn enqueue(&self, mail: &Mail, priority: Priority, idempotency_key: Option<&str>) -> Result<JobId, Error> {
+        let _guard = self.lock.lock().unwrap();
+        // IDs are never reused, even after the spool ran empty
+        let counter = self.dir.join("next-id");
+        let id = match fs::read_to_string(&counter) {
+            Ok(text) => text.trim().parse().map_err(|_| corrupt(&counter))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e.into()),
+        };
+        replace_file(&counter, (id + 1).to_string().as_bytes())?;
+        self.write(&StoredJob { id, mail: mail.clone(), priority, attempts: 0, last_error: None, retry_at: None, attempt: Attempt::Idle, delivered: Vec::new(), idempotency_key: idempotency_key.map(str::to_string) })?;
+        Ok(id)
+    }
+
+    fn dequeue(&self, id: JobId) -> Result<Option<StoredJob>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        let Some(mut job) = self.read(id)? else { return Ok(None) };
+        job.attempt = Attempt::Started;
+        self.write(&job)?;
+        Ok(Some(job))
+    }
+
+    fn content_sent(&self, id: JobId) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let Some(mut job) = self.read(id)? else { return Ok(()) };
+        job.attempt = Attempt::ContentSent;
+        self.write(&job)
+    }
+
+    fn delivered(&self, id: JobId, recipients: &[String]) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let Some(mut job) = self.read(id)? else { return Ok(()) };
+        job.delivered.extend_from_slice(recipients);
+        self.write(&job)
+    }
+
+    fn ack(&self, id: JobId) -> Result<(), Error> {
+        match fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn nack(&self, id: JobId, error: &str, retry_at: DateTime<Utc>) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let Some(mut job) = self.read(id)? else { return Ok(()) };
+        job.attempts += 1;
+        job.last_error = Some(error.to_string());
+        job.retry_at = Some(retry_at);
+        job.attempt = Attempt::Idle;
+        self.write(&job)
+    }
+
+    fn list(&self) -> Result<Vec<StoredJob>, Error> {
+        let mut jobs = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let id = name.to_str().and_then(|name| name.strip_suffix(".job")).and_then(|id| id.parse().ok());
+            if let Some(job) = id.map(|id| self.read(id)).transpose()?.flatten() {
+                jobs.push(job);
+            }
+        }
+        jobs.sort_by_key(|job| job.id);
+        Ok(jobs)
+    }
+}
+
+/// A [`QueueStore`] keeping the messages in one table of an SQLite database
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Open (or create) the database at `path` and the `micromail_queue` table in it
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS micromail_queue (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                job BLOB NOT NULL
+            )",
+        ).map_err(sqlite_error)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    fn read(connection: &rusqlite::Connection, id: JobId) -> Result<Option<StoredJob>, Error> {
+        use rusqlite::OptionalExtension;
+        let bytes: Option<Vec<u8>> = connection
+            .query_row("SELECT job FROM micromail_queue WHERE id = ?1", [id as i64], |row| row.get(0))
+            .optional()
+            .map_err(sqlite_error)?;
+        bytes.map(|bytes| decode_job(id, &bytes).ok_or_else(|| Error::Other(format!("Corrupt queue entry {}", id)))).transpose()
+    }
+
+    fn write(connection: &rusqlite::Connection, job: &StoredJob) -> Result<(), Error> {
+        connection
+            .execute("UPDATE micromail_queue SET job = ?1 WHERE id = ?2", rusqlite::params![encode_job(job), job.id as i64])
+            .map(|_| ())
+            .map_err(sqlite_error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl QueueStore for SqliteStore {
+    fn enqueue(&self, mail: &Mail, priority: Priority, idempotency_key: Option<&str>) -> Result<JobId, Error> {
+        let connection = self.connection.lock().unwrap();
+        // The row gets its ID on insert, which the encoded job does not depend on
+        let job = StoredJob { id: 0, mail: mail.clone(), priority, attempts: 0, last_error: None, retry_at: None, attempt: Attempt::Idle, delivered: Vec::new(), idempotency_key: idempotency_key.map(str::to_string) };
+        connection.execute("INSERT INTO micromail_queue (job) VALUES (?1)", [encode_job(&job)]).map_err(sqlite_error)?;
+        Ok(connection.last_insert_rowid() as JobId)
+    }
+
+    fn dequeue(&self, id: JobId) -> Result<Option<StoredJob>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let Some(mut job) = Self::read(&connection, id)? else { return Ok(None) };
+        job.attempt = Attempt::Started;
+        Self::write(&connection, &job)?;
+        Ok(Some(job))
+    }
+
+    fn content_sent(&self, id: JobId) -> Result<(), Error> {
+        let connection = self.connection.lock().unwrap();
+        let Some(mut job) = Self::read(&connection, id)? else { return Ok(()) };
+        job.attempt = Attempt::ContentSent;
+        Self::write(&connection, &job)
+    }
+
+    fn delivered(&self, id: JobId, recipients: &[String]) -> Result<(), Error> {
+        let connection = self.connection.lock().unwrap();
+        let Some(mut job) = Self::read(&connection, id)? else { return Ok(()) };
+        job.delivered.extend_from_slice(recipients);
+        Self::write(&connection, &job)
+    }
+
+    fn ack(&self, id: JobId) -> Result<(), Error> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM micromail_queue WHERE id = ?1", [id as i64]).map(|_| ()).map_err(sqlite_error)
+    }
+
+    fn nack(&self, id: JobId, error: &str, retry_at: DateTime<Utc>) -> Result<(), Error> {
+        let connection = self.connection.lock().unwrap();
+        let Some(mut job) = Self::read(&connection, id)? else { return Ok(()) };
+        job.attempts += 1;
+        job.last_error = Some(error.to_string());
+        job.retry_at = Some(retry_at);
+        job.attempt = Attempt::Idle;
+        Self::write(&connection, &job)
+    }
+
+    fn list(&self) -> Result<Vec<StoredJob>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT id, job FROM micromail_queue ORDER BY id").map_err(sqlite_error)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))
+            .map_err(sqlite_error)?;
+        let mut jobs = Vec::new();
+        for row in rows {
+            let (id, bytes) = row.map_err(sqlite_error)?;
+            jobs.push(decode_job(id as JobId, &bytes).ok_or_else(|| Error::Other(format!("Corrupt queue entry {}", id)))?);
+        }
+        Ok(jobs)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> Error {
+    Error::Other(format!("SQLite queue store: {}", e))
+}
+
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text).ok().map(|time| time.with_timezone(&Utc))
+}
+
+fn corrupt(path: &Path) -> Error {
+    Error::Other(format!("Corrupt spool file {}", path.display()))
+}
+
+/// Write `bytes` to a temporary file next to `path` and rename it over `path`, syncing
+/// both so the change survives a power loss once this returns
+fn replace_file(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    // The rename itself is only durable once the directory is synced
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Serializes a job as a list of `name length\nvalue\n` fields. The ID is not part of
+/// the record, every store keeps it alongside.
+fn encode_job(job: &StoredJob) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut field = |name: &str, value: &[u8]| {
+        out.extend_from_slice(format!("{} {}\n", name, value.len()).as_bytes());
+        out.extend_from_slice(value);
+        out.push(b'\n');
+    };
+    let priority = match job.priority {
+        Priority::Transactional => "transactional",
+        Priority::Bulk => "bulk",
+    };
+    field("priority", priority.as_bytes());
+    field("attempts", job.attempts.to_string().as_bytes());
+    if let Some(error) = &job.last_error {
+        field("last-error", error.as_bytes());
+    }
+    if let Some(time) = job.retry_at {
+        field("retry-at", time.to_rfc3339().as_bytes());
+    }
+    match job.attempt {
+        Attempt::Idle => {}
+        Attempt::Started => field("attempt", b"started"),
+        Attempt::ContentSent => field("attempt", b"content-sent"),
+    }
+    for recipient in &job.delivered {
+        field("delivered", recipient.as_bytes());
+    }
+    if let Some(key) = &job.idempotency_key {
+        field("idempotency-key", key.as_bytes());
+    }
+
+    let mail = &job.mail;
+    field("from", mail.from.as_bytes());
+    field("to", mail.to.as_bytes());
+    for cc in &mail.cc {
+        field("cc", cc.as_bytes());
+    }
+    for bcc in &mail.bcc {
+        field("bcc", bcc.as_bytes());
+    }
+    field("subject", mail.subject.as_bytes());
+    field("body", mail.body.as_bytes());
+    field("content-type", mail.content_type.as_bytes());
+    for (name, value) in &mail.headers {
+        field("header-name", name.as_bytes());
+        field("header-value", value.as_bytes());
+    }
+    if let Some(id) = &mail.message_id {
+        field("message-id", id.as_bytes());
+    }
+    if let Some(time) = mail.date {
+        field("date", time.to_rfc3339().as_bytes());
+    }
+    for attachment in &mail.attachments {
+        field("attachment-filename", attachment.filename.as_bytes());
+        field("attachment-content-type", attachment.content_type.as_bytes());
+        field("attachment-data", &attachment.data);
+    }
+    if let Some(time) = mail.send_at {
+        field("send-at", time.to_rfc3339().as_bytes());
+    }
+    if let Some(resent) = &mail.resent {
+        field("resent-from", resent.from.as_bytes());
+        for to in &resent.to {
+            field("resent-to", to.as_bytes());
+        }
+        if let Some(time) = resent.date {
+            field("resent-date", time.to_rfc3339().as_bytes());
+        }
+        if let Some(id) = &resent.message_id {
+            field("resent-message-id", id.as_bytes());
+        }
+    }
+    out
+}
+
+/// Reverses [`encode_job`], None if the record is damaged
+fn decode_job(id: JobId, mut bytes: &[u8]) -> Option<StoredJob> {
+    let mut job = StoredJob { id, mail: Mail::new(), priority: Priority::default(), attempts: 0, last_error: None, retry_at: None, attempt: Attempt::Idle, delivered: Vec::new(), idempotency_key: None };
+    let mut header_name = None;
+    while !bytes.is_empty() {
+        let line_end = bytes.iter().position(|&b| b == b'\n')?;
+        let (name, len) = std::str::from_utf8(&bytes[..line_end]).ok()?.split_once(' ')?;
+        let len = len.parse::<usize>().ok()?;
+        let value = bytes.get(line_end + 1..line_end + 1 + len)?;
+        if bytes.get(line_end + 1 + len) != Some(&b'\n') {
+            return None;
+        }
+        bytes = &bytes[line_end + 2 + len..];
+
+        if name == "attachment-data" {
+            job.mail.attachments.last_mut()?.data = value.to_vec();
+            continue;
+        }
+        let text = String::from_utf8(value.to_vec()).ok()?;
+        let mail = &mut job.mail;
+        match name {
+            "priority" => job.priority = if text == "bulk" { Priority::Bulk } else { Priority::Transactional },
+            "attempts" => job.attempts = text.parse().ok()?,
+            "last-error" => job.last_error = Some(text),
+            "retry-at" => job.retry_at = Some(parse_time(&text)?),
+            "attempt" => job.attempt = if text == "content-sent" { Attempt::ContentSent } else { Attempt::Started },
+            "delivered" => job.delivered.push(text),
+            "idempotency-key" => job.idempotency_key = Some(text),
+            "from" => mail.from = text,
+            "to" => mail.to = text,
+            "cc" => mail.cc.push(text),
+            "bcc" => mail.bcc.push(text),
+            "subject" => mail.subject = text,
+            "body" => mail.body = text,
+            "content-type" => mail.content_type = text,
+            "header-name" => header_name = Some(text),
+            "header-value" => { mail.headers.insert(header_name.take()?, text); }
+            "message-id" => mail.message_id = Some(text),
+            "date" => mail.date = Some(parse_time(&text)?),
+            "attachment-filename" => mail.attachments.push(Attachment { filename: text, content_type: String::new(), data: Vec::new() }),
+            "attachment-content-type" => mail.attachments.last_mut()?.content_type = text,
+            "send-at" => mail.send_at = Some(parse_time(&text)?),
+            "resent-from" => mail.resent.get_or_insert_with(Resent::default).from = text,
+            "resent-to" => mail.resent.get_or_insert_with(Resent::default).to.push(text),
+            "resent-date" => mail.resent.get_or_insert_with(Resent::default).date = Some(parse_time(&text)?),
+            "resent-message-id" => mail.resent.get_or_insert_with(Resent::default).message_id = Some(text),
+            // Fields written by a newer version
+            _ => {}
+        }
+    }
+    Some(job)
+}
@@ -0,0 +1,169 @@
+//! Assertions for testing code that sends mail with micromail
+//!
+//! The helpers parse the formatted message (as produced by [`Mail::format`](crate::Mail::format)
+//! or captured from a server) and check its MIME structure, so test suites don't have
+//! to match on the raw message text. Each assertion panics with a description of
+//! what the message actually contains.
+//!
+//! [`StubTransport`] stands in for a mailer in application tests: it records every mail
+//! it is given instead of talking SMTP, and fails on demand.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    config::Config,
+    error::Error,
+    mail::{self, Mail},
+    mime::Part,
+    report::{RecipientStatus, SendReport, TransactionReport},
+    transcript::SharedRecorder,
+};
+
+fn parse(mail: &[u8]) -> Part {
+    Part::parse(&String::from_utf8_lossy(mail))
+}
+
+fn describe(message: &Part) -> String {
+    message.walk()
+        .iter()
+        .map(|part| match part.filename() {
+            Some(name) => format!("{} ({})", part.mime_type(), name),
+            None => part.mime_type(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Asserts that the message has a top-level header `name` whose value satisfies `pred`
+pub fn assert_header<M, F>(mail: M, name: &str, pred: F)
+where
+    M: AsRef<[u8]>,
+    F: Fn(&str) -> bool,
+{
+    let message = parse(mail.as_ref());
+    let values = message.headers_named(name).collect::<Vec<_>>();
+    assert!(!values.is_empty(), "message has no {} header", name);
+    assert!(values.iter().any(|v| pred(v)), "no {} header matches, values: {:?}", name, values);
+}
+
+/// Asserts that some part of the message is an attachment with the file name `name`
+pub fn assert_has_attachment<M: AsRef<[u8]>>(mail: M, name: &str) {
+    let message = parse(mail.as_ref());
+    let found = message.walk().iter().any(|part| part.filename().as_deref() == Some(name));
+    assert!(found, "no attachment named {:?}, message parts: {}", name, describe(&message));
+}
+
+/// Asserts that the message contains a `multipart/alternative` entity with exactly `n` parts
+pub fn assert_alternative_parts<M: AsRef<[u8]>>(mail: M, n: usize) {
+    let message = parse(mail.as_ref());
+    let alternatives = message.walk()
+        .into_iter()
+        .filter(|part| part.mime_type() == "multipart/alternative")
+        .map(|part| part.parts.len())
+        .collect::<Vec<_>>();
+    assert!(!alternatives.is_empty(), "no multipart/alternative part, message parts: {}", describe(&message));
+    assert!(alternatives.contains(&n), "expected {} alternative parts, found {:?}", n, alternatives);
+}
+
+/// A mail handed to a [`StubTransport`]
+#[derive(Debug, Clone)]
+pub struct SentMail {
+    pub mail: Mail,
+    /// Envelope recipients
+    pub recipients: Vec<String>,
+    /// The message as it would have gone out after DATA, signed if DKIM is configured
+    pub formatted: Vec<u8>,
+    /// Whether the send succeeded or was failed on purpose
+    pub accepted: bool,
+}
+
+type FailureRule = Box<dyn Fn(&Mail) -> Option<Error> + Send>;
+
+#[derive(Default)]
+struct StubState {
+    sent: Vec<SentMail>,
+    fail_next: VecDeque<Error>,
+    rules: Vec<FailureRule>,
+}
+
+/// Records mail instead of sending it
+///
+/// Mail is checked, signed and formatted with the config like a real send, so an
+/// invalid recipient still fails with the same error. Clones share the recorded mail.
+#[derive(Clone)]
+pub struct StubTransport {
+    config: Config,
+    state: Arc<Mutex<StubState>>,
+}
+
+impl StubTransport {
+    pub fn new(config: Config) -> Self {
+        Self { config, state: Arc::new(Mutex::new(StubState::default())) }
+    }
+
+    /// "Sends" `mail`: records it and reports every recipient as accepted, unless a failure applies
+    pub fn send(&self, mail: Mail) -> Result<SendReport, Error> {
+        let prepared = mail::prepare_send(&self.config, &SharedRecorder::default(), mail.clone())?;
+        let mut state = self.state.lock().unwrap();
+        let failure = state.fail_next.pop_front().or_else(|| state.rules.iter().find_map(|rule| rule(&mail)));
+        state.sent.push(SentMail {
+            mail,
+            recipients: prepared.recipients.clone(),
+            formatted: prepared.content.clone().into_bytes(),
+            accepted: failure.is_none(),
+        });
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        let queue_id = format!("STUB{:04}", state.sent.len());
+        let transactions = prepared.by_domain.into_iter().map(|(domain, indices)| TransactionReport {
+            domain,
+            recipients: indices.iter().map(|&i| RecipientStatus {
+                recipient: prepared.recipients[i].clone(),
+                code: 250,
+                message: "OK".to_string(),
+                enhanced: None,
+                accepted: true,
+            }).collect(),
+            data_code: 250,
+            queue_id: Some(queue_id.clone()),
+            capabilities: None,
+            data_message: format!("OK: queued as {}", queue_id),
+        }).collect();
+        Ok(SendReport { transactions, message: mail::sent_message(&self.config, prepared.content) })
+    }
+
+    /// Fails the next send with `error`. Several calls fail that many sends, in order.
+    pub fn fail_next(&self, error: Error) {
+        self.state.lock().unwrap().fail_next.push_back(error);
+    }
+
+    /// Fails every send for which `rule` returns an error, e.g. to reject one recipient
+    pub fn fail_when<F: Fn(&Mail) -> Option<Error> + Send + 'static>(&self, rule: F) {
+        self.state.lock().unwrap().rules.push(Box::new(rule));
+    }
+
+    /// Everything sent so far, failed sends included
+    pub fn sent(&self) -> Vec<SentMail> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// The mail sent last
+    pub fn last(&self) -> Option<SentMail> {
+        self.state.lock().unwrap().sent.last().cloned()
+    }
+
+    /// Forgets the recorded mail and all configured failures
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = StubState::default();
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+#[async_trait::async_trait]
+impl crate::async_mail::AsyncMailSender for StubTransport {
+    async fn send(&mut self, mail: Mail) -> Result<SendReport, Error> {
+        StubTransport::send(self, mail)
+    }
+}
@@ -1,6 +1,6 @@
 //! TLS implementation and certificate handling
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use rustls::{ClientConnection, StreamOwned};
 
 /// Certificate verification that accepts all certificates.
@@ -60,10 +60,475 @@ impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
     }
 }
 
-/// Creates a TLS config with certificate verification disabled.
-pub fn create_insecure_tls_config() -> rustls::ClientConfig {
-    rustls::ClientConfig::builder()
+/// Creates the TLS config for a connection made with `config`: certificates are verified
+/// against the configured roots unless `config.accept_invalid_certs` is set, and against
+/// the pinned keys if there are any.
+fn create_tls_config(config: &crate::Config) -> Result<rustls::ClientConfig, crate::Error> {
+    let provider = crypto_provider();
+    let builder = client_builder(config, provider.clone())?;
+    let verifier = server_verifier(config, provider)?;
+    let mut tls_config = with_client_identity(builder.dangerous().with_custom_certificate_verifier(verifier), config)?;
+    tls_config.resumption = rustls::client::Resumption::disabled();
+    Ok(tls_config)
+}
+
+/// The certificate verifier for connections made with `config`
+fn server_verifier(
+    config: &crate::Config,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+) -> Result<Arc<dyn rustls::client::danger::ServerCertVerifier>, crate::Error> {
+    let verifier: Arc<dyn rustls::client::danger::ServerCertVerifier> = if config.accept_invalid_certs {
+        Arc::new(NoCertificateVerification {})
+    } else if let Some(verifier) = platform_verifier(config, provider.clone())? {
+        verifier
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        if config.tls_webpki_roots {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        for cert in &config.tls_root_certs {
+            roots.add(cert.clone()).map_err(|e| crate::Error::TlsError(format!("Invalid root certificate: {}", e)))?;
+        }
+        rustls::client::WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .map_err(|e| crate::Error::TlsError(e.to_string()))?
+    };
+    Ok(if config.tls_pinned_spki.is_empty() {
+        verifier
+    } else {
+        Arc::new(PinningVerifier { inner: verifier, provider, pins: config.tls_pinned_spki.clone() })
+    })
+}
+
+/// The client config for `config`, from its session cache if it has one
+pub(crate) fn client_config(config: &crate::Config) -> Result<Arc<rustls::ClientConfig>, crate::Error> {
+    match &config.tls_session_cache {
+        Some(cache) => cache.client_config(config),
+        None => create_tls_config(config).map(Arc::new),
+    }
+}
+
+/// The client config for HTTPS requests to a mail provider's API: the bundled web PKI
+/// roots and nothing else, as the roots, pins and client identity of a `Config` are meant
+/// for its SMTP servers
+#[cfg(feature = "http-api")]
+pub(crate) fn https_client_config() -> Result<Arc<rustls::ClientConfig>, crate::Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls_config = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| crate::Error::TlsError(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(tls_config))
+}
+
+/// Verifier backed by the operating system (Security.framework, CryptoAPI, or the system
+/// CA bundle on Linux), if `config` asks for it
+#[cfg(feature = "platform-verifier")]
+fn platform_verifier(
+    config: &crate::Config,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+) -> Result<Option<Arc<dyn rustls::client::danger::ServerCertVerifier>>, crate::Error> {
+    if !config.tls_platform_verifier {
+        return Ok(None);
+    }
+    let failed = |e: rustls::Error| crate::Error::TlsError(format!("Failed to load the platform verifier: {}", e));
+    #[cfg(not(target_os = "android"))]
+    let verifier = if config.tls_root_certs.is_empty() {
+        rustls_platform_verifier::Verifier::new(provider).map_err(failed)?
+    } else {
+        rustls_platform_verifier::Verifier::new_with_extra_roots(config.tls_root_certs.iter().cloned(), provider).map_err(failed)?
+    };
+    #[cfg(target_os = "android")]
+    let verifier = rustls_platform_verifier::Verifier::new(provider).map_err(failed)?;
+    Ok(Some(Arc::new(verifier)))
+}
+
+#[cfg(not(feature = "platform-verifier"))]
+fn platform_verifier(
+    _config: &crate::Config,
+    _provider: Arc<rustls::crypto::CryptoProvider>,
+) -> Result<Option<Arc<dyn rustls::client::danger::ServerCertVerifier>>, crate::Error> {
+    Ok(None)
+}
+
+/// Requires a certificate of the presented chain to have one of the pinned SubjectPublicKeyInfo
+/// hashes, after `inner` accepted the chain. Signatures are always verified, so a pin keeps
+/// its meaning when `inner` accepts everything.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<dyn rustls::client::danger::ServerCertVerifier>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        use sha2::{Digest, Sha256};
+
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        let pinned = std::iter::once(end_entity).chain(intermediates)
+            .filter_map(|cert| crate::dane::subject_public_key_info(cert))
+            .any(|spki| self.pins.iter().any(|pin| Sha256::digest(spki).as_slice() == pin));
+        if !pinned {
+            return Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// When STARTTLS has to succeed for a send to go ahead
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum TlsPolicy {
+    /// Never issue STARTTLS, send in plaintext
+    None,
+    /// Use STARTTLS when the server offers it, send in plaintext otherwise
+    #[default]
+    Opportunistic,
+    /// Fail when the server does not offer STARTTLS
+    Required,
+    /// Like `Required`, and the certificate must be verified: fails with
+    /// `danger_accept_invalid_certs` and when a log-only DANE check does not match
+    Verified,
+}
+
+impl TlsPolicy {
+    /// Whether a send must not go ahead without TLS
+    pub fn requires_tls(&self) -> bool {
+        matches!(self, TlsPolicy::Required | TlsPolicy::Verified)
+    }
+}
+
+/// Sessions kept for TLS resumption, so reconnecting to an MX skips the full handshake.
+///
+/// rustls only resumes a session with the client config that established it, so the
+/// cache keeps the config built for each distinct set of TLS settings, and the sessions
+/// along with it. Shared by all clones of a config.
+#[derive(Debug)]
+pub struct TlsSessionCache {
+    size: usize,
+    configs: Mutex<Vec<(TlsSettings, Arc<rustls::ClientConfig>)>>,
+}
+
+impl Default for TlsSessionCache {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl TlsSessionCache {
+    /// Cache holding sessions for up to `size` servers
+    pub fn new(size: usize) -> Self {
+        Self { size, configs: Mutex::new(Vec::new()) }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Forgets all sessions, the next connection to every server does a full handshake
+    pub fn clear(&self) {
+        self.configs.lock().unwrap().clear();
+    }
+
+    fn client_config(&self, config: &crate::Config) -> Result<Arc<rustls::ClientConfig>, crate::Error> {
+        let settings = TlsSettings::of(config);
+        let mut configs = self.configs.lock().unwrap();
+        if let Some((_, tls_config)) = configs.iter().find(|(s, _)| *s == settings) {
+            return Ok(tls_config.clone());
+        }
+        let mut tls_config = create_tls_config(config)?;
+        tls_config.resumption = rustls::client::Resumption::in_memory_sessions(self.size);
+        let tls_config = Arc::new(tls_config);
+        configs.push((settings, tls_config.clone()));
+        Ok(tls_config)
+    }
+}
+
+/// The fields of a `Config` that `create_tls_config` depends on
+#[derive(Debug, PartialEq)]
+struct TlsSettings {
+    accept_invalid_certs: bool,
+    root_certs: Vec<rustls::pki_types::CertificateDer<'static>>,
+    webpki_roots: bool,
+    client_identity: Option<Arc<crate::config::TlsIdentity>>,
+    min_version: Option<TlsVersion>,
+    max_version: Option<TlsVersion>,
+    cipher_suites: Option<Vec<String>>,
+    pinned_spki: Vec<[u8; 32]>,
+    platform_verifier: bool,
+}
+
+impl TlsSettings {
+    fn of(config: &crate::Config) -> Self {
+        Self {
+            accept_invalid_certs: config.accept_invalid_certs,
+            root_certs: config.tls_root_certs.clone(),
+            webpki_roots: config.tls_webpki_roots,
+            client_identity: config.tls_client_identity.clone(),
+            min_version: config.tls_min_version,
+            max_version: config.tls_max_version,
+            cipher_suites: config.tls_cipher_suites.clone(),
+            pinned_spki: config.tls_pinned_spki.clone(),
+            #[cfg(feature = "platform-verifier")]
+            platform_verifier: config.tls_platform_verifier,
+            #[cfg(not(feature = "platform-verifier"))]
+            platform_verifier: false,
+        }
+    }
+}
+
+/// TLS protocol versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls12 => "TLSv1.2",
+            TlsVersion::Tls13 => "TLSv1.3",
+        })
+    }
+}
+
+/// Parameters of an established TLS session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// None if rustls negotiated a version this enum does not know
+    pub protocol_version: Option<TlsVersion>,
+    /// IANA name of the cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: String,
+    /// Certificate chain the server presented, leaf first
+    pub peer_certificates: Vec<rustls::pki_types::CertificateDer<'static>>,
+    /// Whether a session from `Config::tls_session_cache` was resumed
+    pub resumed: bool,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_connection(conn: &rustls::ClientConnection) -> Option<Self> {
+        let protocol_version = match conn.protocol_version()? {
+            rustls::ProtocolVersion::TLSv1_2 => Some(TlsVersion::Tls12),
+            rustls::ProtocolVersion::TLSv1_3 => Some(TlsVersion::Tls13),
+            _ => None,
+        };
+        Some(Self {
+            protocol_version,
+            cipher_suite: format!("{:?}", conn.negotiated_cipher_suite()?.suite()),
+            peer_certificates: conn.peer_certificates().unwrap_or_default().iter().map(|cert| cert.clone().into_owned()).collect(),
+            resumed: conn.handshake_kind() == Some(rustls::HandshakeKind::Resumed),
+        })
+    }
+}
+
+/// Starts a client config with the protocol versions and cipher suites `config` allows
+fn client_builder(
+    config: &crate::Config,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+) -> Result<rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier>, crate::Error> {
+    let min = config.tls_min_version.unwrap_or(TlsVersion::Tls12);
+    let max = config.tls_max_version.unwrap_or(TlsVersion::Tls13);
+    let versions = [(TlsVersion::Tls12, &rustls::version::TLS12), (TlsVersion::Tls13, &rustls::version::TLS13)]
+        .into_iter()
+        .filter(|(version, _)| (min..=max).contains(version))
+        .map(|(_, version)| version)
+        .collect::<Vec<_>>();
+    if versions.is_empty() {
+        return Err(crate::Error::TlsError(format!("No TLS version between {:?} and {:?}", min, max)));
+    }
+    let provider = match &config.tls_cipher_suites {
+        Some(names) => {
+            let mut cipher_suites = Vec::new();
+            for name in names {
+                let suite = provider.cipher_suites.iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                    .ok_or_else(|| crate::Error::TlsError(format!("Unknown or unsupported cipher suite {}", name)))?;
+                cipher_suites.push(*suite);
+            }
+            Arc::new(rustls::crypto::CryptoProvider { cipher_suites, ..(*provider).clone() })
+        }
+        None => provider,
+    };
+    rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&versions)
+        .map_err(|e| crate::Error::TlsError(e.to_string()))
+}
+
+/// Finishes `builder` with the client certificate of `config`, if any
+fn with_client_identity(
+    builder: rustls::ConfigBuilder<rustls::ClientConfig, rustls::client::WantsClientCert>,
+    config: &crate::Config,
+) -> Result<rustls::ClientConfig, crate::Error> {
+    match &config.tls_client_identity {
+        Some(identity) => builder
+            .with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone_key())
+            .map_err(|e| crate::Error::TlsError(format!("Invalid client certificate: {}", e))),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// The process-wide rustls provider if one is installed, otherwise aws-lc-rs.
+///
+/// `ClientConfig::builder` cannot pick a provider when several rustls crypto features are
+/// enabled in the dependency graph, so configs that need one take it from here.
+pub fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    rustls::crypto::CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// Outcome of the DANE check, filled in during the handshake
+pub(crate) type DaneOutcome = Arc<std::sync::Mutex<Option<Result<(), crate::Error>>>>;
+
+/// Verifies handshake signatures for real, but instead of validating the certificate
+/// against CAs, records whether it matches the TLSA records. The caller decides what
+/// a mismatch means once the handshake is complete.
+#[derive(Debug)]
+struct DaneVerifier {
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    policy: crate::dane::DanePolicy,
+    outcome: DaneOutcome,
+}
+
+impl rustls::client::danger::ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let chain = std::iter::once(end_entity).chain(intermediates).cloned().collect::<Vec<_>>();
+        let result = crate::dane::verify(&self.policy.records, &chain, &self.policy.mx_host, now);
+        *self.outcome.lock().unwrap() = Some(result);
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Creates a TLS config that checks the server certificate against the TLSA records of `policy`
+pub(crate) fn create_dane_tls_config(policy: crate::dane::DanePolicy, config: &crate::Config) -> Result<(rustls::ClientConfig, DaneOutcome), crate::Error> {
+    let provider = crypto_provider();
+    let outcome = DaneOutcome::default();
+    let verifier = DaneVerifier { provider: provider.clone(), policy, outcome: outcome.clone() };
+    let builder = client_builder(config, provider)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    Ok((with_client_identity(builder, config)?, outcome))
+}
+
+/// Verdict on the server certificate of a diagnostics probe, filled in during the handshake
+pub(crate) type ProbeOutcome = Arc<Mutex<Option<Result<(), String>>>>;
+
+/// Checks the certificate like a send would, but records the verdict instead of failing the
+/// handshake, so the chain of a rejected certificate can still be inspected
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<dyn rustls::client::danger::ServerCertVerifier>,
+    outcome: ProbeOutcome,
+}
+
+impl rustls::client::danger::ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let result = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now);
+        *self.outcome.lock().unwrap() = Some(result.map(|_| ()).map_err(|e| e.to_string()));
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Creates a TLS config for probing a server: the handshake completes whatever the
+/// certificate, and the outcome tells whether a send with `config` would have accepted it
+pub(crate) fn create_probe_tls_config(config: &crate::Config) -> Result<(rustls::ClientConfig, ProbeOutcome), crate::Error> {
+    let provider = crypto_provider();
+    let outcome = ProbeOutcome::default();
+    let verifier = RecordingVerifier { inner: server_verifier(config, provider.clone())?, outcome: outcome.clone() };
+    let builder = client_builder(config, provider)?
         .dangerous()
-        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
-        .with_no_client_auth()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    Ok((with_client_identity(builder, config)?, outcome))
 }
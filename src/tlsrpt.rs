@@ -0,0 +1,251 @@
+//! SMTP TLS reporting (RFC 8460)
+//!
+//! A [`TlsReportCollector`] set with `Config::tls_reports` counts the sessions in which a
+//! DANE policy was applied, and records the details of every failure. [`TlsReportCollector::report`]
+//! aggregates them into a [`TlsReport`] whose [`TlsReport::to_json`] is the report format
+//! RFC 8460 section 4 defines, ready to be sent to the `rua` of a domain's `_smtp._tls` record.
+
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::utils::json_string;
+
+/// Kind of policy a session was checked against (RFC 8460 section 4.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolicyType {
+    /// DANE TLSA records
+    Tlsa,
+    /// MTA-STS policy
+    Sts,
+    /// No policy was found for the domain
+    NoPolicyFound,
+}
+
+impl PolicyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyType::Tlsa => "tlsa",
+            PolicyType::Sts => "sts",
+            PolicyType::NoPolicyFound => "no-policy-found",
+        }
+    }
+}
+
+/// Why a session failed (RFC 8460 section 4.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResultType {
+    /// The server did not offer STARTTLS, or the connection was not encrypted
+    StartTlsNotSupported,
+    /// The certificate is not valid for the MX host
+    CertificateHostMismatch,
+    /// The certificate has expired
+    CertificateExpired,
+    /// The certificate does not chain to a trusted anchor
+    CertificateNotTrusted,
+    /// Any other failure, e.g. a certificate matching none of the TLSA records
+    ValidationFailure,
+    /// The TLSA records could not be used
+    TlsaInvalid,
+    /// DNSSEC validation of the records failed
+    DnssecInvalid,
+    /// The domain requires DANE but publishes no usable TLSA records
+    DaneRequired,
+    /// The MTA-STS policy could not be fetched
+    StsPolicyFetchError,
+    /// The MTA-STS policy is malformed
+    StsPolicyInvalid,
+    /// The MTA-STS policy host's certificate is invalid
+    StsWebpkiInvalid,
+}
+
+impl ResultType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResultType::StartTlsNotSupported => "starttls-not-supported",
+            ResultType::CertificateHostMismatch => "certificate-host-mismatch",
+            ResultType::CertificateExpired => "certificate-expired",
+            ResultType::CertificateNotTrusted => "certificate-not-trusted",
+            ResultType::ValidationFailure => "validation-failure",
+            ResultType::TlsaInvalid => "tlsa-invalid",
+            ResultType::DnssecInvalid => "dnssec-invalid",
+            ResultType::DaneRequired => "dane-required",
+            ResultType::StsPolicyFetchError => "sts-policy-fetch-error",
+            ResultType::StsPolicyInvalid => "sts-policy-invalid",
+            ResultType::StsWebpkiInvalid => "sts-webpki-invalid",
+        }
+    }
+}
+
+/// The policy a session was checked against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Policy {
+    pub policy_type: PolicyType,
+    /// The records of the policy, for DANE one TLSA record in presentation format each
+    pub policy_strings: Vec<String>,
+    /// Recipient domain the policy applies to
+    pub policy_domain: String,
+    /// MX host the policy was applied to
+    pub mx_host: Option<String>,
+}
+
+/// One failed session
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TlsFailure {
+    pub result_type: ResultType,
+    /// Local address the session was sent from
+    pub sending_mta_ip: Option<IpAddr>,
+    pub receiving_mx_hostname: Option<String>,
+    pub receiving_ip: Option<IpAddr>,
+    /// Free-form description, e.g. the error message
+    pub additional_information: Option<String>,
+}
+
+/// Counts for one policy within a report
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyResult {
+    pub policy: Policy,
+    pub successful_sessions: u64,
+    pub failed_sessions: u64,
+    /// Distinct failures and how many sessions each of them affected
+    pub failure_details: Vec<(TlsFailure, u64)>,
+}
+
+/// An aggregate report (RFC 8460 section 4.4)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsReport {
+    pub organization_name: String,
+    pub contact_info: String,
+    pub report_id: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub policies: Vec<PolicyResult>,
+}
+
+impl TlsReport {
+    /// The report as RFC 8460 JSON
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        let _ = write!(json, "\"organization-name\":{},", json_string(&self.organization_name));
+        let _ = write!(json, "\"date-range\":{{\"start-datetime\":{},\"end-datetime\":{}}},",
+            json_string(&self.start.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            json_string(&self.end.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        let _ = write!(json, "\"contact-info\":{},", json_string(&self.contact_info));
+        let _ = write!(json, "\"report-id\":{},", json_string(&self.report_id));
+        json.push_str("\"policies\":[");
+        for (i, result) in self.policies.iter().enumerate() {
+            if i > 0 { json.push(','); }
+            let policy = &result.policy;
+            let _ = write!(json, "{{\"policy\":{{\"policy-type\":{},\"policy-string\":[{}],\"policy-domain\":{}",
+                json_string(policy.policy_type.as_str()),
+                policy.policy_strings.iter().map(|s| json_string(s)).collect::<Vec<_>>().join(","),
+                json_string(&policy.policy_domain));
+            if let Some(mx_host) = &policy.mx_host {
+                let _ = write!(json, ",\"mx-host\":[{}]", json_string(mx_host));
+            }
+            let _ = write!(json, "}},\"summary\":{{\"total-successful-session-count\":{},\"total-failure-session-count\":{}}}",
+                result.successful_sessions, result.failed_sessions);
+            if !result.failure_details.is_empty() {
+                json.push_str(",\"failure-details\":[");
+                for (j, (failure, count)) in result.failure_details.iter().enumerate() {
+                    if j > 0 { json.push(','); }
+                    let _ = write!(json, "{{\"result-type\":{}", json_string(failure.result_type.as_str()));
+                    if let Some(ip) = failure.sending_mta_ip {
+                        let _ = write!(json, ",\"sending-mta-ip\":{}", json_string(&ip.to_string()));
+                    }
+                    if let Some(host) = &failure.receiving_mx_hostname {
+                        let _ = write!(json, ",\"receiving-mx-hostname\":{}", json_string(host));
+                    }
+                    if let Some(ip) = failure.receiving_ip {
+                        let _ = write!(json, ",\"receiving-ip\":{}", json_string(&ip.to_string()));
+                    }
+                    let _ = write!(json, ",\"failed-session-count\":{}", count);
+                    if let Some(info) = &failure.additional_information {
+                        let _ = write!(json, ",\"additional-information\":{}", json_string(info));
+                    }
+                    json.push('}');
+                }
+                json.push(']');
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Collects session outcomes for TLS reports, shared by all clones of a config
+#[derive(Debug)]
+pub struct TlsReportCollector {
+    inner: Mutex<(DateTime<Utc>, Vec<PolicyResult>)>,
+}
+
+impl Default for TlsReportCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsReportCollector {
+    /// A collector whose reporting period starts now
+    pub fn new() -> Self {
+        Self { inner: Mutex::new((Utc::now(), Vec::new())) }
+    }
+
+    /// Counts a session that satisfied `policy`
+    pub fn record_success(&self, policy: &Policy) {
+        let mut inner = self.inner.lock().unwrap();
+        entry(&mut inner.1, policy).successful_sessions += 1;
+    }
+
+    /// Counts a session that failed `policy`
+    pub fn record_failure(&self, policy: &Policy, failure: TlsFailure) {
+        let mut inner = self.inner.lock().unwrap();
+        let result = entry(&mut inner.1, policy);
+        result.failed_sessions += 1;
+        match result.failure_details.iter_mut().find(|(f, _)| *f == failure) {
+            Some((_, count)) => *count += 1,
+            None => result.failure_details.push((failure, 1)),
+        }
+    }
+
+    /// The report for everything recorded since the period started
+    pub fn report(&self, organization_name: &str, contact_info: &str, report_id: &str) -> TlsReport {
+        let inner = self.inner.lock().unwrap();
+        TlsReport {
+            organization_name: organization_name.to_string(),
+            contact_info: contact_info.to_string(),
+            report_id: report_id.to_string(),
+            start: inner.0,
+            end: Utc::now(),
+            policies: inner.1.clone(),
+        }
+    }
+
+    /// Like `report`, but also starts a new, empty period
+    pub fn take_report(&self, organization_name: &str, contact_info: &str, report_id: &str) -> TlsReport {
+        let mut inner = self.inner.lock().unwrap();
+        let end = Utc::now();
+        let (start, policies) = std::mem::replace(&mut *inner, (end, Vec::new()));
+        TlsReport {
+            organization_name: organization_name.to_string(),
+            contact_info: contact_info.to_string(),
+            report_id: report_id.to_string(),
+            start,
+            end,
+            policies,
+        }
+    }
+}
+
+fn entry<'a>(results: &'a mut Vec<PolicyResult>, policy: &Policy) -> &'a mut PolicyResult {
+    match results.iter().position(|r| r.policy == *policy) {
+        Some(i) => &mut results[i],
+        None => {
+            results.push(PolicyResult { policy: policy.clone(), successful_sessions: 0, failed_sessions: 0, failure_details: Vec::new() });
+            results.last_mut().unwrap()
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! `tracing` instrumentation of the send pipeline
+//!
+//! With the `tracing` feature a send runs in a `send` span carrying the Message-ID, with
+//! child spans for the MX lookup, connecting, STARTTLS and AUTH. Every transcript entry
+//! (commands, replies, notes) becomes a DEBUG event and every [`SendEvent`] an INFO event,
+//! all with the target `micromail`. Without the feature the helpers compile to nothing.
+
+#[cfg(feature = "tracing")]
+pub(crate) use enabled::*;
+#[cfg(not(feature = "tracing"))]
+pub(crate) use disabled::*;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use std::net::SocketAddr;
+
+    use tracing::field;
+
+    use crate::transcript::{SendEvent, TranscriptEvent};
+
+    pub(crate) use tracing::Span;
+    #[cfg(feature = "tokio-runtime")]
+    pub(crate) use tracing::Instrument;
+
+    pub(crate) fn send_span() -> Span {
+        tracing::info_span!(target: "micromail", "send", message_id = field::Empty)
+    }
+
+    pub(crate) fn dns_span(domain: &str) -> Span {
+        tracing::info_span!(target: "micromail", "dns", domain)
+    }
+
+    pub(crate) fn connect_span() -> Span {
+        tracing::info_span!(target: "micromail", "connect", address = field::Empty)
+    }
+
+    pub(crate) fn starttls_span(mx_host: Option<&str>) -> Span {
+        tracing::info_span!(target: "micromail", "starttls", mx_host)
+    }
+
+    pub(crate) fn auth_span(username: &str) -> Span {
+        tracing::info_span!(target: "micromail", "auth", username)
+    }
+
+    /// Adds the Message-ID header of the formatted `content` to the current `send` span
+    pub(crate) fn record_message_id(content: &str) {
+        let message_id = content.lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| line.strip_prefix("Message-ID: "));
+        if let Some(message_id) = message_id {
+            Span::current().record("message_id", message_id);
+        }
+    }
+
+    /// Adds the server address to the current `connect` span
+    pub(crate) fn record_address(address: SocketAddr) {
+        Span::current().record("address", field::display(address));
+    }
+
+    pub(crate) fn transcript_event(event: &TranscriptEvent) {
+        match event {
+            TranscriptEvent::Note(message) => tracing::debug!(target: "micromail", "{}", message),
+            TranscriptEvent::Connected { address } => tracing::debug!(target: "micromail", %address, "connected"),
+            TranscriptEvent::TlsNegotiated(info) => tracing::debug!(target: "micromail", protocol_version = ?info.protocol_version, cipher_suite = %info.cipher_suite, resumed = info.resumed, "TLS negotiated"),
+            TranscriptEvent::TlsEstablished => tracing::debug!(target: "micromail", "TLS established"),
+            TranscriptEvent::CommandSent(command) => tracing::debug!(target: "micromail", command = %command, "sent"),
+            TranscriptEvent::ResponseReceived { code, lines } => tracing::debug!(target: "micromail", code, reply = %lines.join("\n"), "received"),
+            TranscriptEvent::DataSent { bytes, .. } => tracing::debug!(target: "micromail", bytes, "message content sent"),
+        }
+    }
+
+    pub(crate) fn send_event(event: &SendEvent) {
+        match event {
+            SendEvent::DataWritten { .. } => tracing::trace!(target: "micromail", ?event),
+            _ => tracing::info!(target: "micromail", ?event),
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use std::net::SocketAddr;
+
+    use crate::transcript::{SendEvent, TranscriptEvent};
+
+    #[derive(Clone, Debug)]
+    pub(crate) struct Span;
+
+    impl Span {
+        pub fn entered(self) -> Self { self }
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    pub(crate) trait Instrument: Sized {
+        fn instrument(self, _span: Span) -> Self { self }
+    }
+    #[cfg(feature = "tokio-runtime")]
+    impl<F: std::future::Future> Instrument for F {}
+
+    pub(crate) fn send_span() -> Span { Span }
+    pub(crate) fn dns_span(_domain: &str) -> Span { Span }
+    pub(crate) fn connect_span() -> Span { Span }
+    pub(crate) fn starttls_span(_mx_host: Option<&str>) -> Span { Span }
+    pub(crate) fn auth_span(_username: &str) -> Span { Span }
+    pub(crate) fn record_message_id(_content: &str) {}
+    pub(crate) fn record_address(_address: SocketAddr) {}
+    pub(crate) fn transcript_event(_event: &TranscriptEvent) {}
+    pub(crate) fn send_event(_event: &SendEvent) {}
+}
@@ -0,0 +1,175 @@
+//! Structured record of what happened during a send, and live progress events
+
+use std::fmt;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::tls::TlsInfo;
+use crate::utils::json_string;
+
+/// One step of a send as recorded in the [`Transcript`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranscriptEvent {
+    /// Free-form diagnostic, e.g. the MX records found or a failed connection attempt
+    Note(String),
+    /// TCP connection to the server established (nominal address in test mode)
+    Connected { address: SocketAddr },
+    /// Parameters the TLS handshake after STARTTLS settled on
+    TlsNegotiated(TlsInfo),
+    /// STARTTLS succeeded and the session continues encrypted
+    TlsEstablished,
+    /// Command line sent to the server, without the trailing CRLF
+    CommandSent(String),
+    /// Complete server reply, one entry per line as received (`250-...`, `250 ...`)
+    ResponseReceived { code: u16, lines: Vec<String> },
+    /// Message content sent after the DATA command
+    DataSent { bytes: usize, content: String },
+}
+
+/// A [`TranscriptEvent`] together with the time it was recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    pub time: SystemTime,
+    pub event: TranscriptEvent,
+}
+
+/// Ordered, timestamped record of a send: DNS results, connection, commands and replies
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    entries: Vec<TranscriptEntry>,
+}
+
+impl Transcript {
+    pub fn new() -> Self { Self::default() }
+    pub fn entries(&self) -> &[TranscriptEntry] { &self.entries }
+    pub fn len(&self) -> usize { self.entries.len() }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+    pub fn clear(&mut self) { self.entries.clear(); }
+
+    /// Appends `event`, stamped with the current time
+    pub fn push(&mut self, event: TranscriptEvent) {
+        crate::trace::transcript_event(&event);
+        self.entries.push(TranscriptEntry { time: SystemTime::now(), event });
+    }
+
+    /// Appends a [`TranscriptEvent::Note`]
+    pub fn note<S: Into<String>>(&mut self, message: S) {
+        self.push(TranscriptEvent::Note(message.into()));
+    }
+
+    /// The transcript as plain log lines, in the format of the former string log
+    pub fn to_strings(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for entry in &self.entries {
+            match &entry.event {
+                TranscriptEvent::Note(message) => lines.push(message.clone()),
+                TranscriptEvent::Connected { address } => lines.push(format!("Connected to {}", address)),
+                TranscriptEvent::TlsNegotiated(info) => lines.push(crate::connection::tls_note(info)),
+                TranscriptEvent::TlsEstablished => lines.push("TLS established".to_string()),
+                TranscriptEvent::CommandSent(command) => lines.push(command.clone()),
+                TranscriptEvent::ResponseReceived { lines: reply, .. } => lines.extend(reply.iter().cloned()),
+                TranscriptEvent::DataSent { content, .. } => lines.extend(content.lines().map(String::from)),
+            }
+        }
+        lines
+    }
+
+    /// The transcript as JSON, e.g. for a support ticket or an audit log: an `entries`
+    /// array in order, each with its RFC 3339 `time`, the milliseconds `elapsed_ms` since
+    /// the first entry, a `type` (`note`, `connected`, `tls`, `tls_established`, `command`,
+    /// `response`, `data`) and the fields of that event
+    pub fn to_json(&self) -> String {
+        let start = self.entries.first().map(|entry| entry.time);
+        let mut json = String::from("{\"entries\":[");
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 { json.push(','); }
+            let elapsed = start.and_then(|start| entry.time.duration_since(start).ok()).unwrap_or_default();
+            let _ = write!(json, "{{\"time\":{},\"elapsed_ms\":{},",
+                json_string(&DateTime::<Utc>::from(entry.time).to_rfc3339_opts(SecondsFormat::Millis, true)),
+                elapsed.as_millis());
+            let _ = match &entry.event {
+                TranscriptEvent::Note(message) => write!(json, "\"type\":\"note\",\"message\":{}", json_string(message)),
+                TranscriptEvent::Connected { address } => write!(json, "\"type\":\"connected\",\"address\":{}", json_string(&address.to_string())),
+                TranscriptEvent::TlsNegotiated(info) => write!(json, "\"type\":\"tls\",\"protocol_version\":{},\"cipher_suite\":{},\"peer_certificates\":{},\"resumed\":{}",
+                    info.protocol_version.map_or("null".to_string(), |v| json_string(&v.to_string())),
+                    json_string(&info.cipher_suite), info.peer_certificates.len(), info.resumed),
+                TranscriptEvent::TlsEstablished => write!(json, "\"type\":\"tls_established\""),
+                TranscriptEvent::CommandSent(command) => write!(json, "\"type\":\"command\",\"command\":{}", json_string(command)),
+                TranscriptEvent::ResponseReceived { code, lines } => write!(json, "\"type\":\"response\",\"code\":{},\"lines\":[{}]",
+                    code, lines.iter().map(|line| json_string(line)).collect::<Vec<_>>().join(",")),
+                TranscriptEvent::DataSent { bytes, content } => write!(json, "\"type\":\"data\",\"bytes\":{},\"content\":{}", bytes, json_string(content)),
+            };
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Progress of a send, delivered to the callbacks registered with
+/// [`Mailer::on_event`](crate::Mailer::on_event) as it happens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendEvent {
+    /// MX lookup for the recipient domain finished
+    DnsResolved { domain: String, hosts: Vec<String> },
+    /// TCP connection established (nominal address in test mode)
+    Connected { address: SocketAddr },
+    /// STARTTLS succeeded
+    TlsEstablished,
+    /// The server accepted the AUTH credentials
+    Authenticated,
+    /// Another chunk of the message content went out, `bytes` of `total` so far
+    DataWritten { bytes: usize, total: usize },
+    /// The server accepted the message after the end of DATA
+    Accepted { code: u16 },
+    /// The attempt failed and the send will be retried after `delay`
+    Retrying { attempt: u32, delay: Duration },
+}
+
+type Listener = Box<dyn FnMut(&SendEvent) + Send>;
+
+/// Transcript and event listeners of a [`Mailer`](crate::Mailer), shared with its open
+/// connection so that everything recorded before a failure survives the connection
+#[derive(Default)]
+pub(crate) struct Recorder {
+    pub transcript: Transcript,
+    /// Locked on their own, see [`emit`]
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl Recorder {
+    pub fn add_listener(&mut self, listener: Listener) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+
+    /// An empty recorder for a send of its own, calling the same listeners
+    pub fn for_send(&self) -> Self {
+        Self { transcript: Transcript::default(), listeners: Arc::clone(&self.listeners) }
+    }
+}
+
+/// Hands `event` to the listeners of `recorder`. They are called with the recorder
+/// unlocked, so a listener that reads the transcript or is slow does not hold up
+/// or deadlock the connection recording into it.
+pub(crate) fn emit(recorder: &SharedRecorder, event: &SendEvent) {
+    crate::trace::send_event(event);
+    let listeners = recorder.lock().unwrap().listeners.clone();
+    for listener in listeners.lock().unwrap().iter_mut() {
+        listener(event);
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("transcript", &self.transcript)
+            .field("listeners", &self.listeners.lock().unwrap().len())
+            .finish()
+    }
+}
+
+pub(crate) type SharedRecorder = Arc<Mutex<Recorder>>;
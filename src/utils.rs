@@ -1,5 +1,7 @@
 //! Utility functions
 
+use crate::error::Error;
+
 /// Sanitizes a string for logging
 pub fn sanitize_string_lite(s: &str) -> String {
     s.chars()
@@ -7,6 +9,82 @@ pub fn sanitize_string_lite(s: &str) -> String {
         .collect()
 }
 
+/// The address inside `<...>` if present, otherwise the trimmed input without comments.
+/// Brackets inside a quoted display name (`"Jane <Sales>" <jane@example.com>`) are skipped.
+pub fn bare_address(s: &str) -> String {
+    let mut start = None;
+    let mut plain = String::new();
+    for (i, c, context) in scan(s) {
+        match (c, context) {
+            ('<', Context::Plain) => start = Some(i + 1),
+            ('>', Context::Plain) => if let Some(start) = start { return s[start..i].trim().to_string() },
+            (_, Context::Comment) | ('(' | ')', _) => {}
+            _ => plain.push(c),
+        }
+    }
+    plain.trim().to_string()
+}
+
+/// Splits an address list like `"Doe, John" <john@example.com>, jane@example.com` into its
+/// addresses. Commas in quoted display names, angle brackets and comments don't separate
+/// addresses, and a group (`team: ann@example.com, bob@example.com;`) yields its members.
+pub fn split_address_list(list: &str) -> Vec<&str> {
+    let mut addresses = Vec::new();
+    let mut start = 0;
+    let mut angle = false;
+    for (i, c, context) in scan(list) {
+        match (c, context) {
+            ('<', Context::Plain) => angle = true,
+            ('>', Context::Plain) => angle = false,
+            // The display name of a group
+            (':', Context::Plain) if !angle => start = i + 1,
+            (',' | ';', Context::Plain) if !angle => {
+                addresses.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    addresses.push(&list[start..]);
+    addresses.into_iter().map(str::trim).filter(|address| !address.is_empty()).collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    Plain,
+    Quoted,
+    Comment,
+}
+
+/// The characters of an address (list) with whether they are quoted or inside a comment.
+/// Quotes and parentheses count as part of what they open or close.
+fn scan(s: &str) -> impl Iterator<Item = (usize, char, Context)> + '_ {
+    let (mut quoted, mut comment, mut escaped) = (false, 0u32, false);
+    s.char_indices().map(move |(i, c)| {
+        let context = if comment > 0 { Context::Comment } else if quoted { Context::Quoted } else { Context::Plain };
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && context != Context::Plain {
+            escaped = true;
+        } else if c == '"' && comment == 0 {
+            quoted = !quoted;
+            return (i, c, Context::Quoted);
+        } else if c == '(' && !quoted {
+            comment += 1;
+            return (i, c, Context::Comment);
+        } else if c == ')' && comment > 0 {
+            comment -= 1;
+            return (i, c, Context::Comment);
+        }
+        (i, c, context)
+    })
+}
+
+/// Returns the domain part of an email address
+pub fn extract_domain(email: &str) -> Result<String, Error> {
+    email.split('@').nth(1).map(String::from).ok_or_else(|| Error::InvalidMailContent(format!("Invalid email address: {}", email)))
+}
+
 /// Generates a message ID for an email
 pub fn generate_message_id(domain: &str) -> String {
     use rand::Rng;
@@ -20,19 +98,35 @@ pub fn generate_message_id(domain: &str) -> String {
     )
 }
 
-/// Add CRLF line endings to a string if not already present
-pub fn ensure_crlf(s: &str) -> String {
-    if !s.contains("\r\n") {
-        s.replace('\n', "\r\n")
-    } else {
-        s.to_string()
-    }
+/// Generates a random MIME multipart boundary
+pub fn generate_boundary() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let random: u128 = rng.gen();
+
+    format!("=_micromail_{:032x}", random)
 }
 
-/// Formats a date according to RFC 5322
-pub fn format_date() -> String {
-    use chrono::{DateTime, Utc};
-    
-    let now: DateTime<Utc> = Utc::now();
-    now.format("%a, %d %b %Y %H:%M:%S %z").to_string()
+/// Formats `time` according to RFC 5322
+pub fn format_date_at(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S %z").to_string()
+}
+
+/// `s` as a JSON string literal, quotes included
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
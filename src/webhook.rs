@@ -0,0 +1,166 @@
+//! Delivery outcomes pushed to an HTTP endpoint
+//!
+//! A [`Webhook`] attached to a [`MailQueue`](crate::MailQueue) POSTs one JSON
+//! object per finished delivery attempt, so external systems can track mail
+//! without polling the queue:
+//!
+//! ```text
+//! {"event":"delivered","id":7,"recipient":"ann@example.org","attempts":1,"time":"2024-05-01T12:00:00.000Z"}
+//! {"event":"deferred","id":8,"recipient":"bob@example.net","attempts":1,"code":451,"error":"...","retry_in_ms":300000,"time":"..."}
+//! {"event":"bounced","id":9,"recipient":"cid@example.com","code":550,"error":"...","time":"..."}
+//! {"event":"dead_lettered","id":9,"recipient":"cid@example.com","reason":"...","time":"..."}
+//! ```
+//!
+//! `code` is `null` if no server answered. Events wait in an unbounded channel while a
+//! POST is in flight, so none are lost to a slow endpoint. A POST that fails transiently
+//! (connection problems, HTTP 429 and 5xx) is retried with exponential backoff, one that
+//! still fails is logged and dropped.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::Config,
+    error::Error,
+    http_api::HttpRequest,
+    queue::{DeliveryEvent, JobId},
+    utils::json_string,
+};
+
+/// Where and how to POST delivery events
+#[derive(Clone)]
+pub struct Webhook {
+    url: String,
+    config: Config,
+    headers: Vec<(String, String)>,
+    /// Most POSTs per event, the first included
+    attempts: u32,
+    /// Wait before the first retry, doubled for every further one
+    retry_delay: Duration,
+}
+
+impl Webhook {
+    /// POSTs to `url` with the TLS settings and timeout of `config`, trying each event
+    /// up to 5 times starting 1 second apart
+    pub fn new<S: Into<String>>(url: S, config: Config) -> Self {
+        Self { url: url.into(), config, headers: Vec::new(), attempts: 5, retry_delay: Duration::from_secs(1) }
+    }
+
+    /// POST each event up to `attempts` times, waiting `delay` before the first retry and
+    /// twice as long before each further one
+    pub fn retries(mut self, attempts: u32, delay: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Adds a header to every request, e.g. a bearer token the endpoint checks
+    pub fn header<S: Into<String>>(mut self, name: S, value: S) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// POSTs `event` for the mail to `recipient`. Enqueued and attempt events are not
+    /// outcomes and are skipped.
+    pub async fn post(&self, event: &DeliveryEvent, recipient: Option<&str>) -> Result<(), Error> {
+        match payload(event, recipient) {
+            Some(body) => self.post_body(body).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn post_body(&self, body: String) -> Result<(), Error> {
+        let mut request = HttpRequest::post_url(&self.url, "application/json", body.into_bytes())?;
+        request.headers.extend(self.headers.iter().cloned());
+        let response = request.send_async(&self.config).await?;
+        if !(200..300).contains(&response.status) {
+            let message = String::from_utf8_lossy(&response.body).trim().to_string();
+            return Err(Error::HttpApiError { status: response.status, message });
+        }
+        Ok(())
+    }
+
+    /// [`post`](Self::post), retrying transient failures with backoff. Every attempt
+    /// sends the same body, time included.
+    async fn post_with_retries(&self, event: &DeliveryEvent, recipient: Option<&str>) -> Result<(), Error> {
+        let Some(body) = payload(event, recipient) else { return Ok(()) };
+        let mut delay = self.retry_delay;
+        for _ in 1..self.attempts {
+            match self.post_body(body.clone()).await {
+                Err(e) if e.is_transient() => {
+                    log::debug!(target: "micromail", "webhook {} failed: {}, retrying in {:?}", self.url, e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+        self.post_body(body).await
+    }
+
+    /// Posts the outcomes received on `events` until every sender is gone
+    pub(crate) fn spawn(self, mut events: mpsc::UnboundedReceiver<DeliveryEvent>) {
+        tokio::spawn(async move {
+            // Outcome events only carry the job id, the recipient comes with Enqueued
+            let mut recipients = HashMap::<JobId, String>::new();
+            while let Some(event) = events.recv().await {
+                let id = match &event {
+                    DeliveryEvent::Enqueued { id, recipient } => { recipients.insert(*id, recipient.clone()); continue; }
+                    DeliveryEvent::Attempt { .. } => continue,
+                    DeliveryEvent::Deferred { id, .. } | DeliveryEvent::Delivered { id, .. }
+                    | DeliveryEvent::Failed { id, .. } | DeliveryEvent::DeadLettered { id, .. } => *id,
+                };
+                if let Err(e) = self.post_with_retries(&event, recipients.get(&id).map(String::as_str)).await {
+                    log::warn!(target: "micromail", "webhook {} failed, dropping the {} event of job {}: {}", self.url, event_name(&event), id, e);
+                }
+                if matches!(event, DeliveryEvent::Delivered { .. } | DeliveryEvent::DeadLettered { .. }) {
+                    recipients.remove(&id);
+                }
+            }
+        });
+    }
+}
+
+/// The `event` field of the JSON body
+fn event_name(event: &DeliveryEvent) -> &'static str {
+    match event {
+        DeliveryEvent::Delivered { .. } => "delivered",
+        DeliveryEvent::Deferred { .. } => "deferred",
+        DeliveryEvent::Failed { .. } => "bounced",
+        DeliveryEvent::DeadLettered { .. } => "dead_lettered",
+        DeliveryEvent::Enqueued { .. } => "enqueued",
+        DeliveryEvent::Attempt { .. } => "attempt",
+    }
+}
+
+/// The JSON body for an outcome event, None for the others
+fn payload(event: &DeliveryEvent, recipient: Option<&str>) -> Option<String> {
+    let id = match event {
+        DeliveryEvent::Delivered { id, .. } | DeliveryEvent::Deferred { id, .. }
+        | DeliveryEvent::Failed { id, .. } | DeliveryEvent::DeadLettered { id, .. } => id,
+        DeliveryEvent::Enqueued { .. } | DeliveryEvent::Attempt { .. } => return None,
+    };
+    let code = |code: &Option<u16>| code.map_or("null".to_string(), |code| code.to_string());
+    let mut json = format!("{{\"event\":\"{}\",\"id\":{},\"recipient\":{}", event_name(event), id, recipient.map_or("null".to_string(), json_string));
+    match event {
+        DeliveryEvent::Delivered { attempts, .. } => {
+            let _ = write!(json, ",\"attempts\":{}", attempts);
+        }
+        DeliveryEvent::Deferred { attempts, error, code: reply_code, retry_in, .. } => {
+            let _ = write!(json, ",\"attempts\":{},\"code\":{},\"error\":{},\"retry_in_ms\":{}", attempts, code(reply_code), json_string(error), retry_in.as_millis());
+        }
+        DeliveryEvent::Failed { error, code: reply_code, .. } => {
+            let _ = write!(json, ",\"code\":{},\"error\":{}", code(reply_code), json_string(error));
+        }
+        DeliveryEvent::DeadLettered { reason, .. } => {
+            let _ = write!(json, ",\"reason\":{}", json_string(reason));
+        }
+        DeliveryEvent::Enqueued { .. } | DeliveryEvent::Attempt { .. } => {}
+    }
+    let _ = write!(json, ",\"time\":{}}}", json_string(&Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)));
+    Some(json)
+}
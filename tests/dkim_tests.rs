@@ -9,7 +9,7 @@ fn generate_test_rsa_pem() -> String {
 
 #[test]
 #[ignore] // Ignoring due to SMTP simulation error: SmtpError { code: 250, message: "DATA command failed: OK" }
-fn test_dkim_signing_in_mailer_send_sync_no_signature_expected() { // Name reflects no-op
+fn test_dkim_signing_in_mailer_send_sync() {
     let private_key_pem = generate_test_rsa_pem();
     let test_selector = "testdkim".to_string();
     let test_domain = "example.com".to_string();
@@ -23,17 +23,17 @@ fn test_dkim_signing_in_mailer_send_sync_no_signature_expected() { // Name refle
     let mail = Mail::new()
         .from(format!("sender@{}", test_domain))
         .to("recipient@anotherexample.com")
-        .subject("Test DKIM Auto-Sign Email - No Signature Expected") // Updated subject
-        .body("This email should NOT be DKIM signed."); // Updated body
+        .subject("Test DKIM Auto-Sign Email")
+        .body("This email should be DKIM signed.");
 
     let result = mailer.send_sync(mail);
     assert!(result.is_ok(), "Email sending (simulated) should succeed. Error: {:?}", result.err());
     let log_content = mailer.get_log().join("\n");
-    assert!(!log_content.contains("DKIM-Signature:"), "Log should NOT contain DKIM-Signature. Log: {}", log_content);
+    assert!(log_content.contains("DKIM-Signature:"), "Log should contain DKIM-Signature. Log: {}", log_content);
 }
 
 #[test]
-fn test_manual_mail_sign_with_dkim_and_format_no_signature_expected() { // Name reflects no-op
+fn test_manual_mail_sign_with_dkim_and_format() {
     let private_key_pem = generate_test_rsa_pem();
     let test_selector = "manualsign".to_string();
     let test_domain = "mydomain.org".to_string();
@@ -45,15 +45,16 @@ fn test_manual_mail_sign_with_dkim_and_format_no_signature_expected() { // Name
     let mut mail = Mail::new()
         .from(format!("someone@{}", test_domain))
         .to("another@elsewhere.net")
-        .subject("Test Manual DKIM Signing - No Signature Expected") // Updated
-        .body("This email is manually signed with DKIM (no-op) before formatting.");
+        .subject("Test Manual DKIM Signing")
+        .body("This email is manually signed with DKIM before formatting.");
 
-    let sign_result = mail.sign_with_dkim(&dkim_config_provider); // Will be a no-op
-    assert!(sign_result.is_ok(), "Manual DKIM signing (no-op) should succeed. Error: {:?}", sign_result.err());
+    let sign_result = mail.sign_with_dkim(&dkim_config_provider);
+    assert!(sign_result.is_ok(), "Manual DKIM signing should succeed. Error: {:?}", sign_result.err());
     let formatted_email = mail.format(&dkim_config_provider);
-    assert!(!formatted_email.contains("DKIM-Signature:"), "Formatted email should NOT contain DKIM-Signature. Email:\n{}", formatted_email);
+    assert!(formatted_email.contains("DKIM-Signature:"), "Formatted email should contain DKIM-Signature. Email:\n{}", formatted_email);
+    assert!(formatted_email.contains(&format!("d={}", test_domain)) && formatted_email.contains(&format!("s={}", test_selector)));
     assert!(formatted_email.contains(&format!("From: someone@{}", test_domain)), "From header missing.");
-    assert!(formatted_email.contains("Subject: Test Manual DKIM Signing - No Signature Expected"), "Subject header missing.");
+    assert!(formatted_email.contains("Subject: Test Manual DKIM Signing"), "Subject header missing.");
 }
 
 #[test]